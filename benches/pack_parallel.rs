@@ -0,0 +1,39 @@
+//! Throughput of `pack_parallel` at a few thread counts, against single-threaded `pack`.
+//!
+//! Run with `cargo bench --features parallel`.
+
+use bzimage::BzImageHeader;
+use std::hint::black_box;
+use std::time::Instant;
+
+const SIZE: usize = 64 << 20;
+
+fn main() {
+    // compressible but not trivially so: a counter run through a cheap mixing step
+    let data: Vec<u8> = (0..SIZE as u32)
+        .map(|i| (i.wrapping_mul(2_654_435_761) >> 28) as u8 + b'a')
+        .collect();
+
+    let report = |name: &str, run: &dyn Fn() -> usize| {
+        let start = Instant::now();
+        let len = black_box(run());
+        let secs = start.elapsed().as_secs_f64();
+        println!(
+            "{name:>14}: {:7.1} MiB/s, {len} bytes",
+            SIZE as f64 / (1 << 20) as f64 / secs
+        );
+    };
+
+    report("pack", &|| {
+        let mut out = Vec::new();
+        BzImageHeader::pack(&data, &mut out).unwrap();
+        out.len()
+    });
+    for threads in [1, 2, 4, 8] {
+        report(&format!("parallel x{threads}"), &|| {
+            let mut out = Vec::new();
+            BzImageHeader::pack_parallel(&data, threads, &mut out).unwrap();
+            out.len()
+        });
+    }
+}
@@ -0,0 +1,120 @@
+//! Block-aligned buffered writing for direct IO.
+//!
+//! Files opened with `O_DIRECT` (and raw block devices) only accept writes whose buffer
+//! address, length and file offset are multiples of the device block size. `AlignedWriter`
+//! collects output in a block-aligned buffer and only ever hands the inner writer whole
+//! `DIRECT_IO_ALIGNMENT`-sized blocks, zero-padding the final one. The image format is
+//! unchanged; the padding simply follows the image, where readers never look.
+
+use crate::{BzImageError, BzImageHeader, Codec, HEADER_SIZE};
+use std::io::{self, Write};
+
+/// Alignment, in bytes, of every buffer address, write length and offset produced.
+pub const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+/// Number of blocks buffered before they are written out (1 MiB).
+const BUFFER_BLOCKS: usize = 256;
+
+#[repr(C, align(4096))]
+#[derive(Copy, Clone)]
+struct Block([u8; DIRECT_IO_ALIGNMENT]);
+
+fn as_bytes(blocks: &mut [Block]) -> &mut [u8] {
+    // SAFETY: `Block` is a `repr(C)` byte array without padding, so the blocks are contiguous,
+    // initialized bytes, and the start of the slice keeps the blocks' alignment.
+    unsafe {
+        std::slice::from_raw_parts_mut(
+            blocks.as_mut_ptr() as *mut u8,
+            blocks.len() * DIRECT_IO_ALIGNMENT,
+        )
+    }
+}
+
+/// A writer that forwards data to `W` in whole, aligned blocks.
+///
+/// `flush` writes out the complete blocks buffered so far; a trailing partial block is only
+/// written (zero-padded) by `finish`, which must be called to emit the last bytes.
+pub struct AlignedWriter<W: Write> {
+    inner: W,
+    buf: Vec<Block>,
+    len: usize,
+    written: u64,
+}
+
+impl<W: Write> AlignedWriter<W> {
+    /// Wrap `inner`, which should be positioned at an aligned offset.
+    pub fn new(inner: W) -> AlignedWriter<W> {
+        AlignedWriter {
+            inner,
+            buf: vec![Block([0; DIRECT_IO_ALIGNMENT]); BUFFER_BLOCKS],
+            len: 0,
+            written: 0,
+        }
+    }
+
+    /// Write out every complete block in the buffer, keeping any partial block.
+    fn write_full_blocks(&mut self) -> io::Result<()> {
+        let full = self.len / DIRECT_IO_ALIGNMENT * DIRECT_IO_ALIGNMENT;
+        if full == 0 {
+            return Ok(());
+        }
+        let bytes = as_bytes(&mut self.buf);
+        self.inner.write_all(&bytes[..full])?;
+        bytes.copy_within(full..self.len, 0);
+        self.len -= full;
+        Ok(())
+    }
+
+    /// Pad the final block with zeros, write it, and return the inner writer along with the
+    /// number of logical (unpadded) bytes that were written through this writer.
+    pub fn finish(mut self) -> Result<(W, u64), BzImageError> {
+        let padded = self.len.next_multiple_of(DIRECT_IO_ALIGNMENT);
+        as_bytes(&mut self.buf)[self.len..padded].fill(0);
+        self.len = padded;
+        self.write_full_blocks()?;
+        self.inner.flush()?;
+        Ok((self.inner, self.written))
+    }
+}
+
+impl<W: Write> Write for AlignedWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let capacity = BUFFER_BLOCKS * DIRECT_IO_ALIGNMENT;
+        if self.len == capacity {
+            self.write_full_blocks()?;
+        }
+        let n = data.len().min(capacity - self.len);
+        as_bytes(&mut self.buf)[self.len..self.len + n].copy_from_slice(&data[..n]);
+        self.len += n;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_full_blocks()?;
+        self.inner.flush()
+    }
+}
+
+/// Compress `data` with `codec` and write the image to `w` in `DIRECT_IO_ALIGNMENT`-sized
+/// blocks, suitable for a file opened with `O_DIRECT` or a raw block device.
+///
+/// The output is zero-padded to a whole block; the image itself is `HEADER_SIZE +
+/// compressed_size` bytes, so callers writing to a regular file may truncate it to that length.
+/// Returns the header that was written.
+pub fn write_image_direct<W: Write>(
+    w: W,
+    data: &[u8],
+    codec: Codec,
+) -> Result<BzImageHeader, BzImageError> {
+    let compressed = codec.compress(data)?;
+    let mut header = BzImageHeader::new_for_payload(data.len() as u64, &compressed);
+    header.set_codec(codec);
+
+    let mut aligned = AlignedWriter::new(w);
+    header.write_to(&mut aligned)?;
+    aligned.write_all(&compressed)?;
+    let (_, written) = aligned.finish()?;
+    debug_assert_eq!(written, (HEADER_SIZE + compressed.len()) as u64);
+    Ok(header)
+}
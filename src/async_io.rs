@@ -0,0 +1,374 @@
+//! Async decompression, behind the `async` feature.
+//!
+//! Decompression is CPU-bound, so it runs on tokio's blocking pool. The async side reads
+//! compressed chunks from the source and hands them to the decoder over a bounded channel, and
+//! decoded chunks come back over another; both are bounded, so a slow consumer stops the
+//! source from being read.
+//!
+//! The header and payload can also be read and written directly, optionally under a deadline
+//! so that a stalled peer cannot hold an operation open forever.
+
+use crate::digest::Digester;
+use crate::extended;
+use crate::{BzImageError, BzImageFlags, BzImageHeader, HEADER_SIZE, MAGIC, is_magic};
+use bytes::{Buf, Bytes};
+use futures_core::Stream;
+use std::future::Future;
+use std::io::{self, Read};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Largest chunk, compressed or decompressed, passed through [`decompress_stream_async`].
+pub const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Chunks buffered in each direction between the stream and the decoder.
+const CHANNEL_DEPTH: usize = 4;
+
+/// Blocking `Read` over the compressed chunks sent by the async side. The sender being dropped
+/// is end of input.
+struct ChannelReader {
+    rx: mpsc::Receiver<Bytes>,
+    current: Bytes,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            match self.rx.blocking_recv() {
+                Some(chunk) => self.current = chunk,
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.current.len());
+        buf[..n].copy_from_slice(&self.current[..n]);
+        self.current.advance(n);
+        Ok(n)
+    }
+}
+
+fn decode_blocking(
+    header: BzImageHeader,
+    input: ChannelReader,
+    output: mpsc::Sender<Result<Bytes, BzImageError>>,
+) {
+    let mut decoder = match header.payload_decoder(input) {
+        Ok(decoder) => decoder,
+        Err(err) => {
+            let _ = output.blocking_send(Err(err));
+            return;
+        }
+    };
+    loop {
+        let mut buf = vec![0u8; STREAM_CHUNK_SIZE];
+        match decoder.read(&mut buf) {
+            Ok(0) => return,
+            Ok(n) => {
+                buf.truncate(n);
+                if output.blocking_send(Ok(buf.into())).is_err() {
+                    // the stream was dropped
+                    return;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                let _ = output.blocking_send(Err(BzImageError::Decompression(e)));
+                return;
+            }
+        }
+    }
+}
+
+enum Event {
+    Output(Option<Result<Bytes, BzImageError>>),
+    Input(Option<mpsc::OwnedPermit<Bytes>>),
+}
+
+struct State<R> {
+    reader: R,
+    declared: u64,
+    remaining: u64,
+    expected: [u8; 32],
+    digester: Option<Digester>,
+    start: Option<Box<dyn FnOnce() -> JoinHandle<()> + Send>>,
+    decoder: Option<JoinHandle<()>>,
+    input: Option<mpsc::Sender<Bytes>>,
+    output: mpsc::Receiver<Result<Bytes, BzImageError>>,
+    failed: Option<BzImageError>,
+    finished: bool,
+}
+
+impl<R> State<R> {
+    /// Signal end of input to the decoder and check the checksum of everything read.
+    fn finish_input(&mut self) -> Result<(), BzImageError> {
+        self.input = None;
+        let actual = match self.digester.take() {
+            Some(digester) => digester.finalize(),
+            None => return Ok(()),
+        };
+        if actual != self.expected {
+            return Err(BzImageError::ChecksumMismatch {
+                expected: self.expected,
+                actual,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Stream the decompressed payload described by `header` from `reader`, which must be
+/// positioned at the start of the payload (just past the header).
+///
+/// Chunks are decoded on tokio's blocking pool and yielded, at most [`STREAM_CHUNK_SIZE`] bytes
+/// at a time, as they are produced; the stream must be polled from within a tokio runtime. The
+/// compressed bytes are hashed on the way through, and once the whole payload has been read a
+/// checksum disagreement is reported as `BzImageError::ChecksumMismatch`. Chunks yielded before
+/// that point are unverified, so a consumer that cannot retract them (an HTTP body, say) should
+/// treat an error as a failed transfer. The stream ends after the first error.
+pub fn decompress_stream_async<R: AsyncRead + Unpin>(
+    reader: R,
+    header: &BzImageHeader,
+) -> impl Stream<Item = Result<Bytes, BzImageError>> {
+    let (input_tx, input_rx) = mpsc::channel(CHANNEL_DEPTH);
+    let (output_tx, output_rx) = mpsc::channel(CHANNEL_DEPTH);
+
+    let (digester, failed) = match header.can_read().and_then(|()| header.digest_algo()) {
+        Ok(algo) => (Some(Digester::new(algo)), None),
+        Err(e) => (None, Some(e)),
+    };
+    let header = *header;
+    let input = ChannelReader {
+        rx: input_rx,
+        current: Bytes::new(),
+    };
+    let declared = header.compressed_size();
+    let state = State {
+        reader,
+        declared,
+        remaining: declared,
+        expected: header.checksum_copy(),
+        digester,
+        // spawned on first poll, so that building the stream does not require a runtime
+        start: Some(Box::new(move || {
+            tokio::task::spawn_blocking(move || decode_blocking(header, input, output_tx))
+        })),
+        decoder: None,
+        input: Some(input_tx),
+        output: output_rx,
+        failed,
+        finished: false,
+    };
+
+    futures_util::stream::unfold(state, |mut s| async move {
+        if s.finished {
+            return None;
+        }
+        if let Some(err) = s.failed.take() {
+            s.finished = true;
+            return Some((Err(err), s));
+        }
+        if let Some(start) = s.start.take() {
+            s.decoder = Some(start());
+        }
+        loop {
+            let input = s.input.clone();
+            let event = tokio::select! {
+                biased;
+                item = s.output.recv() => Event::Output(item),
+                permit = async move { input?.reserve_owned().await.ok() }, if s.input.is_some() => {
+                    Event::Input(permit)
+                }
+            };
+            match event {
+                Event::Output(Some(Ok(chunk))) => return Some((Ok(chunk), s)),
+                Event::Output(Some(Err(err))) => {
+                    s.finished = true;
+                    return Some((Err(err), s));
+                }
+                Event::Output(None) => {
+                    // the decoder is done; make sure it did not die mid-stream
+                    s.finished = true;
+                    if let Err(e) = s.decoder.take()?.await {
+                        return Some((Err(BzImageError::Io(io::Error::other(e))), s));
+                    }
+                    if s.digester.is_some() {
+                        let err = io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "compressed stream ended before the end of the payload",
+                        );
+                        return Some((Err(BzImageError::Decompression(err)), s));
+                    }
+                    return None;
+                }
+                Event::Input(None) => {
+                    // the decoder stopped early; its error, if any, is on `output`
+                    s.input = None;
+                }
+                Event::Input(Some(permit)) => {
+                    if s.remaining > 0 {
+                        let want = s.remaining.min(STREAM_CHUNK_SIZE as u64) as usize;
+                        let mut buf = vec![0u8; want];
+                        match s.reader.read(&mut buf).await {
+                            Ok(0) => {
+                                s.finished = true;
+                                let err = BzImageError::TruncatedPayload {
+                                    declared: s.declared,
+                                    available: Some(s.declared - s.remaining),
+                                };
+                                return Some((Err(err), s));
+                            }
+                            Ok(n) => {
+                                buf.truncate(n);
+                                if let Some(digester) = s.digester.as_mut() {
+                                    digester.update(&buf);
+                                }
+                                s.remaining -= n as u64;
+                                permit.send(buf.into());
+                            }
+                            Err(e) => {
+                                s.finished = true;
+                                return Some((Err(e.into()), s));
+                            }
+                        }
+                    } else {
+                        drop(permit);
+                    }
+                    if s.remaining == 0
+                        && let Err(err) = s.finish_input()
+                    {
+                        s.finished = true;
+                        return Some((Err(err), s));
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Run `op`, failing with `BzImageError::TimedOut` if it does not finish within `timeout`.
+async fn with_timeout<T>(
+    timeout: Duration,
+    op: impl Future<Output = Result<T, BzImageError>>,
+) -> Result<T, BzImageError> {
+    match tokio::time::timeout(timeout, op).await {
+        Ok(result) => result,
+        Err(_) => Err(BzImageError::TimedOut(timeout)),
+    }
+}
+
+/// Map end of input while reading the header to `TruncatedHeader`, as the sync parser does.
+fn truncated(e: io::Error) -> BzImageError {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        BzImageError::TruncatedHeader
+    } else {
+        BzImageError::Io(e)
+    }
+}
+
+fn extended_truncated(e: io::Error) -> BzImageError {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        BzImageError::InvalidExtendedHeader("truncated")
+    } else {
+        BzImageError::Io(e)
+    }
+}
+
+impl BzImageHeader {
+    /// Read a header from an async reader, consuming exactly `HEADER_SIZE` bytes.
+    ///
+    /// The checks and errors are those of `read_from`: the magic is checked as soon as it has
+    /// arrived, and input that ends early is `BzImageError::TruncatedHeader`.
+    pub async fn read_from_async<R: AsyncRead + Unpin>(
+        mut r: R,
+    ) -> Result<BzImageHeader, BzImageError> {
+        let mut bytes = [0u8; HEADER_SIZE];
+        r.read_exact(&mut bytes[..MAGIC.len()])
+            .await
+            .map_err(truncated)?;
+        if !is_magic(&bytes[..MAGIC.len()]) {
+            let found = bytes[..MAGIC.len()].try_into().unwrap();
+            return Err(BzImageError::InvalidMagic { found });
+        }
+        r.read_exact(&mut bytes[MAGIC.len()..])
+            .await
+            .map_err(truncated)?;
+        BzImageHeader::from_bytes(&bytes)
+    }
+
+    /// Like `read_from_async`, failing with `BzImageError::TimedOut` if the whole header has
+    /// not arrived within `timeout`.
+    pub async fn read_from_async_timeout<R: AsyncRead + Unpin>(
+        r: R,
+        timeout: Duration,
+    ) -> Result<BzImageHeader, BzImageError> {
+        with_timeout(timeout, BzImageHeader::read_from_async(r)).await
+    }
+
+    /// Read a header and the following compressed payload from an async reader; the async
+    /// counterpart of `read_header_and_payload`.
+    ///
+    /// Any extended header is skipped. The payload buffer grows as data arrives rather than
+    /// being sized from the header up front. A short payload fails with
+    /// `BzImageError::TruncatedPayload`.
+    pub async fn read_header_and_payload_async<R: AsyncRead + Unpin>(
+        mut r: R,
+    ) -> Result<(BzImageHeader, Vec<u8>), BzImageError> {
+        let header = BzImageHeader::read_from_async(&mut r).await?;
+        if header.flags().contains(BzImageFlags::DETACHED_PAYLOAD) {
+            return Err(BzImageError::DetachedPayload);
+        }
+        header.validate_header()?;
+        if header.flags().contains(BzImageFlags::HAS_EXTENDED_HEADER) {
+            // skip the extended header
+            let mut prefix = [0u8; 4];
+            r.read_exact(&mut prefix)
+                .await
+                .map_err(extended_truncated)?;
+            let len = extended::region_len(&prefix)? as u64;
+            let skipped = tokio::io::copy(&mut (&mut r).take(len), &mut tokio::io::sink()).await?;
+            if skipped < len {
+                return Err(BzImageError::InvalidExtendedHeader("truncated"));
+            }
+        }
+        let declared = header.compressed_size();
+        let mut compressed = Vec::new();
+        (&mut r).take(declared).read_to_end(&mut compressed).await?;
+        if (compressed.len() as u64) < declared {
+            return Err(BzImageError::TruncatedPayload {
+                declared,
+                available: Some(compressed.len() as u64),
+            });
+        }
+        Ok((header, compressed))
+    }
+
+    /// Like `read_header_and_payload_async`, failing with `BzImageError::TimedOut` if the
+    /// whole image has not been read within `timeout`.
+    pub async fn read_header_and_payload_async_timeout<R: AsyncRead + Unpin>(
+        r: R,
+        timeout: Duration,
+    ) -> Result<(BzImageHeader, Vec<u8>), BzImageError> {
+        with_timeout(timeout, BzImageHeader::read_header_and_payload_async(r)).await
+    }
+
+    /// Write the header to an async writer and flush it.
+    pub async fn write_to_async<W: AsyncWrite + Unpin>(
+        &self,
+        mut w: W,
+    ) -> Result<(), BzImageError> {
+        w.write_all(&self.to_bytes()).await?;
+        w.flush().await?;
+        Ok(())
+    }
+
+    /// Like `write_to_async`, failing with `BzImageError::TimedOut` if the peer has not taken
+    /// the header within `timeout`.
+    pub async fn write_to_async_timeout<W: AsyncWrite + Unpin>(
+        &self,
+        w: W,
+        timeout: Duration,
+    ) -> Result<(), BzImageError> {
+        with_timeout(timeout, self.write_to_async(w)).await
+    }
+}
@@ -0,0 +1,119 @@
+//! HMAC-SHA256 authentication of an image, for tampering rather than mere damage.
+//!
+//! The checksum only detects corruption: anyone who changes the payload can recompute it. A
+//! `SIGNED` image also carries an HMAC-SHA256 tag, keyed by a secret shared between writer
+//! and reader, over the 64 header bytes, the extended header region if there is one, and the
+//! payload. The tag is kept in the signature trailer (see `append_signature`), so `SIGNED`
+//! images also set `HAS_SIGNATURE` and readers without the key read them as usual.
+//!
+//! The header bytes are signed as they are on disk, flags included, so anything that rewrites
+//! the header afterwards (adding a footer, say) invalidates the tag; sign last. A footer
+//! itself is not covered.
+
+use crate::digest::checksums_match;
+#[cfg(feature = "std")]
+use crate::signature::{self, SIGNATURE_MAGIC};
+#[cfg(feature = "std")]
+use crate::{BzImageError, Codec, HEADER_SIZE};
+use crate::{BzImageFlags, BzImageHeader};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+#[cfg(feature = "std")]
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// Length of an HMAC-SHA256 tag.
+pub const HMAC_TAG_SIZE: usize = 32;
+
+impl BzImageHeader {
+    /// Compute the HMAC-SHA256 tag of this header, `extended` and `payload` under `key`.
+    fn hmac(&self, key: &[u8], extended: &[u8], payload: &[u8]) -> [u8; HMAC_TAG_SIZE] {
+        // HMAC takes keys of any length
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        mac.update(&self.to_bytes());
+        mac.update(extended);
+        mac.update(payload);
+        mac.finalize().into_bytes().into()
+    }
+
+    /// Set `SIGNED` and `HAS_SIGNATURE` and return the HMAC-SHA256 tag of the header,
+    /// `extended` and `payload` under `key`, for the caller to write as the image's signature
+    /// trailer.
+    ///
+    /// `extended` is the extended header region as written after the header, length prefix
+    /// included (`ExtendedHeader::to_bytes`), or empty if the image has none. Call this once
+    /// the header is otherwise final: the tag covers every header byte.
+    pub fn sign(&mut self, key: &[u8], extended: &[u8], payload: &[u8]) -> [u8; HMAC_TAG_SIZE] {
+        self.set_flags(self.flags() | BzImageFlags::SIGNED | BzImageFlags::HAS_SIGNATURE);
+        self.hmac(key, extended, payload)
+    }
+
+    /// Whether `tag` is the HMAC-SHA256 tag of this header, `extended` and `payload` under
+    /// `key`; `extended` is as for `sign`.
+    ///
+    /// The comparison takes the same time wherever the tags first differ. A header without
+    /// `SIGNED` never verifies.
+    pub fn verify_signature(
+        &self,
+        key: &[u8],
+        extended: &[u8],
+        payload: &[u8],
+        tag: &[u8],
+    ) -> bool {
+        let Ok(tag) = <&[u8; HMAC_TAG_SIZE]>::try_from(tag) else {
+            return false;
+        };
+        self.flags().contains(BzImageFlags::SIGNED)
+            && checksums_match(&self.hmac(key, extended, payload), tag)
+    }
+
+    /// Like `pack`, and sign the image with `key`, writing the tag as its signature trailer.
+    /// The header also records a CRC-32 of `uncompressed`, since a signed header cannot be
+    /// given a header CRC afterwards.
+    #[cfg(feature = "std")]
+    pub fn pack_signed<W: Write>(
+        uncompressed: &[u8],
+        key: &[u8],
+        w: &mut W,
+    ) -> Result<BzImageHeader, BzImageError> {
+        let compressed = Codec::Gzip.compress(uncompressed)?;
+        let mut header = BzImageHeader::new_for_payload(uncompressed.len() as u64, &compressed);
+        header.set_uncompressed_crc(uncompressed)?;
+        let tag = header.sign(key, &[], &compressed);
+        header.write_to(&mut *w)?;
+        w.write_all(&compressed)?;
+        w.write_all(&tag)?;
+        w.write_all(&(HMAC_TAG_SIZE as u32).to_le_bytes())?;
+        w.write_all(SIGNATURE_MAGIC)?;
+        Ok(header)
+    }
+
+    /// Read the signed image at the start of `r`, check its tag under `key`, and return the
+    /// decompressed data.
+    ///
+    /// The tag, which covers any extended header as well as the header and payload, is checked
+    /// before anything is decompressed. An image without `SIGNED` fails with
+    /// `BzImageError::NotSigned`, one whose trailer is missing with
+    /// `BzImageError::MissingSignature`, and one whose tag does not match, whether from a
+    /// wrong key or from tampering, with `BzImageError::AuthenticationFailed`. The remaining
+    /// checks are those of `unpack`.
+    #[cfg(feature = "std")]
+    pub fn unpack_signed<R: Read + Seek>(r: &mut R, key: &[u8]) -> Result<Vec<u8>, BzImageError> {
+        r.seek(SeekFrom::Start(0))?;
+        let (header, compressed) = Self::read_header_and_payload(&mut *r)?;
+        header.can_read()?;
+        if !header.flags().contains(BzImageFlags::SIGNED) {
+            return Err(BzImageError::NotSigned);
+        }
+        r.seek(SeekFrom::Start(HEADER_SIZE as u64))?;
+        let extended = header.read_extended_bytes(&mut *r)?;
+        let (start, len) = signature::find_trailer(r)?.ok_or(BzImageError::MissingSignature)?;
+        let mut tag = vec![0u8; len.min(HMAC_TAG_SIZE as u64 + 1) as usize];
+        r.seek(SeekFrom::Start(start))?;
+        r.read_exact(&mut tag)?;
+        if !header.verify_signature(key, &extended, &compressed, &tag) {
+            return Err(BzImageError::AuthenticationFailed);
+        }
+        header.validate_payload(&compressed)?;
+        header.decode_verified(&compressed)
+    }
+}
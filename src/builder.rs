@@ -0,0 +1,140 @@
+//! A checked way to assemble a header field by field.
+
+use crate::{BzImageError, BzImageFlags, BzImageHeader, Codec, MAGIC, VERSION};
+
+/// Builds a [`BzImageHeader`] from its parts, refusing to produce one that is incomplete or
+/// that `BzImageHeader::from_bytes` would reject.
+///
+/// The magic is `MAGIC`, or `MAGIC_BE` for a big-endian header, and the version defaults to
+/// `VERSION`. Both sizes and the checksum must be given; the codec defaults to gzip and the
+/// flags to none. When the header is for a payload already in hand,
+/// `BzImageHeader::new_for_payload` is simpler.
+#[derive(Copy, Clone, Debug)]
+pub struct BzImageHeaderBuilder {
+    version: u32,
+    uncompressed_size: Option<u64>,
+    compressed_size: Option<u64>,
+    checksum: Option<[u8; 32]>,
+    flags: BzImageFlags,
+    compression: Codec,
+    big_endian: bool,
+}
+
+impl Default for BzImageHeaderBuilder {
+    fn default() -> BzImageHeaderBuilder {
+        BzImageHeaderBuilder {
+            version: VERSION,
+            uncompressed_size: None,
+            compressed_size: None,
+            checksum: None,
+            flags: BzImageFlags::empty(),
+            compression: Codec::Gzip,
+            big_endian: false,
+        }
+    }
+}
+
+impl BzImageHeaderBuilder {
+    pub fn new() -> BzImageHeaderBuilder {
+        BzImageHeaderBuilder::default()
+    }
+
+    /// The format version; it must be in `SUPPORTED_VERSIONS`.
+    pub fn version(mut self, version: u32) -> BzImageHeaderBuilder {
+        self.version = version;
+        self
+    }
+
+    pub fn uncompressed_size(mut self, size: u64) -> BzImageHeaderBuilder {
+        self.uncompressed_size = Some(size);
+        self
+    }
+
+    pub fn compressed_size(mut self, size: u64) -> BzImageHeaderBuilder {
+        self.compressed_size = Some(size);
+        self
+    }
+
+    /// The digest of the compressed payload. An all-zero checksum counts as not set.
+    pub fn checksum(mut self, checksum: [u8; 32]) -> BzImageHeaderBuilder {
+        self.checksum = Some(checksum);
+        self
+    }
+
+    /// The feature flags; critical flags this build does not understand are refused.
+    pub fn flags(mut self, flags: BzImageFlags) -> BzImageHeaderBuilder {
+        self.flags = flags;
+        self
+    }
+
+    /// The codec the payload is compressed with.
+    pub fn compression(mut self, codec: Codec) -> BzImageHeaderBuilder {
+        self.compression = codec;
+        self
+    }
+
+    /// Write the integer fields big-endian, under `MAGIC_BE`; little-endian by default.
+    pub fn big_endian(mut self, big_endian: bool) -> BzImageHeaderBuilder {
+        self.big_endian = big_endian;
+        self
+    }
+
+    /// Assemble the header.
+    ///
+    /// A size or checksum left unset is `BzImageError::IncompleteHeader` naming the field; an
+    /// unsupported version, unknown critical flag or impossible sizes (see
+    /// `BzImageHeader::validate_header`) fail as they would when the header is read.
+    /// `HEADER_CRC32` gets the CRC of the finished header. The builder cannot set an
+    /// uncompressed CRC or a dictionary id, so `UNCOMPRESSED_CRC32` and `ZSTD_DICTIONARY` are
+    /// `IncompleteHeader` too; set those on the built header.
+    pub fn build(self) -> Result<BzImageHeader, BzImageError> {
+        if !BzImageHeader::is_version_supported(self.version) {
+            return Err(BzImageError::UnsupportedVersion(self.version));
+        }
+        let unknown = self.flags.unknown_critical();
+        if unknown != BzImageFlags::empty() {
+            return Err(BzImageError::UnknownCriticalFlag(unknown.bits()));
+        }
+        let uncompressed_size = self
+            .uncompressed_size
+            .ok_or(BzImageError::IncompleteHeader("uncompressed_size"))?;
+        let compressed_size = self
+            .compressed_size
+            .ok_or(BzImageError::IncompleteHeader("compressed_size"))?;
+        let checksum = self
+            .checksum
+            .filter(|c| c != &[0u8; 32])
+            .ok_or(BzImageError::IncompleteHeader("checksum"))?;
+        if self.flags.contains(BzImageFlags::UNCOMPRESSED_CRC32) {
+            return Err(BzImageError::IncompleteHeader("uncompressed_crc"));
+        }
+        if self.flags.contains(BzImageFlags::ZSTD_DICTIONARY) {
+            return Err(BzImageError::IncompleteHeader("dictionary_id"));
+        }
+
+        let mut header = BzImageHeader {
+            magic: *MAGIC,
+            version: self.version.into(),
+            reserved1: 0u32.into(),
+            uncompressed_size: uncompressed_size.into(),
+            compressed_size: compressed_size.into(),
+            checksum,
+            reserved2: 0u32.into(),
+        };
+        header.set_codec(self.compression);
+        header.set_flags(self.flags);
+        header.set_big_endian(self.big_endian);
+        header.validate_header()?;
+        if self.flags.contains(BzImageFlags::HEADER_CRC32) {
+            header.set_header_crc()?;
+        }
+        Ok(header)
+    }
+}
+
+impl BzImageHeader {
+    /// Start building a header; see [`BzImageHeaderBuilder`].
+    pub fn builder() -> BzImageHeaderBuilder {
+        BzImageHeaderBuilder::new()
+    }
+}
@@ -0,0 +1,126 @@
+//! An LRU cache of decompressed payloads, behind the `cache` feature.
+
+use crate::{BzImageError, BzImageHeader};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+struct Entry {
+    data: Arc<Vec<u8>>,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<[u8; 32], Entry>,
+    /// Keys by last use, oldest first.
+    order: BTreeMap<u64, [u8; 32]>,
+    bytes: usize,
+    tick: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, key: &[u8; 32]) -> Option<Arc<Vec<u8>>> {
+        let entry = self.entries.get_mut(key)?;
+        self.order.remove(&entry.last_used);
+        self.tick += 1;
+        entry.last_used = self.tick;
+        self.order.insert(self.tick, *key);
+        Some(Arc::clone(&entry.data))
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some((_, key)) = self.order.pop_first()
+            && let Some(entry) = self.entries.remove(&key)
+        {
+            self.bytes -= entry.data.len();
+        }
+    }
+}
+
+/// Decompressed payloads keyed by their header checksum, evicting the least recently used
+/// once their total size exceeds a byte budget.
+///
+/// The cache is internally locked, so one instance can be shared between threads; the lock is
+/// not held while decompressing.
+pub struct DecompressCache {
+    capacity: usize,
+    inner: Mutex<Lru>,
+}
+
+impl DecompressCache {
+    /// Create a cache holding at most `capacity` bytes of decompressed data.
+    pub fn new(capacity: usize) -> DecompressCache {
+        DecompressCache {
+            capacity,
+            inner: Mutex::new(Lru::default()),
+        }
+    }
+
+    /// The byte budget given to `new`.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Total decompressed bytes currently cached.
+    pub fn cached_bytes(&self) -> usize {
+        self.lock().bytes
+    }
+
+    /// Number of payloads currently cached.
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return the decompressed payload of the image described by `header`, from the cache if
+    /// its checksum is present and otherwise by decompressing `compressed` and caching it.
+    ///
+    /// On a miss `compressed` is checked with `validate_payload` first, so a corrupt payload is
+    /// never cached under its header's checksum. A hit trusts the checksum and does not look
+    /// at `compressed`. Payloads larger than the whole capacity are returned but not cached.
+    pub fn get_or_decompress(
+        &self,
+        header: &BzImageHeader,
+        compressed: &[u8],
+    ) -> Result<Arc<Vec<u8>>, BzImageError> {
+        let key = header.checksum_copy();
+        if let Some(data) = self.lock().touch(&key) {
+            return Ok(data);
+        }
+
+        header.validate_payload(compressed)?;
+        let data = Arc::new(header.codec()?.decompress(compressed)?);
+        if data.len() > self.capacity {
+            return Ok(data);
+        }
+
+        let mut lru = self.lock();
+        if let Some(existing) = lru.touch(&key) {
+            // another thread decompressed it first
+            return Ok(existing);
+        }
+        while lru.bytes + data.len() > self.capacity {
+            lru.evict_oldest();
+        }
+        lru.tick += 1;
+        let tick = lru.tick;
+        lru.order.insert(tick, key);
+        lru.bytes += data.len();
+        lru.entries.insert(
+            key,
+            Entry {
+                data: Arc::clone(&data),
+                last_used: tick,
+            },
+        );
+        Ok(data)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Lru> {
+        // the cache holds no invariants a panicking holder could break half-way
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
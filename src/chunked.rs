@@ -0,0 +1,398 @@
+//! The chunked payload layout, flagged by `BzImageFlags::STREAMING_FRAMED`, in which every
+//! chunk carries its own checksum so that damage is found where it is rather than at the end.
+//!
+//! The payload (still exactly `compressed_size` bytes, and still covered as a whole by the
+//! header's checksum) is a sequence of chunks, each (integers little-endian):
+//!
+//! - compressed_len: u32 — length of the chunk's data
+//! - uncompressed_len: u32 — length of the data once decompressed, at most `MAX_CHUNK_SIZE`
+//! - checksum: 32 bytes — digest of the chunk's data, with the header's digest algorithm
+//! - data: `compressed_len` bytes — an independent stream in the header's codec
+//!
+//! Chunks are decoded one at a time, so a reader holds at most one chunk in memory and can
+//! stop at the first chunk that fails its checksum. An empty payload has no chunks.
+
+use crate::digest::{DigestReader, Digester, checksums_match, compute_checksum};
+use crate::{BzImageError, BzImageFlags, BzImageHeader, Codec, DigestAlgo, HEADER_SIZE};
+use std::io::{self, ErrorKind, Read, Seek, Take, Write};
+
+/// Uncompressed bytes per chunk written by [`FramedWriter::new`].
+pub const DEFAULT_CHUNK_SIZE: usize = 1 << 20;
+
+/// Largest uncompressed chunk accepted when reading or writing, to bound allocation on
+/// corrupt input.
+pub const MAX_CHUNK_SIZE: usize = 64 << 20;
+
+/// Length of the fixed part of a chunk, before its data.
+const CHUNK_HEADER_SIZE: usize = 40;
+
+/// Compress `data` as one chunk, header included.
+pub(crate) fn encode_chunk(
+    data: &[u8],
+    codec: Codec,
+    level: u32,
+    algo: DigestAlgo,
+) -> Result<Vec<u8>, BzImageError> {
+    let compressed = codec.compress_with_level(data, level)?;
+    let mut chunk = Vec::with_capacity(CHUNK_HEADER_SIZE + compressed.len());
+    chunk.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+    chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    chunk.extend_from_slice(&compute_checksum(algo, &compressed));
+    chunk.extend_from_slice(&compressed);
+    Ok(chunk)
+}
+
+/// Decodes a chunked payload read from `R`, checking each chunk as it arrives.
+pub(crate) struct ChunkDecoder<R> {
+    inner: R,
+    codec: Codec,
+    algo: DigestAlgo,
+    index: u64,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> ChunkDecoder<R> {
+    pub(crate) fn new(inner: R, header: &BzImageHeader) -> Result<ChunkDecoder<R>, BzImageError> {
+        Ok(ChunkDecoder {
+            inner,
+            codec: header.codec()?,
+            algo: header.digest_algo()?,
+            index: 0,
+            chunk: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub(crate) fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Read, verify and decode the next chunk, or return `None` at a clean end of input.
+    ///
+    /// A chunk whose data does not match its checksum is `BzImageError::ChunkChecksumMismatch`
+    /// and one that is malformed or decodes to the wrong length is `BzImageError::InvalidChunk`;
+    /// input that ends inside a chunk is an `UnexpectedEof` I/O error.
+    pub(crate) fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, BzImageError> {
+        let index = self.index;
+        let mut head = [0u8; CHUNK_HEADER_SIZE];
+        let mut filled = 0;
+        while filled < head.len() {
+            match self.inner.read(&mut head[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(io::Error::from(ErrorKind::UnexpectedEof).into()),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let compressed_len = u32::from_le_bytes(head[0..4].try_into().unwrap()) as usize;
+        let uncompressed_len = u32::from_le_bytes(head[4..8].try_into().unwrap()) as usize;
+        if uncompressed_len > MAX_CHUNK_SIZE || compressed_len > 2 * MAX_CHUNK_SIZE {
+            return Err(BzImageError::InvalidChunk { index });
+        }
+
+        let mut compressed = vec![0u8; compressed_len];
+        self.inner.read_exact(&mut compressed)?;
+        let actual = compute_checksum(self.algo, &compressed);
+        if !checksums_match(&actual, head[8..40].try_into().unwrap()) {
+            return Err(BzImageError::ChunkChecksumMismatch { index });
+        }
+        let data = match self
+            .codec
+            .decompress_limited(&compressed, uncompressed_len as u64)
+        {
+            Ok(data) if data.len() == uncompressed_len => data,
+            Ok(_) | Err(BzImageError::OutputLimitExceeded { .. }) => {
+                return Err(BzImageError::InvalidChunk { index });
+            }
+            Err(e) => return Err(e),
+        };
+        self.index += 1;
+        Ok(Some(data))
+    }
+}
+
+impl<R: Read> Read for ChunkDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.next_chunk() {
+                Ok(Some(chunk)) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Ok(None) => return Ok(0),
+                Err(BzImageError::Io(e)) => return Err(e),
+                Err(e) => return Err(io::Error::new(ErrorKind::InvalidData, e)),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Writes an image in the chunked layout, compressing each chunk as soon as it fills.
+///
+/// Like `BzImageWriter`, the writer must be seekable: a zeroed placeholder header is written
+/// first and `finish` goes back to fill it in. At most one chunk of input is held in memory.
+pub struct FramedWriter<W: Write + Seek> {
+    inner: W,
+    codec: Codec,
+    chunk_size: usize,
+    pending: Vec<u8>,
+    digester: Digester,
+    header_offset: u64,
+    uncompressed_len: u64,
+    compressed_len: u64,
+}
+
+impl<W: Write + Seek> FramedWriter<W> {
+    /// Reserve the header at the current position of `w` and prepare to write chunks of
+    /// `DEFAULT_CHUNK_SIZE` bytes compressed with `codec`.
+    pub fn new(w: W, codec: Codec) -> Result<FramedWriter<W>, BzImageError> {
+        Self::with_chunk_size(w, codec, DEFAULT_CHUNK_SIZE)
+    }
+
+    /// Like `new`, with `chunk_size` uncompressed bytes per chunk, from 1 to `MAX_CHUNK_SIZE`;
+    /// any other size is `BzImageError::InvalidChunkSize`.
+    pub fn with_chunk_size(
+        mut w: W,
+        codec: Codec,
+        chunk_size: usize,
+    ) -> Result<FramedWriter<W>, BzImageError> {
+        if !(1..=MAX_CHUNK_SIZE).contains(&chunk_size) {
+            return Err(BzImageError::InvalidChunkSize {
+                size: chunk_size,
+                max: MAX_CHUNK_SIZE,
+            });
+        }
+        if !codec.is_enabled() {
+            return Err(BzImageError::CodecNotEnabled(codec));
+        }
+        let header_offset = w.stream_position()?;
+        w.write_all(&[0u8; HEADER_SIZE])?;
+        Ok(FramedWriter {
+            inner: w,
+            codec,
+            chunk_size,
+            pending: Vec::with_capacity(chunk_size),
+            digester: Digester::new(DigestAlgo::Sha256),
+            header_offset,
+            uncompressed_len: 0,
+            compressed_len: 0,
+        })
+    }
+
+    fn emit(&mut self) -> io::Result<()> {
+        let chunk = encode_chunk(
+            &self.pending,
+            self.codec,
+            Codec::MAX_LEVEL,
+            DigestAlgo::Sha256,
+        )
+        .map_err(|e| match e {
+            BzImageError::Io(e) => e,
+            e => io::Error::other(e),
+        })?;
+        self.inner.write_all(&chunk)?;
+        self.digester.update(&chunk);
+        self.compressed_len += chunk.len() as u64;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Write the last partial chunk, fill in the header, and return the writer and the
+    /// header. The writer is left positioned at the end of the payload.
+    pub fn finish(mut self) -> Result<(W, BzImageHeader), BzImageError> {
+        if !self.pending.is_empty() {
+            self.emit()?;
+        }
+        let header = BzImageHeader::builder()
+            .uncompressed_size(self.uncompressed_len)
+            .compressed_size(self.compressed_len)
+            .checksum(self.digester.finalize())
+            .compression(self.codec)
+            .flags(BzImageFlags::STREAMING_FRAMED)
+            .build()?;
+
+        header.overwrite_at(&mut self.inner, self.header_offset)?;
+        self.inner.flush()?;
+        Ok((self.inner, header))
+    }
+}
+
+impl<W: Write + Seek> Write for FramedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.chunk_size - self.pending.len());
+        self.pending.extend_from_slice(&buf[..n]);
+        self.uncompressed_len += n as u64;
+        if self.pending.len() == self.chunk_size {
+            self.emit()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(feature = "parallel")]
+impl BzImageHeader {
+    /// Gzip `data` in `DEFAULT_CHUNK_SIZE` chunks on a pool of `threads` threads (0 for one
+    /// per CPU) and write it to `w` as a complete `STREAMING_FRAMED` image, returning the
+    /// header.
+    ///
+    /// Chunk boundaries depend only on `data`, so the image is byte-for-byte the same whatever
+    /// the thread count, and the same as `FramedWriter` writes. Every compressed chunk is held
+    /// in memory until all are done.
+    pub fn pack_parallel<W: Write>(
+        data: &[u8],
+        threads: usize,
+        w: &mut W,
+    ) -> Result<BzImageHeader, BzImageError> {
+        use rayon::prelude::*;
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .map_err(io::Error::other)?;
+        let chunks = pool.install(|| {
+            data.par_chunks(DEFAULT_CHUNK_SIZE)
+                .map(|chunk| encode_chunk(chunk, Codec::Gzip, Codec::MAX_LEVEL, DigestAlgo::Sha256))
+                .collect::<Result<Vec<_>, _>>()
+        })?;
+
+        let mut digester = Digester::new(DigestAlgo::Sha256);
+        for chunk in &chunks {
+            digester.update(chunk);
+        }
+        let header = BzImageHeader::builder()
+            .uncompressed_size(data.len() as u64)
+            .compressed_size(chunks.iter().map(|chunk| chunk.len() as u64).sum())
+            .checksum(digester.finalize())
+            .compression(Codec::Gzip)
+            .flags(BzImageFlags::STREAMING_FRAMED)
+            .build()?;
+        header.write_to(&mut *w)?;
+        for chunk in &chunks {
+            w.write_all(chunk)?;
+        }
+        Ok(header)
+    }
+}
+
+/// Reads an image in the chunked layout one verified chunk at a time.
+///
+/// Each chunk is checked against its own checksum before any of it is returned, so damage is
+/// reported at the chunk it is in, as `BzImageError::ChunkChecksumMismatch` with the chunk's
+/// index. Once the last chunk has been read, the payload is also checked as a whole: its
+/// length, the header's checksum and `uncompressed_size`.
+pub struct FramedReader<R: Read> {
+    header: BzImageHeader,
+    chunks: ChunkDecoder<DigestReader<Take<R>>>,
+    produced: u64,
+    done: bool,
+}
+
+impl<R: Read> FramedReader<R> {
+    /// Read and check the header from `r`, which must be positioned at the start of an image
+    /// with `STREAMING_FRAMED` set.
+    pub fn new(r: R) -> Result<FramedReader<R>, BzImageError> {
+        let (header, payload) = BzImageHeader::split_reader(r)?;
+        header.can_read()?;
+        if !header.flags().contains(BzImageFlags::STREAMING_FRAMED) {
+            return Err(BzImageError::NotFramed);
+        }
+        if header.flags().contains(BzImageFlags::DETACHED_PAYLOAD) {
+            return Err(BzImageError::DetachedPayload);
+        }
+        let payload = DigestReader {
+            inner: payload,
+            digester: Digester::new(header.digest_algo()?),
+        };
+        Ok(FramedReader {
+            header,
+            chunks: ChunkDecoder::new(payload, &header)?,
+            produced: 0,
+            done: false,
+        })
+    }
+
+    pub fn header(&self) -> &BzImageHeader {
+        &self.header
+    }
+
+    /// Index of the chunk the next call to `next_chunk` will return.
+    pub fn chunk_index(&self) -> u64 {
+        self.chunks.index
+    }
+
+    /// Return the next chunk's decompressed data, or `None` once the payload has been read
+    /// and verified.
+    pub fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, BzImageError> {
+        if self.done {
+            return Ok(None);
+        }
+        match self.chunks.next_chunk() {
+            Ok(Some(chunk)) => {
+                self.produced += chunk.len() as u64;
+                Ok(Some(chunk))
+            }
+            Ok(None) => {
+                self.done = true;
+                self.finish().map(|()| None)
+            }
+            Err(BzImageError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                self.done = true;
+                let declared = self.header.compressed_size();
+                let unread = self.chunks.get_mut().inner.limit();
+                Err(BzImageError::TruncatedPayload {
+                    declared,
+                    available: Some(declared - unread),
+                })
+            }
+            Err(e) => {
+                self.done = true;
+                Err(e)
+            }
+        }
+    }
+
+    /// Check the payload as a whole once every chunk has been read.
+    fn finish(&mut self) -> Result<(), BzImageError> {
+        let declared = self.header.compressed_size();
+        let payload = self.chunks.get_mut();
+        let unread = payload.inner.limit();
+        if unread > 0 {
+            return Err(BzImageError::TruncatedPayload {
+                declared,
+                available: Some(declared - unread),
+            });
+        }
+        let digester = std::mem::replace(&mut payload.digester, Digester::new(DigestAlgo::Sha256));
+        let expected = self.header.checksum_copy();
+        let actual = digester.finalize();
+        if !checksums_match(&actual, &expected) {
+            return Err(BzImageError::ChecksumMismatch { expected, actual });
+        }
+        if self.produced != self.header.uncompressed_size() {
+            return Err(BzImageError::UncompressedSizeMismatch {
+                declared: self.header.uncompressed_size(),
+                actual: self.produced,
+            });
+        }
+        Ok(())
+    }
+
+    /// Return the underlying reader, positioned just past the last chunk read.
+    pub fn into_inner(self) -> R {
+        self.chunks.into_inner().inner.into_inner()
+    }
+}
@@ -0,0 +1,221 @@
+//! Payload compression codecs.
+//!
+//! The codec an image was written with is recorded in bits 0..8 of the header's `reserved1`
+//! field. Images written before codec selection existed have zero there, which is gzip.
+//!
+//! Every codec the format defines has an identifier, but some are only compiled in behind a
+//! feature (`zstd`). A build without one still recognizes the identifier and reports
+//! `BzImageError::CodecNotEnabled` instead of `UnknownCodec`.
+
+#[cfg(feature = "std")]
+use crate::BzImageError;
+#[cfg(feature = "std")]
+use alloc::{boxed::Box, format, vec::Vec};
+#[cfg(feature = "std")]
+use flate2::Compression;
+#[cfg(feature = "std")]
+use flate2::read::GzDecoder;
+#[cfg(feature = "std")]
+use flate2::write::GzEncoder;
+#[cfg(feature = "std")]
+use std::io::{Read, Write};
+
+/// How the payload bytes are compressed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Codec {
+    /// A gzip (RFC 1952) stream. This is the format's original codec.
+    Gzip = 0,
+    /// No compression: the payload is the data itself.
+    Stored = 1,
+    /// A zstd (RFC 8878) stream. Needs the `zstd` feature.
+    Zstd = 2,
+}
+
+/// Another name for [`Codec`], for callers that think of it as the header's compression
+/// algorithm field.
+pub type CompressionAlgorithm = Codec;
+
+impl Codec {
+    /// Every codec this build can read and write, in the order `write_image_auto` tries them.
+    #[cfg(feature = "zstd")]
+    pub const ALL: &'static [Codec] = &[Codec::Gzip, Codec::Zstd, Codec::Stored];
+    /// Every codec this build can read and write, in the order `write_image_auto` tries them.
+    #[cfg(not(feature = "zstd"))]
+    pub const ALL: &'static [Codec] = &[Codec::Gzip, Codec::Stored];
+
+    /// The on-disk identifier of this codec.
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    /// Look up a codec by its on-disk identifier.
+    pub fn from_id(id: u8) -> Option<Codec> {
+        match id {
+            0 => Some(Codec::Gzip),
+            1 => Some(Codec::Stored),
+            2 => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Whether this build can compress and decompress with this codec.
+    pub fn is_enabled(self) -> bool {
+        Codec::ALL.contains(&self)
+    }
+
+    /// Strongest compression level accepted by [`Codec::compress_with_level`].
+    pub const MAX_LEVEL: u32 = 9;
+
+    /// Compress `data` with this codec at its strongest setting.
+    #[cfg(feature = "std")]
+    pub fn compress(self, data: &[u8]) -> Result<Vec<u8>, BzImageError> {
+        self.compress_with_level(data, Codec::MAX_LEVEL)
+    }
+
+    /// Compress `data` with this codec at `level`, from 0 (fastest) to [`Codec::MAX_LEVEL`]
+    /// (smallest). Codecs without levels ignore it; zstd spreads the range over its own levels
+    /// 1 to 19.
+    ///
+    /// A level above the maximum is `BzImageError::InvalidLevel`.
+    #[cfg(feature = "std")]
+    pub fn compress_with_level(self, data: &[u8], level: u32) -> Result<Vec<u8>, BzImageError> {
+        if level > Codec::MAX_LEVEL {
+            return Err(BzImageError::InvalidLevel {
+                level,
+                max: Codec::MAX_LEVEL,
+            });
+        }
+        match self {
+            Codec::Gzip => {
+                let mut enc = GzEncoder::new(Vec::new(), Compression::new(level));
+                enc.write_all(data)?;
+                Ok(enc.finish()?)
+            }
+            Codec::Stored => Ok(data.to_vec()),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Ok(zstd::stream::encode_all(data, zstd_level(level))?),
+            #[cfg(not(feature = "zstd"))]
+            Codec::Zstd => Err(BzImageError::CodecNotEnabled(self)),
+        }
+    }
+
+    /// Wrap `r`, which yields data produced by this codec, in a reader of the decompressed bytes.
+    ///
+    /// Fails with `BzImageError::CodecNotEnabled` for a codec this build cannot decode.
+    #[cfg(feature = "std")]
+    pub(crate) fn decoder<'a, R: Read + 'a>(
+        self,
+        r: R,
+    ) -> Result<Box<dyn Read + 'a>, BzImageError> {
+        match self {
+            Codec::Gzip => Ok(Box::new(GzDecoder::new(r))),
+            Codec::Stored => Ok(Box::new(r)),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => {
+                let decoder =
+                    zstd::stream::read::Decoder::new(r).map_err(BzImageError::Decompression)?;
+                Ok(Box::new(decoder))
+            }
+            #[cfg(not(feature = "zstd"))]
+            Codec::Zstd => Err(BzImageError::CodecNotEnabled(self)),
+        }
+    }
+
+    /// Decompress `data`, which was produced by this codec.
+    #[cfg(feature = "std")]
+    pub fn decompress(self, data: &[u8]) -> Result<Vec<u8>, BzImageError> {
+        match self {
+            Codec::Stored => Ok(data.to_vec()),
+            _ => {
+                let mut out = Vec::new();
+                self.decoder(data)?
+                    .read_to_end(&mut out)
+                    .map_err(BzImageError::Decompression)?;
+                Ok(out)
+            }
+        }
+    }
+
+    /// Like [`Codec::decompress`], but fails with `BzImageError::OutputLimitExceeded` as soon
+    /// as the output would pass `limit` bytes, so a small input that expands enormously is
+    /// stopped after at most `limit + 1` bytes of output.
+    #[cfg(feature = "std")]
+    pub fn decompress_limited(self, data: &[u8], limit: u64) -> Result<Vec<u8>, BzImageError> {
+        let mut out = Vec::new();
+        self.decoder(data)?
+            .take(limit.saturating_add(1))
+            .read_to_end(&mut out)
+            .map_err(BzImageError::Decompression)?;
+        if out.len() as u64 > limit {
+            return Err(BzImageError::OutputLimitExceeded { limit });
+        }
+        Ok(out)
+    }
+}
+
+/// Guess the codec of `compressed` from its leading magic bytes: `1f 8b` for gzip and
+/// `28 b5 2f fd` for zstd.
+///
+/// For payloads whose header does not say, e.g. images written by tools that leave
+/// `reserved1` zero whatever the codec. Anything else, including xz (`fd 37 7a 58 5a 00`),
+/// which the format has no codec for, is `None`; so is `Codec::Stored`, which has no magic.
+pub fn detect_compression(compressed: &[u8]) -> Option<Codec> {
+    if compressed.starts_with(&[0x1f, 0x8b]) {
+        Some(Codec::Gzip)
+    } else if compressed.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Some(Codec::Zstd)
+    } else {
+        None
+    }
+}
+
+/// Map a level in 0..=`Codec::MAX_LEVEL` onto zstd's levels 1..=19.
+#[cfg(feature = "zstd")]
+pub(crate) fn zstd_level(level: u32) -> i32 {
+    1 + 2 * level.min(Codec::MAX_LEVEL) as i32
+}
+
+/// Most decompressed bytes a single [`try_decompress`] attempt may produce.
+#[cfg(feature = "std")]
+pub const TRY_DECOMPRESS_LIMIT: u64 = 256 << 20;
+
+/// Most codecs [`try_decompress`] will try before giving up.
+#[cfg(feature = "std")]
+pub const MAX_DECOMPRESS_ATTEMPTS: usize = 8;
+
+/// Decompress `compressed` with whichever enabled codec accepts it, for payloads whose codec
+/// byte is missing or wrong. Returns the data and the codec that worked.
+///
+/// Codecs are tried in `Codec::ALL` order, at most [`MAX_DECOMPRESS_ATTEMPTS`] of them, and
+/// an attempt is abandoned once it produces more than [`TRY_DECOMPRESS_LIMIT`] bytes.
+/// `Codec::Stored` is not tried, since any bytes at all are a valid stored payload; a caller
+/// that wants raw bytes as a last resort can fall back to `compressed` itself. The codec
+/// found is a guess, so check the result by other means (e.g. `uncompressed_size`) before
+/// relying on it. When no codec works the error is `BzImageError::NoMatchingCodec`, listing
+/// why each attempt failed.
+#[cfg(feature = "std")]
+pub fn try_decompress(compressed: &[u8]) -> Result<(Vec<u8>, Codec), BzImageError> {
+    let mut failures = Vec::new();
+    let candidates = Codec::ALL
+        .iter()
+        .copied()
+        .filter(|&codec| codec != Codec::Stored && codec.is_enabled())
+        .take(MAX_DECOMPRESS_ATTEMPTS);
+    for codec in candidates {
+        let mut out = Vec::new();
+        let result = codec
+            .decoder(compressed)?
+            .take(TRY_DECOMPRESS_LIMIT + 1)
+            .read_to_end(&mut out);
+        match result {
+            Ok(_) if out.len() as u64 > TRY_DECOMPRESS_LIMIT => {
+                failures.push(format!(
+                    "{codec:?}: output exceeds {TRY_DECOMPRESS_LIMIT} bytes"
+                ));
+            }
+            Ok(_) => return Ok((out, codec)),
+            Err(e) => failures.push(format!("{codec:?}: {e}")),
+        }
+    }
+    Err(BzImageError::NoMatchingCodec(failures.join("; ")))
+}
@@ -0,0 +1,88 @@
+//! Images whose payload lives in a separate blob file.
+//!
+//! The index file holds the header, with `BzImageFlags::DETACHED_PAYLOAD` set, followed
+//! directly by a footer whose [`DETACHED_PAYLOAD_KEY`] entry names the blob. The header's sizes
+//! and checksum describe the blob, so blobs can be shared between index files and stored under
+//! their checksum in a content-addressed directory.
+
+use crate::{BzImageError, BzImageFlags, BzImageHeader, Codec, Footer, read_footer};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Component, Path};
+
+/// Footer key holding the file name of a detached payload, relative to the payload directory.
+pub const DETACHED_PAYLOAD_KEY: &str = "bzimage.payload";
+
+/// Compress `data` with gzip into the blob `payload_path` and write an index file referring to
+/// it at `header_path`.
+///
+/// The index records only the blob's file name, so it can be read back with the blob in any
+/// directory (see [`read_detached`]). Returns the header written to the index.
+pub fn write_detached(
+    header_path: &Path,
+    payload_path: &Path,
+    data: &[u8],
+) -> Result<BzImageHeader, BzImageError> {
+    let name = payload_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or(BzImageError::InvalidDetachedReference(
+            "payload path has no UTF-8 file name",
+        ))?;
+
+    let compressed = Codec::Gzip.compress(data)?;
+    fs::write(payload_path, &compressed)?;
+
+    let mut header = BzImageHeader::new_for_payload(data.len() as u64, &compressed);
+    let mut flags = header.flags();
+    flags.insert(BzImageFlags::DETACHED_PAYLOAD | BzImageFlags::HAS_FOOTER);
+    header.set_flags(flags);
+    let mut footer = Footer::new();
+    footer.insert(DETACHED_PAYLOAD_KEY, name);
+
+    let file = File::create(header_path)?;
+    let mut w = BufWriter::new(file);
+    header.write_to(&mut w)?;
+    footer.write_to(&mut w)?;
+    w.flush()?;
+    Ok(header)
+}
+
+/// Read the index file at `header_path`, load the blob it names from `payload_dir`, verify it
+/// against the header and return the decompressed data.
+///
+/// The blob name must be a plain file name; a reference that tries to leave `payload_dir` is
+/// rejected.
+///
+/// An index without `DETACHED_PAYLOAD` is `BzImageError::NotDetached`; one whose footer does
+/// not name a usable blob is `BzImageError::InvalidDetachedReference`.
+pub fn read_detached(header_path: &Path, payload_dir: &Path) -> Result<Vec<u8>, BzImageError> {
+    let mut file = File::open(header_path)?;
+    let header = BzImageHeader::read_from(&mut file)?;
+    header.can_read()?;
+    if !header.flags().contains(BzImageFlags::DETACHED_PAYLOAD) {
+        return Err(BzImageError::NotDetached);
+    }
+    let footer = read_footer(&mut file)?.ok_or(BzImageError::InvalidDetachedReference(
+        "index file has no footer",
+    ))?;
+    let name = footer
+        .get(DETACHED_PAYLOAD_KEY)
+        .ok_or(BzImageError::InvalidDetachedReference(
+            "footer does not name the payload file",
+        ))?;
+    let mut components = Path::new(name).components();
+    if !matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) {
+        return Err(BzImageError::InvalidDetachedReference(
+            "payload reference is not a plain file name",
+        ));
+    }
+
+    let payload_path = payload_dir.join(name);
+    let compressed = fs::read(&payload_path)?;
+    header.validate_payload(&compressed)?;
+    header.codec()?.decompress(&compressed)
+}
@@ -0,0 +1,73 @@
+//! zstd dictionary compression, for many small images that share structure.
+//!
+//! An image compressed against a dictionary sets the critical `ZSTD_DICTIONARY` flag and
+//! records the dictionary's id in `reserved2`, so the reader can tell the right dictionary
+//! from a wrong one before decompressing anything. The codec is always `Codec::Zstd`; the
+//! checksum and sizes describe the payload as usual, so the image can be checked without the
+//! dictionary.
+
+use crate::codec::zstd_level;
+use crate::{BzImageError, BzImageFlags, BzImageHeader, Codec};
+use std::io::{Read, Write};
+
+/// The id recorded for `dict`: the id in its header for a dictionary trained by zstd, or a
+/// CRC-32 of its bytes for a raw-content dictionary, which has none.
+pub fn dictionary_id(dict: &[u8]) -> u32 {
+    zstd::zstd_safe::get_dict_id(dict).map_or_else(|| crc32fast::hash(dict), |id| id.get())
+}
+
+impl BzImageHeader {
+    /// Compress `data` with zstd against `dict` and write it to `w` as a complete image,
+    /// returning the header.
+    ///
+    /// `reserved2` holds the dictionary id, so the header can record neither a CRC-32 of `data`
+    /// nor one of itself.
+    pub fn pack_with_dict<W: Write>(
+        data: &[u8],
+        dict: &[u8],
+        w: &mut W,
+    ) -> Result<BzImageHeader, BzImageError> {
+        let compressed =
+            zstd::bulk::Compressor::with_dictionary(zstd_level(Codec::MAX_LEVEL), dict)?
+                .compress(data)?;
+        let mut header = BzImageHeader::new_for_payload(data.len() as u64, &[]);
+        header.set_codec(Codec::Zstd);
+        header.set_flags(header.flags() | BzImageFlags::ZSTD_DICTIONARY);
+        header.reserved2 = dictionary_id(dict).into();
+        header.write_with_payload(&compressed, w)?;
+        Ok(header)
+    }
+
+    /// Read the image at `r`, compressed against `dict`, and return the decompressed data;
+    /// the inverse of `pack_with_dict`.
+    ///
+    /// An image without `ZSTD_DICTIONARY` fails with `BzImageError::NoDictionary`, and one
+    /// recording another dictionary's id with `BzImageError::DictionaryMismatch`, both before
+    /// anything is decompressed. The checksum and decompressed length are then checked as by
+    /// `unpack`.
+    pub fn unpack_with_dict<R: Read>(r: &mut R, dict: &[u8]) -> Result<Vec<u8>, BzImageError> {
+        let (header, compressed) = Self::read_header_and_payload(r)?;
+        header.can_read()?;
+        let expected = header.dictionary_id().ok_or(BzImageError::NoDictionary)?;
+        let actual = dictionary_id(dict);
+        if expected != actual {
+            return Err(BzImageError::DictionaryMismatch { expected, actual });
+        }
+        header.validate_payload(&compressed)?;
+
+        let declared = header.uncompressed_size();
+        let mut decompressed = Vec::new();
+        zstd::stream::read::Decoder::with_dictionary(&compressed[..], dict)
+            .map_err(BzImageError::Decompression)?
+            .take(declared.saturating_add(1))
+            .read_to_end(&mut decompressed)
+            .map_err(BzImageError::Decompression)?;
+        if decompressed.len() as u64 != declared {
+            return Err(BzImageError::UncompressedSizeMismatch {
+                declared,
+                actual: decompressed.len() as u64,
+            });
+        }
+        Ok(decompressed)
+    }
+}
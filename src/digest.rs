@@ -0,0 +1,266 @@
+//! Payload digest algorithms.
+//!
+//! The header's 32-byte `checksum` field is SHA-256 of the compressed payload by default.
+//! Images can instead opt into [`DigestAlgo::Sha256Tree`], a two-level hash tree whose leaves
+//! can be hashed independently, which lets verification of very large payloads use every core
+//! (see [`compute_checksum_parallel`], behind the `parallel` feature).
+//!
+//! Tree construction: the payload is split into [`TREE_BLOCK_SIZE`] blocks (the last one may be
+//! shorter). Each leaf is `SHA-256(0x00 || block)` and the root stored in the header is
+//! `SHA-256(0x01 || leaf_0 || leaf_1 || ...)`. An empty payload has no leaves, so its root is
+//! `SHA-256(0x01)`. The domain-separation prefixes keep a leaf from being confused with a root.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use sha2::{Digest, Sha256, Sha512};
+#[cfg(feature = "std")]
+use std::io::{self, Read, Write};
+use subtle::ConstantTimeEq;
+
+/// Compare two checksums in time that does not depend on where they first differ, so a
+/// caller probing with forged checksums learns nothing from how long a rejection takes.
+pub(crate) fn checksums_match(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.ct_eq(b).into()
+}
+
+/// Size of the blocks hashed as leaves by [`DigestAlgo::Sha256Tree`].
+pub const TREE_BLOCK_SIZE: usize = 1 << 20;
+
+const LEAF_PREFIX: u8 = 0x00;
+const ROOT_PREFIX: u8 = 0x01;
+
+/// The algorithm used to compute the header checksum.
+///
+/// Every algorithm produces the 32 bytes the header's `checksum` field holds.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DigestAlgo {
+    /// Plain SHA-256 over the whole compressed payload. This is the format's original digest.
+    Sha256 = 0,
+    /// SHA-256 hash tree over fixed-size blocks; see the module docs.
+    Sha256Tree = 1,
+    /// The first 32 bytes of SHA-512 over the whole compressed payload, for policies that call
+    /// for SHA-512. (This is not SHA-512/256, which uses different initial values.)
+    Sha512Truncated = 2,
+    /// BLAKE3 over the whole compressed payload, with its default 32-byte output. Needs the
+    /// `blake3` feature; without it a header naming it fails with
+    /// `BzImageError::DigestNotEnabled`.
+    Blake3 = 3,
+}
+
+/// Another name for [`DigestAlgo`], for callers that think of it as the header's checksum
+/// algorithm field.
+pub type ChecksumAlgorithm = DigestAlgo;
+
+impl DigestAlgo {
+    /// The on-disk identifier of this algorithm.
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    /// Look up an algorithm by its on-disk identifier.
+    pub fn from_id(id: u8) -> Option<DigestAlgo> {
+        match id {
+            0 => Some(DigestAlgo::Sha256),
+            1 => Some(DigestAlgo::Sha256Tree),
+            2 => Some(DigestAlgo::Sha512Truncated),
+            3 => Some(DigestAlgo::Blake3),
+            _ => None,
+        }
+    }
+
+    /// Whether this build can compute checksums with this algorithm.
+    pub fn is_enabled(self) -> bool {
+        cfg!(feature = "blake3") || self != DigestAlgo::Blake3
+    }
+}
+
+/// Panic for an algorithm this build was compiled without; the header paths check
+/// [`DigestAlgo::is_enabled`] first.
+#[cfg(not(feature = "blake3"))]
+fn not_enabled(algo: DigestAlgo) -> ! {
+    panic!("digest algorithm {algo:?} is not enabled in this build")
+}
+
+fn leaf_digest(block: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(block);
+    hasher.finalize().into()
+}
+
+fn root_digest<'a>(leaves: impl IntoIterator<Item = &'a [u8; 32]>) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([ROOT_PREFIX]);
+    for leaf in leaves {
+        hasher.update(leaf);
+    }
+    hasher.finalize().into()
+}
+
+/// Format `bytes` as lowercase hexadecimal.
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Parse 64 hexadecimal digits, of either case, into a checksum.
+#[cfg(feature = "serde")]
+pub(crate) fn from_hex(s: &str) -> Option<[u8; 32]> {
+    let digits = s.as_bytes();
+    if digits.len() != 64 {
+        return None;
+    }
+    let nibble = |d: u8| (d as char).to_digit(16).map(|n| n as u8);
+    let mut out = [0u8; 32];
+    for (byte, pair) in out.iter_mut().zip(digits.chunks(2)) {
+        *byte = nibble(pair[0])? << 4 | nibble(pair[1])?;
+    }
+    Some(out)
+}
+
+/// Keep the first 32 bytes of a SHA-512 digest.
+fn truncate_sha512(hasher: Sha512) -> [u8; 32] {
+    hasher.finalize()[..32].try_into().unwrap()
+}
+
+/// Compute the checksum of `data` with `algo` on the current thread.
+///
+/// # Panics
+///
+/// If `algo` is not enabled in this build (see [`DigestAlgo::is_enabled`]).
+pub fn compute_checksum(algo: DigestAlgo, data: &[u8]) -> [u8; 32] {
+    match algo {
+        DigestAlgo::Sha256 => Sha256::digest(data).into(),
+        DigestAlgo::Sha256Tree => {
+            let leaves: Vec<[u8; 32]> = data.chunks(TREE_BLOCK_SIZE).map(leaf_digest).collect();
+            root_digest(&leaves)
+        }
+        DigestAlgo::Sha512Truncated => truncate_sha512(Sha512::new_with_prefix(data)),
+        #[cfg(feature = "blake3")]
+        DigestAlgo::Blake3 => blake3::hash(data).into(),
+        #[cfg(not(feature = "blake3"))]
+        DigestAlgo::Blake3 => not_enabled(algo),
+    }
+}
+
+#[cfg(feature = "std")]
+enum DigestState {
+    Sha256(Sha256),
+    Tree {
+        block: Vec<u8>,
+        leaves: Vec<[u8; 32]>,
+    },
+    Sha512(Sha512),
+    #[cfg(feature = "blake3")]
+    Blake3(Box<blake3::Hasher>),
+}
+
+#[cfg(feature = "std")]
+/// Incrementally computes a checksum over data written to it, producing the same value as
+/// [`compute_checksum`] without holding the whole payload in memory.
+///
+/// The tree algorithm buffers at most one [`TREE_BLOCK_SIZE`] block.
+pub(crate) struct Digester {
+    state: DigestState,
+}
+
+#[cfg(feature = "std")]
+impl Digester {
+    pub(crate) fn new(algo: DigestAlgo) -> Digester {
+        let state = match algo {
+            DigestAlgo::Sha256 => DigestState::Sha256(Sha256::new()),
+            DigestAlgo::Sha256Tree => DigestState::Tree {
+                block: Vec::new(),
+                leaves: Vec::new(),
+            },
+            DigestAlgo::Sha512Truncated => DigestState::Sha512(Sha512::new()),
+            #[cfg(feature = "blake3")]
+            DigestAlgo::Blake3 => DigestState::Blake3(Box::default()),
+            #[cfg(not(feature = "blake3"))]
+            DigestAlgo::Blake3 => not_enabled(algo),
+        };
+        Digester { state }
+    }
+
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        match &mut self.state {
+            DigestState::Sha256(hasher) => hasher.update(data),
+            DigestState::Tree { block, leaves } => {
+                while !data.is_empty() {
+                    let take = (TREE_BLOCK_SIZE - block.len()).min(data.len());
+                    block.extend_from_slice(&data[..take]);
+                    data = &data[take..];
+                    if block.len() == TREE_BLOCK_SIZE {
+                        leaves.push(leaf_digest(block));
+                        block.clear();
+                    }
+                }
+            }
+            DigestState::Sha512(hasher) => hasher.update(data),
+            #[cfg(feature = "blake3")]
+            DigestState::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    pub(crate) fn finalize(self) -> [u8; 32] {
+        match self.state {
+            DigestState::Sha256(hasher) => hasher.finalize().into(),
+            DigestState::Tree { block, mut leaves } => {
+                if !block.is_empty() {
+                    leaves.push(leaf_digest(&block));
+                }
+                root_digest(&leaves)
+            }
+            DigestState::Sha512(hasher) => truncate_sha512(hasher),
+            #[cfg(feature = "blake3")]
+            DigestState::Blake3(hasher) => hasher.finalize().into(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Write for Digester {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+/// Hashes bytes as they are read through it, e.g. as a decoder pulls payload bytes.
+pub(crate) struct DigestReader<R> {
+    pub(crate) inner: R,
+    pub(crate) digester: Digester,
+}
+
+#[cfg(feature = "std")]
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.digester.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Compute the checksum of `data` with `algo`, hashing tree leaves on the rayon thread pool.
+///
+/// Produces exactly the same value as [`compute_checksum`]. Only the tree can be split; the
+/// other algorithms are still hashed on the calling thread.
+#[cfg(feature = "parallel")]
+pub fn compute_checksum_parallel(algo: DigestAlgo, data: &[u8]) -> [u8; 32] {
+    use rayon::prelude::*;
+
+    match algo {
+        DigestAlgo::Sha256Tree => {
+            let leaves: Vec<[u8; 32]> = data.par_chunks(TREE_BLOCK_SIZE).map(leaf_digest).collect();
+            root_digest(&leaves)
+        }
+        _ => compute_checksum(algo, data),
+    }
+}
@@ -0,0 +1,133 @@
+//! Streaming payload encoding shared by the incremental writers.
+
+use crate::{BzImageError, Codec};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use sha2::{Digest, Sha256};
+use std::io::{self, Write};
+
+/// Forwards writes to `inner` while counting and SHA-256 hashing them.
+pub(crate) struct HashWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<W: Write> HashWriter<W> {
+    pub(crate) fn new(inner: W) -> HashWriter<W> {
+        HashWriter {
+            inner,
+            hasher: Sha256::new(),
+            len: 0,
+        }
+    }
+
+    /// Return the inner writer, the byte count and the digest of everything written.
+    pub(crate) fn finish(self) -> (W, u64, [u8; 32]) {
+        (self.inner, self.len, self.hasher.finalize().into())
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+enum Stage<W: Write> {
+    Gzip(GzEncoder<HashWriter<W>>),
+    Stored(HashWriter<W>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::write::Encoder<'static, HashWriter<W>>),
+}
+
+/// The sizes and SHA-256 checksum of a payload produced by `Encoder`.
+pub(crate) struct Encoded {
+    pub(crate) uncompressed_len: u64,
+    pub(crate) compressed_len: u64,
+    pub(crate) checksum: [u8; 32],
+}
+
+/// Compresses data written to it with a codec, writing the compressed bytes to `W` as they
+/// are produced and hashing them on the way out.
+pub(crate) struct Encoder<W: Write> {
+    stage: Stage<W>,
+    uncompressed_len: u64,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Compress with `codec` at `level`, as `Codec::compress_with_level` does.
+    ///
+    /// Fails with `BzImageError::CodecNotEnabled` for a codec this build cannot encode and
+    /// `BzImageError::InvalidLevel` for a level above `Codec::MAX_LEVEL`.
+    pub(crate) fn new(inner: W, codec: Codec, level: u32) -> Result<Encoder<W>, BzImageError> {
+        if level > Codec::MAX_LEVEL {
+            return Err(BzImageError::InvalidLevel {
+                level,
+                max: Codec::MAX_LEVEL,
+            });
+        }
+        let sink = HashWriter::new(inner);
+        let stage = match codec {
+            Codec::Gzip => Stage::Gzip(GzEncoder::new(sink, Compression::new(level))),
+            Codec::Stored => Stage::Stored(sink),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Stage::Zstd(zstd::stream::write::Encoder::new(
+                sink,
+                crate::codec::zstd_level(level),
+            )?),
+            #[cfg(not(feature = "zstd"))]
+            Codec::Zstd => return Err(BzImageError::CodecNotEnabled(codec)),
+        };
+        Ok(Encoder {
+            stage,
+            uncompressed_len: 0,
+        })
+    }
+
+    /// Flush the codec's final bytes and return the inner writer and the payload summary.
+    pub(crate) fn finish(self) -> io::Result<(W, Encoded)> {
+        let sink = match self.stage {
+            Stage::Gzip(enc) => enc.finish()?,
+            Stage::Stored(sink) => sink,
+            #[cfg(feature = "zstd")]
+            Stage::Zstd(enc) => enc.finish()?,
+        };
+        let (inner, compressed_len, checksum) = sink.finish();
+        let encoded = Encoded {
+            uncompressed_len: self.uncompressed_len,
+            compressed_len,
+            checksum,
+        };
+        Ok((inner, encoded))
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = match &mut self.stage {
+            Stage::Gzip(enc) => enc.write(buf)?,
+            Stage::Stored(sink) => sink.write(buf)?,
+            #[cfg(feature = "zstd")]
+            Stage::Zstd(enc) => enc.write(buf)?,
+        };
+        self.uncompressed_len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.stage {
+            Stage::Gzip(enc) => enc.flush(),
+            Stage::Stored(sink) => sink.flush(),
+            #[cfg(feature = "zstd")]
+            Stage::Zstd(enc) => enc.flush(),
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! Typed errors for conditions callers may want to handle programmatically.
+//!
+//! Every fallible public API returns `Result<T, BzImageError>`, so callers can `match` on the
+//! variant. The underlying I/O or codec error of `Io` and `Decompression` is reachable through
+//! `source()`.
+
+use crate::digest::to_hex;
+use crate::{Codec, DigestAlgo, HEADER_SIZE, MAGIC};
+use alloc::format;
+use alloc::string::String;
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::io;
+
+fn truncated_payload(declared: u64, available: Option<u64>) -> String {
+    match available {
+        Some(available) => {
+            format!(
+                "truncated payload: header declares {declared} bytes, only {available} available"
+            )
+        }
+        None => format!("truncated payload: header declares {declared} bytes"),
+    }
+}
+
+/// A bzimage-specific failure.
+#[derive(Debug, thiserror::Error)]
+pub enum BzImageError {
+    /// The input does not start with the bzimage magic, `MAGIC` or `MAGIC_BE`.
+    #[error("invalid magic: expected {}, found 0x{}", MAGIC.escape_ascii(), to_hex(.found))]
+    InvalidMagic { found: [u8; 4] },
+    /// The input ended before a whole `HEADER_SIZE`-byte header was read.
+    #[error("truncated header: expected {HEADER_SIZE} bytes")]
+    TruncatedHeader,
+    /// The header's fields contradict each other or the format, for the reason given; see
+    /// `BzImageHeader::validate_header`.
+    #[error("malformed header: {0}")]
+    MalformedHeader(&'static str),
+    /// The input ended before the `compressed_size` bytes announced by the header.
+    ///
+    /// `available` is the number of payload bytes that were actually present, when the reader
+    /// was able to tell.
+    #[error("{}", truncated_payload(*.declared, *.available))]
+    TruncatedPayload {
+        declared: u64,
+        available: Option<u64>,
+    },
+    /// The header does not match the CRC-32 it records for itself (`HEADER_CRC32`), so at
+    /// least one of its fields is damaged.
+    #[error("header CRC-32 mismatch: expected {expected:#010x}, computed {actual:#010x}")]
+    HeaderCrcMismatch { expected: u32, actual: u32 },
+    /// `reserved2` already holds the value of another flag.
+    #[error("reserved2 is already in use by another flag")]
+    Reserved2InUse,
+    /// The extended header (`HAS_EXTENDED_HEADER`) is malformed, for the reason given.
+    #[error("invalid extended header: {0}")]
+    InvalidExtendedHeader(&'static str),
+    /// The image has an extended header, whose length the 64-byte header does not record.
+    #[error("the header is followed by an extended header of unrecorded length")]
+    ExtendedHeaderPresent,
+    /// The metadata footer (`HAS_FOOTER`) is malformed, for the reason given.
+    #[error("invalid footer: {0}")]
+    InvalidFooter(&'static str),
+    /// An image in the trailing-header layout is malformed, for the reason given.
+    #[error("invalid trailing-header layout: {0}")]
+    InvalidTrailingLayout(&'static str),
+    /// The length prefix of a frame written by `write_framed` is malformed.
+    #[error("invalid frame: {0}")]
+    InvalidFrame(&'static str),
+    /// A frame holds fewer bytes than its length prefix declares.
+    #[error("frame truncated: prefix declares {declared} bytes, read {actual}")]
+    TruncatedFrame { declared: u64, actual: u64 },
+    /// Chunk `index` of a `STREAMING_FRAMED` payload does not match its own checksum.
+    #[error("checksum mismatch in chunk {index}")]
+    ChunkChecksumMismatch { index: u64 },
+    /// Chunk `index` of a `STREAMING_FRAMED` payload has impossible lengths or does not
+    /// decode to the length it declares.
+    #[error("invalid chunk {index}")]
+    InvalidChunk { index: u64 },
+    /// A `FramedWriter` was asked for chunks of `size` bytes, outside 1..=`max`.
+    #[error("chunk size {size} is not between 1 and {max}")]
+    InvalidChunkSize { size: usize, max: usize },
+    /// A chunked reader was given an image without `STREAMING_FRAMED`.
+    #[error("image is not STREAMING_FRAMED")]
+    NotFramed,
+    /// The payload length differs from the header's `compressed_size`.
+    #[error("payload size mismatch: header declares {declared} bytes, got {actual}")]
+    SizeMismatch { declared: u64, actual: u64 },
+    /// The payload decompresses to a different length than the header's `uncompressed_size`.
+    ///
+    /// Every path that checks the decoded length reports this, `decompress_verified` included:
+    /// `declared` is the expected length and `actual` the length decoded.
+    #[error("uncompressed size mismatch: header declares {declared} bytes, decoded {actual}")]
+    UncompressedSizeMismatch { declared: u64, actual: u64 },
+    /// The decompressed data does not match the CRC-32 the header records for it
+    /// (`UNCOMPRESSED_CRC32`).
+    #[error("uncompressed CRC-32 mismatch: expected {expected:#010x}, computed {actual:#010x}")]
+    UncompressedCrcMismatch { expected: u32, actual: u32 },
+    /// The header's `compressed_size` is larger than the caller's limit of `limit` bytes.
+    #[error("payload of {declared} bytes exceeds the {limit} byte limit")]
+    PayloadTooLarge { declared: u64, limit: u64 },
+    /// Decompressing would produce more than the caller's limit of `limit` bytes.
+    #[error("decompressed output exceeds the {limit} byte limit")]
+    OutputLimitExceeded { limit: u64 },
+    /// A reader yielded more than `limit` bytes without reaching end of input.
+    #[error("input exceeds the {limit} byte stream limit")]
+    StreamTooLong { limit: u64 },
+    /// The payload does not hash to the header's stored checksum.
+    #[error("checksum mismatch: expected {}, computed {}", to_hex(.expected), to_hex(.actual))]
+    ChecksumMismatch {
+        expected: [u8; 32],
+        actual: [u8; 32],
+    },
+    /// `BzImageHeaderBuilder::build` was called without the named field being set.
+    #[error("incomplete header: {0} is not set")]
+    IncompleteHeader(&'static str),
+    /// The header's format version is not one this build understands.
+    #[error("unsupported format version {0}")]
+    UnsupportedVersion(u32),
+    /// The header names a codec identifier this build has never heard of.
+    #[error("unknown codec {0}")]
+    UnknownCodec(u8),
+    /// `try_decompress` found no enabled codec that decodes the payload; the message lists
+    /// each attempt's failure.
+    #[error("no codec could decompress the payload ({0})")]
+    NoMatchingCodec(String),
+    /// `recompress` re-encoded the payload with this codec, but decoding the result did not
+    /// give back the original data.
+    #[error("{0:?} did not give back the original data")]
+    RoundTripMismatch(Codec),
+    /// The header names a known codec that this build was compiled without.
+    #[error("codec {0:?} is not enabled in this build")]
+    CodecNotEnabled(Codec),
+    /// A compression level above `Codec::MAX_LEVEL` was requested.
+    #[error("compression level {level} is above the maximum of {max}")]
+    InvalidLevel { level: u32, max: u32 },
+    /// The header names a digest algorithm identifier this build has never heard of.
+    #[error("unknown digest algorithm {0}")]
+    UnknownDigestAlgo(u8),
+    /// The header names a known digest algorithm that this build was compiled without.
+    #[error("digest algorithm {0:?} is not enabled in this build")]
+    DigestNotEnabled(DigestAlgo),
+    /// The header sets critical flag bits this build does not understand.
+    #[error("unknown critical flags {0:#06x}")]
+    UnknownCriticalFlag(u16),
+    /// The payload lives in a separate file (`DETACHED_PAYLOAD`), so it cannot be read from
+    /// the image itself.
+    #[error("the payload is stored in a separate file; use read_detached")]
+    DetachedPayload,
+    /// The payload is encrypted (`ENCRYPTED`), so it cannot be decompressed as it stands.
+    #[error("the payload is encrypted; use unpack_encrypted")]
+    Encrypted,
+    /// A gzip-only operation was given data or an image that is not gzip.
+    #[error("not gzip compressed")]
+    NotGzip,
+    /// `read_detached` was given an index file without the `DETACHED_PAYLOAD` flag.
+    #[error("image is not DETACHED_PAYLOAD")]
+    NotDetached,
+    /// The footer of a detached index does not name a blob that can be read, for the reason
+    /// given.
+    #[error("invalid detached payload reference: {0}")]
+    InvalidDetachedReference(&'static str),
+    /// `unpack_encrypted` was given an image without the `ENCRYPTED` flag.
+    #[error("image is not ENCRYPTED")]
+    NotEncrypted,
+    /// The payload was compressed against the zstd dictionary with this id
+    /// (`ZSTD_DICTIONARY`), so it cannot be decompressed without it.
+    #[error("the payload needs zstd dictionary {0:#010x}; use unpack_with_dict")]
+    DictionaryRequired(u32),
+    /// `unpack_with_dict` was given a dictionary other than the one the image records.
+    #[error("dictionary mismatch: image needs {expected:#010x}, got {actual:#010x}")]
+    DictionaryMismatch { expected: u32, actual: u32 },
+    /// `unpack_with_dict` was given an image without the `ZSTD_DICTIONARY` flag.
+    #[error("image was not compressed with a dictionary")]
+    NoDictionary,
+    /// An encrypted payload did not decrypt, or a `SIGNED` image's tag did not match: the
+    /// key is wrong, or the payload or header was altered.
+    #[error("authentication failed: wrong key or tampered image")]
+    AuthenticationFailed,
+    /// `unpack_signed` was given an image without the `SIGNED` flag.
+    #[error("image is not SIGNED")]
+    NotSigned,
+    /// The header announces a signature trailer (`HAS_SIGNATURE`) that is missing or does not
+    /// fit in the image.
+    #[error("image is flagged HAS_SIGNATURE but has no valid signature trailer")]
+    MissingSignature,
+    /// A signature of this many bytes does not fit the trailer's 32-bit length.
+    #[error("signature of {0} bytes is too long")]
+    SignatureTooLong(usize),
+    /// The image's checksum is not in the caller's allowlist.
+    #[error("untrusted checksum {}", to_hex(.0))]
+    UntrustedChecksum([u8; 32]),
+    /// An operation with a deadline did not finish within it.
+    #[error("timed out after {0:?}")]
+    TimedOut(Duration),
+    /// Reading or writing the image failed.
+    // the wrapped error is reported through `source()`
+    #[cfg(feature = "std")]
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+    /// The codec rejected the payload as corrupt.
+    #[cfg(feature = "std")]
+    #[error("decompression failed")]
+    Decompression(#[source] io::Error),
+}
@@ -0,0 +1,325 @@
+//! The extended header: a variable-length region between the fixed header and the payload,
+//! flagged by the critical `BzImageFlags::HAS_EXTENDED_HEADER`.
+//!
+//! Layout (integers little-endian):
+//!
+//! - len: u32 — length of the entries that follow, at most `MAX_EXTENDED_HEADER_SIZE`
+//! - entries: `len` bytes of records, each a u16 tag, a u32 value length and the value
+//!
+//! Entries are written in tag order, each tag at most once. Readers skip tags they do not
+//! know, so new entries can be added without a new flag. The region is not covered by the
+//! checksum, which describes the payload only, and `compressed_size` does not count it
+//! either; the HMAC tag of a `SIGNED` image does cover it (see `BzImageHeader::sign`).
+//!
+//! | tag | entry   | value |
+//! |-----|---------|-------|
+//! | 1   | name    | the original file name, UTF-8 |
+//! | 2   | mtime   | the original modification time, u64 seconds since the Unix epoch |
+//! | 3   | meta    | string key/value pairs, each a u32 length and UTF-8 key then a u32 length and UTF-8 value, in key order |
+//!
+//! A map serializes to the same bytes however it was built, so identical metadata gives
+//! identical images.
+
+use crate::BzImageError;
+#[cfg(feature = "std")]
+use crate::{BzImageFlags, BzImageHeader, compute_checksum};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::io::{self, Read, Write};
+
+/// Largest extended header accepted, counting the entries but not the length prefix.
+pub const MAX_EXTENDED_HEADER_SIZE: usize = 64 << 10;
+
+/// Largest key/value metadata accepted, as serialized in its entry.
+pub const MAX_METADATA_SIZE: usize = 16 << 10;
+
+const TAG_NAME: u16 = 1;
+const TAG_MTIME: u16 = 2;
+const TAG_META: u16 = 3;
+
+/// The entries of an extended header.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExtendedHeader {
+    name: Option<String>,
+    mtime: Option<u64>,
+    meta: BTreeMap<String, String>,
+}
+
+/// Bytes `key` and `value` take up in the metadata entry.
+fn meta_len(key: &str, value: &str) -> usize {
+    8 + key.len() + value.len()
+}
+
+impl ExtendedHeader {
+    pub fn new() -> ExtendedHeader {
+        ExtendedHeader::default()
+    }
+
+    /// Whether there are no entries, in which case images are written without the region.
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none() && self.mtime.is_none() && self.meta.is_empty()
+    }
+
+    /// Record the original file name and modification time (seconds since the Unix epoch).
+    pub fn set_metadata(&mut self, name: impl Into<String>, mtime: u64) {
+        self.name = Some(name.into());
+        self.mtime = Some(mtime);
+    }
+
+    /// The original file name and modification time, if both were recorded.
+    pub fn metadata(&self) -> Option<(&str, u64)> {
+        Some((self.name.as_deref()?, self.mtime?))
+    }
+
+    /// The original file name, if recorded.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The original modification time, in seconds since the Unix epoch, if recorded.
+    pub fn mtime(&self) -> Option<u64> {
+        self.mtime
+    }
+
+    /// Set the metadata entry `key` to `value`, replacing any previous value.
+    ///
+    /// Fails with `BzImageError::InvalidExtendedHeader`, leaving the map unchanged, if the
+    /// metadata would then exceed `MAX_METADATA_SIZE`.
+    pub fn set_meta(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<(), BzImageError> {
+        let (key, value) = (key.into(), value.into());
+        let replaced = self.meta.get(&key).map_or(0, |old| meta_len(&key, old));
+        if self.meta_size() - replaced + meta_len(&key, &value) > MAX_METADATA_SIZE {
+            return Err(BzImageError::InvalidExtendedHeader("metadata too large"));
+        }
+        self.meta.insert(key, value);
+        Ok(())
+    }
+
+    /// The metadata value for `key`, if set.
+    pub fn get_meta(&self, key: &str) -> Option<&str> {
+        self.meta.get(key).map(String::as_str)
+    }
+
+    /// The metadata entries, in key order.
+    pub fn meta_iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.meta.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    fn meta_size(&self) -> usize {
+        self.meta.iter().map(|(k, v)| meta_len(k, v)).sum()
+    }
+
+    /// Serialize the region, length prefix included. Fails with
+    /// `BzImageError::InvalidExtendedHeader` if it would exceed `MAX_EXTENDED_HEADER_SIZE`.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, BzImageError> {
+        fn entry(out: &mut Vec<u8>, tag: u16, value: &[u8]) {
+            out.extend_from_slice(&tag.to_le_bytes());
+            out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            out.extend_from_slice(value);
+        }
+
+        let mut entries = Vec::new();
+        if let Some(name) = &self.name {
+            if name.len() > MAX_EXTENDED_HEADER_SIZE {
+                return Err(BzImageError::InvalidExtendedHeader("too large"));
+            }
+            entry(&mut entries, TAG_NAME, name.as_bytes());
+        }
+        if let Some(mtime) = self.mtime {
+            entry(&mut entries, TAG_MTIME, &mtime.to_le_bytes());
+        }
+        if !self.meta.is_empty() {
+            let mut meta = Vec::with_capacity(self.meta_size());
+            for (key, value) in &self.meta {
+                for s in [key, value] {
+                    meta.extend_from_slice(&(s.len() as u32).to_le_bytes());
+                    meta.extend_from_slice(s.as_bytes());
+                }
+            }
+            entry(&mut entries, TAG_META, &meta);
+        }
+        if entries.len() > MAX_EXTENDED_HEADER_SIZE {
+            return Err(BzImageError::InvalidExtendedHeader("too large"));
+        }
+        let mut out = Vec::with_capacity(4 + entries.len());
+        out.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+        out.extend_from_slice(&entries);
+        Ok(out)
+    }
+
+    /// Parse the entries of a region (without its length prefix).
+    pub(crate) fn parse_entries(mut entries: &[u8]) -> Result<ExtendedHeader, BzImageError> {
+        let mut ext = ExtendedHeader::new();
+        let mut last = None;
+        while !entries.is_empty() {
+            if entries.len() < 6 {
+                return Err(BzImageError::InvalidExtendedHeader("truncated entry"));
+            }
+            let tag = u16::from_le_bytes(entries[..2].try_into().unwrap());
+            let len = u32::from_le_bytes(entries[2..6].try_into().unwrap()) as usize;
+            let value = entries
+                .get(6..6 + len)
+                .ok_or(BzImageError::InvalidExtendedHeader("truncated entry"))?;
+            entries = &entries[6 + len..];
+            if last.is_some_and(|last| tag <= last) {
+                return Err(BzImageError::InvalidExtendedHeader("entries out of order"));
+            }
+            last = Some(tag);
+            match tag {
+                TAG_NAME => {
+                    let name = core::str::from_utf8(value)
+                        .map_err(|_| BzImageError::InvalidExtendedHeader("name is not UTF-8"))?;
+                    ext.name = Some(name.into());
+                }
+                TAG_MTIME => {
+                    let mtime = value
+                        .try_into()
+                        .map_err(|_| BzImageError::InvalidExtendedHeader("bad mtime"))?;
+                    ext.mtime = Some(u64::from_le_bytes(mtime));
+                }
+                TAG_META => ext.meta = parse_meta(value)?,
+                _ => {}
+            }
+        }
+        Ok(ext)
+    }
+
+    /// Parse a region, length prefix included, from the start of `bytes`, returning it and
+    /// the number of bytes it takes up.
+    pub fn from_bytes(bytes: &[u8]) -> Result<(ExtendedHeader, usize), BzImageError> {
+        let len = region_len(bytes)?;
+        let entries = bytes
+            .get(4..4 + len)
+            .ok_or(BzImageError::InvalidExtendedHeader("truncated"))?;
+        Ok((ExtendedHeader::parse_entries(entries)?, 4 + len))
+    }
+}
+
+/// Parse the value of a metadata entry.
+fn parse_meta(mut value: &[u8]) -> Result<BTreeMap<String, String>, BzImageError> {
+    fn string<'a>(value: &mut &'a [u8]) -> Result<&'a str, BzImageError> {
+        let bad = || BzImageError::InvalidExtendedHeader("bad metadata");
+        let len = value.get(..4).ok_or_else(bad)?;
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        let s = value.get(4..4 + len).ok_or_else(bad)?;
+        *value = &value[4 + len..];
+        core::str::from_utf8(s).map_err(|_| bad())
+    }
+
+    if value.len() > MAX_METADATA_SIZE {
+        return Err(BzImageError::InvalidExtendedHeader("metadata too large"));
+    }
+    let mut meta = BTreeMap::new();
+    let mut last: Option<&str> = None;
+    while !value.is_empty() {
+        let key = string(&mut value)?;
+        let val = string(&mut value)?;
+        // strictly increasing keys keep the encoding of a map unique
+        if last.is_some_and(|last| key <= last) {
+            return Err(BzImageError::InvalidExtendedHeader("metadata out of order"));
+        }
+        last = Some(key);
+        meta.insert(key.into(), val.into());
+    }
+    Ok(meta)
+}
+
+/// The entries length from the prefix at the start of `bytes`, checked against the maximum.
+pub(crate) fn region_len(bytes: &[u8]) -> Result<usize, BzImageError> {
+    let prefix = bytes
+        .get(..4)
+        .ok_or(BzImageError::InvalidExtendedHeader("truncated"))?;
+    let len = u32::from_le_bytes(prefix.try_into().unwrap()) as usize;
+    if len > MAX_EXTENDED_HEADER_SIZE {
+        return Err(BzImageError::InvalidExtendedHeader("too large"));
+    }
+    Ok(len)
+}
+
+#[cfg(feature = "std")]
+impl BzImageHeader {
+    /// Read the extended header from `r`, which must be positioned just past the fixed header,
+    /// leaving `r` at the start of the payload. Returns `None`, reading nothing, if the image
+    /// has no extended header.
+    pub fn read_extended<R: Read>(&self, r: R) -> Result<Option<ExtendedHeader>, BzImageError> {
+        if !self.flags().contains(BzImageFlags::HAS_EXTENDED_HEADER) {
+            return Ok(None);
+        }
+        let region = self.read_extended_bytes(r)?;
+        ExtendedHeader::parse_entries(&region[4..]).map(Some)
+    }
+
+    /// Like `read_extended`, returning the region as it is on disk, length prefix included,
+    /// or nothing if there is none.
+    pub(crate) fn read_extended_bytes<R: Read>(&self, mut r: R) -> Result<Vec<u8>, BzImageError> {
+        if !self.flags().contains(BzImageFlags::HAS_EXTENDED_HEADER) {
+            return Ok(Vec::new());
+        }
+        let mut region = vec![0u8; 4];
+        r.read_exact(&mut region).map_err(truncated)?;
+        region.resize(4 + region_len(&region)?, 0);
+        r.read_exact(&mut region[4..]).map_err(truncated)?;
+        Ok(region)
+    }
+
+    /// Write the header, `extended` and then `compressed` to `w`, setting or clearing
+    /// `HAS_EXTENDED_HEADER` first; an empty `extended` is not written at all.
+    ///
+    /// As with `write_with_payload`, `compressed_size` and `checksum` are recomputed from
+    /// `compressed`.
+    pub fn write_with_extended<W: Write>(
+        &mut self,
+        extended: &ExtendedHeader,
+        compressed: &[u8],
+        w: &mut W,
+    ) -> Result<(), BzImageError> {
+        let mut flags = self.flags();
+        let region = if extended.is_empty() {
+            flags.remove(BzImageFlags::HAS_EXTENDED_HEADER);
+            Vec::new()
+        } else {
+            flags.insert(BzImageFlags::HAS_EXTENDED_HEADER);
+            extended.to_bytes()?
+        };
+        self.set_flags(flags);
+        self.compressed_size = (compressed.len() as u64).into();
+        self.checksum = compute_checksum(self.digest_algo()?, compressed);
+        self.write_to(&mut *w)?;
+        w.write_all(&region)?;
+        w.write_all(compressed)?;
+        Ok(())
+    }
+
+    /// Like `read_extended`, discarding the entries.
+    pub(crate) fn skip_extended<R: Read>(&self, r: R) -> Result<(), BzImageError> {
+        self.read_extended_bytes(r).map(drop)
+    }
+
+    /// Bytes between the fixed header and the payload in `after_header`, the image bytes that
+    /// follow the fixed header: the extended header's size, or 0 if there is none.
+    pub(crate) fn extended_len(&self, after_header: &[u8]) -> Result<usize, BzImageError> {
+        if !self.flags().contains(BzImageFlags::HAS_EXTENDED_HEADER) {
+            return Ok(0);
+        }
+        let len = 4 + region_len(after_header)?;
+        if after_header.len() < len {
+            return Err(BzImageError::InvalidExtendedHeader("truncated"));
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(feature = "std")]
+fn truncated(e: io::Error) -> BzImageError {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        BzImageError::InvalidExtendedHeader("truncated")
+    } else {
+        BzImageError::Io(e)
+    }
+}
@@ -0,0 +1,239 @@
+//! C ABI for parsing headers, behind the `ffi` feature.
+//!
+//! Callers that already hold the header bytes can validate and decode them with
+//! [`bzimage_parse_header`] instead of reimplementing the layout.
+
+use crate::{BzImageError, BzImageHeader, HEADER_SIZE};
+
+/// The header was parsed and `out` was filled in.
+pub const BZIMAGE_OK: i32 = 0;
+/// `ptr` or `out` was null.
+pub const BZIMAGE_ERR_NULL: i32 = -1;
+/// `len` was less than `HEADER_SIZE`, or the input ended early.
+pub const BZIMAGE_ERR_TRUNCATED: i32 = -2;
+/// The buffer does not start with the `DMNZ` magic.
+pub const BZIMAGE_ERR_BAD_MAGIC: i32 = -3;
+/// The header is well formed but records a format version this build does not support.
+pub const BZIMAGE_ERR_UNSUPPORTED: i32 = -4;
+/// The header is damaged: it does not match the CRC-32 it records for itself.
+pub const BZIMAGE_ERR_CORRUPT: i32 = -5;
+/// The header's fields contradict each other (`BzImageError::MalformedHeader`).
+pub const BZIMAGE_ERR_MALFORMED: i32 = -6;
+/// The header names a codec this crate does not know.
+pub const BZIMAGE_ERR_UNKNOWN_CODEC: i32 = -7;
+/// The header's codec is known but not compiled into this build.
+pub const BZIMAGE_ERR_CODEC_NOT_ENABLED: i32 = -8;
+/// The header names a digest algorithm this crate does not know.
+pub const BZIMAGE_ERR_UNKNOWN_DIGEST: i32 = -9;
+/// The header sets critical flags this build does not understand.
+pub const BZIMAGE_ERR_UNKNOWN_CRITICAL_FLAG: i32 = -10;
+/// `reserved2` is already claimed by another flag.
+pub const BZIMAGE_ERR_RESERVED2_IN_USE: i32 = -11;
+/// The extended header is malformed.
+pub const BZIMAGE_ERR_INVALID_EXTENDED_HEADER: i32 = -12;
+/// The image has an extended header whose length is not recorded.
+pub const BZIMAGE_ERR_EXTENDED_HEADER_PRESENT: i32 = -13;
+/// The metadata footer is malformed.
+pub const BZIMAGE_ERR_INVALID_FOOTER: i32 = -14;
+/// An image in the trailing-header layout is malformed.
+pub const BZIMAGE_ERR_INVALID_TRAILING_LAYOUT: i32 = -15;
+/// A frame's length prefix is malformed.
+pub const BZIMAGE_ERR_INVALID_FRAME: i32 = -16;
+/// A frame holds fewer bytes than its prefix declares.
+pub const BZIMAGE_ERR_TRUNCATED_FRAME: i32 = -17;
+/// A chunk of a `STREAMING_FRAMED` payload fails its own checksum.
+pub const BZIMAGE_ERR_CHUNK_CHECKSUM_MISMATCH: i32 = -18;
+/// A chunk of a `STREAMING_FRAMED` payload is malformed.
+pub const BZIMAGE_ERR_INVALID_CHUNK: i32 = -19;
+/// A chunk size is out of range.
+pub const BZIMAGE_ERR_INVALID_CHUNK_SIZE: i32 = -20;
+/// The image is not `STREAMING_FRAMED`.
+pub const BZIMAGE_ERR_NOT_FRAMED: i32 = -21;
+/// The payload is not the length the header declares.
+pub const BZIMAGE_ERR_SIZE_MISMATCH: i32 = -22;
+/// The payload decodes to a different length than declared.
+pub const BZIMAGE_ERR_UNCOMPRESSED_SIZE_MISMATCH: i32 = -23;
+/// The decoded data does not match the recorded CRC-32.
+pub const BZIMAGE_ERR_UNCOMPRESSED_CRC_MISMATCH: i32 = -24;
+/// The declared payload exceeds the allowed size.
+pub const BZIMAGE_ERR_PAYLOAD_TOO_LARGE: i32 = -25;
+/// Decompressed output exceeds the allowed size.
+pub const BZIMAGE_ERR_OUTPUT_LIMIT_EXCEEDED: i32 = -26;
+/// Input exceeds the stream limit.
+pub const BZIMAGE_ERR_STREAM_TOO_LONG: i32 = -27;
+/// The payload does not match the stored checksum.
+pub const BZIMAGE_ERR_CHECKSUM_MISMATCH: i32 = -28;
+/// A header was built without a required field.
+pub const BZIMAGE_ERR_INCOMPLETE_HEADER: i32 = -29;
+/// No codec could decompress the payload.
+pub const BZIMAGE_ERR_NO_MATCHING_CODEC: i32 = -30;
+/// A codec did not give back the original data.
+pub const BZIMAGE_ERR_ROUND_TRIP_MISMATCH: i32 = -31;
+/// A compression level is out of range.
+pub const BZIMAGE_ERR_INVALID_LEVEL: i32 = -32;
+/// The payload is stored in a separate file.
+pub const BZIMAGE_ERR_DETACHED_PAYLOAD: i32 = -33;
+/// The payload is encrypted.
+pub const BZIMAGE_ERR_ENCRYPTED: i32 = -34;
+/// The data or image is not gzip.
+pub const BZIMAGE_ERR_NOT_GZIP: i32 = -35;
+/// The image is not `DETACHED_PAYLOAD`.
+pub const BZIMAGE_ERR_NOT_DETACHED: i32 = -36;
+/// A detached index does not name a readable blob.
+pub const BZIMAGE_ERR_INVALID_DETACHED_REFERENCE: i32 = -37;
+/// The image is not `ENCRYPTED`.
+pub const BZIMAGE_ERR_NOT_ENCRYPTED: i32 = -38;
+/// The payload needs a zstd dictionary.
+pub const BZIMAGE_ERR_DICTIONARY_REQUIRED: i32 = -39;
+/// The wrong zstd dictionary was given.
+pub const BZIMAGE_ERR_DICTIONARY_MISMATCH: i32 = -40;
+/// The image was not compressed with a dictionary.
+pub const BZIMAGE_ERR_NO_DICTIONARY: i32 = -41;
+/// Authentication failed: wrong key or tampered image.
+pub const BZIMAGE_ERR_AUTHENTICATION_FAILED: i32 = -42;
+/// The image is not `SIGNED`.
+pub const BZIMAGE_ERR_NOT_SIGNED: i32 = -43;
+/// The image announces a signature trailer it does not have.
+pub const BZIMAGE_ERR_MISSING_SIGNATURE: i32 = -44;
+/// A signature is too long for the trailer.
+pub const BZIMAGE_ERR_SIGNATURE_TOO_LONG: i32 = -45;
+/// The image's checksum is not in the allowlist.
+pub const BZIMAGE_ERR_UNTRUSTED_CHECKSUM: i32 = -46;
+/// An operation did not finish within its deadline.
+pub const BZIMAGE_ERR_TIMED_OUT: i32 = -47;
+/// An I/O error other than a short read.
+pub const BZIMAGE_ERR_IO: i32 = -48;
+/// The codec failed to decode the payload.
+pub const BZIMAGE_ERR_DECOMPRESSION: i32 = -49;
+/// The digest algorithm is not compiled into this build.
+pub const BZIMAGE_ERR_DIGEST_NOT_ENABLED: i32 = -50;
+
+/// A decoded header with native-endian integer fields, laid out for C.
+///
+/// The fields mirror [`BzImageHeader`]; `reserved1` still packs the codec, digest algorithm
+/// and flags described in the README.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CBzImageHeader {
+    pub magic: [u8; 4],
+    pub version: u32,
+    pub reserved1: u32,
+    pub uncompressed_size: u64,
+    pub compressed_size: u64,
+    pub checksum: [u8; 32],
+    pub reserved2: u32,
+}
+
+impl From<&BzImageHeader> for CBzImageHeader {
+    fn from(h: &BzImageHeader) -> CBzImageHeader {
+        CBzImageHeader {
+            magic: h.magic_copy(),
+            version: h.version(),
+            reserved1: h.reserved1(),
+            uncompressed_size: h.uncompressed_size(),
+            compressed_size: h.compressed_size(),
+            checksum: h.checksum_copy(),
+            reserved2: h.reserved2(),
+        }
+    }
+}
+
+/// The status code reported for `err`: one per variant, except that every way of running out of
+/// input is [`BZIMAGE_ERR_TRUNCATED`].
+fn status_code(err: &BzImageError) -> i32 {
+    match err {
+        BzImageError::TruncatedHeader | BzImageError::TruncatedPayload { .. } => {
+            BZIMAGE_ERR_TRUNCATED
+        }
+        BzImageError::InvalidMagic { .. } => BZIMAGE_ERR_BAD_MAGIC,
+        BzImageError::UnsupportedVersion(_) => BZIMAGE_ERR_UNSUPPORTED,
+        BzImageError::HeaderCrcMismatch { .. } => BZIMAGE_ERR_CORRUPT,
+        BzImageError::MalformedHeader(_) => BZIMAGE_ERR_MALFORMED,
+        BzImageError::UnknownCodec(_) => BZIMAGE_ERR_UNKNOWN_CODEC,
+        BzImageError::CodecNotEnabled(_) => BZIMAGE_ERR_CODEC_NOT_ENABLED,
+        BzImageError::UnknownDigestAlgo(_) => BZIMAGE_ERR_UNKNOWN_DIGEST,
+        BzImageError::DigestNotEnabled(_) => BZIMAGE_ERR_DIGEST_NOT_ENABLED,
+        BzImageError::UnknownCriticalFlag(_) => BZIMAGE_ERR_UNKNOWN_CRITICAL_FLAG,
+        BzImageError::Reserved2InUse => BZIMAGE_ERR_RESERVED2_IN_USE,
+        BzImageError::InvalidExtendedHeader(_) => BZIMAGE_ERR_INVALID_EXTENDED_HEADER,
+        BzImageError::ExtendedHeaderPresent => BZIMAGE_ERR_EXTENDED_HEADER_PRESENT,
+        BzImageError::InvalidFooter(_) => BZIMAGE_ERR_INVALID_FOOTER,
+        BzImageError::InvalidTrailingLayout(_) => BZIMAGE_ERR_INVALID_TRAILING_LAYOUT,
+        BzImageError::InvalidFrame(_) => BZIMAGE_ERR_INVALID_FRAME,
+        BzImageError::TruncatedFrame { .. } => BZIMAGE_ERR_TRUNCATED_FRAME,
+        BzImageError::ChunkChecksumMismatch { .. } => BZIMAGE_ERR_CHUNK_CHECKSUM_MISMATCH,
+        BzImageError::InvalidChunk { .. } => BZIMAGE_ERR_INVALID_CHUNK,
+        BzImageError::InvalidChunkSize { .. } => BZIMAGE_ERR_INVALID_CHUNK_SIZE,
+        BzImageError::NotFramed => BZIMAGE_ERR_NOT_FRAMED,
+        BzImageError::SizeMismatch { .. } => BZIMAGE_ERR_SIZE_MISMATCH,
+        BzImageError::UncompressedSizeMismatch { .. } => BZIMAGE_ERR_UNCOMPRESSED_SIZE_MISMATCH,
+        BzImageError::UncompressedCrcMismatch { .. } => BZIMAGE_ERR_UNCOMPRESSED_CRC_MISMATCH,
+        BzImageError::PayloadTooLarge { .. } => BZIMAGE_ERR_PAYLOAD_TOO_LARGE,
+        BzImageError::OutputLimitExceeded { .. } => BZIMAGE_ERR_OUTPUT_LIMIT_EXCEEDED,
+        BzImageError::StreamTooLong { .. } => BZIMAGE_ERR_STREAM_TOO_LONG,
+        BzImageError::ChecksumMismatch { .. } => BZIMAGE_ERR_CHECKSUM_MISMATCH,
+        BzImageError::IncompleteHeader(_) => BZIMAGE_ERR_INCOMPLETE_HEADER,
+        BzImageError::NoMatchingCodec(_) => BZIMAGE_ERR_NO_MATCHING_CODEC,
+        BzImageError::RoundTripMismatch(_) => BZIMAGE_ERR_ROUND_TRIP_MISMATCH,
+        BzImageError::InvalidLevel { .. } => BZIMAGE_ERR_INVALID_LEVEL,
+        BzImageError::DetachedPayload => BZIMAGE_ERR_DETACHED_PAYLOAD,
+        BzImageError::Encrypted => BZIMAGE_ERR_ENCRYPTED,
+        BzImageError::NotGzip => BZIMAGE_ERR_NOT_GZIP,
+        BzImageError::NotDetached => BZIMAGE_ERR_NOT_DETACHED,
+        BzImageError::InvalidDetachedReference(_) => BZIMAGE_ERR_INVALID_DETACHED_REFERENCE,
+        BzImageError::NotEncrypted => BZIMAGE_ERR_NOT_ENCRYPTED,
+        BzImageError::DictionaryRequired(_) => BZIMAGE_ERR_DICTIONARY_REQUIRED,
+        BzImageError::DictionaryMismatch { .. } => BZIMAGE_ERR_DICTIONARY_MISMATCH,
+        BzImageError::NoDictionary => BZIMAGE_ERR_NO_DICTIONARY,
+        BzImageError::AuthenticationFailed => BZIMAGE_ERR_AUTHENTICATION_FAILED,
+        BzImageError::NotSigned => BZIMAGE_ERR_NOT_SIGNED,
+        BzImageError::MissingSignature => BZIMAGE_ERR_MISSING_SIGNATURE,
+        BzImageError::SignatureTooLong(_) => BZIMAGE_ERR_SIGNATURE_TOO_LONG,
+        BzImageError::UntrustedChecksum(_) => BZIMAGE_ERR_UNTRUSTED_CHECKSUM,
+        BzImageError::TimedOut(_) => BZIMAGE_ERR_TIMED_OUT,
+        #[cfg(feature = "std")]
+        BzImageError::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            BZIMAGE_ERR_TRUNCATED
+        }
+        #[cfg(feature = "std")]
+        BzImageError::Io(_) => BZIMAGE_ERR_IO,
+        #[cfg(feature = "std")]
+        BzImageError::Decompression(_) => BZIMAGE_ERR_DECOMPRESSION,
+    }
+}
+
+/// Parse and validate the header in the first `HEADER_SIZE` bytes of `ptr[..len]`, writing
+/// it to `*out`.
+///
+/// Returns [`BZIMAGE_OK`] on success or the negative `BZIMAGE_ERR_*` code of the failure;
+/// `*out` is only written on success.
+///
+/// # Safety
+///
+/// `ptr` must be valid for reads of `len` bytes and `out` must be valid for a write of one
+/// `CBzImageHeader`. Either may be null, which is reported as [`BZIMAGE_ERR_NULL`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bzimage_parse_header(
+    ptr: *const u8,
+    len: usize,
+    out: *mut CBzImageHeader,
+) -> i32 {
+    if ptr.is_null() || out.is_null() {
+        return BZIMAGE_ERR_NULL;
+    }
+    if len < HEADER_SIZE {
+        return BZIMAGE_ERR_TRUNCATED;
+    }
+    // SAFETY: the caller guarantees `ptr` is readable for `len >= HEADER_SIZE` bytes.
+    let bytes = unsafe { core::slice::from_raw_parts(ptr, HEADER_SIZE) };
+    let header = match BzImageHeader::from_bytes(bytes) {
+        Ok(header) => header,
+        Err(e) => return status_code(&e),
+    };
+    if let Err(e) = header.can_read() {
+        return status_code(&e);
+    }
+    // SAFETY: the caller guarantees `out` is valid for a write.
+    unsafe { out.write(CBzImageHeader::from(&header)) };
+    BZIMAGE_OK
+}
@@ -0,0 +1,389 @@
+//! Helpers that operate on image files by path.
+
+use crate::digest::{Digester, checksums_match, to_hex};
+use crate::encoder::HashWriter;
+use crate::limit::copy_to_eof;
+use crate::{BzImageError, BzImageHeader, DEFAULT_STREAM_LIMIT, HEADER_SIZE, is_magic};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// What [`payload_checksum_of_file_with`] does when the computed digest disagrees with the
+/// checksum stored in the header.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum OnMismatch {
+    /// Return both digests in the [`PayloadChecksum`] and leave it to the caller to act on.
+    #[default]
+    Report,
+    /// Fail with `BzImageError::ChecksumMismatch`.
+    Error,
+}
+
+/// The checksum stored in an image's header next to the one computed from its payload on disk.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PayloadChecksum {
+    /// The checksum recorded in the header.
+    pub stored: [u8; 32],
+    /// The checksum of the payload bytes as read now.
+    pub computed: [u8; 32],
+}
+
+impl PayloadChecksum {
+    /// Whether the payload hashes to the stored checksum.
+    pub fn is_match(&self) -> bool {
+        checksums_match(&self.stored, &self.computed)
+    }
+}
+
+/// Compute the checksum of the payload stored in the image at `path`, streaming it from disk.
+///
+/// The payload is hashed with the digest algorithm named in the header, so the result is
+/// directly comparable with the stored checksum. A disagreement is not an error: both digests
+/// are returned and [`PayloadChecksum::is_match`] tells them apart; use
+/// [`payload_checksum_of_file_with`] to make it one instead.
+pub fn payload_checksum_of_file(path: &Path) -> Result<PayloadChecksum, BzImageError> {
+    payload_checksum_of_file_with(path, OnMismatch::default())
+}
+
+/// Like [`payload_checksum_of_file`], with an explicit policy for checksum disagreements.
+pub fn payload_checksum_of_file_with(
+    path: &Path,
+    on_mismatch: OnMismatch,
+) -> Result<PayloadChecksum, BzImageError> {
+    let file = File::open(path)?;
+    let mut r = BufReader::new(file);
+    let header = BzImageHeader::read_from(&mut r)?;
+    header.skip_extended(&mut r)?;
+    let algo = header.digest_algo()?;
+    let declared = header.compressed_size();
+
+    let mut digester = Digester::new(algo);
+    let copied = io::copy(&mut r.by_ref().take(declared), &mut digester)?;
+    if copied < declared {
+        return Err(BzImageError::TruncatedPayload {
+            declared,
+            available: Some(copied),
+        });
+    }
+
+    let result = PayloadChecksum {
+        stored: header.checksum_copy(),
+        computed: digester.finalize(),
+    };
+    if on_mismatch == OnMismatch::Error && !result.is_match() {
+        return Err(BzImageError::ChecksumMismatch {
+            expected: result.stored,
+            actual: result.computed,
+        });
+    }
+    Ok(result)
+}
+
+/// Images in a directory found by [`dedup_dir`] to have identical contents.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DedupGroup {
+    /// The payload checksum shared by the group.
+    pub checksum: [u8; 32],
+    /// The file the duplicates were linked to (the first in path order).
+    pub original: PathBuf,
+    /// Files replaced by a hard link to `original`.
+    pub linked: Vec<PathBuf>,
+    /// Duplicates that could not be replaced, with the reason.
+    pub unlinked: Vec<(PathBuf, String)>,
+}
+
+/// The result of [`dedup_dir`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DedupReport {
+    /// One entry per set of two or more identical images.
+    pub groups: Vec<DedupGroup>,
+    /// Bytes freed by the links that were made.
+    pub reclaimed_bytes: u64,
+    /// Bytes that could also be freed if the remaining duplicates were linked.
+    pub reclaimable_bytes: u64,
+}
+
+/// Replace duplicate images in `dir` with hard links to a single copy.
+///
+/// Images are grouped by their stored checksum, which identifies the payload; since two files
+/// with the same payload can still differ elsewhere (flags, footer), each candidate is compared
+/// byte for byte with the group's original before it is replaced. Files that are not images are
+/// skipped, and subdirectories are not descended into. Each replacement links to a temporary
+/// name and renames it over the duplicate, so a failure never leaves the duplicate missing;
+/// duplicates that cannot be linked (another filesystem, no hard-link support) are reported in
+/// [`DedupGroup::unlinked`] instead.
+pub fn dedup_dir(dir: &Path) -> Result<DedupReport, BzImageError> {
+    let mut by_checksum: BTreeMap<[u8; 32], Vec<(PathBuf, u64)>> = BTreeMap::new();
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    for path in paths {
+        let mut file = File::open(&path)?;
+        let Ok(header) = BzImageHeader::read_from(&mut file) else {
+            continue;
+        };
+        let len = file.metadata()?.len();
+        by_checksum
+            .entry(header.checksum_copy())
+            .or_default()
+            .push((path, len));
+    }
+
+    let mut report = DedupReport::default();
+    for (checksum, files) in by_checksum {
+        let mut files = files.into_iter();
+        let Some((original, original_len)) = files.next() else {
+            continue;
+        };
+        let mut group = DedupGroup {
+            checksum,
+            original,
+            linked: Vec::new(),
+            unlinked: Vec::new(),
+        };
+        for (path, len) in files {
+            if len != original_len || !files_equal(&group.original, &path)? {
+                continue;
+            }
+            if same_file(&group.original, &path)? {
+                // already a link to the original
+                continue;
+            }
+            match replace_with_link(&group.original, &path) {
+                Ok(()) => {
+                    report.reclaimed_bytes += len;
+                    group.linked.push(path);
+                }
+                Err(e) => {
+                    report.reclaimable_bytes += len;
+                    group.unlinked.push((path, e.to_string()));
+                }
+            }
+        }
+        if !group.linked.is_empty() || !group.unlinked.is_empty() {
+            report.groups.push(group);
+        }
+    }
+    Ok(report)
+}
+
+fn files_equal(a: &Path, b: &Path) -> Result<bool, BzImageError> {
+    let mut a = BufReader::new(File::open(a)?);
+    let mut b = BufReader::new(File::open(b)?);
+    loop {
+        let left = a.fill_buf()?;
+        let right = b.fill_buf()?;
+        let n = left.len().min(right.len());
+        if n == 0 {
+            return Ok(left.is_empty() && right.is_empty());
+        }
+        if left[..n] != right[..n] {
+            return Ok(false);
+        }
+        a.consume(n);
+        b.consume(n);
+    }
+}
+
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> Result<bool, BzImageError> {
+    use std::os::unix::fs::MetadataExt;
+
+    let (a, b) = (fs::metadata(a)?, fs::metadata(b)?);
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &Path, _b: &Path) -> Result<bool, BzImageError> {
+    Ok(false)
+}
+
+fn replace_with_link(original: &Path, duplicate: &Path) -> io::Result<()> {
+    let mut tmp = duplicate.as_os_str().to_owned();
+    tmp.push(".dedup-tmp");
+    let tmp = PathBuf::from(tmp);
+    fs::hard_link(original, &tmp)?;
+    fs::rename(&tmp, duplicate).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })
+}
+
+/// Copy the image read from `reader` into the content-addressed store `store_dir`, returning
+/// the SHA-256 of the whole image and the path it was stored under.
+///
+/// The image is named by the lowercase hex SHA-256 of all its bytes (header, payload and any
+/// footer). In the same pass the payload is checked against the header's checksum, so a
+/// corrupt image is never stored: it fails with `BzImageError::ChecksumMismatch` (or
+/// `TruncatedPayload`) and the partial copy is removed. The data is written to a temporary
+/// file in `store_dir` and renamed into place, so the store never holds a partial image under
+/// its final name. Data after the payload is copied until end of input, up to
+/// `DEFAULT_STREAM_LIMIT` bytes; a reader that yields more fails with
+/// `BzImageError::StreamTooLong`.
+pub fn store_cas<R: Read>(
+    reader: R,
+    store_dir: &Path,
+) -> Result<([u8; 32], PathBuf), BzImageError> {
+    static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+
+    let tmp_path = store_dir.join(format!(
+        ".incoming-{}-{}",
+        process::id(),
+        NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp_path)?;
+
+    let digest = copy_verified(reader, file).and_then(|digest| {
+        let path = store_dir.join(to_hex(&digest));
+        fs::rename(&tmp_path, &path)?;
+        Ok((digest, path))
+    });
+    if digest.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    digest
+}
+
+/// Copy an image from `r` to `file`, checking its payload checksum, and return the SHA-256 of
+/// everything copied.
+fn copy_verified<R: Read>(mut r: R, file: File) -> Result<[u8; 32], BzImageError> {
+    let mut header_bytes = [0u8; HEADER_SIZE];
+    r.read_exact(&mut header_bytes)?;
+    let header = BzImageHeader::from_bytes(&header_bytes)?;
+    let declared = header.compressed_size();
+
+    let mut w = HashWriter::new(BufWriter::new(file));
+    w.write_all(&header_bytes)?;
+    let extended = header.read_extended_bytes(&mut r)?;
+    w.write_all(&extended)?;
+
+    let mut digester = Digester::new(header.digest_algo()?);
+    let mut remaining = declared;
+    let mut buf = vec![0u8; 64 * 1024];
+    while remaining > 0 {
+        let want = remaining.min(buf.len() as u64) as usize;
+        let n = match r.read(&mut buf[..want]) {
+            Ok(0) => {
+                return Err(BzImageError::TruncatedPayload {
+                    declared,
+                    available: Some(declared - remaining),
+                });
+            }
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        digester.update(&buf[..n]);
+        w.write_all(&buf[..n])?;
+        remaining -= n as u64;
+    }
+    let actual = digester.finalize();
+    let expected = header.checksum_copy();
+    if !checksums_match(&actual, &expected) {
+        return Err(BzImageError::ChecksumMismatch { expected, actual });
+    }
+
+    // anything after the payload (a footer) is stored as-is
+    copy_to_eof(r, &mut w, DEFAULT_STREAM_LIMIT)?;
+    let (w, _, digest) = w.finish();
+    let file = w.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    Ok(digest)
+}
+
+/// Whether the file at `path` starts with the bzimage magic, in either byte order.
+///
+/// Only the first four bytes are read; a file that cannot be opened or is too short is not an
+/// image.
+pub fn is_bzimage(path: &Path) -> bool {
+    let mut magic = [0u8; 4];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .is_ok_and(|()| is_magic(&magic))
+}
+
+/// Decompress the payload read from `compressed` into `out`, which is first extended to the
+/// header's `uncompressed_size`.
+///
+/// On Linux the space is reserved with `fallocate`, so a full disk fails up front and the
+/// output is laid out contiguously where the filesystem can manage it; elsewhere the file is
+/// just `set_len`. Data is written from offset 0. If the payload decodes to fewer bytes than
+/// declared, the file is truncated to what was written and the call fails with
+/// `BzImageError::UncompressedSizeMismatch`; if it decodes to more, only the declared length
+/// is written before failing the same way. Returns the number of bytes written.
+pub fn extract_to_preallocated<R: Read>(
+    header: &BzImageHeader,
+    compressed: R,
+    out: &File,
+) -> Result<u64, BzImageError> {
+    header.can_read()?;
+    let declared = header.uncompressed_size();
+    preallocate(out, declared)?;
+
+    let mut w = BufWriter::new(out);
+    w.seek(SeekFrom::Start(0))?;
+    let mut decoder = header.payload_decoder(compressed)?;
+    let written = io::copy(&mut (&mut decoder).take(declared), &mut w)
+        .map_err(BzImageError::Decompression)?;
+    w.flush()?;
+
+    if written < declared {
+        out.set_len(written)?;
+        return Err(BzImageError::UncompressedSizeMismatch {
+            declared,
+            actual: written,
+        });
+    }
+    let extra =
+        copy_to_eof(&mut decoder, &mut io::sink(), DEFAULT_STREAM_LIMIT).map_err(|e| match e {
+            BzImageError::Io(e) => BzImageError::Decompression(e),
+            e => e,
+        })?;
+    if extra > 0 {
+        return Err(BzImageError::UncompressedSizeMismatch {
+            declared,
+            actual: declared + extra,
+        });
+    }
+    Ok(written)
+}
+
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, len: u64) -> Result<(), BzImageError> {
+    use std::os::fd::AsRawFd;
+
+    if len == 0 {
+        return Ok(file.set_len(0)?);
+    }
+    let len = libc::off_t::try_from(len).map_err(|_| BzImageError::PayloadTooLarge {
+        declared: len,
+        limit: libc::off_t::MAX as u64,
+    })?;
+    // SAFETY: the descriptor is owned by `file`, which outlives the call.
+    let rc = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len) };
+    if rc == 0 {
+        // fallocate only grows; drop anything a longer, reused file had past the end
+        return Ok(file.set_len(len as u64)?);
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        // the filesystem cannot preallocate; fall back to extending the file
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => Ok(file.set_len(len as u64)?),
+        _ => Err(err.into()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn preallocate(file: &File, len: u64) -> Result<(), BzImageError> {
+    Ok(file.set_len(len)?)
+}
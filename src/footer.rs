@@ -0,0 +1,229 @@
+//! Optional metadata footer stored after the compressed payload.
+//!
+//! Readers that only know the fixed header stop after `compressed_size` payload bytes, so a
+//! footer can be added to, or replaced on, an existing image without touching the payload.
+//! Images with a footer have `BzImageFlags::HAS_FOOTER` set.
+//!
+//! Layout (integers little-endian):
+//!
+//! - magic: 4 bytes — the ASCII magic `DMNF`
+//! - body_len: u32 — length of the body that follows
+//! - body: entry count (u32), then per entry in ascending key order: key length (u32),
+//!   UTF-8 key, value length (u32), UTF-8 value
+//!
+//! Entries are kept sorted so the same metadata always serializes to the same bytes.
+
+use crate::signature::{self, read_signature};
+use crate::{BzImageError, BzImageFlags, BzImageHeader, rewrite_header};
+use simple_endian::{read_specific, u32le};
+use std::collections::BTreeMap;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+
+/// Four-byte ASCII magic that starts a footer: `DMNF`.
+pub const FOOTER_MAGIC: &[u8; 4] = b"DMNF";
+
+/// Largest footer body accepted when reading, to bound allocation on corrupt input, and so
+/// also the largest one written.
+pub const MAX_FOOTER_SIZE: usize = 1 << 20;
+
+/// Key/value metadata carried in an image footer.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Footer {
+    entries: BTreeMap<String, String>,
+}
+
+impl Footer {
+    pub fn new() -> Footer {
+        Footer::default()
+    }
+
+    /// Set `key` to `value`, returning the previous value if there was one.
+    pub fn insert(&mut self, key: impl Into<String>, value: impl Into<String>) -> Option<String> {
+        self.entries.insert(key.into(), value.into())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(String::as_str)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.entries.remove(key)
+    }
+
+    /// Iterate over the entries in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn body(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&(self.entries.len() as u32).to_le_bytes());
+        for (key, value) in &self.entries {
+            for s in [key, value] {
+                body.extend_from_slice(&(s.len() as u32).to_le_bytes());
+                body.extend_from_slice(s.as_bytes());
+            }
+        }
+        body
+    }
+
+    /// Serialize the footer, including its magic and length prefix.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let body = self.body();
+        let mut out = Vec::with_capacity(8 + body.len());
+        out.extend_from_slice(FOOTER_MAGIC);
+        out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        out.extend_from_slice(&body);
+        out
+    }
+
+    /// Write the footer to `w`.
+    ///
+    /// A body larger than `MAX_FOOTER_SIZE`, which `read_from` would refuse, fails with
+    /// `BzImageError::InvalidFooter` before anything is written.
+    pub fn write_to<W: Write>(&self, mut w: W) -> Result<(), BzImageError> {
+        let bytes = self.to_bytes();
+        // the magic and length prefix come before the body
+        if bytes.len() - 8 > MAX_FOOTER_SIZE {
+            return Err(BzImageError::InvalidFooter("larger than MAX_FOOTER_SIZE"));
+        }
+        w.write_all(&bytes)?;
+        Ok(())
+    }
+
+    /// Read a footer from `r`, which must be positioned at the footer magic.
+    ///
+    /// Returns `None` if `r` is already at end of input, meaning the image has no footer. A
+    /// footer that is there but malformed is `BzImageError::InvalidFooter`.
+    pub fn read_from<R: Read>(mut r: R) -> Result<Option<Footer>, BzImageError> {
+        let mut magic = [0u8; 4];
+        match r.read_exact(&mut magic) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        if &magic != FOOTER_MAGIC {
+            return Err(BzImageError::InvalidFooter("bad magic"));
+        }
+
+        let body_len: u32le = read_specific(&mut r).map_err(truncated)?;
+        let body_len = u32::from(body_len) as usize;
+        if body_len > MAX_FOOTER_SIZE {
+            return Err(BzImageError::InvalidFooter("larger than MAX_FOOTER_SIZE"));
+        }
+        let mut body = vec![0u8; body_len];
+        r.read_exact(&mut body).map_err(truncated)?;
+        Self::parse_body(&body).map(Some)
+    }
+
+    fn parse_body(mut body: &[u8]) -> Result<Footer, BzImageError> {
+        fn read_string(r: &mut &[u8]) -> Result<String, BzImageError> {
+            let len: u32le = read_specific(r).map_err(truncated)?;
+            let len = u32::from(len) as usize;
+            if r.len() < len {
+                return Err(BzImageError::InvalidFooter("string runs past the end"));
+            }
+            let (bytes, rest) = r.split_at(len);
+            *r = rest;
+            String::from_utf8(bytes.to_vec())
+                .map_err(|_| BzImageError::InvalidFooter("string is not UTF-8"))
+        }
+
+        let count: u32le = read_specific(&mut body).map_err(truncated)?;
+        let mut footer = Footer::new();
+        for _ in 0..u32::from(count) {
+            let key = read_string(&mut body)?;
+            let value = read_string(&mut body)?;
+            footer.insert(key, value);
+        }
+        Ok(footer)
+    }
+}
+
+/// A footer that ends early is malformed rather than an I/O failure.
+fn truncated(e: std::io::Error) -> BzImageError {
+    match e.kind() {
+        ErrorKind::UnexpectedEof => BzImageError::InvalidFooter("truncated"),
+        _ => BzImageError::Io(e),
+    }
+}
+
+/// Read the header of the image at the start of `r` and locate the end of its payload and the
+/// end of its data (before any signature trailer).
+fn payload_end<R: Read + Seek>(r: &mut R) -> Result<(BzImageHeader, u64, u64), BzImageError> {
+    r.seek(SeekFrom::Start(0))?;
+    let header = BzImageHeader::read_from(&mut *r)?;
+    header.skip_extended(&mut *r)?;
+    let stored = if header.flags().contains(BzImageFlags::DETACHED_PAYLOAD) {
+        0
+    } else {
+        header.compressed_size()
+    };
+    let start = r.stream_position()?;
+    let end = start
+        .checked_add(stored)
+        .ok_or(BzImageError::PayloadTooLarge {
+            declared: stored,
+            limit: u64::MAX - start,
+        })?;
+    let data_end = signature::data_end(&header, r)?;
+    if data_end < end {
+        return Err(BzImageError::TruncatedPayload {
+            declared: stored,
+            available: Some(data_end.saturating_sub(start)),
+        });
+    }
+    Ok((header, end, data_end))
+}
+
+/// Read the footer of the image at the start of `r`, if it has one.
+pub fn read_footer<R: Read + Seek>(mut r: R) -> Result<Option<Footer>, BzImageError> {
+    let (_, end, data_end) = payload_end(&mut r)?;
+    r.seek(SeekFrom::Start(end))?;
+    // stop short of any signature trailer
+    Footer::read_from(r.take(data_end - end))
+}
+
+/// Write `meta` as the footer of the image at the start of `rw`, replacing any existing footer.
+///
+/// The payload is not read or rewritten: the footer is written straight after the payload
+/// (after the header and any extended header for a detached payload), and the header is
+/// rewritten only if `HAS_FOOTER` was not yet set. A signature trailer, if present, is moved
+/// after the new footer. A footer too large for `read_footer` to accept is refused as by
+/// `Footer::write_to`, leaving the image untouched. Returns the new length of the image. A
+/// generic writer cannot shrink, so when the old footer was longer the caller must truncate the
+/// underlying storage to the returned length (e.g. `File::set_len`).
+pub fn append_footer<RW: Read + Write + Seek>(
+    mut rw: RW,
+    meta: &Footer,
+) -> Result<u64, BzImageError> {
+    let (mut header, end, _) = payload_end(&mut rw)?;
+    // the signature trailer must stay last, so it is moved past the new footer
+    let signature = read_signature(&mut rw)?;
+    rw.seek(SeekFrom::Start(end))?;
+    meta.write_to(&mut rw)?;
+    let footer_end = rw.stream_position()?;
+
+    let mut flags = header.flags();
+    if !flags.contains(BzImageFlags::HAS_FOOTER) {
+        flags.insert(BzImageFlags::HAS_FOOTER);
+        header.set_flags(flags);
+        rewrite_header(&mut rw, &header)?;
+        rw.seek(SeekFrom::Start(footer_end))?;
+    }
+    let mut image_end = footer_end;
+    if let Some(signature) = signature {
+        signature::write_trailer(&mut rw, &signature)?;
+        image_end = rw.stream_position()?;
+    }
+    rw.flush()?;
+    Ok(image_end)
+}
@@ -0,0 +1,82 @@
+//! Length-delimited framing, for sending several images back to back over one stream.
+//!
+//! Each frame is the image's total length as an unsigned LEB128 varint, followed by exactly that
+//! many bytes of image (header, payload and anything after it). A receiver reads one frame per
+//! image and never has to scan for the next magic or seek.
+
+use crate::{BzImageError, BzImageHeader, Codec, write_image};
+use std::io::{self, Cursor, Read, Write};
+
+/// Longest encoding of a u64 varint.
+const MAX_VARINT_LEN: usize = 10;
+
+fn write_varint<W: Write>(mut w: W, mut value: u64) -> io::Result<()> {
+    let mut buf = [0u8; MAX_VARINT_LEN];
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+    w.write_all(&buf[..len])
+}
+
+/// Read a varint, returning `None` at a clean end of input (no bytes at all).
+fn read_varint<R: Read>(mut r: R) -> Result<Option<u64>, BzImageError> {
+    let mut value = 0u64;
+    for i in 0..MAX_VARINT_LEN {
+        let mut byte = [0u8; 1];
+        match r.read_exact(&mut byte) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && i == 0 => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let bits = u64::from(byte[0] & 0x7f);
+        if i == MAX_VARINT_LEN - 1 && bits > 1 {
+            return Err(BzImageError::InvalidFrame("length overflows a u64"));
+        }
+        value |= bits << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+    Err(BzImageError::InvalidFrame("length varint is too long"))
+}
+
+/// Compress `data` into a gzip image and write it to `w` as one length-prefixed frame.
+///
+/// Returns the header that was written.
+pub fn write_framed<W: Write>(mut w: W, data: &[u8]) -> Result<BzImageHeader, BzImageError> {
+    let mut image = Vec::new();
+    let header = write_image(&mut image, data, Codec::Gzip)?;
+    write_varint(&mut w, image.len() as u64)?;
+    w.write_all(&image)?;
+    Ok(header)
+}
+
+/// Read one frame written by [`write_framed`] from `r`, returning the header and compressed
+/// payload.
+///
+/// Exactly one frame is consumed, so `r` is left at the start of the next one. The frame buffer
+/// grows as data arrives rather than being sized from the length prefix, so a corrupt prefix
+/// cannot force a huge allocation. Fails with an `UnexpectedEof` I/O error if `r` is already at
+/// end of input, with `BzImageError::TruncatedFrame` if the frame is shorter than its prefix
+/// says, or as `read_header_and_payload` does if the image inside it is malformed or truncated.
+pub fn read_framed<R: Read>(mut r: R) -> Result<(BzImageHeader, Vec<u8>), BzImageError> {
+    let len = read_varint(&mut r)?.ok_or(io::Error::from(io::ErrorKind::UnexpectedEof))?;
+    let mut frame = Vec::new();
+    (&mut r).take(len).read_to_end(&mut frame)?;
+    if (frame.len() as u64) < len {
+        return Err(BzImageError::TruncatedFrame {
+            declared: len,
+            actual: frame.len() as u64,
+        });
+    }
+    BzImageHeader::read_header_and_payload(Cursor::new(&frame))
+}
@@ -0,0 +1,181 @@
+//! Helpers that produce or consume a whole image: header plus payload.
+
+use crate::digest::{Digester, checksums_match};
+use crate::{
+    BzImageError, BzImageFlags, BzImageHeader, Codec, CompressionAlgorithm, DigestAlgo,
+    HEADER_SIZE, compute_checksum,
+};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// How much of the input `write_image_auto` compresses with each codec to choose between them.
+pub const AUTO_SAMPLE_SIZE: usize = 4 << 20;
+
+/// Compress `data` with `codec` and write a complete image to `w`.
+///
+/// Returns the header that was written.
+pub fn write_image<W: Write>(
+    mut w: W,
+    data: &[u8],
+    codec: Codec,
+) -> Result<BzImageHeader, BzImageError> {
+    let compressed = codec.compress(data)?;
+    let mut header = BzImageHeader::new_for_payload(data.len() as u64, &compressed);
+    header.set_codec(codec);
+    header.write_to(&mut w)?;
+    w.write_all(&compressed)?;
+    Ok(header)
+}
+
+/// Write `data` as an image using whichever codec compresses it best.
+///
+/// The first `AUTO_SAMPLE_SIZE` bytes of `data` are compressed with every codec in
+/// `Codec::ALL`, and the one giving the smallest output (the earliest on a tie) is used for
+/// the whole input and recorded in the header. The sample assumes the start of the input is
+/// representative of the rest.
+///
+/// Time cost: on top of the final compression, one compression of up to `AUTO_SAMPLE_SIZE`
+/// bytes per codec. For inputs no larger than the sample, the chosen codec's sample output is
+/// reused, so the input is compressed once per codec in total.
+pub fn write_image_auto<W: Write>(mut w: W, data: &[u8]) -> Result<BzImageHeader, BzImageError> {
+    let sample = &data[..data.len().min(AUTO_SAMPLE_SIZE)];
+    let mut best: Option<(Codec, Vec<u8>)> = None;
+    for &codec in Codec::ALL {
+        let compressed = codec.compress(sample)?;
+        if best
+            .as_ref()
+            .is_none_or(|(_, b)| compressed.len() < b.len())
+        {
+            best = Some((codec, compressed));
+        }
+    }
+    let (codec, sampled) = best.expect("Codec::ALL always holds gzip");
+
+    if sample.len() < data.len() {
+        return write_image(w, data, codec);
+    }
+    let mut header = BzImageHeader::new_for_payload(data.len() as u64, &sampled);
+    header.set_codec(codec);
+    header.write_to(&mut w)?;
+    w.write_all(&sampled)?;
+    Ok(header)
+}
+
+/// Overwrite the header at the start of `rw` with `header`, leaving everything after it alone.
+///
+/// Exactly `HEADER_SIZE` bytes are written at offset 0, so the payload and any footer are not
+/// touched; `rw` is left positioned just past the header. This is the primitive for in-place
+/// repairs of sizes, flags or the checksum. A header that records its own CRC
+/// (`HEADER_CRC32`) is written with the CRC recomputed, so it still parses afterwards.
+pub fn rewrite_header<RW: Write + Seek>(
+    mut rw: RW,
+    header: &BzImageHeader,
+) -> Result<(), BzImageError> {
+    let mut header = *header;
+    header.refresh_header_crc()?;
+    let mut bytes = [0u8; HEADER_SIZE];
+    header.write_to(&mut bytes[..])?;
+    rw.seek(SeekFrom::Start(0))?;
+    rw.write_all(&bytes)?;
+    Ok(())
+}
+
+/// Re-checksum the image at the start of `rw` with `new_algo`, without re-encoding the payload.
+///
+/// The payload is read once and hashed with both the recorded algorithm and `new_algo`; the
+/// stored checksum must match first, so an already-corrupt image fails with
+/// `BzImageError::ChecksumMismatch` (or `TruncatedPayload`) and is left untouched. Only the
+/// header is then rewritten, with the new checksum and digest algorithm. Returns the new
+/// header. A signature trailer is left in place but no longer covers the image, so signed
+/// images must be re-signed afterwards.
+pub fn upgrade_checksum<RW: Read + Write + Seek>(
+    mut rw: RW,
+    new_algo: DigestAlgo,
+) -> Result<BzImageHeader, BzImageError> {
+    if !new_algo.is_enabled() {
+        return Err(BzImageError::DigestNotEnabled(new_algo));
+    }
+    rw.seek(SeekFrom::Start(0))?;
+    let mut header = BzImageHeader::read_from(&mut rw)?;
+    header.can_read()?;
+    if header.flags().contains(BzImageFlags::DETACHED_PAYLOAD) {
+        return Err(BzImageError::DetachedPayload);
+    }
+    header.skip_extended(&mut rw)?;
+
+    let declared = header.compressed_size();
+    let mut old = Digester::new(header.digest_algo()?);
+    let mut new = Digester::new(new_algo);
+    let mut payload = (&mut rw).take(declared);
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut read = 0u64;
+    loop {
+        let n = match payload.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        old.update(&buf[..n]);
+        new.update(&buf[..n]);
+        read += n as u64;
+    }
+    if read < declared {
+        return Err(BzImageError::TruncatedPayload {
+            declared,
+            available: Some(read),
+        });
+    }
+    let expected = header.checksum_copy();
+    let actual = old.finalize();
+    if !checksums_match(&actual, &expected) {
+        return Err(BzImageError::ChecksumMismatch { expected, actual });
+    }
+
+    header.checksum = new.finalize();
+    header.set_digest_algo(new_algo);
+    header.refresh_header_crc()?;
+    rewrite_header(&mut rw, &header)?;
+    rw.flush()?;
+    Ok(header)
+}
+
+/// Re-encode the image at the start of `r` with `target` at `level` and write the result to
+/// `w`, returning the new header.
+///
+/// The source is verified as by `unpack` before anything is written, so a corrupt image fails
+/// with its checksum error and `w` is left untouched. The new payload is then checked against
+/// its own checksum and decoded again to make sure it gives back the same data. The digest
+/// algorithm, byte order and extended header are kept, as is whatever `reserved2` held: a
+/// CRC-32 of the data or of the header is recomputed for the new image. Images compressed
+/// against a dictionary are refused, as by `unpack`. A footer or signature trailer is not
+/// carried over.
+pub fn recompress<R: Read + Seek, W: Write>(
+    mut r: R,
+    mut w: W,
+    target: CompressionAlgorithm,
+    level: u32,
+) -> Result<BzImageHeader, BzImageError> {
+    r.seek(SeekFrom::Start(0))?;
+    let (source, extended, data) = BzImageHeader::read_verified_extended(&mut r)?;
+
+    let compressed = target.compress_with_level(&data, level)?;
+    let algo = source.digest_algo()?;
+    let mut header = BzImageHeader::new_for_payload(data.len() as u64, &compressed);
+    header.checksum = compute_checksum(algo, &compressed);
+    header.set_digest_algo(algo);
+    header.set_codec(target);
+    header.set_big_endian(source.is_big_endian());
+    if source.uncompressed_crc().is_some() {
+        header.set_uncompressed_crc(&data)?;
+    }
+
+    header.validate_payload(&compressed)?;
+    if header.decode_verified(&compressed)? != data {
+        return Err(BzImageError::RoundTripMismatch(target));
+    }
+    if source.verify_header_crc() {
+        header.set_header_crc()?;
+    }
+    header.write_with_extended(&extended.unwrap_or_default(), &compressed, &mut w)?;
+    Ok(header)
+}
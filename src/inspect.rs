@@ -0,0 +1,240 @@
+//! Tools for inspecting and comparing headers.
+
+use crate::digest::{checksums_match, to_hex};
+use crate::{BzImageError, BzImageFlags, BzImageHeader, DigestAlgo, HEADER_SIZE, compute_checksum};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+/// One header field whose decoded value differs between two headers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FieldDiff {
+    /// The field name, e.g. `"compressed_size"`.
+    pub field: &'static str,
+    /// The decoded value in the first header.
+    pub left: String,
+    /// The decoded value in the second header.
+    pub right: String,
+}
+
+impl fmt::Display for FieldDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} vs {}", self.field, self.left, self.right)
+    }
+}
+
+/// Decode every header field into a display string, in on-disk order.
+fn fields(h: &BzImageHeader) -> [(&'static str, String); 9] {
+    let magic = h.magic_copy().escape_ascii().to_string();
+    let codec = match h.codec() {
+        Ok(codec) => format!("{codec:?}"),
+        Err(e) => e.to_string(),
+    };
+    let digest_algo = match h.digest_algo() {
+        Ok(algo) => format!("{algo:?}"),
+        Err(e) => e.to_string(),
+    };
+    [
+        ("magic", magic),
+        ("version", h.version().to_string()),
+        ("codec", codec),
+        ("digest_algo", digest_algo),
+        ("flags", format!("{:#06x}", h.flags().bits())),
+        ("uncompressed_size", h.uncompressed_size().to_string()),
+        ("compressed_size", h.compressed_size().to_string()),
+        ("checksum", to_hex(&h.checksum_copy())),
+        ("reserved2", format!("{:#010x}", h.reserved2())),
+    ]
+}
+
+/// The compressed size as a percentage of the uncompressed size, e.g. `"19.8%"`, or `"n/a"`
+/// for an empty payload. This is the inverse of `compression_ratio`, as a percentage.
+fn compressed_percent(h: &BzImageHeader) -> String {
+    match h.uncompressed_size() {
+        0 => "n/a".to_string(),
+        uncompressed => format!(
+            "{:.1}%",
+            h.compressed_size() as f64 * 100.0 / uncompressed as f64
+        ),
+    }
+}
+
+/// One line: magic, version, codec, both sizes with the compressed size as a percentage of the
+/// uncompressed size, and the checksum in hex, e.g. `DMNZ v1 Gzip, 4096 -> 812 bytes (19.8%), checksum 9f86...`.
+impl fmt::Display for BzImageHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [(_, magic), (_, version), (_, codec), ..] = fields(self);
+        write!(
+            f,
+            "{magic} v{version} {codec}, {} -> {} bytes ({}), checksum {}",
+            self.uncompressed_size(),
+            self.compressed_size(),
+            compressed_percent(self),
+            to_hex(&self.checksum_copy())
+        )
+    }
+}
+
+/// An image's sizes and how well it compressed, from its header; see `BzImageHeader::stats`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Stats {
+    pub uncompressed_size: u64,
+    pub compressed_size: u64,
+    /// `BzImageHeader::compression_ratio`.
+    pub compression_ratio: Option<f64>,
+    /// `BzImageHeader::space_saved`.
+    pub space_saved: Option<f64>,
+}
+
+impl BzImageHeader {
+    /// Uncompressed size over compressed size, e.g. 4.0 for a payload a quarter of the
+    /// data's size, or `None` if `compressed_size` is 0.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        match self.compressed_size() {
+            0 => None,
+            compressed => Some(self.uncompressed_size() as f64 / compressed as f64),
+        }
+    }
+
+    /// The fraction of the data's size that compression saved, 1 - compressed/uncompressed,
+    /// e.g. 0.75 for a payload a quarter of the data's size, or `None` if `uncompressed_size`
+    /// is 0. Negative when the payload is larger than the data.
+    pub fn space_saved(&self) -> Option<f64> {
+        match self.uncompressed_size() {
+            0 => None,
+            uncompressed => Some(1.0 - self.compressed_size() as f64 / uncompressed as f64),
+        }
+    }
+
+    /// Both sizes with `compression_ratio` and `space_saved`.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            uncompressed_size: self.uncompressed_size(),
+            compressed_size: self.compressed_size(),
+            compression_ratio: self.compression_ratio(),
+            space_saved: self.space_saved(),
+        }
+    }
+
+    /// Every decoded field, one `name: value` line each in on-disk order. `reserved1` is split
+    /// into its codec, digest algorithm and flags; see `stats` for how well the image
+    /// compressed.
+    pub fn summary(&self) -> String {
+        let mut out = String::new();
+        for (field, value) in fields(self) {
+            out.push_str(&format!("{field}: {value}\n"));
+        }
+        out
+    }
+}
+
+/// List the header fields that differ between `a` and `b`, with their decoded values.
+///
+/// `reserved1` is split into its codec, digest algorithm and flags parts. An empty result
+/// means the headers are identical. A differing `checksum` with equal sizes usually means the
+/// payload changed; differences only in the other fields mean only metadata changed.
+pub fn diff_headers(a: &BzImageHeader, b: &BzImageHeader) -> Vec<FieldDiff> {
+    fields(a)
+        .into_iter()
+        .zip(fields(b))
+        .filter(|((_, left), (_, right))| left != right)
+        .map(|((field, left), (_, right))| FieldDiff { field, left, right })
+        .collect()
+}
+
+/// The outcome of `BzImageHeader::checksum_status`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChecksumStatus {
+    /// The payload matches the stored checksum.
+    Valid,
+    /// The payload does not match the stored checksum.
+    Mismatch,
+    /// The stored checksum is all zeros: the writer recorded none, so integrity is unknown.
+    NoChecksumPresent,
+}
+
+/// How an image's format version relates to this build's `VERSION`; see
+/// `BzImageHeader::compatibility`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Compatibility {
+    /// Written in an earlier format version. This build does not read older versions, so
+    /// migrate the image: read it with a release of the crate that supports its version and
+    /// write it again with this one.
+    Older,
+    /// Written in this build's format version; read it directly.
+    Current,
+    /// Written in a later format version than this build knows. Refuse it rather than guess
+    /// at the layout, and upgrade the crate to read it.
+    Newer,
+}
+
+/// Which bytes, if any, a header's stored checksum was computed over; see
+/// [`diagnose_checksum`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChecksumDiagnosis {
+    /// The checksum matches the compressed payload, as the format requires.
+    Compressed,
+    /// The checksum matches the decompressed data: the writer hashed the wrong side of the codec.
+    Uncompressed,
+    /// The checksum matches neither, so the payload or the header itself is corrupt.
+    Neither,
+}
+
+impl fmt::Display for ChecksumDiagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ChecksumDiagnosis::Compressed => "checksum matches the compressed payload",
+            ChecksumDiagnosis::Uncompressed => {
+                "checksum matches the uncompressed data; the writer hashed the data before \
+                 compressing it"
+            }
+            ChecksumDiagnosis::Neither => {
+                "checksum matches neither the compressed payload nor the uncompressed data"
+            }
+        })
+    }
+}
+
+/// Work out what the stored checksum of `header` was computed over, given the image's
+/// `compressed` payload and its `decompressed` data.
+///
+/// Both sides are hashed with the header's digest algorithm (plain SHA-256 if it names an
+/// unknown one). Use this to explain a failed `validate_checksum`.
+pub fn diagnose_checksum(
+    header: &BzImageHeader,
+    compressed: &[u8],
+    decompressed: &[u8],
+) -> ChecksumDiagnosis {
+    let algo = header.digest_algo().unwrap_or(DigestAlgo::Sha256);
+    let stored = header.checksum_copy();
+    if checksums_match(&compute_checksum(algo, compressed), &stored) {
+        ChecksumDiagnosis::Compressed
+    } else if checksums_match(&compute_checksum(algo, decompressed), &stored) {
+        ChecksumDiagnosis::Uncompressed
+    } else {
+        ChecksumDiagnosis::Neither
+    }
+}
+
+/// The length of the image that starts with `header_bytes`: the header plus its compressed
+/// payload.
+///
+/// Only the magic, version and sizes are looked at, so this works on the first `HEADER_SIZE`
+/// bytes of a partial download; compare the result with the bytes received (or a
+/// `Content-Length`) to tell whether the image is complete. An image with the `HAS_FOOTER` flag
+/// has its footer after this point, and how long that is cannot be known from the header;
+/// neither can the length of an extended header, so images with `HAS_EXTENDED_HEADER` are
+/// refused with `BzImageError::ExtendedHeaderPresent`.
+pub fn expected_total_from_header(header_bytes: &[u8; HEADER_SIZE]) -> Result<u64, BzImageError> {
+    let header = BzImageHeader::from_bytes(header_bytes)?;
+    if header.flags().contains(BzImageFlags::HAS_EXTENDED_HEADER) {
+        return Err(BzImageError::ExtendedHeaderPresent);
+    }
+    (HEADER_SIZE as u64)
+        .checked_add(header.compressed_size())
+        .ok_or(BzImageError::PayloadTooLarge {
+            declared: header.compressed_size(),
+            limit: u64::MAX - HEADER_SIZE as u64,
+        })
+}
@@ -0,0 +1,101 @@
+//! Conversion between bzimage files and plain gzip files.
+//!
+//! These helpers let a bzimage be handed to tools that only understand `.gz`, and let an
+//! existing `.gz` be wrapped in a bzimage header without recompressing it.
+
+use crate::{BzImageError, BzImageHeader, Codec};
+use flate2::read::GzDecoder;
+use flate2::{Compression, GzBuilder};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// The two leading bytes of every gzip member (RFC 1952).
+pub(crate) const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+fn ensure_gzip(data: &[u8]) -> Result<(), BzImageError> {
+    if !data.starts_with(&GZIP_MAGIC) {
+        return Err(BzImageError::NotGzip);
+    }
+    Ok(())
+}
+
+/// Strip the bzimage header from `src` and write the bare gzip payload to `dst`.
+///
+/// The payload checksum is verified before anything is written, and the call fails if the
+/// image's codec is not gzip (`BzImageError::NotGzip`).
+pub fn to_gzip(src: &Path, dst: &Path) -> Result<(), BzImageError> {
+    let file = File::open(src)?;
+    let (header, compressed) = BzImageHeader::read_header_and_payload(BufReader::new(file))?;
+
+    header.validate_payload(&compressed)?;
+    if header.codec()? != Codec::Gzip {
+        return Err(BzImageError::NotGzip);
+    }
+
+    std::fs::write(dst, &compressed)?;
+    Ok(())
+}
+
+/// Wrap the existing gzip file `src` in a bzimage header and write the image to `dst`.
+///
+/// The gzip stream is decoded once to learn its uncompressed size but is stored unchanged.
+/// Returns the header that was written; `src` not starting with the gzip magic is
+/// `BzImageError::NotGzip`.
+pub fn from_gzip(src: &Path, dst: &Path) -> Result<BzImageHeader, BzImageError> {
+    let compressed = std::fs::read(src)?;
+    ensure_gzip(&compressed)?;
+
+    let uncompressed_len = io::copy(&mut GzDecoder::new(&compressed[..]), &mut io::sink())
+        .map_err(BzImageError::Decompression)?;
+    let header = BzImageHeader::new_for_payload(uncompressed_len, &compressed);
+
+    let file = File::create(dst)?;
+    let mut w = BufWriter::new(file);
+    header.write_to(&mut w)?;
+    w.write_all(&compressed)?;
+    w.flush()?;
+    Ok(header)
+}
+
+/// Write `data` as a gzip image whose gzip stream carries `name` and `comment` in its own
+/// FNAME and FCOMMENT header fields, where tools such as `gzip -l` and `file` can see them.
+///
+/// The metadata is part of the compressed payload, so it is covered by the checksum and
+/// survives `to_gzip`. Returns the header that was written.
+pub fn write_image_gzip_meta<W: Write>(
+    mut w: W,
+    data: &[u8],
+    name: Option<&str>,
+    comment: Option<&str>,
+) -> Result<BzImageHeader, BzImageError> {
+    let mut builder = GzBuilder::new();
+    if let Some(name) = name {
+        builder = builder.filename(name);
+    }
+    if let Some(comment) = comment {
+        builder = builder.comment(comment);
+    }
+    let mut enc = builder.write(Vec::new(), Compression::best());
+    enc.write_all(data)?;
+    let compressed = enc.finish()?;
+
+    let mut header = BzImageHeader::new_for_payload(data.len() as u64, &compressed);
+    header.set_codec(Codec::Gzip);
+    header.write_to(&mut w)?;
+    w.write_all(&compressed)?;
+    Ok(header)
+}
+
+/// The FNAME and FCOMMENT fields of the gzip stream `compressed`, if it sets them.
+///
+/// Both are `None` when `compressed` is not gzip. The fields are nominally Latin-1; bytes that
+/// are not valid UTF-8 are replaced.
+pub fn gzip_meta(compressed: &[u8]) -> (Option<String>, Option<String>) {
+    let decoder = GzDecoder::new(compressed);
+    let Some(header) = decoder.header() else {
+        return (None, None);
+    };
+    let text = |field: Option<&[u8]>| field.map(|b| String::from_utf8_lossy(b).into_owned());
+    (text(header.filename()), text(header.comment()))
+}
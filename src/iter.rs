@@ -0,0 +1,87 @@
+//! Walking a stream of images written back to back, without an index of their offsets.
+
+use crate::{BzImageError, BzImageHeader, HEADER_SIZE};
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+
+/// Iterates over the images concatenated in `R`, yielding each header with the offset it
+/// starts at.
+///
+/// Each step reads a header and seeks past its `compressed_size` payload bytes, so payloads are
+/// never read. Iteration ends cleanly when the stream ends exactly where the next header would
+/// start. A partial or invalid header there, or a payload that runs past the end of the
+/// stream, is yielded as an error, after which the iterator is finished.
+///
+/// Only the header and payload are skipped: images followed by a footer or signature trailer
+/// cannot be walked this way.
+pub struct BzImageIter<R: Read + Seek> {
+    inner: R,
+    offset: Option<u64>,
+    done: bool,
+}
+
+impl<R: Read + Seek> BzImageIter<R> {
+    /// Start walking images from the current position of `r`.
+    pub fn new(r: R) -> BzImageIter<R> {
+        BzImageIter {
+            inner: r,
+            offset: None,
+            done: false,
+        }
+    }
+
+    /// Return the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn step(&mut self) -> Result<Option<(BzImageHeader, u64)>, BzImageError> {
+        let offset = match self.offset {
+            Some(offset) => offset,
+            None => self.inner.stream_position()?,
+        };
+        let mut bytes = [0u8; HEADER_SIZE];
+        let mut filled = 0;
+        while filled < HEADER_SIZE {
+            match self.inner.read(&mut bytes[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        match filled {
+            0 => return Ok(None),
+            HEADER_SIZE => {}
+            _ => return Err(BzImageError::TruncatedHeader),
+        }
+        let header = BzImageHeader::from_bytes(&bytes)?;
+        header.skip_extended(&mut self.inner)?;
+
+        let declared = header.compressed_size();
+        let payload = self.inner.stream_position()?;
+        let end = self.inner.seek(SeekFrom::End(0))?;
+        let next = payload.saturating_add(declared);
+        if next > end {
+            return Err(BzImageError::TruncatedPayload {
+                declared,
+                available: Some(end - payload),
+            });
+        }
+        self.inner.seek(SeekFrom::Start(next))?;
+        self.offset = Some(next);
+        Ok(Some((header, offset)))
+    }
+}
+
+impl<R: Read + Seek> Iterator for BzImageIter<R> {
+    type Item = Result<(BzImageHeader, u64), BzImageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let item = self.step().transpose();
+        self.done = !matches!(item, Some(Ok(_)));
+        item
+    }
+}
@@ -1,18 +1,223 @@
-use anyhow::{Context, Result};
-use flate2::read::GzDecoder;
+#![cfg_attr(not(feature = "std"), no_std)]
+
+//! Without the default `std` feature the crate is `no_std` (it still needs `alloc`): headers
+//! can be parsed with `from_bytes`, serialized with `to_bytes`, inspected through the
+//! accessors and checked with `validate_checksum` and `validate_payload`. Everything that
+//! reads, writes or (de)compresses needs `std`.
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+use digest::Digester;
 use sha2::{Digest, Sha256};
-use simple_endian::{u32le, u64le, read_specific};
-use std::io::{Read, Seek, Write};
+use simple_endian::{u32le, u64le};
+use core::ops::RangeInclusive;
+#[cfg(feature = "std")]
+use std::borrow::Cow;
+#[cfg(feature = "std")]
+use std::collections::HashSet;
+#[cfg(feature = "std")]
+use std::fs::File;
+#[cfg(feature = "std")]
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Take, Write};
+#[cfg(feature = "std")]
+use std::path::Path;
+
+#[cfg(feature = "std")]
+mod aligned;
+#[cfg(feature = "async")]
+mod async_io;
+mod auth;
+mod builder;
+#[cfg(feature = "cache")]
+mod cache;
+#[cfg(feature = "std")]
+mod chunked;
+mod codec;
+#[cfg(feature = "std")]
+mod detached;
+#[cfg(feature = "zstd")]
+mod dict;
+mod digest;
+#[cfg(feature = "std")]
+mod encoder;
+#[cfg(feature = "encryption")]
+mod encrypt;
+mod error;
+mod extended;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "std")]
+mod file;
+#[cfg(feature = "std")]
+mod footer;
+#[cfg(feature = "std")]
+mod framed;
+#[cfg(feature = "std")]
+mod image;
+mod inspect;
+#[cfg(feature = "std")]
+mod interop;
+#[cfg(feature = "std")]
+mod iter;
+#[cfg(feature = "std")]
+mod limit;
+#[cfg(feature = "mmap")]
+mod mmap;
+#[cfg(feature = "std")]
+mod options;
+#[cfg(feature = "std")]
+mod owned;
+#[cfg(feature = "std")]
+mod progress;
+#[cfg(feature = "std")]
+mod reader;
+mod reserved;
+#[cfg(feature = "serde")]
+mod serialize;
+#[cfg(feature = "std")]
+mod signature;
+#[cfg(feature = "std")]
+mod tee;
+#[cfg(feature = "std")]
+mod trailing;
+#[cfg(feature = "std")]
+mod verify;
+mod view;
+#[cfg(feature = "std")]
+mod writer;
+
+#[cfg(feature = "std")]
+pub use aligned::{AlignedWriter, DIRECT_IO_ALIGNMENT, write_image_direct};
+#[cfg(feature = "async")]
+pub use async_io::{STREAM_CHUNK_SIZE, decompress_stream_async};
+pub use auth::HMAC_TAG_SIZE;
+pub use builder::BzImageHeaderBuilder;
+#[cfg(feature = "cache")]
+pub use cache::DecompressCache;
+#[cfg(feature = "std")]
+pub use chunked::{DEFAULT_CHUNK_SIZE, FramedReader, FramedWriter, MAX_CHUNK_SIZE};
+pub use codec::{Codec, CompressionAlgorithm, detect_compression};
+#[cfg(feature = "std")]
+pub use codec::{MAX_DECOMPRESS_ATTEMPTS, TRY_DECOMPRESS_LIMIT, try_decompress};
+#[cfg(feature = "std")]
+pub use detached::{DETACHED_PAYLOAD_KEY, read_detached, write_detached};
+#[cfg(feature = "zstd")]
+pub use dict::dictionary_id;
+#[cfg(feature = "parallel")]
+pub use digest::compute_checksum_parallel;
+pub use digest::{ChecksumAlgorithm, DigestAlgo, TREE_BLOCK_SIZE, compute_checksum};
+#[cfg(feature = "encryption")]
+pub use encrypt::NONCE_SIZE;
+pub use error::BzImageError;
+pub use extended::{ExtendedHeader, MAX_EXTENDED_HEADER_SIZE, MAX_METADATA_SIZE};
+#[cfg(feature = "std")]
+pub use file::{
+    DedupGroup, DedupReport, OnMismatch, PayloadChecksum, dedup_dir, extract_to_preallocated, is_bzimage,
+    payload_checksum_of_file, payload_checksum_of_file_with, store_cas,
+};
+#[cfg(feature = "std")]
+pub use footer::{FOOTER_MAGIC, Footer, MAX_FOOTER_SIZE, append_footer, read_footer};
+#[cfg(feature = "std")]
+pub use framed::{read_framed, write_framed};
+#[cfg(feature = "std")]
+pub use image::{
+    AUTO_SAMPLE_SIZE, recompress, rewrite_header, upgrade_checksum, write_image, write_image_auto,
+};
+pub use inspect::{
+    ChecksumDiagnosis, ChecksumStatus, Compatibility, FieldDiff, Stats, diagnose_checksum, diff_headers,
+    expected_total_from_header,
+};
+#[cfg(feature = "std")]
+pub use interop::{from_gzip, gzip_meta, to_gzip, write_image_gzip_meta};
+#[cfg(feature = "std")]
+pub use iter::BzImageIter;
+#[cfg(feature = "std")]
+pub use limit::DEFAULT_STREAM_LIMIT;
+#[cfg(feature = "mmap")]
+pub use mmap::{MappedBzImage, map_payload, write_image_mmap};
+#[cfg(feature = "std")]
+pub use options::{BzImageOptions, read_with_options, write_image_with_options};
+#[cfg(feature = "std")]
+pub use owned::BzImage;
+#[cfg(feature = "std")]
+pub use progress::{PROGRESS_INTERVAL, PayloadProgress};
+#[cfg(feature = "std")]
+pub use reader::BzImageReader;
+pub use reserved::BzImageFlags;
+#[cfg(feature = "std")]
+pub use signature::{
+    SIGNATURE_MAGIC, SIGNATURE_TRAILER_OVERHEAD, append_signature, read_signature,
+};
+#[cfg(feature = "std")]
+pub use tee::TeeWriter;
+#[cfg(feature = "std")]
+pub use trailing::{TRAILING_MAGIC, TRAILING_PREFIX_SIZE, TrailingWriter, read_trailing_image};
+#[cfg(feature = "parallel")]
+pub use verify::verify_dir;
+#[cfg(feature = "std")]
+pub use verify::{IntegrityReport, ScrubResult, VerifyReport, scrub, verify_file};
+pub use view::HeaderView;
+#[cfg(feature = "std")]
+pub use writer::BzImageWriter;
 
 /// Four-byte ASCII magic that identifies a bzimage header on disk: `DMNZ`.
 pub const MAGIC: &[u8; 4] = b"DMNZ";
 
+/// The magic of a header whose integer fields are big-endian: `MAGIC` reversed, `ZNMD`, as
+/// a byte-swapping reader of `MAGIC` would see it. Readers pick the byte order from the
+/// magic; see `BzImageHeader::set_big_endian`.
+pub const MAGIC_BE: &[u8; 4] = b"ZNMD";
+
+/// Whether `magic` starts an image header in either byte order.
+pub(crate) fn is_magic(magic: &[u8]) -> bool {
+    magic == MAGIC || magic == MAGIC_BE
+}
+
+/// The byte ranges of the integer fields, whose order a big-endian header reverses. The
+/// magic and checksum are byte strings and read the same either way.
+const INTEGER_FIELDS: [core::ops::Range<usize>; 5] = [4..8, 8..12, 12..20, 20..28, 60..64];
+
+/// Convert the integer fields of `bytes` between little- and big-endian, either way.
+pub(crate) fn swap_integer_fields(bytes: &mut [u8; HEADER_SIZE]) {
+    for field in INTEGER_FIELDS {
+        bytes[field].reverse();
+    }
+}
+
 /// Current on-disk format version.
 pub const VERSION: u32 = 1;
 
+/// The format versions this build can parse. `read_from` and the other header readers reject
+/// anything outside it with `BzImageError::UnsupportedVersion`.
+pub const SUPPORTED_VERSIONS: RangeInclusive<u32> = VERSION..=VERSION;
+
 /// The header size in bytes (the packed header is 64 bytes).
 pub const HEADER_SIZE: usize = 64;
 
+
+/// `BzImageHeader` describes the 64-byte packed on-disk header used by the `bzimage` crate.
+/// The header stores a magic, version, sizes and a SHA-256 checksum of the compressed payload.
+/// Use `write_to` to write the header into a writer and `read_from` to parse it back from a
+/// reader. To read both header and payload, use `read_header_and_payload` which returns the
+/// header and the compressed payload bytes.
+///
+/// API contract (inputs/outputs and errors)
+///
+/// - Inputs: read/write operate on types implementing `Read`/`Write`. Reading only goes
+///   forward, so pipes, sockets and decoders work as well as files; only helpers that jump to
+///   an offset, like `read_from_at`, also need `Seek`.
+/// - Outputs: `write_to` writes exactly `HEADER_SIZE` bytes; `read_from` returns a header with
+///   endian-typed integer wrappers so callers can convert to native integers via `Into`.
+/// - Error modes: IO errors, invalid magic, unsupported version, truncated header, or
+///   decompression failures.
+/// - Partial IO: every read and write goes through `read_exact`/`write_all`, so readers and
+///   writers that transfer only a few bytes per call are handled.
+///
+/// Safety: callers should avoid taking references into the packed struct; helper accessors
+/// like `magic_copy` and `checksum_copy` are provided to safely access those byte fields, and
+/// `version`, `uncompressed_size`, `compressed_size`, `reserved1` and `reserved2` return the
+/// integer fields as native values.
 #[repr(C, packed)]
 #[derive(Copy, Clone, Debug)]
 pub struct BzImageHeader {
@@ -25,77 +230,290 @@ pub struct BzImageHeader {
     pub reserved2: u32le,
 }
 
+/// Headers are equal when every field is, so two images of the same payload written the same
+/// way compare equal. The fields are compared as native values, read out of the packed struct
+/// by the accessors. A big-endian header is never equal to its little-endian twin: the magic
+/// differs.
+impl PartialEq for BzImageHeader {
+    fn eq(&self, other: &BzImageHeader) -> bool {
+        self.fields() == other.fields()
+    }
+}
+
+impl Eq for BzImageHeader {}
+
+impl core::hash::Hash for BzImageHeader {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        self.fields().hash(state);
+    }
+}
+
 impl BzImageHeader {
-    /// API contract (inputs/outputs and errors)
-    ///
-    /// - Inputs: read/write operate on types implementing `Read`/`Write` (and `Seek` for read helpers).
-    /// - Outputs: `write_to` writes exactly `HEADER_SIZE` bytes; `read_from` returns a header with
-    ///   endian-typed integer wrappers so callers can convert to native integers via `Into`.
-    /// - Error modes: IO errors, invalid magic, truncated header, or decompression failures.
-    ///
-    /// Safety: callers should avoid taking references into the packed struct; helper accessors
-    /// like `magic_copy` and `checksum_copy` are provided to safely access those byte fields.
+    /// Every field, by value, for the comparison and hashing impls.
+    fn fields(&self) -> ([u8; 4], u32, u32, u64, u64, [u8; 32], u32) {
+        (
+            self.magic_copy(),
+            self.version(),
+            self.reserved1(),
+            self.uncompressed_size(),
+            self.compressed_size(),
+            self.checksum_copy(),
+            self.reserved2(),
+        )
+    }
 
-    /// Top-level documentation
+    /// Build a header describing `compressed`, which decompresses to `uncompressed_len` bytes.
     ///
-    /// `BzImageHeader` describes the 64-byte packed on-disk header used by the `bzimage` crate.
-    /// The header stores a magic, version, sizes and a SHA-256 checksum of the compressed payload.
-    /// Use `write_to` to write the header into a writer and `read_from` to parse it back from a
-    /// reader. To read both header and payload, use `read_header_and_payload` which returns the
-    /// header and the compressed payload bytes.
+    /// The checksum (SHA-256) and both sizes are computed here, the version is `VERSION`, and
+    /// `reserved1`/`reserved2` are zero, which records gzip and no flags; use `set_codec` and
+    /// friends for anything else.
+    pub fn new_for_payload(uncompressed_len: u64, compressed: &[u8]) -> BzImageHeader {
+        let mut hasher = Sha256::new();
+        hasher.update(compressed);
+        BzImageHeader {
+            magic: *MAGIC,
+            version: VERSION.into(),
+            reserved1: 0u32.into(),
+            uncompressed_size: uncompressed_len.into(),
+            compressed_size: (compressed.len() as u64).into(),
+            checksum: hasher.finalize().into(),
+            reserved2: 0u32.into(),
+        }
+    }
 
     pub fn size() -> usize {
-        std::mem::size_of::<BzImageHeader>()
+        core::mem::size_of::<BzImageHeader>()
+    }
+
+    #[cfg(feature = "std")]
+    pub fn write_to<W: Write>(&self, mut w: W) -> Result<(), BzImageError> {
+        w.write_all(&self.to_bytes())?;
+        Ok(())
+    }
+
+    /// Write the header over the `HEADER_SIZE` bytes at `offset` in `w`, then seek back to
+    /// where `w` was.
+    ///
+    /// Nothing else is touched, so this fills in a placeholder header once the payload after
+    /// it has been streamed out, or patches an image inside a larger container.
+    #[cfg(feature = "std")]
+    pub fn overwrite_at<W: Write + Seek>(&self, w: &mut W, offset: u64) -> Result<(), BzImageError> {
+        let pos = w.stream_position()?;
+        w.seek(SeekFrom::Start(offset))?;
+        w.write_all(&self.to_bytes())?;
+        w.seek(SeekFrom::Start(pos))?;
+        Ok(())
     }
 
-    pub fn write_to<W: Write>(&self, mut w: W) -> Result<()> {
+    /// Write the header followed by `compressed` to `w`.
+    ///
+    /// `compressed_size` and `checksum` are first recomputed from `compressed`, with the
+    /// header's digest algorithm, so the two cannot disagree; `uncompressed_size` and
+    /// `reserved1`/`reserved2` are left as they are.
+    #[cfg(feature = "std")]
+    pub fn write_with_payload<W: Write>(
+        &mut self,
+        compressed: &[u8],
+        w: &mut W,
+    ) -> Result<(), BzImageError> {
+        self.compressed_size = (compressed.len() as u64).into();
+        self.checksum = compute_checksum(self.digest_algo()?, compressed);
+        self.write_to(&mut *w)?;
+        w.write_all(compressed)?;
+        Ok(())
+    }
+
+    /// Serialize the header into its `HEADER_SIZE`-byte on-disk form, in the byte order its
+    /// magic names.
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut out = [0u8; HEADER_SIZE];
         // Write the struct as bytes
         let bytes = unsafe {
-            std::slice::from_raw_parts(self as *const BzImageHeader as *const u8, Self::size())
+            core::slice::from_raw_parts(self as *const BzImageHeader as *const u8, Self::size())
         };
-        w.write_all(bytes).context("writing header bytes")?;
-        Ok(())
+        out.copy_from_slice(bytes);
+        if self.is_big_endian() {
+            swap_integer_fields(&mut out);
+        }
+        out
+    }
+
+    /// Whether the header is written big-endian, with the magic `MAGIC_BE`.
+    pub fn is_big_endian(&self) -> bool {
+        &self.magic_copy() == MAGIC_BE
+    }
+
+    /// Write the integer fields big-endian (`MAGIC_BE`) or, the default, little-endian
+    /// (`MAGIC`), for exchanging images with a system that expects one or the other.
+    ///
+    /// Only the on-disk form changes: the accessors return the same native values either
+    /// way, and the payload is untouched. Every reader in the crate accepts both orders. The
+    /// header CRC covers the on-disk bytes, so call `set_header_crc` after this.
+    pub fn set_big_endian(&mut self, big_endian: bool) {
+        self.magic = if big_endian { *MAGIC_BE } else { *MAGIC };
     }
 
     /// Read a header from the reader and return the endian-typed `BzImageHeader`.
     /// Callers should use the provided accessor methods to get native values.
-    pub fn read_from<R: Read + Seek>(mut r: R) -> Result<BzImageHeader> {
-        // Read fields individually using read_specific to avoid taking references into packed struct
-        let mut magic = [0u8; 4];
-        r.read_exact(&mut magic).context("reading magic")?;
+    ///
+    /// The header is read in more than one piece, as are payloads elsewhere in the crate, so
+    /// wrap an unbuffered reader such as a bare `File` in a `BufReader` first, or use
+    /// `read_from_path`.
+    #[cfg(feature = "std")]
+    pub fn read_from<R: Read>(r: R) -> Result<BzImageHeader, BzImageError> {
+        Self::parse(r)
+    }
 
-        if &magic != MAGIC {
-            anyhow::bail!("invalid magic");
-        }
+    /// Read the header at the start of the file at `path`, through a `BufReader`.
+    #[cfg(feature = "std")]
+    pub fn read_from_path(path: &Path) -> Result<BzImageHeader, BzImageError> {
+        Self::parse(BufReader::new(File::open(path)?))
+    }
 
-    let version: u32le = read_specific(&mut r).context("reading version")?;
-    let reserved1: u32le = read_specific(&mut r).context("reading reserved1")?;
-    let uncompressed_size: u64le = read_specific(&mut r).context("reading uncompressed_size")?;
-    let compressed_size: u64le = read_specific(&mut r).context("reading compressed_size")?;
+    /// Create (or truncate) the file at `path` and write this header followed by `payload`
+    /// to it, through a `BufWriter`.
+    ///
+    /// The header is written as it is; use `write_with_payload` on a `BufWriter` instead to
+    /// have the sizes and checksum recomputed from `payload`.
+    #[cfg(feature = "std")]
+    pub fn write_to_path(&self, path: &Path, payload: &[u8]) -> Result<(), BzImageError> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write_to(&mut w)?;
+        w.write_all(payload)?;
+        w.flush()?;
+        Ok(())
+    }
 
-        let mut checksum = [0u8; 32];
-        r.read_exact(&mut checksum).context("reading checksum")?;
+    /// Parse a header from the first `HEADER_SIZE` bytes of `buf`, with the same checks as
+    /// `read_from`. Any bytes after the header are ignored; a shorter `buf` is
+    /// `BzImageError::TruncatedHeader`.
+    ///
+    /// A version outside `SUPPORTED_VERSIONS` is `BzImageError::UnsupportedVersion`, since the
+    /// rest of the header may not mean what this build thinks it does. For the same reason,
+    /// critical flag bits this build does not understand are `BzImageError::UnknownCriticalFlag`;
+    /// unknown optional flags are kept and can be seen through `flags()`. A header carrying
+    /// its own CRC (`HEADER_CRC32`) that does not match it is `BzImageError::HeaderCrcMismatch`.
+    pub fn from_bytes(buf: &[u8]) -> Result<BzImageHeader, BzImageError> {
+        let mut buf: [u8; HEADER_SIZE] = buf
+            .get(..HEADER_SIZE)
+            .ok_or(BzImageError::TruncatedHeader)?
+            .try_into()
+            .unwrap();
+        // decode a big-endian header as the little-endian one it stands for
+        if &buf[..4] == MAGIC_BE {
+            swap_integer_fields(&mut buf);
+        }
+        let u32_at = |at: usize| u32::from_le_bytes(buf[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(buf[at..at + 8].try_into().unwrap());
 
-    let reserved2: u32le = read_specific(&mut r).context("reading reserved2")?;
+        let magic: [u8; 4] = buf[..4].try_into().unwrap();
+        if !is_magic(&magic) {
+            return Err(BzImageError::InvalidMagic { found: magic });
+        }
+        let version = u32_at(4);
+        if !Self::is_version_supported(version) {
+            return Err(BzImageError::UnsupportedVersion(version));
+        }
+        let reserved1 = u32_at(8);
+        let flags = BzImageFlags::from_bits_retain(reserved::FLAGS.get(reserved1) as u16);
+        let unknown = flags.unknown_critical();
+        if unknown != BzImageFlags::empty() {
+            return Err(BzImageError::UnknownCriticalFlag(unknown.bits()));
+        }
 
         // Build endian-typed packed header to return. Callers should use accessors.
-        Ok(BzImageHeader {
+        let header = BzImageHeader {
             magic,
-            version: version,
-            reserved1: reserved1,
-            uncompressed_size: uncompressed_size,
-            compressed_size: compressed_size,
-            checksum,
-            reserved2: reserved2,
-        })
+            version: version.into(),
+            reserved1: reserved1.into(),
+            uncompressed_size: u64_at(12).into(),
+            compressed_size: u64_at(20).into(),
+            checksum: buf[28..60].try_into().unwrap(),
+            reserved2: u32_at(60).into(),
+        };
+        if flags.contains(BzImageFlags::HEADER_CRC32) && !header.verify_header_crc() {
+            return Err(BzImageError::HeaderCrcMismatch {
+                expected: header.reserved2(),
+                actual: header.compute_header_crc(),
+            });
+        }
+        Ok(header)
     }
-    
+
+    /// Parse a header from a plain reader, consuming exactly `HEADER_SIZE` bytes.
+    ///
+    /// Input that ends early is `BzImageError::TruncatedHeader`; otherwise the checks are
+    /// those of `from_bytes`.
+    #[cfg(feature = "std")]
+    fn parse<R: Read>(mut r: R) -> Result<BzImageHeader, BzImageError> {
+        fn truncated(e: std::io::Error) -> BzImageError {
+            if e.kind() == ErrorKind::UnexpectedEof {
+                BzImageError::TruncatedHeader
+            } else {
+                BzImageError::Io(e)
+            }
+        }
+
+        // The magic comes first, so that input which is not an image is reported as such
+        // however short it is.
+        let mut bytes = [0u8; HEADER_SIZE];
+        r.read_exact(&mut bytes[..MAGIC.len()]).map_err(truncated)?;
+        if !is_magic(&bytes[..MAGIC.len()]) {
+            let found = bytes[..MAGIC.len()].try_into().unwrap();
+            return Err(BzImageError::InvalidMagic { found });
+        }
+        r.read_exact(&mut bytes[MAGIC.len()..]).map_err(truncated)?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// Read the header from `r` and return it with a reader over exactly its `compressed_size`
+    /// payload bytes.
+    ///
+    /// Nothing is buffered and `r` need not be seekable, so this is the building block for
+    /// streaming the payload somewhere (decoding, hashing, copying). The payload reader ends at
+    /// the payload even if more data follows; a `Take::limit` above zero once it reports end of
+    /// input means the payload was truncated.
+    #[cfg(feature = "std")]
+    pub fn split_reader<R: Read>(mut r: R) -> Result<(BzImageHeader, Take<R>), BzImageError> {
+        let header = Self::parse(&mut r)?;
+        header.validate_header()?;
+        header.skip_extended(&mut r)?;
+        let payload = r.take(header.compressed_size());
+        Ok((header, payload))
+    }
+
+    /// Read the header from `r`, stream its payload through the digest without keeping it, and
+    /// return the header once the payload is known to be complete and intact.
+    ///
+    /// A short payload fails with `BzImageError::TruncatedPayload` and a corrupt one with
+    /// `BzImageError::ChecksumMismatch`. Memory use is constant, and `r` need not be seekable.
+    #[cfg(feature = "std")]
+    pub fn read_and_verify_header<R: Read>(r: R) -> Result<BzImageHeader, BzImageError> {
+        let (header, mut payload) = Self::split_reader(r)?;
+        let declared = header.compressed_size();
+        let mut digester = Digester::new(header.digest_algo()?);
+        std::io::copy(&mut payload, &mut digester)?;
+        let unread = payload.limit();
+        if unread > 0 {
+            return Err(BzImageError::TruncatedPayload {
+                declared,
+                available: Some(declared - unread),
+            });
+        }
+        let expected = header.checksum_copy();
+        let actual = digester.finalize();
+        if !digest::checksums_match(&actual, &expected) {
+            return Err(BzImageError::ChecksumMismatch { expected, actual });
+        }
+        Ok(header)
+    }
+
     /// Return a copy of the 4-byte magic.
     pub fn magic_copy(&self) -> [u8; 4] {
         let mut out = [0u8; 4];
         unsafe {
-            std::ptr::copy_nonoverlapping(
-                std::ptr::addr_of!(self.magic) as *const u8,
+            core::ptr::copy_nonoverlapping(
+                core::ptr::addr_of!(self.magic) as *const u8,
                 out.as_mut_ptr(),
                 4,
             );
@@ -107,8 +525,8 @@ impl BzImageHeader {
     pub fn checksum_copy(&self) -> [u8; 32] {
         let mut out = [0u8; 32];
         unsafe {
-            std::ptr::copy_nonoverlapping(
-                std::ptr::addr_of!(self.checksum) as *const u8,
+            core::ptr::copy_nonoverlapping(
+                core::ptr::addr_of!(self.checksum) as *const u8,
                 out.as_mut_ptr(),
                 32,
             );
@@ -116,42 +534,863 @@ impl BzImageHeader {
         out
     }
 
+    /// Return the format version as a native integer.
+    pub fn version(&self) -> u32 {
+        let field: u32le = unsafe { core::ptr::read_unaligned(core::ptr::addr_of!(self.version)) };
+        field.into()
+    }
+
+    /// Return the decompressed payload size as a native integer.
+    pub fn uncompressed_size(&self) -> u64 {
+        let field: u64le =
+            unsafe { core::ptr::read_unaligned(core::ptr::addr_of!(self.uncompressed_size)) };
+        field.into()
+    }
+
+    /// Return the compressed payload size as a native integer.
+    pub fn compressed_size(&self) -> u64 {
+        let field: u64le =
+            unsafe { core::ptr::read_unaligned(core::ptr::addr_of!(self.compressed_size)) };
+        field.into()
+    }
+
+    /// Return the raw `reserved1` word (codec, digest algorithm and flags) as a native integer.
+    pub fn reserved1(&self) -> u32 {
+        let field: u32le = unsafe { core::ptr::read_unaligned(core::ptr::addr_of!(self.reserved1)) };
+        field.into()
+    }
+
+    /// Return the raw `reserved2` word as a native integer.
+    pub fn reserved2(&self) -> u32 {
+        let field: u32le = unsafe { core::ptr::read_unaligned(core::ptr::addr_of!(self.reserved2)) };
+        field.into()
+    }
+
+    fn set_reserved1_bits(&mut self, bits: u32) {
+        self.reserved1 = bits.into();
+    }
+
+    /// Return the codec the payload was compressed with, stored in bits 0..8 of `reserved1`.
+    ///
+    /// Images written before codec selection existed have zero there, which is gzip.
+    pub fn codec(&self) -> Result<Codec, BzImageError> {
+        let id = reserved::CODEC.get(self.reserved1()) as u8;
+        Codec::from_id(id).ok_or(BzImageError::UnknownCodec(id))
+    }
+
+    /// Same as `codec`, under the name of the `CompressionAlgorithm` alias. An identifier this
+    /// crate does not define is `BzImageError::UnknownCodec`; one it defines but this build
+    /// does not include (e.g. zstd without the `zstd` feature) still decodes here, and is
+    /// refused by `can_read` and the decompression paths.
+    pub fn compression_algorithm(&self) -> Result<CompressionAlgorithm, BzImageError> {
+        self.codec()
+    }
+
+    /// Record `codec` as the payload codec. This does not touch the payload or its sizes.
+    pub fn set_codec(&mut self, codec: Codec) {
+        let bits = reserved::CODEC.set(self.reserved1(), codec.id().into());
+        self.set_reserved1_bits(bits);
+    }
+
+    /// Return the algorithm the checksum was computed with, stored in bits 8..16 of
+    /// `reserved1`.
+    ///
+    /// Images written before digest selection existed have zero there, which is SHA-256. An
+    /// identifier this crate does not define is `BzImageError::UnknownDigestAlgo`; one it
+    /// defines but this build does not include (BLAKE3 without the `blake3` feature) is
+    /// `BzImageError::DigestNotEnabled`, since nothing could check the checksum.
+    pub fn digest_algo(&self) -> Result<DigestAlgo, BzImageError> {
+        let id = reserved::DIGEST_ALGO.get(self.reserved1()) as u8;
+        let algo = DigestAlgo::from_id(id).ok_or(BzImageError::UnknownDigestAlgo(id))?;
+        if !algo.is_enabled() {
+            return Err(BzImageError::DigestNotEnabled(algo));
+        }
+        Ok(algo)
+    }
+
+    /// Record `algo` as the checksum algorithm. This does not recompute `checksum`.
+    pub fn set_digest_algo(&mut self, algo: DigestAlgo) {
+        let bits = reserved::DIGEST_ALGO.set(self.reserved1(), algo.id().into());
+        self.set_reserved1_bits(bits);
+    }
+
+    /// Return the feature flags stored in bits 16..32 of `reserved1`, including any bits this
+    /// build does not know about.
+    pub fn flags(&self) -> BzImageFlags {
+        BzImageFlags::from_bits_retain(reserved::FLAGS.get(self.reserved1()) as u16)
+    }
+
+    /// Replace the feature flags.
+    pub fn set_flags(&mut self, flags: BzImageFlags) {
+        let bits = reserved::FLAGS.set(self.reserved1(), flags.bits().into());
+        self.set_reserved1_bits(bits);
+    }
+
+    /// Whether the image ends with a signature trailer (`HAS_SIGNATURE`).
+    ///
+    /// The signature's bytes are never counted as payload or footer, whether or not the reader
+    /// verifies them; see `read_signature`.
+    pub fn has_signature(&self) -> bool {
+        self.flags().contains(BzImageFlags::HAS_SIGNATURE)
+    }
+
+    /// Record a CRC-32 of `decompressed` in `reserved2` and set `UNCOMPRESSED_CRC32`.
+    ///
+    /// This is a cheap sanity check of the decompressor's output, independent of the payload
+    /// checksum, which only covers the compressed bytes. Like `set_header_crc`, this fails with
+    /// `BzImageError::Reserved2InUse` if `reserved2` already holds a header CRC or a dictionary
+    /// id; replacing an earlier uncompressed CRC is fine.
+    pub fn set_uncompressed_crc(&mut self, decompressed: &[u8]) -> Result<(), BzImageError> {
+        let mut flags = self.flags();
+        if flags.contains(BzImageFlags::HEADER_CRC32)
+            || flags.contains(BzImageFlags::ZSTD_DICTIONARY)
+        {
+            return Err(BzImageError::Reserved2InUse);
+        }
+        self.reserved2 = crc32fast::hash(decompressed).into();
+        flags.insert(BzImageFlags::UNCOMPRESSED_CRC32);
+        self.set_flags(flags);
+        Ok(())
+    }
+
+    /// Return the stored CRC-32 of the uncompressed data, if the image records one.
+    pub fn uncompressed_crc(&self) -> Option<u32> {
+        self.flags()
+            .contains(BzImageFlags::UNCOMPRESSED_CRC32)
+            .then(|| self.reserved2())
+    }
+
+    /// Return the id of the zstd dictionary the payload was compressed against, if it was
+    /// (`ZSTD_DICTIONARY`); see `pack_with_dict`.
+    pub fn dictionary_id(&self) -> Option<u32> {
+        self.flags()
+            .contains(BzImageFlags::ZSTD_DICTIONARY)
+            .then(|| self.reserved2())
+    }
+
+    /// Check `decompressed` against the stored CRC-32 of the uncompressed data.
+    ///
+    /// Returns `false` if the image does not record one; use `uncompressed_crc` to tell the two
+    /// cases apart.
+    pub fn validate_uncompressed_crc(&self, decompressed: &[u8]) -> bool {
+        self.uncompressed_crc() == Some(crc32fast::hash(decompressed))
+    }
+
+    /// Check `decompressed` against the checksum the header keeps of the uncompressed data.
+    ///
+    /// That checksum is the CRC-32 in `reserved2`, so this is `validate_uncompressed_crc`, and
+    /// likewise `false` for an image that records none.
+    pub fn validate_uncompressed_checksum(&self, decompressed: &[u8]) -> bool {
+        self.validate_uncompressed_crc(decompressed)
+    }
+
+    /// The CRC-32 (IEEE) of header bytes 0..60: every field except `reserved2`, where
+    /// `set_header_crc` stores it. The flags are covered, `HEADER_CRC32` included.
+    pub fn compute_header_crc(&self) -> u32 {
+        crc32fast::hash(&self.to_bytes()[..HEADER_SIZE - 4])
+    }
+
+    /// Set `HEADER_CRC32` and store `compute_header_crc` in `reserved2`, so that a damaged
+    /// header is caught when it is parsed rather than mistaken for a damaged payload.
+    ///
+    /// Call this after every other change to the header, since any later change invalidates
+    /// the CRC. `reserved2` can only hold one value, so this fails with
+    /// `BzImageError::Reserved2InUse` if the header already records an uncompressed CRC or a
+    /// dictionary id.
+    pub fn set_header_crc(&mut self) -> Result<(), BzImageError> {
+        let mut flags = self.flags();
+        if flags.contains(BzImageFlags::UNCOMPRESSED_CRC32)
+            || flags.contains(BzImageFlags::ZSTD_DICTIONARY)
+        {
+            return Err(BzImageError::Reserved2InUse);
+        }
+        flags.insert(BzImageFlags::HEADER_CRC32);
+        self.set_flags(flags);
+        self.reserved2 = self.compute_header_crc().into();
+        Ok(())
+    }
+
+    /// Recompute the header CRC if the header records one, after a change to another field.
+    #[cfg(feature = "std")]
+    pub(crate) fn refresh_header_crc(&mut self) -> Result<(), BzImageError> {
+        if self.flags().contains(BzImageFlags::HEADER_CRC32) {
+            self.set_header_crc()?;
+        }
+        Ok(())
+    }
+
+    /// Check the header against the CRC-32 in `reserved2`.
+    ///
+    /// Returns `false` if the header does not record one (`HEADER_CRC32` is clear).
+    /// `from_bytes`, and so `read_from`, already reject headers that fail this check.
+    pub fn verify_header_crc(&self) -> bool {
+        self.flags().contains(BzImageFlags::HEADER_CRC32)
+            && self.reserved2() == self.compute_header_crc()
+    }
+
+    /// Check that the sizes are possible for the payload the header describes.
+    ///
+    /// A zero `compressed_size` is only valid for an empty payload that really is empty: no
+    /// data, stored as is or as a `STREAMING_FRAMED` payload of no chunks. A gzip or zstd
+    /// stream is several bytes long even for no data, so a header claiming one of 0 bytes
+    /// fails with `BzImageError::MalformedHeader`, as does one claiming data in 0 bytes.
+    /// Payload reads (`read_header_and_payload`, `unpack`, `split_reader` and the like) make
+    /// this check; `from_bytes` does not, so zeroed placeholder headers still parse.
+    pub fn validate_header(&self) -> Result<(), BzImageError> {
+        if self.compressed_size() != 0 {
+            return Ok(());
+        }
+        if self.uncompressed_size() != 0 {
+            return Err(BzImageError::MalformedHeader(
+                "compressed_size is 0 but uncompressed_size is not",
+            ));
+        }
+        match self.codec() {
+            Ok(Codec::Gzip | Codec::Zstd) if !self.flags().contains(BzImageFlags::STREAMING_FRAMED) => {
+                Err(BzImageError::MalformedHeader(
+                    "compressed_size is 0, shorter than any gzip or zstd stream",
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether this build can parse headers of format version `v`; see `SUPPORTED_VERSIONS`.
+    pub fn is_version_supported(v: u32) -> bool {
+        SUPPORTED_VERSIONS.contains(&v)
+    }
+
+    /// Compare the image's format version with this build's `VERSION`.
+    ///
+    /// This looks only at the version; `can_read` also checks the codec, digest and flags.
+    /// See [`Compatibility`] for the recommended action in each case.
+    pub fn compatibility(&self) -> Compatibility {
+        match self.version().cmp(&VERSION) {
+            core::cmp::Ordering::Less => Compatibility::Older,
+            core::cmp::Ordering::Equal => Compatibility::Current,
+            core::cmp::Ordering::Greater => Compatibility::Newer,
+        }
+    }
+
+    /// Check whether this build can fully handle the image described by this header.
+    ///
+    /// Returns the first blocking reason found, in this order: an unsupported version
+    /// (`UnsupportedVersion`), an unknown or disabled codec (`UnknownCodec`,
+    /// `CodecNotEnabled`), an unknown or disabled digest algorithm (`UnknownDigestAlgo`,
+    /// `DigestNotEnabled`), or critical flag bits this build does not understand
+    /// (`UnknownCriticalFlag`). Unknown optional flags are not an obstacle.
+    pub fn can_read(&self) -> Result<(), BzImageError> {
+        let version = self.version();
+        if !Self::is_version_supported(version) {
+            return Err(BzImageError::UnsupportedVersion(version));
+        }
+        let codec = self.codec()?;
+        if !codec.is_enabled() {
+            return Err(BzImageError::CodecNotEnabled(codec));
+        }
+        self.digest_algo()?;
+        let unknown = self.flags().unknown_critical();
+        if unknown != BzImageFlags::empty() {
+            return Err(BzImageError::UnknownCriticalFlag(unknown.bits()));
+        }
+        Ok(())
+    }
+
+    /// Check `compressed_data` against the stored checksum using the header's digest algorithm.
+    /// Returns `false` if the algorithm is unknown.
+    ///
+    /// The checksums are compared in constant time, so when they double as integrity tokens
+    /// the time taken does not reveal how many leading bytes matched.
     pub fn validate_checksum(&self, compressed_data: &[u8]) -> bool {
-        let mut hasher = Sha256::new();
-        hasher.update(compressed_data);
-        let actual: [u8; 32] = hasher.finalize().into();
-        actual == self.checksum_copy()
+        match self.digest_algo() {
+            Ok(algo) => {
+                let actual = compute_checksum(algo, compressed_data);
+                digest::checksums_match(&actual, &self.checksum_copy())
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Like `validate_checksum`, but hashes everything `r` yields instead of a slice, so the
+    /// payload never has to be collected in memory.
+    ///
+    /// Returns `Ok(false)` on a mismatch; errors are reserved for read failures and an unknown
+    /// digest algorithm. `r` should yield exactly the payload, e.g. the reader returned by
+    /// `split_reader`. At most `DEFAULT_STREAM_LIMIT` bytes are read; a longer input fails
+    /// with `BzImageError::StreamTooLong`.
+    #[cfg(feature = "std")]
+    pub fn validate_checksum_reader<R: Read>(&self, r: R) -> Result<bool, BzImageError> {
+        self.validate_checksum_reader_limited(r, DEFAULT_STREAM_LIMIT)
+    }
+
+    /// Like `validate_checksum_reader`, reading at most `limit` bytes before failing with
+    /// `BzImageError::StreamTooLong`.
+    #[cfg(feature = "std")]
+    pub fn validate_checksum_reader_limited<R: Read>(
+        &self,
+        r: R,
+        limit: u64,
+    ) -> Result<bool, BzImageError> {
+        let mut digester = Digester::new(self.digest_algo()?);
+        limit::copy_to_eof(r, &mut digester, limit)?;
+        Ok(digest::checksums_match(&digester.finalize(), &self.checksum_copy()))
+    }
+
+    /// Hash exactly `compressed_size` bytes from `r`, which must be positioned at the start of
+    /// the payload, and compare them with the stored checksum.
+    ///
+    /// Memory use is constant whatever the payload size, and nothing after the payload is
+    /// read. Returns `Ok(false)` on a mismatch; a reader that ends early fails with
+    /// `BzImageError::TruncatedPayload`.
+    #[cfg(feature = "std")]
+    pub fn validate_checksum_streaming<R: Read>(&self, r: R) -> Result<bool, BzImageError> {
+        let declared = self.compressed_size();
+        let mut digester = Digester::new(self.digest_algo()?);
+        let read = std::io::copy(&mut r.take(declared), &mut digester)?;
+        if read < declared {
+            return Err(BzImageError::TruncatedPayload {
+                declared,
+                available: Some(read),
+            });
+        }
+        Ok(digest::checksums_match(&digester.finalize(), &self.checksum_copy()))
+    }
+
+    /// Whether the header records a checksum at all. Some writers leave the field all zeros,
+    /// which no real payload hashes to.
+    pub fn has_checksum(&self) -> bool {
+        self.checksum_copy() != [0u8; 32]
+    }
+
+    /// Check `compressed_data` against the stored checksum, telling an image whose writer never
+    /// recorded a checksum apart from one whose payload does not match it.
+    ///
+    /// An all-zero checksum is `ChecksumStatus::NoChecksumPresent` without hashing anything:
+    /// the payload's integrity is unknown rather than failed. Stricter checks such as
+    /// `validate_payload` still reject those images.
+    pub fn checksum_status(&self, compressed_data: &[u8]) -> ChecksumStatus {
+        if !self.has_checksum() {
+            ChecksumStatus::NoChecksumPresent
+        } else if self.validate_checksum(compressed_data) {
+            ChecksumStatus::Valid
+        } else {
+            ChecksumStatus::Mismatch
+        }
+    }
+
+    /// Check `compressed_data` against both the stored size and the stored checksum.
+    ///
+    /// The length is compared first, so a wrongly sized buffer fails with
+    /// `BzImageError::SizeMismatch` without being hashed; a correctly sized one that does not
+    /// hash to the stored checksum fails with `BzImageError::ChecksumMismatch`.
+    pub fn validate_payload(&self, compressed_data: &[u8]) -> Result<(), BzImageError> {
+        let declared = self.compressed_size();
+        let actual = compressed_data.len() as u64;
+        if actual != declared {
+            return Err(BzImageError::SizeMismatch { declared, actual });
+        }
+
+        let expected = self.checksum_copy();
+        let actual = compute_checksum(self.digest_algo()?, compressed_data);
+        if !digest::checksums_match(&actual, &expected) {
+            return Err(BzImageError::ChecksumMismatch { expected, actual });
+        }
+        Ok(())
+    }
+
+    /// Check that `decompressed` has the stored `uncompressed_size`, failing with
+    /// `BzImageError::UncompressedSizeMismatch` otherwise.
+    pub fn validate_uncompressed_size(&self, decompressed: &[u8]) -> Result<(), BzImageError> {
+        let declared = self.uncompressed_size();
+        let actual = decompressed.len() as u64;
+        if actual != declared {
+            return Err(BzImageError::UncompressedSizeMismatch { declared, actual });
+        }
+        Ok(())
+    }
+
+    /// Like `validate_checksum`, but hashes the leaves of a `Sha256Tree` image in parallel.
+    ///
+    /// Only images written with `DigestAlgo::Sha256Tree` benefit; plain SHA-256 images are
+    /// verified sequentially.
+    #[cfg(feature = "parallel")]
+    pub fn validate_checksum_parallel(&self, compressed_data: &[u8]) -> bool {
+        match self.digest_algo() {
+            Ok(algo) => {
+                let actual = compute_checksum_parallel(algo, compressed_data);
+                digest::checksums_match(&actual, &self.checksum_copy())
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Compress `data` with `codec` at `level`.
+    ///
+    /// Levels run from 0 (fastest) to 9 (smallest) for gzip and zstd; `Codec::Stored` ignores
+    /// the level.
+    /// Images written by this crate use level 9.
+    #[cfg(feature = "std")]
+    pub fn compress_data(data: &[u8], codec: Codec, level: u32) -> Result<Vec<u8>, BzImageError> {
+        codec.compress_with_level(data, level)
+    }
+
+    /// Gzip `data` at `level`, from 0 (no compression, fastest) to 9 (`Codec::MAX_LEVEL`,
+    /// smallest), the same scale as flate2's `Compression::new`.
+    #[cfg(feature = "std")]
+    pub fn compress_gzip(data: &[u8], level: u32) -> Result<Vec<u8>, BzImageError> {
+        Codec::Gzip.compress_with_level(data, level)
+    }
+
+    /// Compress `data` with zstd at `level`, in zstd's own terms: 1 to 22, or negative for
+    /// its faster modes, with 0 meaning zstd's default. `Codec::compress_with_level` and
+    /// `pack_with` use the crate-wide 0 to 9 scale instead.
+    #[cfg(feature = "zstd")]
+    pub fn compress_zstd(data: &[u8], level: i32) -> Result<Vec<u8>, BzImageError> {
+        Ok(zstd::stream::encode_all(data, level)?)
+    }
+
+    /// Decompress `compressed`, which was produced by `codec`.
+    #[cfg(feature = "std")]
+    pub fn decompress_data(compressed: &[u8], codec: Codec) -> Result<Vec<u8>, BzImageError> {
+        codec.decompress(compressed)
+    }
+
+    /// Like `decompress_data`, but fails with `BzImageError::OutputLimitExceeded` once the
+    /// output would exceed `max_output` bytes, without decoding further.
+    #[cfg(feature = "std")]
+    pub fn decompress_data_limited(
+        compressed: &[u8],
+        codec: Codec,
+        max_output: usize,
+    ) -> Result<Vec<u8>, BzImageError> {
+        codec.decompress_limited(compressed, max_output as u64)
+    }
+
+    /// Decompress `compressed` with this header's codec, holding at most `max_output` bytes.
+    ///
+    /// The output is also checked against `uncompressed_size`: a header declaring more than
+    /// `max_output` fails with `BzImageError::OutputLimitExceeded` before decoding, and a
+    /// payload that decodes to a different length than declared fails with
+    /// `BzImageError::UncompressedSizeMismatch`. Decoding stops at the smaller of the two
+    /// bounds, so an understated header cannot make this allocate past it.
+    #[cfg(feature = "std")]
+    pub fn decompress_limited(
+        &self,
+        compressed: &[u8],
+        max_output: usize,
+    ) -> Result<Vec<u8>, BzImageError> {
+        self.decompress_checked(compressed, Some(max_output as u64), true)
+    }
+
+    /// Decompress `compressed` with this header's codec and check that the output is exactly
+    /// `uncompressed_size` bytes long.
+    ///
+    /// A different length fails with `BzImageError::UncompressedSizeMismatch`, which catches
+    /// corruption the checksum cannot see, since the checksum covers only the compressed bytes.
+    /// No more than `uncompressed_size` bytes of output are ever held. The checksum itself is
+    /// not verified; see `validate_payload`.
+    #[cfg(feature = "std")]
+    pub fn decompress_verified(&self, compressed: &[u8]) -> Result<Vec<u8>, BzImageError> {
+        self.decompress_checked(compressed, None, true)
+    }
+
+    /// Wrap `r`, which yields this image's payload, in a reader of the decompressed bytes:
+    /// the header's codec, or the chunk decoder when the payload is `STREAMING_FRAMED`.
+    ///
+    /// A damaged chunk surfaces from `read` as an `io::ErrorKind::InvalidData` error wrapping
+    /// the `BzImageError`.
+    #[cfg(feature = "std")]
+    pub(crate) fn payload_decoder<'a, R: Read + 'a>(
+        &self,
+        r: R,
+    ) -> Result<Box<dyn Read + 'a>, BzImageError> {
+        if self.flags().contains(BzImageFlags::ENCRYPTED) {
+            return Err(BzImageError::Encrypted);
+        }
+        if let Some(id) = self.dictionary_id() {
+            return Err(BzImageError::DictionaryRequired(id));
+        }
+        if self.flags().contains(BzImageFlags::STREAMING_FRAMED) {
+            return Ok(Box::new(chunked::ChunkDecoder::new(r, self)?));
+        }
+        self.codec()?.decoder(r)
     }
 
-    pub fn decompress_data(compressed: &[u8]) -> Result<Vec<u8>> {
-        let mut decoder = GzDecoder::new(compressed);
-        let mut out = Vec::new();
-        decoder
-            .read_to_end(&mut out)
-            .context("decompressing gzip data")?;
-        Ok(out)
+    /// Decompress `compressed` with this header's codec straight into `w`, returning the
+    /// number of bytes written.
+    ///
+    /// Only a small buffer is held, however large the output. The length is not checked, so
+    /// compare the result with `uncompressed_size` to be sure the image was complete. A corrupt
+    /// payload fails with `BzImageError::Decompression` and a failing `w` with
+    /// `BzImageError::Io`; either way, `w` may already hold part of the output.
+    #[cfg(feature = "std")]
+    pub fn decompress_to<W: Write>(&self, compressed: &[u8], w: &mut W) -> Result<u64, BzImageError> {
+        let mut decoder = self.payload_decoder(compressed)?;
+        let mut buf = [0u8; 64 * 1024];
+        let mut written = 0u64;
+        loop {
+            let n = match decoder.read(&mut buf) {
+                Ok(0) => return Ok(written),
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(BzImageError::Decompression(e)),
+            };
+            w.write_all(&buf[..n])?;
+            written += n as u64;
+        }
     }
     
+    /// Return the uncompressed payload of `image_bytes`, an in-memory image described by this
+    /// header (header bytes included).
+    ///
+    /// For `Codec::Stored` payloads this borrows from `image_bytes` without copying; other
+    /// codecs decompress into an owned buffer. The checksum is not verified.
+    #[cfg(feature = "std")]
+    pub fn payload_cow<'a>(&self, image_bytes: &'a [u8]) -> Result<Cow<'a, [u8]>, BzImageError> {
+        let compressed = self.payload_bytes(image_bytes)?;
+        match self.codec()? {
+            Codec::Stored => Ok(Cow::Borrowed(compressed)),
+            codec => codec.decompress(compressed).map(Cow::Owned),
+        }
+    }
+
+    /// The `compressed_size` payload bytes of `image_bytes`, an in-memory image described by
+    /// this header (header bytes included), or `TruncatedPayload` if it is too short.
+    #[cfg(feature = "std")]
+    pub(crate) fn payload_bytes<'a>(&self, image_bytes: &'a [u8]) -> Result<&'a [u8], BzImageError> {
+        if self.flags().contains(BzImageFlags::DETACHED_PAYLOAD) {
+            return Err(BzImageError::DetachedPayload);
+        }
+        let declared = self.compressed_size();
+        let start = HEADER_SIZE + self.extended_len(image_bytes.get(HEADER_SIZE..).unwrap_or(&[]))?;
+        let available = (image_bytes.len() as u64).saturating_sub(start as u64);
+        if available < declared {
+            return Err(BzImageError::TruncatedPayload {
+                declared,
+                available: Some(available),
+            });
+        }
+        Ok(&image_bytes[start..start + declared as usize])
+    }
+
     /// Read a header and the following compressed payload from `r`.
     /// Returns the header and the compressed bytes as a Vec<u8>.
-    pub fn read_header_and_payload<R: Read + Seek>(mut r: R) -> Result<(BzImageHeader, Vec<u8>)> {
+    ///
+    /// If the input holds fewer than `compressed_size` payload bytes the error is a
+    /// `BzImageError::TruncatedPayload` reporting how many bytes were available; sizes that
+    /// cannot be right fail `validate_header` first.
+    #[cfg(feature = "std")]
+    pub fn read_header_and_payload<R: Read>(
+        mut r: R,
+    ) -> Result<(BzImageHeader, Vec<u8>), BzImageError> {
         // Read header
-        let header = Self::read_from(&mut r).context("reading header")?;
+        let header = Self::read_from(&mut r)?;
+        let compressed = header.read_payload(r)?;
+        Ok((header, compressed))
+    }
 
-        // Extract compressed size safely from endian-typed field.
-    let compressed_field: u64le = unsafe { std::ptr::read_unaligned(std::ptr::addr_of!(header.compressed_size)) };
-    let compressed_size_u64: u64 = compressed_field.into();
-    let compressed_size: usize = compressed_size_u64 as usize;
+    /// Like `read_header_and_payload`, but refuses a header whose `compressed_size` is larger
+    /// than `max_compressed` with `BzImageError::PayloadTooLarge`, before reading any of the
+    /// payload.
+    #[cfg(feature = "std")]
+    pub fn read_header_and_payload_limited<R: Read>(
+        mut r: R,
+        max_compressed: usize,
+    ) -> Result<(BzImageHeader, Vec<u8>), BzImageError> {
+        let header = Self::read_from(&mut r)?;
+        let declared = header.compressed_size();
+        if declared > max_compressed as u64 {
+            return Err(BzImageError::PayloadTooLarge {
+                declared,
+                limit: max_compressed as u64,
+            });
+        }
+        let compressed = header.read_payload(r)?;
+        Ok((header, compressed))
+    }
+
+    /// Like `read_header_and_payload`, but a payload cut short is returned rather than
+    /// refused: the bytes are whatever arrived before the input ended, and the flag is `true`
+    /// only if all `compressed_size` of them did.
+    ///
+    /// Meant for diagnosing and resuming interrupted transfers; a `false` payload has not been
+    /// checked and will not match the checksum. A truncated header, and any read error other
+    /// than the input ending, is still an error.
+    #[cfg(feature = "std")]
+    pub fn read_header_and_payload_partial<R: Read>(
+        mut r: R,
+    ) -> Result<(BzImageHeader, Vec<u8>, bool), BzImageError> {
+        let header = Self::read_from(&mut r)?;
+        header.skip_extended(&mut r)?;
+        let (compressed, complete) = header.read_available_payload(r)?;
+        Ok((header, compressed, complete))
+    }
+
+    /// Read the `compressed_size` payload bytes this header describes from `r`, which must be
+    /// positioned just past the header; any extended header is skipped.
+    ///
+    /// This only reads forward. It stops at the end of the payload, leaving any footer unread,
+    /// except for a signed image: the signature trailer can only be found from the end of
+    /// input, so the rest of `r` is read to make sure a short payload was not padded out by
+    /// the trailer.
+    #[cfg(feature = "std")]
+    fn read_payload<R: Read>(&self, mut r: R) -> Result<Vec<u8>, BzImageError> {
+        self.skip_extended(&mut r)?;
+        self.read_payload_body(r)
+    }
 
-        // read the compressed payload. `read_exact` will error if there are fewer bytes than stated.
-        let mut compressed = vec![0u8; compressed_size];
-        r.read_exact(&mut compressed).context("reading compressed payload")?;
+    /// Like `read_payload`, for `r` positioned at the payload itself, past any extended
+    /// header.
+    #[cfg(feature = "std")]
+    fn read_payload_body<R: Read>(&self, r: R) -> Result<Vec<u8>, BzImageError> {
+        let (compressed, complete) = self.read_available_payload(r)?;
+        if !complete {
+            return Err(BzImageError::TruncatedPayload {
+                declared: self.compressed_size(),
+                available: Some(compressed.len() as u64),
+            });
+        }
+        Ok(compressed)
+    }
 
+    /// Read up to `compressed_size` payload bytes from `r`, positioned at the payload, and
+    /// whether there were that many.
+    #[cfg(feature = "std")]
+    fn read_available_payload<R: Read>(&self, mut r: R) -> Result<(Vec<u8>, bool), BzImageError> {
+        if self.flags().contains(BzImageFlags::DETACHED_PAYLOAD) {
+            return Err(BzImageError::DetachedPayload);
+        }
+        self.validate_header()?;
+        let declared = self.compressed_size();
+
+        // Grow the buffer as data arrives rather than trusting `declared` with one allocation,
+        // so a lying header cannot make this allocate more than the input holds.
+        let mut compressed = Vec::new();
+        (&mut r).take(declared).read_to_end(&mut compressed)?;
+        let mut read = compressed.len() as u64;
+        if self.has_signature() {
+            read = read.min(signature::data_end_forward(&compressed, r)?);
+        }
+        // a short signed payload may have taken in some of the trailer
+        compressed.truncate(read as usize);
+        Ok((compressed, read == declared))
+    }
+
+    /// Whether the stored payload checksum is one of `allowlist`.
+    ///
+    /// This only inspects the header; pair it with `validate_payload`, or use
+    /// `read_if_trusted`, to be sure the payload really has that checksum.
+    #[cfg(feature = "std")]
+    pub fn is_trusted(&self, allowlist: &HashSet<[u8; 32]>) -> bool {
+        allowlist.contains(&self.checksum_copy())
+    }
+
+    /// Read an image from `r` only if its checksum is in `allowlist`.
+    ///
+    /// The header is checked before the payload is read, so untrusted images fail with
+    /// `BzImageError::UntrustedChecksum` without allocating for their payload. A trusted header
+    /// is not enough: the payload must also match the checksum, or `validate_payload` fails.
+    #[cfg(feature = "std")]
+    pub fn read_if_trusted<R: Read>(
+        mut r: R,
+        allowlist: &HashSet<[u8; 32]>,
+    ) -> Result<(BzImageHeader, Vec<u8>), BzImageError> {
+        let header = Self::read_from(&mut r)?;
+        if !header.is_trusted(allowlist) {
+            return Err(BzImageError::UntrustedChecksum(header.checksum_copy()));
+        }
+        let compressed = header.read_payload(r)?;
+        header.validate_payload(&compressed)?;
         Ok((header, compressed))
     }
+
+    /// Read an image from `r`, verify it completely and return the header and the decompressed
+    /// data.
+    ///
+    /// On top of `validate_payload`, the decoded length must equal `uncompressed_size`, or
+    /// the read fails with `BzImageError::UncompressedSizeMismatch`, and if the header records
+    /// a CRC-32 of the uncompressed data the decoded bytes must match it, or the read fails
+    /// with `BzImageError::UncompressedCrcMismatch`. Decoding stops holding output once it
+    /// passes the declared size, so a header understating a payload that expands enormously
+    /// cannot make this allocate more than `uncompressed_size` bytes.
+    ///
+    /// When `reserved1` is entirely zero, the codec is taken from the payload's magic bytes
+    /// (see `detect_compression`) rather than assumed to be gzip.
+    #[cfg(feature = "std")]
+    pub fn read_verified<R: Read>(r: R) -> Result<(BzImageHeader, Vec<u8>), BzImageError> {
+        Self::read_verified_extended(r).map(|(header, _, decompressed)| (header, decompressed))
+    }
+
+    /// Like `read_verified`, also returning the extended header, if there is one.
+    #[cfg(feature = "std")]
+    pub(crate) fn read_verified_extended<R: Read>(
+        mut r: R,
+    ) -> Result<(BzImageHeader, Option<ExtendedHeader>, Vec<u8>), BzImageError> {
+        let header = Self::read_from(&mut r)?;
+        let extended = header.read_extended(&mut r)?;
+        let compressed = header.read_payload_body(r)?;
+        header.can_read()?;
+        header.validate_payload(&compressed)?;
+        let decompressed = header.decode_verified(&compressed)?;
+        Ok((header, extended, decompressed))
+    }
+
+    /// Decompress a payload whose checksum has already been checked, applying the size and
+    /// CRC-32 checks of `read_verified`.
+    #[cfg(feature = "std")]
+    pub(crate) fn decode_verified(&self, compressed: &[u8]) -> Result<Vec<u8>, BzImageError> {
+        self.decode_verified_limited(compressed, None)
+    }
+
+    /// Like `decode_verified`, but failing with `BzImageError::OutputLimitExceeded` once the
+    /// output would pass `limit`.
+    #[cfg(feature = "std")]
+    pub(crate) fn decode_verified_limited(
+        &self,
+        compressed: &[u8],
+        limit: Option<u64>,
+    ) -> Result<Vec<u8>, BzImageError> {
+        // An all-zero `reserved1` says gzip, but some writers leave it zero whatever the codec.
+        let mut decoding = *self;
+        if self.reserved1() == 0
+            && let Some(codec) = codec::detect_compression(compressed)
+        {
+            decoding.set_codec(codec);
+        }
+        let decompressed = decoding.decompress_checked(compressed, limit, true)?;
+        if let Some(expected) = self.uncompressed_crc() {
+            let actual = crc32fast::hash(&decompressed);
+            if actual != expected {
+                return Err(BzImageError::UncompressedCrcMismatch { expected, actual });
+            }
+        }
+        Ok(decompressed)
+    }
+
+    /// Gzip `uncompressed` and write it to `w` as a complete image, returning the header.
+    ///
+    /// This is `write_image` with `Codec::Gzip`, for callers that just want bytes in an image.
+    /// `reserved2` is left free, so the header can still take `set_header_crc`; use
+    /// `set_uncompressed_crc` to record a CRC-32 of the data there instead. Empty
+    /// `uncompressed` is fine: the payload is then gzip's 20-byte stream for no data, which
+    /// `unpack` turns back into an empty `Vec`.
+    #[cfg(feature = "std")]
+    pub fn pack<W: Write>(uncompressed: &[u8], w: &mut W) -> Result<BzImageHeader, BzImageError> {
+        Self::pack_with(uncompressed, Codec::Gzip, Codec::MAX_LEVEL, w)
+    }
+
+    /// Like `pack`, but with `codec` at `level`, from 0 (fastest) to `Codec::MAX_LEVEL`
+    /// (smallest). The checksum covers the compressed bytes whatever the codec, and `unpack`
+    /// reads the result back.
+    #[cfg(feature = "std")]
+    pub fn pack_with<W: Write>(
+        uncompressed: &[u8],
+        codec: Codec,
+        level: u32,
+        w: &mut W,
+    ) -> Result<BzImageHeader, BzImageError> {
+        Self::pack_with_extended(uncompressed, codec, level, &ExtendedHeader::new(), w)
+    }
+
+    /// Like `pack_with`, writing `extended` between the header and the payload (see
+    /// `write_with_extended`). `BzImage::read` reads it back.
+    #[cfg(feature = "std")]
+    pub fn pack_with_extended<W: Write>(
+        uncompressed: &[u8],
+        codec: Codec,
+        level: u32,
+        extended: &ExtendedHeader,
+        w: &mut W,
+    ) -> Result<BzImageHeader, BzImageError> {
+        let compressed = codec.compress_with_level(uncompressed, level)?;
+        let mut header = BzImageHeader::new_for_payload(uncompressed.len() as u64, &compressed);
+        header.set_codec(codec);
+        header.write_with_extended(extended, &compressed, w)?;
+        Ok(header)
+    }
+
+    /// Read the image at `r`, verify it and return the decompressed data; the inverse of
+    /// `pack`, for any codec this build supports.
+    ///
+    /// A payload that does not match its checksum fails with `BzImageError::ChecksumMismatch`
+    /// before any decoding is attempted; one that matches but cannot be decoded fails with
+    /// `BzImageError::Decompression`. See `read_verified` for the remaining checks.
+    #[cfg(feature = "std")]
+    pub fn unpack<R: Read>(r: &mut R) -> Result<Vec<u8>, BzImageError> {
+        Self::read_verified(r).map(|(_, decompressed)| decompressed)
+    }
+
+    /// Decompress `compressed` with this header's codec, holding at most `limit` bytes of
+    /// output and, if `check_size`, requiring exactly `uncompressed_size` bytes.
+    ///
+    /// Exceeding `limit` fails with `BzImageError::OutputLimitExceeded`. When checking the
+    /// size, output is also capped at `uncompressed_size`, and anything beyond it is counted
+    /// (up to `limit`) but not kept, so the mismatch error can report the real length.
+    #[cfg(feature = "std")]
+    pub(crate) fn decompress_checked(
+        &self,
+        compressed: &[u8],
+        limit: Option<u64>,
+        check_size: bool,
+    ) -> Result<Vec<u8>, BzImageError> {
+        let declared = self.uncompressed_size();
+        if let Some(limit) = limit
+            && check_size
+            && declared > limit
+        {
+            return Err(BzImageError::OutputLimitExceeded { limit });
+        }
+        let cap = match (limit, check_size) {
+            (Some(limit), true) => limit.min(declared),
+            (Some(limit), false) => limit,
+            (None, true) => declared,
+            (None, false) => u64::MAX,
+        };
+
+        let mut decoder = self.payload_decoder(compressed)?;
+        let mut decompressed = Vec::new();
+        (&mut decoder)
+            .take(cap.saturating_add(1))
+            .read_to_end(&mut decompressed)
+            .map_err(BzImageError::Decompression)?;
+        let len = decompressed.len() as u64;
+        if len > cap {
+            if let Some(limit) = limit
+                && len > limit
+            {
+                return Err(BzImageError::OutputLimitExceeded { limit });
+            }
+            // count the rest without keeping it, to report the real size
+            let budget = limit.map_or(u64::MAX, |limit| limit - len + 1);
+            let rest = std::io::copy(&mut decoder.take(budget), &mut std::io::sink())
+                .map_err(BzImageError::Decompression)?;
+            if limit.is_some_and(|limit| len + rest > limit) {
+                return Err(BzImageError::OutputLimitExceeded { limit: limit.unwrap() });
+            }
+            return Err(BzImageError::UncompressedSizeMismatch {
+                declared,
+                actual: len + rest,
+            });
+        }
+        if check_size {
+            self.validate_uncompressed_size(&decompressed)?;
+        }
+        Ok(decompressed)
+    }
+
+    /// Read the header and compressed payload of an image that starts `offset` bytes into `r`,
+    /// e.g. one embedded after a fixed preamble in a larger file.
+    #[cfg(feature = "std")]
+    pub fn read_from_at<R: Read + Seek>(
+        mut r: R,
+        offset: u64,
+    ) -> Result<(BzImageHeader, Vec<u8>), BzImageError> {
+        r.seek(SeekFrom::Start(offset))?;
+        Self::read_header_and_payload(r)
+    }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod unit_tests {
     use super::*;
     use flate2::write::GzEncoder;
@@ -195,7 +1434,7 @@ mod unit_tests {
         assert_eq!(&read_header.magic_copy(), MAGIC);
         assert_eq!(read_compressed.len(), compressed.len());
         assert!(read_header.validate_checksum(&read_compressed));
-        let decompressed = BzImageHeader::decompress_data(&read_compressed).unwrap();
+        let decompressed = BzImageHeader::decompress_data(&read_compressed, Codec::Gzip).unwrap();
         assert_eq!(decompressed, payload);
     }
 }
@@ -0,0 +1,29 @@
+//! Bounds on the paths that read until end of input.
+//!
+//! Most reads are sized by the header, but a few consume whatever the caller's reader yields.
+//! A reader that never reaches end of input (a `/dev/zero`-like source, or a buggy adapter)
+//! would keep those running forever, so they stop after a fixed number of bytes instead.
+
+use crate::BzImageError;
+use std::io::{self, Read, Write};
+
+/// Most bytes a read-to-end path processes before failing with
+/// `BzImageError::StreamTooLong`, unless the caller gives its own limit.
+pub const DEFAULT_STREAM_LIMIT: u64 = 64 << 30;
+
+/// Copy `r` to `w` until end of input, failing with `BzImageError::StreamTooLong` once more
+/// than `limit` bytes have been read. Returns the number of bytes copied.
+///
+/// At most `limit + 1` bytes reach `w` before the error. Read and write failures are
+/// `BzImageError::Io`.
+pub(crate) fn copy_to_eof<R: Read, W: Write + ?Sized>(
+    r: R,
+    w: &mut W,
+    limit: u64,
+) -> Result<u64, BzImageError> {
+    let copied = io::copy(&mut r.take(limit.saturating_add(1)), w)?;
+    if copied > limit {
+        return Err(BzImageError::StreamTooLong { limit });
+    }
+    Ok(copied)
+}
@@ -0,0 +1,224 @@
+//! The `bzimage` command: pack, unpack and inspect images from the shell.
+//!
+//! Built with the `cli` feature: `cargo install bzimage --features cli`.
+
+use anyhow::{Context, Result, bail};
+use bzimage::{BzImage, BzImageHeader, Codec, ExtendedHeader};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime};
+
+#[derive(Parser)]
+#[command(
+    name = "bzimage",
+    version,
+    about = "Pack, unpack and inspect bzimage files"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Compress a file into an image.
+    Pack {
+        input: PathBuf,
+        output: PathBuf,
+        /// The payload codec.
+        #[arg(long, value_enum, default_value_t = Algo::Gzip)]
+        algo: Algo,
+        /// Compression level, from 0 (fastest) to 9 (smallest).
+        #[arg(long, default_value_t = Codec::MAX_LEVEL,
+              value_parser = clap::value_parser!(u32).range(0..=Codec::MAX_LEVEL as i64))]
+        level: u32,
+    },
+    /// Verify an image and write out its decompressed data.
+    ///
+    /// Nothing is written unless the checksum and sizes all check out. Without an output
+    /// path, the data is written to the file name recorded by `pack`, in the current
+    /// directory, and given its recorded modification time; an existing file of that name is
+    /// left alone and the command fails.
+    Unpack {
+        input: PathBuf,
+        output: Option<PathBuf>,
+    },
+    /// Print an image's header and whether its payload matches the checksum.
+    ///
+    /// Exits with status 1 if it does not.
+    Info { file: PathBuf },
+    /// Check that an image is intact without decompressing it.
+    ///
+    /// Exits with status 1 if it is not.
+    Verify { file: PathBuf },
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+enum Algo {
+    Gzip,
+    Zstd,
+    Stored,
+}
+
+impl From<Algo> for Codec {
+    fn from(algo: Algo) -> Codec {
+        match algo {
+            Algo::Gzip => Codec::Gzip,
+            Algo::Zstd => Codec::Zstd,
+            Algo::Stored => Codec::Stored,
+        }
+    }
+}
+
+/// The file name and modification time of `input`, to record in the image.
+fn metadata_of(input: &Path) -> Result<ExtendedHeader> {
+    let mut extended = ExtendedHeader::new();
+    let Some(name) = input.file_name().and_then(|name| name.to_str()) else {
+        return Ok(extended);
+    };
+    let modified = fs::metadata(input)
+        .and_then(|meta| meta.modified())
+        .with_context(|| format!("reading metadata of {}", input.display()))?;
+    let mtime = modified
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    extended.set_metadata(name, mtime);
+    Ok(extended)
+}
+
+fn pack(input: &Path, output: &Path, algo: Algo, level: u32) -> Result<()> {
+    let data = fs::read(input).with_context(|| format!("reading {}", input.display()))?;
+    let extended = metadata_of(input)?;
+    let file = File::create(output).with_context(|| format!("creating {}", output.display()))?;
+    let mut w = BufWriter::new(file);
+    BzImageHeader::pack_with_extended(&data, algo.into(), level, &extended, &mut w)
+        .with_context(|| format!("writing {}", output.display()))?;
+    w.flush()
+        .with_context(|| format!("writing {}", output.display()))?;
+    Ok(())
+}
+
+fn unpack(input: &Path, output: Option<&Path>) -> Result<()> {
+    let file = File::open(input).with_context(|| format!("opening {}", input.display()))?;
+    let image = BzImage::read(&mut BufReader::new(file))
+        .with_context(|| format!("unpacking {}", input.display()))?;
+    let (output, mut file) = match output {
+        Some(output) => {
+            let file =
+                File::create(output).with_context(|| format!("creating {}", output.display()))?;
+            (output, file)
+        }
+        // only the last component, so an image cannot write outside the current directory
+        None => {
+            let output = image
+                .extended
+                .name()
+                .and_then(|name| Path::new(name).file_name())
+                .map(Path::new)
+                .with_context(|| {
+                    format!(
+                        "{} records no file name; give an output path",
+                        input.display()
+                    )
+                })?;
+            // the name comes from the image, so never clobber what is already there
+            let file = match File::options().write(true).create_new(true).open(output) {
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => bail!(
+                    "{} already exists; give an output path to write elsewhere",
+                    output.display()
+                ),
+                file => file.with_context(|| format!("creating {}", output.display()))?,
+            };
+            (output, file)
+        }
+    };
+    file.write_all(&image.data)
+        .with_context(|| format!("writing {}", output.display()))?;
+    if let Some(mtime) = image.extended.mtime() {
+        file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime))
+            .with_context(|| format!("setting modification time of {}", output.display()))?;
+    }
+    Ok(())
+}
+
+fn info(path: &Path) -> Result<bool> {
+    let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let mut r = BufReader::new(file);
+    let header = BzImageHeader::read_from(&mut r)
+        .with_context(|| format!("reading header of {}", path.display()))?;
+    print!("{}", header.summary());
+    let stats = header.stats();
+    match stats.compression_ratio {
+        Some(ratio) => println!("compression ratio: {ratio:.2}x"),
+        None => println!("compression ratio: n/a"),
+    }
+    match stats.space_saved {
+        Some(saved) => println!("space saved: {:.1}%", saved * 100.0),
+        None => println!("space saved: n/a"),
+    }
+    if let Some(extended) = header
+        .read_extended(&mut r)
+        .with_context(|| format!("reading extended header of {}", path.display()))?
+    {
+        if let Some(name) = extended.name() {
+            println!("name: {name}");
+        }
+        if let Some(mtime) = extended.mtime() {
+            println!("mtime: {mtime}");
+        }
+    }
+    let valid = match header.validate_checksum_streaming(&mut r) {
+        Ok(true) => {
+            println!("checksum: valid");
+            true
+        }
+        Ok(false) => {
+            println!("checksum: MISMATCH");
+            false
+        }
+        Err(e) => {
+            println!("checksum: not verified ({e})");
+            false
+        }
+    };
+    Ok(valid)
+}
+
+fn verify(path: &Path) -> Result<bool> {
+    let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let report = BzImageHeader::verify(BufReader::new(file))
+        .with_context(|| format!("verifying {}", path.display()))?;
+    let yes_no = |ok: bool| if ok { "yes" } else { "no" };
+    println!("magic ok: {}", yes_no(report.magic_ok));
+    println!("version supported: {}", yes_no(report.version_supported));
+    println!("payload complete: {}", yes_no(report.payload_complete));
+    println!("checksum ok: {}", yes_no(report.checksum_ok));
+    println!("uncompressed size: {}", report.uncompressed_size);
+    println!("compressed size: {}", report.compressed_size);
+    Ok(report.is_ok())
+}
+
+fn main() -> ExitCode {
+    let result = match Cli::parse().command {
+        Command::Pack {
+            input,
+            output,
+            algo,
+            level,
+        } => pack(&input, &output, algo, level).map(|()| true),
+        Command::Unpack { input, output } => unpack(&input, output.as_deref()).map(|()| true),
+        Command::Info { file } => info(&file),
+        Command::Verify { file } => verify(&file),
+    };
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("bzimage: {e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
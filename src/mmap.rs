@@ -0,0 +1,180 @@
+//! Memory-mapped image IO (the `mmap` feature).
+
+use crate::{BzImage, BzImageError, BzImageHeader, Codec, ExtendedHeader, HEADER_SIZE};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use memmap2::{Mmap, MmapMut};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// A generous estimate of the gzip output size for `len` input bytes.
+///
+/// Incompressible input grows by a few bytes per deflate block plus the gzip framing; if the
+/// estimate is ever exceeded the mapping is grown, so it only needs to be right usually.
+fn gzip_estimate(len: usize) -> usize {
+    len + len / 64 + 1024
+}
+
+/// A `Write` sink that writes straight into a file mapping, growing it when full.
+struct MmapSink {
+    file: File,
+    map: MmapMut,
+    pos: usize,
+}
+
+impl MmapSink {
+    fn new(file: File, start: usize, len: usize) -> io::Result<MmapSink> {
+        file.set_len(len as u64)?;
+        // SAFETY: the file was just created by us and is not expected to be modified by other
+        // processes while it is mapped.
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        Ok(MmapSink {
+            file,
+            map,
+            pos: start,
+        })
+    }
+
+    fn grow(&mut self, needed: usize) -> io::Result<()> {
+        let len = (self.map.len() * 2).max(needed);
+        self.map.flush()?;
+        self.file.set_len(len as u64)?;
+        // SAFETY: as in `new`; the old mapping is replaced before anything else uses it.
+        self.map = unsafe { MmapMut::map_mut(&self.file)? };
+        Ok(())
+    }
+}
+
+impl Write for MmapSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let end = self.pos + buf.len();
+        if end > self.map.len() {
+            self.grow(end)?;
+        }
+        self.map[self.pos..end].copy_from_slice(buf);
+        self.pos = end;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.map.flush()
+    }
+}
+
+/// Gzip-compress `data` into a new image at `path` through a writable memory mapping.
+///
+/// The file is pre-sized to an estimate of the final length and mapped, the compressor writes
+/// directly into the mapping after the header slot, and the header is filled in last. The
+/// file is then truncated to the image's exact length, since the estimate is usually larger
+/// than the compressed output. Returns the header that was written.
+pub fn write_image_mmap(path: &Path, data: &[u8]) -> Result<BzImageHeader, BzImageError> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    let mut sink = MmapSink::new(file, HEADER_SIZE, HEADER_SIZE + gzip_estimate(data.len()))?;
+
+    let mut enc = GzEncoder::new(&mut sink, Compression::best());
+    enc.write_all(data)?;
+    enc.finish()?;
+
+    let end = sink.pos;
+    let mut header = BzImageHeader::new_for_payload(data.len() as u64, &sink.map[HEADER_SIZE..end]);
+    header.set_codec(Codec::Gzip);
+    header.write_to(&mut sink.map[..HEADER_SIZE])?;
+
+    sink.map.flush()?;
+    let MmapSink { file, map, .. } = sink;
+    drop(map);
+    file.set_len(end as u64)?;
+    Ok(header)
+}
+
+/// Map the image file at `path` read-only and parse its header.
+///
+/// Pair with [`BzImageHeader::payload_slice`] for a zero-copy view of the compressed payload.
+pub fn map_payload(path: &Path) -> Result<(BzImageHeader, Mmap), BzImageError> {
+    let file = File::open(path)?;
+    // SAFETY: the mapping is read-only; as with any file mapping, another process truncating
+    // or rewriting the file while it is mapped is outside what this crate can guard against.
+    let map = unsafe { Mmap::map(&file) }?;
+    let header = BzImageHeader::from_bytes(&map)?;
+    Ok((header, map))
+}
+
+impl BzImageHeader {
+    /// The compressed payload inside `mmap`, a mapping of the whole image (see
+    /// [`map_payload`]), borrowed without copying.
+    ///
+    /// Fails with `BzImageError::TruncatedPayload` if the mapping is shorter than
+    /// `HEADER_SIZE + compressed_size`.
+    pub fn payload_slice<'a>(&self, mmap: &'a Mmap) -> Result<&'a [u8], BzImageError> {
+        self.payload_bytes(mmap)
+    }
+}
+
+/// An image file mapped into memory, with its header parsed and its payload bounds checked.
+///
+/// Header inspection and checksum verification work on the mapped bytes directly; only
+/// decompression allocates.
+#[derive(Debug)]
+pub struct MappedBzImage {
+    header: BzImageHeader,
+    map: Mmap,
+    payload_start: usize,
+}
+
+impl MappedBzImage {
+    /// The image's header.
+    pub fn header(&self) -> &BzImageHeader {
+        &self.header
+    }
+
+    /// The compressed payload, borrowed from the mapping.
+    pub fn payload(&self) -> &[u8] {
+        &self.map[self.payload_start..self.payload_start + self.header.compressed_size() as usize]
+    }
+
+    /// Check the mapped payload against the header; see `BzImageHeader::validate_payload`.
+    pub fn validate(&self) -> Result<(), BzImageError> {
+        self.header.validate_payload(self.payload())
+    }
+
+    /// Decompress the mapped payload into an owned [`BzImage`], after verifying it as
+    /// `BzImageHeader::read_verified` does.
+    pub fn load(&self) -> Result<BzImage, BzImageError> {
+        self.header.can_read()?;
+        self.validate()?;
+        let data = self.header.decode_verified(self.payload())?;
+        let extended = match self.payload_start > HEADER_SIZE {
+            true => ExtendedHeader::from_bytes(&self.map[HEADER_SIZE..])?.0,
+            false => ExtendedHeader::new(),
+        };
+        Ok(BzImage {
+            header: self.header,
+            data,
+            extended,
+        })
+    }
+}
+
+impl BzImage {
+    /// Map the image file at `path` read-only, parse its header with
+    /// `BzImageHeader::from_bytes`, and check that the whole payload is present.
+    ///
+    /// Nothing is copied or decompressed; see [`MappedBzImage`]. A file shorter than its
+    /// header declares fails with `BzImageError::TruncatedPayload`.
+    pub fn open_mmap(path: &Path) -> Result<MappedBzImage, BzImageError> {
+        let (header, map) = map_payload(path)?;
+        let payload = header.payload_slice(&map)?;
+        let payload_start = payload.as_ptr() as usize - map.as_ptr() as usize;
+        Ok(MappedBzImage {
+            header,
+            map,
+            payload_start,
+        })
+    }
+}
@@ -0,0 +1,113 @@
+//! Reusable read and write settings.
+
+use crate::{BzImageError, BzImageHeader, Codec};
+use std::io::{Read, Write};
+
+/// Settings for [`write_image_with_options`] and [`read_with_options`].
+///
+/// Build one with the chained `with_*` setters and reuse it across images. The defaults match
+/// `write_image` with gzip and a plain `read_header_and_payload` plus decompression: gzip at
+/// level 9, no verification and no output limit.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BzImageOptions {
+    codec: Codec,
+    level: u32,
+    verify: bool,
+    max_uncompressed_size: Option<u64>,
+}
+
+impl Default for BzImageOptions {
+    fn default() -> BzImageOptions {
+        BzImageOptions {
+            codec: Codec::Gzip,
+            level: Codec::MAX_LEVEL,
+            verify: false,
+            max_uncompressed_size: None,
+        }
+    }
+}
+
+impl BzImageOptions {
+    pub fn new() -> BzImageOptions {
+        BzImageOptions::default()
+    }
+
+    /// The codec used when writing.
+    pub fn with_codec(mut self, codec: Codec) -> BzImageOptions {
+        self.codec = codec;
+        self
+    }
+
+    /// The compression level used when writing, from 0 (fastest) to `Codec::MAX_LEVEL`.
+    pub fn with_level(mut self, level: u32) -> BzImageOptions {
+        self.level = level;
+        self
+    }
+
+    /// Whether reads check the payload's size and checksum and the decompressed length.
+    pub fn with_verify(mut self, verify: bool) -> BzImageOptions {
+        self.verify = verify;
+        self
+    }
+
+    /// The most decompressed bytes a read may produce, or `None` for no limit.
+    pub fn with_max_uncompressed_size(mut self, max: Option<u64>) -> BzImageOptions {
+        self.max_uncompressed_size = max;
+        self
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub fn level(&self) -> u32 {
+        self.level
+    }
+
+    pub fn verify(&self) -> bool {
+        self.verify
+    }
+
+    pub fn max_uncompressed_size(&self) -> Option<u64> {
+        self.max_uncompressed_size
+    }
+}
+
+/// Compress `data` as configured by `options` and write a complete image to `w`.
+///
+/// Returns the header that was written.
+pub fn write_image_with_options<W: Write>(
+    mut w: W,
+    data: &[u8],
+    options: &BzImageOptions,
+) -> Result<BzImageHeader, BzImageError> {
+    let compressed = options.codec.compress_with_level(data, options.level)?;
+    let mut header = BzImageHeader::new_for_payload(data.len() as u64, &compressed);
+    header.set_codec(options.codec);
+    header.write_to(&mut w)?;
+    w.write_all(&compressed)?;
+    Ok(header)
+}
+
+/// Read an image from `r` and decompress it as configured by `options`, returning the header
+/// and the decompressed data.
+///
+/// With `verify`, the payload and its decoding get the checks of `BzImageHeader::read_verified`:
+/// size and checksum, the decoded length and any recorded CRC-32 of the data. Without it, only
+/// the header is checked and the payload is decoded with the codec it names. With a
+/// `max_uncompressed_size`, decoding stops with `BzImageError::OutputLimitExceeded` as soon as
+/// the output would pass the limit.
+pub fn read_with_options<R: Read>(
+    r: R,
+    options: &BzImageOptions,
+) -> Result<(BzImageHeader, Vec<u8>), BzImageError> {
+    let (header, compressed) = BzImageHeader::read_header_and_payload(r)?;
+    header.can_read()?;
+    let decompressed = if options.verify {
+        header.validate_payload(&compressed)?;
+        header.decode_verified_limited(&compressed, options.max_uncompressed_size)?
+    } else {
+        header.decompress_checked(&compressed, options.max_uncompressed_size, false)?
+    };
+    Ok((header, decompressed))
+}
@@ -0,0 +1,67 @@
+//! A whole image held in memory: the header and the decompressed data.
+
+use crate::{BzImageError, BzImageHeader, Codec, ExtendedHeader};
+use std::io::{Read, Write};
+
+/// A fully loaded image, for callers that want the data and do not care about streaming.
+///
+/// `data` is always the decompressed payload; the compressed bytes are not kept. `extended`
+/// holds the extended header's entries, such as the original file name, and is empty for
+/// images without one.
+#[derive(Clone, Debug)]
+pub struct BzImage {
+    pub header: BzImageHeader,
+    pub data: Vec<u8>,
+    pub extended: ExtendedHeader,
+}
+
+impl BzImage {
+    /// Read an image from `r`, verify its checksum and sizes, and decompress it.
+    ///
+    /// This is `BzImageHeader::read_verified`, keeping the extended header; see there for the
+    /// checks made.
+    pub fn read<R: Read>(r: &mut R) -> Result<BzImage, BzImageError> {
+        let (header, extended, data) = BzImageHeader::read_verified_extended(r)?;
+        Ok(BzImage {
+            header,
+            data,
+            extended: extended.unwrap_or_default(),
+        })
+    }
+
+    /// Gzip `data` and build an image of it with a matching header.
+    pub fn from_data(data: &[u8]) -> Result<BzImage, BzImageError> {
+        let compressed = Codec::Gzip.compress(data)?;
+        Ok(BzImage {
+            header: BzImageHeader::new_for_payload(data.len() as u64, &compressed),
+            data: data.to_vec(),
+            extended: ExtendedHeader::new(),
+        })
+    }
+
+    /// Record the original file name and modification time in the extended header.
+    pub fn set_metadata(&mut self, name: impl Into<String>, mtime: u64) {
+        self.extended.set_metadata(name, mtime);
+    }
+
+    /// The original file name and modification time, if the image records them.
+    pub fn metadata(&self) -> Option<(&str, u64)> {
+        self.extended.metadata()
+    }
+
+    /// Compress `data` with the header's codec and write it to `w` as a complete image,
+    /// returning the header that was written.
+    ///
+    /// The header is built afresh for the new payload, so only the codec carries over from
+    /// `header`: a different compression level may give a different checksum, and the digest
+    /// algorithm, flags, footer and signature of an image this was read from are not kept.
+    /// `extended` is written after the header unless it is empty.
+    pub fn write<W: Write>(&self, w: &mut W) -> Result<BzImageHeader, BzImageError> {
+        let codec = self.header.codec()?;
+        let compressed = codec.compress(&self.data)?;
+        let mut header = BzImageHeader::new_for_payload(self.data.len() as u64, &compressed);
+        header.set_codec(codec);
+        header.write_with_extended(&self.extended, &compressed, w)?;
+        Ok(header)
+    }
+}
@@ -0,0 +1,163 @@
+//! Progress tracking for copies of the compressed payload, and progress callbacks for
+//! packing and unpacking.
+
+use crate::reader;
+use crate::{BzImageError, BzImageHeader, BzImageReader, Codec};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use std::io::{self, Read, Write};
+
+/// Fewest bytes processed between two calls to a progress callback, other than the last.
+pub const PROGRESS_INTERVAL: u64 = 64 << 10;
+
+/// Calls a progress callback with (bytes processed, total bytes) once at least
+/// `PROGRESS_INTERVAL` more bytes are done, and once more at the end.
+struct Reporter<'a> {
+    callback: &'a mut dyn FnMut(u64, u64),
+    total: u64,
+    done: u64,
+    reported: Option<u64>,
+}
+
+impl<'a> Reporter<'a> {
+    fn new(callback: &'a mut dyn FnMut(u64, u64), total: u64) -> Reporter<'a> {
+        Reporter {
+            callback,
+            total,
+            done: 0,
+            reported: None,
+        }
+    }
+
+    fn advance(&mut self, n: usize) {
+        self.done += n as u64;
+        if self.done - self.reported.unwrap_or(0) >= PROGRESS_INTERVAL {
+            self.report();
+        }
+    }
+
+    /// Report the final count, unless it was the last one reported.
+    fn finish(mut self) {
+        if self.reported != Some(self.done) {
+            self.report();
+        }
+    }
+
+    fn report(&mut self) {
+        self.reported = Some(self.done);
+        (self.callback)(self.done, self.total);
+    }
+}
+
+impl BzImageHeader {
+    /// Like `pack`, calling `progress` with the bytes of `data` compressed so far and
+    /// `data.len()` as it goes.
+    ///
+    /// Calls are at least `PROGRESS_INTERVAL` bytes apart, and the last one always reports
+    /// all of `data`, even when it is empty.
+    pub fn pack_with_progress<W: Write>(
+        data: &[u8],
+        w: &mut W,
+        progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<BzImageHeader, BzImageError> {
+        let mut reporter = Reporter::new(progress, data.len() as u64);
+        let mut enc = GzEncoder::new(Vec::new(), Compression::new(Codec::MAX_LEVEL));
+        for block in data.chunks(PROGRESS_INTERVAL as usize) {
+            enc.write_all(block)?;
+            reporter.advance(block.len());
+        }
+        let compressed = enc.finish()?;
+        let mut header = BzImageHeader::new_for_payload(data.len() as u64, &compressed);
+        header.write_with_payload(&compressed, w)?;
+        reporter.finish();
+        Ok(header)
+    }
+
+    /// Like `unpack`, decoding through `BzImageReader` and calling `progress` with the bytes
+    /// decompressed so far and `uncompressed_size` as it goes.
+    ///
+    /// Calls are spaced as by `pack_with_progress`. The image is verified as by
+    /// `BzImageReader`, so the last call can come before a failed check; its errors are
+    /// returned here.
+    pub fn unpack_with_progress<R: Read>(
+        r: R,
+        progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<Vec<u8>, BzImageError> {
+        let mut reader = BzImageReader::new(r)?;
+        let mut reporter = Reporter::new(progress, reader.header().uncompressed_size());
+        let mut data = Vec::new();
+        let mut buf = vec![0u8; PROGRESS_INTERVAL as usize];
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(reader::into_error(e)),
+            };
+            data.extend_from_slice(&buf[..n]);
+            reporter.advance(n);
+        }
+        reporter.finish();
+        Ok(data)
+    }
+}
+
+/// A reader over the compressed payload that counts what has been read.
+///
+/// Reads stop at the header's `compressed_size`, so wrapping a reader positioned just past the
+/// header yields exactly the payload and then end of input, even if more data (a footer)
+/// follows.
+#[derive(Debug)]
+pub struct PayloadProgress<R> {
+    inner: R,
+    total: u64,
+    read: u64,
+}
+
+impl<R: Read> PayloadProgress<R> {
+    /// Wrap `inner`, positioned at the start of the payload described by `header`.
+    pub fn new(inner: R, header: &BzImageHeader) -> PayloadProgress<R> {
+        PayloadProgress {
+            inner,
+            total: header.compressed_size(),
+            read: 0,
+        }
+    }
+
+    /// Payload bytes read so far.
+    pub fn bytes_read(&self) -> u64 {
+        self.read
+    }
+
+    /// Payload bytes still to be read.
+    pub fn remaining(&self) -> u64 {
+        self.total - self.read
+    }
+
+    /// The fraction of the payload read so far, from 0.0 to 1.0. An empty payload is always
+    /// complete.
+    pub fn progress(&self) -> f64 {
+        if self.total == 0 {
+            1.0
+        } else {
+            self.read as f64 / self.total as f64
+        }
+    }
+
+    /// Return the wrapped reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for PayloadProgress<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = self.remaining().min(buf.len() as u64) as usize;
+        if max == 0 {
+            return Ok(0);
+        }
+        let n = self.inner.read(&mut buf[..max])?;
+        self.read += n as u64;
+        Ok(n)
+    }
+}
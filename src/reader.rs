@@ -0,0 +1,197 @@
+//! Streaming decompression of an image through `Read`.
+
+use crate::chunked::ChunkDecoder;
+use crate::digest::{DigestReader, Digester, checksums_match};
+use crate::{BzImageError, BzImageFlags, BzImageHeader, Codec, DigestAlgo};
+use flate2::read::GzDecoder;
+use std::io::{self, Read, Take};
+
+type Payload<R> = DigestReader<Take<R>>;
+
+enum Stage<R: Read> {
+    Gzip(GzDecoder<Payload<R>>),
+    Stored(Payload<R>),
+    Framed(ChunkDecoder<Payload<R>>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::read::Decoder<'static, io::BufReader<Payload<R>>>),
+}
+
+impl<R: Read> Stage<R> {
+    fn payload(&mut self) -> &mut Payload<R> {
+        match self {
+            Stage::Gzip(dec) => dec.get_mut(),
+            Stage::Stored(payload) => payload,
+            Stage::Framed(dec) => dec.get_mut(),
+            #[cfg(feature = "zstd")]
+            Stage::Zstd(dec) => dec.get_mut().get_mut(),
+        }
+    }
+
+    fn into_payload(self) -> Payload<R> {
+        match self {
+            Stage::Gzip(dec) => dec.into_inner(),
+            Stage::Stored(payload) => payload,
+            Stage::Framed(dec) => dec.into_inner(),
+            #[cfg(feature = "zstd")]
+            Stage::Zstd(dec) => dec.finish().into_inner(),
+        }
+    }
+}
+
+impl<R: Read> Read for Stage<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stage::Gzip(dec) => dec.read(buf),
+            Stage::Stored(payload) => payload.read(buf),
+            Stage::Framed(dec) => dec.read(buf),
+            #[cfg(feature = "zstd")]
+            Stage::Zstd(dec) => dec.read(buf),
+        }
+    }
+}
+
+/// Reads an image and yields its decompressed payload on demand.
+///
+/// The constructor reads the header and checks that this build can handle it; after that,
+/// `read` decodes from a region bounded to exactly `compressed_size` bytes, so nothing after
+/// the payload (a footer, the next image on a stream) is consumed from `R`. Memory use does not
+/// depend on the payload size.
+///
+/// The compressed bytes are hashed as they are decoded. When the decoder reaches the end, the
+/// payload is checked for truncation, against the stored checksum and against
+/// `uncompressed_size`, and the decoded bytes against the CRC-32 of the uncompressed data if
+/// the header records one (`UNCOMPRESSED_CRC32`); a failure is returned from that last `read` as
+/// an `io::ErrorKind::InvalidData` error wrapping the `BzImageError`, and every later `read`
+/// fails with the same message rather than reporting a clean end. Bytes returned before then
+/// are unverified.
+pub struct BzImageReader<R: Read> {
+    header: BzImageHeader,
+    stage: Stage<R>,
+    produced: u64,
+    /// Running CRC-32 of the decoded bytes, kept only when the header records one.
+    crc: Option<crc32fast::Hasher>,
+    done: bool,
+    /// Why the end-of-payload checks failed, to report again on later reads.
+    failed: Option<String>,
+}
+
+impl<R: Read> BzImageReader<R> {
+    /// Read and check the header from `r`, which must be positioned at the start of an image.
+    pub fn new(r: R) -> Result<BzImageReader<R>, BzImageError> {
+        let (header, payload) = BzImageHeader::split_reader(r)?;
+        header.can_read()?;
+        if header.flags().contains(BzImageFlags::DETACHED_PAYLOAD) {
+            return Err(BzImageError::DetachedPayload);
+        }
+        if header.flags().contains(BzImageFlags::ENCRYPTED) {
+            return Err(BzImageError::Encrypted);
+        }
+        if let Some(id) = header.dictionary_id() {
+            return Err(BzImageError::DictionaryRequired(id));
+        }
+        let payload = DigestReader {
+            inner: payload,
+            digester: Digester::new(header.digest_algo()?),
+        };
+        let stage = match header.codec()? {
+            _ if header.flags().contains(BzImageFlags::STREAMING_FRAMED) => {
+                Stage::Framed(ChunkDecoder::new(payload, &header)?)
+            }
+            Codec::Gzip => Stage::Gzip(GzDecoder::new(payload)),
+            Codec::Stored => Stage::Stored(payload),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Stage::Zstd(
+                zstd::stream::read::Decoder::new(payload).map_err(BzImageError::Decompression)?,
+            ),
+            #[cfg(not(feature = "zstd"))]
+            codec @ Codec::Zstd => return Err(BzImageError::CodecNotEnabled(codec)),
+        };
+        Ok(BzImageReader {
+            header,
+            stage,
+            produced: 0,
+            crc: header.uncompressed_crc().map(|_| crc32fast::Hasher::new()),
+            done: false,
+            failed: None,
+        })
+    }
+
+    /// The image's header, e.g. to look at `uncompressed_size` before streaming.
+    pub fn header(&self) -> &BzImageHeader {
+        &self.header
+    }
+
+    /// Return the underlying reader. Once the payload has been read to the end it is positioned
+    /// just past the payload.
+    pub fn into_inner(self) -> R {
+        self.stage.into_payload().inner.into_inner()
+    }
+
+    /// Check the payload once the decoder has finished with it.
+    fn finish(&mut self) -> Result<(), BzImageError> {
+        let declared = self.header.compressed_size();
+        let payload = self.stage.payload();
+        // hash whatever the decoder left unread
+        io::copy(payload, &mut io::sink())?;
+        let unread = payload.inner.limit();
+        if unread > 0 {
+            return Err(BzImageError::TruncatedPayload {
+                declared,
+                available: Some(declared - unread),
+            });
+        }
+        let digester = std::mem::replace(&mut payload.digester, Digester::new(DigestAlgo::Sha256));
+        let expected = self.header.checksum_copy();
+        let actual = digester.finalize();
+        if !checksums_match(&actual, &expected) {
+            return Err(BzImageError::ChecksumMismatch { expected, actual });
+        }
+        if self.produced != self.header.uncompressed_size() {
+            return Err(BzImageError::UncompressedSizeMismatch {
+                declared: self.header.uncompressed_size(),
+                actual: self.produced,
+            });
+        }
+        if let (Some(expected), Some(crc)) = (self.header.uncompressed_crc(), self.crc.take()) {
+            let actual = crc.finalize();
+            if actual != expected {
+                return Err(BzImageError::UncompressedCrcMismatch { expected, actual });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for BzImageReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(failed) = &self.failed {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, failed.clone()));
+        }
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        let n = self.stage.read(buf)?;
+        if n == 0 {
+            self.done = true;
+            if let Err(e) = self.finish() {
+                self.failed = Some(e.to_string());
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+        }
+        if let Some(crc) = &mut self.crc {
+            crc.update(&buf[..n]);
+        }
+        self.produced += n as u64;
+        Ok(n)
+    }
+}
+
+/// Turn an error from `BzImageReader::read` back into a `BzImageError`: the failed check it
+/// wraps, or otherwise the decoder's own error as `BzImageError::Decompression`.
+pub(crate) fn into_error(e: io::Error) -> BzImageError {
+    if e.get_ref().is_some_and(|inner| inner.is::<BzImageError>()) {
+        // just checked
+        return *e.into_inner().unwrap().downcast::<BzImageError>().unwrap();
+    }
+    BzImageError::Decompression(e)
+}
@@ -0,0 +1,160 @@
+//! Allocation of the header's reserved fields.
+//!
+//! Extensions must claim space here rather than using `reserved1`/`reserved2` ad hoc, so that
+//! they cannot step on each other. Every allocation is zero in images written before it
+//! existed, and zero always means "the format's original behavior".
+//!
+//! `reserved1` (u32, little-endian on disk):
+//!
+//! | bits   | field        | accessor                       |
+//! |--------|--------------|--------------------------------|
+//! | 0..8   | codec        | `codec()` / `set_codec()`      |
+//! | 8..16  | digest algo  | `digest_algo()` / `set_digest_algo()` |
+//! | 16..32 | flags        | `flags()` / `set_flags()`      |
+//!
+//! `reserved2` (u32) is a single extension-defined value. It must be zero unless a flag
+//! announces what it holds, and at most one flag may claim it. Current claims:
+//!
+//! | flag                 | `reserved2` holds                     |
+//! |----------------------|---------------------------------------|
+//! | `UNCOMPRESSED_CRC32` | CRC-32 (IEEE) of the uncompressed data |
+//! | `HEADER_CRC32`       | CRC-32 (IEEE) of header bytes 0..60, everything before `reserved2` |
+//! | `ZSTD_DICTIONARY`    | id of the zstd dictionary the payload was compressed against |
+
+/// A bit field within `reserved1`.
+pub(crate) struct Field {
+    shift: u32,
+    mask: u32,
+}
+
+impl Field {
+    pub(crate) fn get(&self, reserved1: u32) -> u32 {
+        (reserved1 >> self.shift) & self.mask
+    }
+
+    pub(crate) fn set(&self, reserved1: u32, value: u32) -> u32 {
+        (reserved1 & !(self.mask << self.shift)) | ((value & self.mask) << self.shift)
+    }
+}
+
+pub(crate) const CODEC: Field = Field {
+    shift: 0,
+    mask: 0xff,
+};
+pub(crate) const DIGEST_ALGO: Field = Field {
+    shift: 8,
+    mask: 0xff,
+};
+pub(crate) const FLAGS: Field = Field {
+    shift: 16,
+    mask: 0xffff,
+};
+
+/// Feature flags stored in bits 16..32 of `reserved1`.
+///
+/// The low byte holds optional flags: hints a reader may ignore and still read the image
+/// correctly. The high byte holds critical flags: a reader that does not understand one of
+/// them must refuse the image, and `BzImageHeader::from_bytes` (and so `read_from`) does.
+///
+/// | bits   | kind     | assigned                                                        |
+/// |--------|----------|-----------------------------------------------------------------|
+/// | 0..6   | optional | `HAS_FOOTER`, `UNCOMPRESSED_CRC32`, `TRAILING_HEADER`, `HAS_SIGNATURE`, `SIGNED`, `HEADER_CRC32` |
+/// | 6..8   | optional | reserved for future use                                         |
+/// | 8..13  | critical | `DETACHED_PAYLOAD`, `ENCRYPTED`, `HAS_EXTENDED_HEADER`, `STREAMING_FRAMED`, `ZSTD_DICTIONARY` |
+/// | 13..16 | critical | reserved for future use                                         |
+///
+/// The flags live here rather than in `reserved2` because the allocation above already gave
+/// them these bits, and `reserved2` is the one extension-defined value that several flags
+/// claim. They are a plain newtype rather than a `bitflags` type, so the crate takes no
+/// dependency for a handful of set operations.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct BzImageFlags(u16);
+
+impl BzImageFlags {
+    /// A metadata footer follows the payload (see `Footer`).
+    pub const HAS_FOOTER: BzImageFlags = BzImageFlags(1 << 0);
+    /// `reserved2` holds a CRC-32 of the uncompressed data.
+    pub const UNCOMPRESSED_CRC32: BzImageFlags = BzImageFlags(1 << 1);
+    /// The header follows the payload (the trailing-header layout, see `TrailingWriter`).
+    pub const TRAILING_HEADER: BzImageFlags = BzImageFlags(1 << 2);
+    /// A signature trailer ends the image (see `append_signature`).
+    pub const HAS_SIGNATURE: BzImageFlags = BzImageFlags(1 << 3);
+    /// The signature trailer holds an HMAC-SHA256 tag over the header and payload (see
+    /// `BzImageHeader::sign`).
+    pub const SIGNED: BzImageFlags = BzImageFlags(1 << 4);
+    /// `reserved2` holds a CRC-32 of the rest of the header (see `set_header_crc`).
+    pub const HEADER_CRC32: BzImageFlags = BzImageFlags(1 << 5);
+    /// The payload is stored in a separate file named in the footer (see `write_detached`).
+    /// Critical: a reader that ignored it would take the footer for the payload.
+    pub const DETACHED_PAYLOAD: BzImageFlags = BzImageFlags(1 << 8);
+    /// The payload is encrypted (see `pack_encrypted`). Critical, since the payload would not
+    /// decompress.
+    pub const ENCRYPTED: BzImageFlags = BzImageFlags(1 << 9);
+    /// An extended header region sits between the header and the payload (see
+    /// `ExtendedHeader`). Critical, since a reader that ignored it would take the region for
+    /// payload.
+    pub const HAS_EXTENDED_HEADER: BzImageFlags = BzImageFlags(1 << 10);
+    /// The payload is split into individually checksummed chunks (see `FramedWriter`).
+    /// Critical, since the payload is not a single codec stream.
+    pub const STREAMING_FRAMED: BzImageFlags = BzImageFlags(1 << 11);
+    /// The payload was compressed against a zstd dictionary whose id is in `reserved2` (see
+    /// `pack_with_dict`). Critical, since it cannot be decompressed without the dictionary.
+    pub const ZSTD_DICTIONARY: BzImageFlags = BzImageFlags(1 << 12);
+
+    /// The bits that hold critical flags.
+    pub const CRITICAL_MASK: u16 = 0xff00;
+
+    /// Every flag this build understands. A flag that is named above but missing here has its
+    /// bit reserved, and images that set it are refused if it is critical.
+    pub const KNOWN: BzImageFlags = BzImageFlags(
+        BzImageFlags::HAS_FOOTER.0
+            | BzImageFlags::UNCOMPRESSED_CRC32.0
+            | BzImageFlags::TRAILING_HEADER.0
+            | BzImageFlags::HAS_SIGNATURE.0
+            | BzImageFlags::SIGNED.0
+            | BzImageFlags::HEADER_CRC32.0
+            | BzImageFlags::DETACHED_PAYLOAD.0
+            | BzImageFlags::ENCRYPTED.0
+            | BzImageFlags::HAS_EXTENDED_HEADER.0
+            | BzImageFlags::STREAMING_FRAMED.0
+            | BzImageFlags::ZSTD_DICTIONARY.0,
+    );
+
+    pub const fn empty() -> BzImageFlags {
+        BzImageFlags(0)
+    }
+
+    /// Wrap raw bits, keeping bits that have no assigned meaning.
+    pub const fn from_bits_retain(bits: u16) -> BzImageFlags {
+        BzImageFlags(bits)
+    }
+
+    pub const fn bits(self) -> u16 {
+        self.0
+    }
+
+    /// The critical bits set in `self` that this build does not understand.
+    pub const fn unknown_critical(self) -> BzImageFlags {
+        BzImageFlags(self.0 & BzImageFlags::CRITICAL_MASK & !BzImageFlags::KNOWN.0)
+    }
+
+    pub const fn contains(self, other: BzImageFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: BzImageFlags) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: BzImageFlags) {
+        self.0 &= !other.0;
+    }
+}
+
+impl core::ops::BitOr for BzImageFlags {
+    type Output = BzImageFlags;
+
+    fn bitor(self, rhs: BzImageFlags) -> BzImageFlags {
+        BzImageFlags(self.0 | rhs.0)
+    }
+}
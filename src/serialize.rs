@@ -0,0 +1,77 @@
+//! Serde support for `BzImageHeader`, behind the `serde` feature.
+//!
+//! Headers are represented with the magic as an ASCII string, native integers for the
+//! numeric fields and the checksum as lowercase hex:
+//!
+//! ```json
+//! {"magic":"DMNZ","version":1,"reserved1":0,"uncompressed_size":4096,
+//!  "compressed_size":812,"checksum":"9f86…","reserved2":0}
+//! ```
+//!
+//! This is for manifests and logs only; the on-disk format is unaffected. Deserializing
+//! applies the checks of `BzImageHeader::from_bytes`, so a header that round-trips through
+//! serde serializes to the same bytes it started from.
+
+use crate::digest::{from_hex, to_hex};
+use crate::{
+    BzImageError, BzImageHeader, HEADER_SIZE, MAGIC, MAGIC_BE, is_magic, swap_integer_fields,
+};
+use alloc::string::{String, ToString};
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct HeaderRepr {
+    magic: String,
+    version: u32,
+    reserved1: u32,
+    uncompressed_size: u64,
+    compressed_size: u64,
+    checksum: String,
+    reserved2: u32,
+}
+
+impl Serialize for BzImageHeader {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        HeaderRepr {
+            magic: self.magic_copy().escape_ascii().to_string(),
+            version: self.version(),
+            reserved1: self.reserved1(),
+            uncompressed_size: self.uncompressed_size(),
+            compressed_size: self.compressed_size(),
+            checksum: to_hex(&self.checksum_copy()),
+            reserved2: self.reserved2(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for BzImageHeader {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<BzImageHeader, D::Error> {
+        let repr = HeaderRepr::deserialize(deserializer)?;
+        if !is_magic(repr.magic.as_bytes()) {
+            let mut found = [0u8; 4];
+            let magic = repr.magic.as_bytes();
+            let n = magic.len().min(found.len());
+            found[..n].copy_from_slice(&magic[..n]);
+            return Err(de::Error::custom(BzImageError::InvalidMagic { found }));
+        }
+        let checksum = from_hex(&repr.checksum)
+            .ok_or_else(|| de::Error::custom("checksum is not 64 hexadecimal digits"))?;
+
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[..4].copy_from_slice(MAGIC);
+        bytes[4..8].copy_from_slice(&repr.version.to_le_bytes());
+        bytes[8..12].copy_from_slice(&repr.reserved1.to_le_bytes());
+        bytes[12..20].copy_from_slice(&repr.uncompressed_size.to_le_bytes());
+        bytes[20..28].copy_from_slice(&repr.compressed_size.to_le_bytes());
+        bytes[28..60].copy_from_slice(&checksum);
+        bytes[60..64].copy_from_slice(&repr.reserved2.to_le_bytes());
+        // a header CRC covers the on-disk bytes, so check a big-endian one in that order
+        if repr.magic.as_bytes() == MAGIC_BE {
+            bytes[..4].copy_from_slice(MAGIC_BE);
+            swap_integer_fields(&mut bytes);
+        }
+        BzImageHeader::from_bytes(&bytes).map_err(de::Error::custom)
+    }
+}
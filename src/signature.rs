@@ -0,0 +1,152 @@
+//! Optional signature trailer at the very end of an image.
+//!
+//! A signature is opaque bytes appended as the very last thing in an image, after the payload
+//! and any footer, and announced by `BzImageFlags::HAS_SIGNATURE`. It is laid out so it can be
+//! found from the end of the file by its marker (integers little-endian):
+//!
+//! - signature: `len` bytes
+//! - len: u32
+//! - magic: 4 bytes — the ASCII magic `DMNS`
+//!
+//! Readers that do not check signatures use this only to know where the image's own data
+//! ends, so signed and unsigned images read the same way.
+
+use crate::limit::copy_to_eof;
+use crate::{BzImageError, BzImageFlags, BzImageHeader, DEFAULT_STREAM_LIMIT, rewrite_header};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Four-byte ASCII magic that ends a signature trailer: `DMNS`.
+pub const SIGNATURE_MAGIC: &[u8; 4] = b"DMNS";
+
+/// Bytes a signature trailer adds beyond the signature itself (its length and magic).
+pub const SIGNATURE_TRAILER_OVERHEAD: u64 = 8;
+
+/// Locate the signature trailer at the end of `r`, returning `(start, signature_len)`, or
+/// `None` if `r` does not end with a trailer.
+///
+/// A trailer whose length runs past the start of `r` is `BzImageError::MissingSignature`.
+pub(crate) fn find_trailer<R: Read + Seek>(r: &mut R) -> Result<Option<(u64, u64)>, BzImageError> {
+    let len = r.seek(SeekFrom::End(0))?;
+    if len < SIGNATURE_TRAILER_OVERHEAD {
+        return Ok(None);
+    }
+    r.seek(SeekFrom::Start(len - SIGNATURE_TRAILER_OVERHEAD))?;
+    let mut tail = [0u8; 8];
+    r.read_exact(&mut tail)?;
+    if &tail[4..] != SIGNATURE_MAGIC {
+        return Ok(None);
+    }
+    let sig_len = u64::from(u32::from_le_bytes(tail[..4].try_into().unwrap()));
+    let start = (len - SIGNATURE_TRAILER_OVERHEAD)
+        .checked_sub(sig_len)
+        .ok_or(BzImageError::MissingSignature)?;
+    Ok(Some((start, sig_len)))
+}
+
+/// The offset where the image data described by `header` ends in `r`: the end of input, less
+/// the signature trailer if the header announces one.
+pub(crate) fn data_end<R: Read + Seek>(
+    header: &BzImageHeader,
+    r: &mut R,
+) -> Result<u64, BzImageError> {
+    if !header.has_signature() {
+        return Ok(r.seek(SeekFrom::End(0))?);
+    }
+    find_trailer(r)?
+        .map(|(start, _)| start)
+        .ok_or(BzImageError::MissingSignature)
+}
+
+/// Counts what is written to it and keeps the last `SIGNATURE_TRAILER_OVERHEAD` bytes.
+struct TailWriter {
+    len: u64,
+    tail: [u8; 8],
+}
+
+impl Write for TailWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let keep = buf.len().min(self.tail.len());
+        self.tail.rotate_left(keep);
+        let at = self.tail.len() - keep;
+        self.tail[at..].copy_from_slice(&buf[buf.len() - keep..]);
+        self.len += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Like `data_end`, for a signed image in a reader that cannot seek: `consumed` is what has
+/// already been read past the header and `rest` yields everything after it. Returns the end
+/// of the image data as an offset from the end of the header.
+///
+/// `rest` is read to end of input (at most `DEFAULT_STREAM_LIMIT` bytes) and discarded, since
+/// the trailer can only be found from the end.
+pub(crate) fn data_end_forward<R: Read>(consumed: &[u8], rest: R) -> Result<u64, BzImageError> {
+    let mut w = TailWriter {
+        len: 0,
+        tail: [0u8; 8],
+    };
+    w.write_all(consumed)?;
+    copy_to_eof(rest, &mut w, DEFAULT_STREAM_LIMIT)?;
+    if w.len < SIGNATURE_TRAILER_OVERHEAD || &w.tail[4..] != SIGNATURE_MAGIC {
+        return Err(BzImageError::MissingSignature);
+    }
+    let sig_len = u64::from(u32::from_le_bytes(w.tail[..4].try_into().unwrap()));
+    (w.len - SIGNATURE_TRAILER_OVERHEAD)
+        .checked_sub(sig_len)
+        .ok_or(BzImageError::MissingSignature)
+}
+
+/// Read the signature of the image at the start of `r`, if it has one.
+pub fn read_signature<R: Read + Seek>(mut r: R) -> Result<Option<Vec<u8>>, BzImageError> {
+    r.seek(SeekFrom::Start(0))?;
+    let header = BzImageHeader::read_from(&mut r)?;
+    if !header.has_signature() {
+        return Ok(None);
+    }
+    let (start, sig_len) = find_trailer(&mut r)?.ok_or(BzImageError::MissingSignature)?;
+    r.seek(SeekFrom::Start(start))?;
+    let mut signature = vec![0u8; sig_len as usize];
+    r.read_exact(&mut signature)?;
+    Ok(Some(signature))
+}
+
+/// Write `signature` followed by its length and magic to `w`.
+pub(crate) fn write_trailer<W: Write>(mut w: W, signature: &[u8]) -> Result<(), BzImageError> {
+    let sig_len = u32::try_from(signature.len())
+        .map_err(|_| BzImageError::SignatureTooLong(signature.len()))?;
+    w.write_all(signature)?;
+    w.write_all(&sig_len.to_le_bytes())?;
+    w.write_all(SIGNATURE_MAGIC)?;
+    Ok(())
+}
+
+/// Write `signature` as the signature trailer of the image at the start of `rw`, replacing any
+/// existing one, and set `HAS_SIGNATURE`.
+///
+/// Returns the new length of the image; as with `append_footer`, the caller must truncate the
+/// underlying storage to it if an old, longer signature was replaced.
+pub fn append_signature<RW: Read + Write + Seek>(
+    mut rw: RW,
+    signature: &[u8],
+) -> Result<u64, BzImageError> {
+    rw.seek(SeekFrom::Start(0))?;
+    let mut header = BzImageHeader::read_from(&mut rw)?;
+    let end = data_end(&header, &mut rw)?;
+
+    rw.seek(SeekFrom::Start(end))?;
+    write_trailer(&mut rw, signature)?;
+    let new_end = rw.stream_position()?;
+
+    if !header.has_signature() {
+        let mut flags = header.flags();
+        flags.insert(BzImageFlags::HAS_SIGNATURE);
+        header.set_flags(flags);
+        rewrite_header(&mut rw, &header)?;
+    }
+    rw.flush()?;
+    Ok(new_end)
+}
@@ -0,0 +1,48 @@
+//! Writing an image while passing the uncompressed bytes on to a second consumer.
+
+use crate::{BzImageError, BzImageHeader, BzImageWriter, Codec};
+use std::io::{self, Seek, Write};
+
+/// A sink that compresses everything written to it into an image and copies the same
+/// uncompressed bytes to a second writer.
+///
+/// The image is produced by a [`BzImageWriter`], so the image writer must be seekable; the raw
+/// writer need not be. Each write reaches the raw writer only after the encoder has accepted
+/// it, so both outputs always see the same bytes.
+pub struct TeeWriter<W: Write + Seek, T: Write> {
+    image: BzImageWriter<W>,
+    raw: T,
+}
+
+impl<W: Write + Seek, T: Write> TeeWriter<W, T> {
+    /// Start an image at the current position of `image`, compressing with `codec` and
+    /// forwarding uncompressed bytes to `raw`.
+    pub fn new(image: W, raw: T, codec: Codec) -> Result<TeeWriter<W, T>, BzImageError> {
+        Ok(TeeWriter {
+            image: BzImageWriter::new(image, codec)?,
+            raw,
+        })
+    }
+
+    /// Finish the payload, fill in the header, and return both writers and the header.
+    ///
+    /// The image writer is left positioned at the end of the payload.
+    pub fn finish(mut self) -> Result<(W, T, BzImageHeader), BzImageError> {
+        self.raw.flush()?;
+        let (image, header) = self.image.finish()?;
+        Ok((image, self.raw, header))
+    }
+}
+
+impl<W: Write + Seek, T: Write> Write for TeeWriter<W, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.image.write(buf)?;
+        self.raw.write_all(&buf[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.image.flush()?;
+        self.raw.flush()
+    }
+}
@@ -0,0 +1,135 @@
+//! The trailing-header layout, for writing images to non-seekable outputs such as pipes.
+//!
+//! A normal image starts with its header, but the header holds the payload sizes and
+//! checksum, which are only known once compression has finished. A writer that cannot seek
+//! back therefore uses this layout instead:
+//!
+//! - prefix: the ASCII magic `DMNT` followed by the format version (u32, little-endian)
+//! - the compressed payload
+//! - the full 64-byte header, with `BzImageFlags::TRAILING_HEADER` set
+//!
+//! Writing needs only `Write`; reading needs `Seek` to fetch the header from the end.
+
+use crate::encoder::Encoder;
+use crate::{BzImageError, BzImageFlags, BzImageHeader, Codec, HEADER_SIZE, MAGIC, VERSION};
+use simple_endian::{read_specific, u32le};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Four-byte ASCII magic that starts an image in the trailing-header layout: `DMNT`.
+pub const TRAILING_MAGIC: &[u8; 4] = b"DMNT";
+
+/// Length of the prefix that precedes the payload in the trailing-header layout.
+pub const TRAILING_PREFIX_SIZE: usize = 8;
+
+/// Streams an image in the trailing-header layout to a writer that need not be seekable.
+///
+/// Data written to it is compressed and forwarded immediately; `finish` appends the header.
+pub struct TrailingWriter<W: Write> {
+    encoder: Encoder<W>,
+    codec: Codec,
+}
+
+impl<W: Write> TrailingWriter<W> {
+    /// Write the layout prefix to `w` and prepare to compress with `codec` at its strongest
+    /// level.
+    pub fn new(w: W, codec: Codec) -> Result<TrailingWriter<W>, BzImageError> {
+        Self::with_level(w, codec, Codec::MAX_LEVEL)
+    }
+
+    /// Like `new`, compressing at `level`, from 0 (fastest) to `Codec::MAX_LEVEL` (smallest).
+    pub fn with_level(
+        mut w: W,
+        codec: Codec,
+        level: u32,
+    ) -> Result<TrailingWriter<W>, BzImageError> {
+        w.write_all(TRAILING_MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        Ok(TrailingWriter {
+            encoder: Encoder::new(w, codec, level)?,
+            codec,
+        })
+    }
+
+    /// Finish the payload, append the header, and return the writer and the header.
+    pub fn finish(self) -> Result<(W, BzImageHeader), BzImageError> {
+        let (mut w, encoded) = self.encoder.finish()?;
+        let mut header = BzImageHeader {
+            magic: *MAGIC,
+            version: VERSION.into(),
+            reserved1: 0u32.into(),
+            uncompressed_size: encoded.uncompressed_len.into(),
+            compressed_size: encoded.compressed_len.into(),
+            checksum: encoded.checksum,
+            reserved2: 0u32.into(),
+        };
+        header.set_codec(self.codec);
+        header.set_flags(BzImageFlags::TRAILING_HEADER);
+        header.write_to(&mut w)?;
+        w.flush()?;
+        Ok((w, header))
+    }
+}
+
+impl<W: Write> Write for TrailingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+/// Read an image in the trailing-header layout, returning the header and compressed payload.
+///
+/// A missing prefix, a prefix whose version differs from the header's, or a misplaced or
+/// misflagged header is `BzImageError::InvalidTrailingLayout`; a payload of the wrong length is
+/// `SizeMismatch`.
+pub fn read_trailing_image<R: Read + Seek>(
+    mut r: R,
+) -> Result<(BzImageHeader, Vec<u8>), BzImageError> {
+    r.seek(SeekFrom::Start(0))?;
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != TRAILING_MAGIC {
+        return Err(BzImageError::InvalidTrailingLayout("bad magic"));
+    }
+    let version: u32le = read_specific(&mut r)?;
+
+    let len = r.seek(SeekFrom::End(0))?;
+    let framing = (TRAILING_PREFIX_SIZE + HEADER_SIZE) as u64;
+    if len < framing {
+        return Err(BzImageError::InvalidTrailingLayout(
+            "too short to hold a header",
+        ));
+    }
+    r.seek(SeekFrom::Start(len - HEADER_SIZE as u64))?;
+    let header = BzImageHeader::read_from(&mut r)?;
+    if !header.flags().contains(BzImageFlags::TRAILING_HEADER) {
+        return Err(BzImageError::InvalidTrailingLayout(
+            "header is not flagged TRAILING_HEADER",
+        ));
+    }
+    if header.flags().contains(BzImageFlags::HAS_EXTENDED_HEADER) {
+        return Err(BzImageError::InvalidTrailingLayout(
+            "no room for an extended header",
+        ));
+    }
+    if u32::from(version) != header.version() {
+        return Err(BzImageError::InvalidTrailingLayout(
+            "prefix and header versions differ",
+        ));
+    }
+
+    let declared = header.compressed_size();
+    if declared != len - framing {
+        return Err(BzImageError::SizeMismatch {
+            declared,
+            actual: len - framing,
+        });
+    }
+    r.seek(SeekFrom::Start(TRAILING_PREFIX_SIZE as u64))?;
+    let mut compressed = vec![0u8; declared as usize];
+    r.read_exact(&mut compressed)?;
+    Ok((header, compressed))
+}
@@ -0,0 +1,247 @@
+//! Whole-file verification of images on disk, and checksum scrubbing for periodic scans.
+
+use crate::digest::{DigestReader, Digester, checksums_match, to_hex};
+use crate::{BzImageError, BzImageFlags, BzImageHeader, HEADER_SIZE, HeaderView, MAGIC, is_magic};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+/// The outcome of verifying one image file.
+#[derive(Debug)]
+pub struct VerifyReport {
+    /// The image's header, if it could be read.
+    pub header: Option<BzImageHeader>,
+    /// Why verification failed; `None` means the image is intact.
+    pub error: Option<BzImageError>,
+}
+
+impl VerifyReport {
+    /// Whether the image passed every check.
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Verify the image at `path`: the header must be readable by this build, the payload must be
+/// complete and match the checksum, and it must decompress to `uncompressed_size` bytes.
+///
+/// The payload is streamed from disk and the decompressed data is discarded, so memory use does
+/// not depend on the image size. Failures are reported in the returned [`VerifyReport`] rather
+/// than as an `Err`.
+pub fn verify_file(path: &Path) -> VerifyReport {
+    let mut header = None;
+    let error = verify_into(path, &mut header).err();
+    VerifyReport { header, error }
+}
+
+fn verify_into(path: &Path, out: &mut Option<BzImageHeader>) -> Result<(), BzImageError> {
+    let file = File::open(path)?;
+    let (header, payload) = BzImageHeader::split_reader(BufReader::new(file))?;
+    *out = Some(header);
+    header.can_read()?;
+    if header.flags().contains(BzImageFlags::DETACHED_PAYLOAD) {
+        return Err(BzImageError::DetachedPayload);
+    }
+
+    let declared = header.compressed_size();
+    let mut hashed = DigestReader {
+        inner: payload,
+        digester: Digester::new(header.digest_algo()?),
+    };
+    let decoded = io::copy(&mut header.payload_decoder(&mut hashed)?, &mut io::sink())
+        .map_err(BzImageError::Decompression)?;
+    // hash whatever the decoder left unread
+    io::copy(&mut hashed, &mut io::sink())?;
+
+    let unread = hashed.inner.limit();
+    if unread > 0 {
+        return Err(BzImageError::TruncatedPayload {
+            declared,
+            available: Some(declared - unread),
+        });
+    }
+    let expected = header.checksum_copy();
+    let actual = hashed.digester.finalize();
+    if !checksums_match(&actual, &expected) {
+        return Err(BzImageError::ChecksumMismatch { expected, actual });
+    }
+    if decoded != header.uncompressed_size() {
+        return Err(BzImageError::UncompressedSizeMismatch {
+            declared: header.uncompressed_size(),
+            actual: decoded,
+        });
+    }
+    Ok(())
+}
+
+/// The outcome of `BzImageHeader::verify`: each integrity check answered separately.
+///
+/// Checks after a failed one are not attempted and read `false`: a bad magic leaves the
+/// rest unchecked, and an unsupported version leaves the payload unhashed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct IntegrityReport {
+    pub magic_ok: bool,
+    pub version_supported: bool,
+    /// Whether all `compressed_size` payload bytes were present.
+    pub payload_complete: bool,
+    pub checksum_ok: bool,
+    /// The header's `uncompressed_size`, or zero if the magic was wrong.
+    pub uncompressed_size: u64,
+    /// The header's `compressed_size`, or zero if the magic was wrong.
+    pub compressed_size: u64,
+}
+
+impl IntegrityReport {
+    /// Whether the image passed every check.
+    pub fn is_ok(&self) -> bool {
+        self.magic_ok && self.version_supported && self.payload_complete && self.checksum_ok
+    }
+}
+
+impl BzImageHeader {
+    /// Read an image from `r` and check that it is intact, without decompressing it.
+    ///
+    /// The payload is streamed through the header's digest, so memory use is constant. A bad
+    /// magic, unsupported version, short payload or wrong checksum is reported in the returned
+    /// [`IntegrityReport`]; errors are left for input that cannot be judged at all: a header
+    /// cut short, an I/O failure, unknown critical flags or digest algorithm, or a detached
+    /// payload. The decompressed length is not checked; `verify_file` does that too.
+    pub fn verify<R: Read>(mut r: R) -> Result<IntegrityReport, BzImageError> {
+        let mut bytes = [0u8; HEADER_SIZE];
+        r.read_exact(&mut bytes).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => BzImageError::TruncatedHeader,
+            _ => BzImageError::Io(e),
+        })?;
+        let mut report = IntegrityReport {
+            magic_ok: is_magic(&bytes[..MAGIC.len()]),
+            version_supported: false,
+            payload_complete: false,
+            checksum_ok: false,
+            uncompressed_size: 0,
+            compressed_size: 0,
+        };
+        if !report.magic_ok {
+            return Ok(report);
+        }
+        // the view decodes the fields in whichever byte order the magic names
+        let view = HeaderView::new(&bytes)?;
+        report.uncompressed_size = view.uncompressed_size();
+        report.compressed_size = view.compressed_size();
+        report.version_supported = BzImageHeader::is_version_supported(view.version());
+        if !report.version_supported {
+            return Ok(report);
+        }
+
+        let header = BzImageHeader::from_bytes(&bytes)?;
+        if header.flags().contains(BzImageFlags::DETACHED_PAYLOAD) {
+            return Err(BzImageError::DetachedPayload);
+        }
+        header.skip_extended(&mut r)?;
+        let mut digester = Digester::new(header.digest_algo()?);
+        let read = io::copy(&mut r.take(header.compressed_size()), &mut digester)?;
+        report.payload_complete = read == header.compressed_size();
+        report.checksum_ok = report.payload_complete
+            && checksums_match(&digester.finalize(), &header.checksum_copy());
+        Ok(report)
+    }
+}
+
+/// The outcome of [`scrub`]: the stored checksum next to one recomputed from the payload.
+#[derive(Copy, Clone, Debug)]
+pub struct ScrubResult {
+    /// The image's header.
+    pub header: BzImageHeader,
+    /// The checksum recorded in the header.
+    pub stored: [u8; 32],
+    /// The checksum of the payload bytes as read now.
+    pub computed: [u8; 32],
+}
+
+impl ScrubResult {
+    /// Whether the payload still hashes to the stored checksum.
+    pub fn is_clean(&self) -> bool {
+        checksums_match(&self.stored, &self.computed)
+    }
+
+    /// The stored and recomputed checksums, if they differ.
+    pub fn drift(&self) -> Option<([u8; 32], [u8; 32])> {
+        (!self.is_clean()).then_some((self.stored, self.computed))
+    }
+}
+
+impl fmt::Display for ScrubResult {
+    /// One line suitable for a log: the checksum if clean, both checksums if not.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_clean() {
+            write!(f, "checksum ok: {}", to_hex(&self.stored))
+        } else {
+            write!(
+                f,
+                "checksum drift: stored {}, computed {}",
+                to_hex(&self.stored),
+                to_hex(&self.computed)
+            )
+        }
+    }
+}
+
+/// Recompute the payload checksum of the image read from `r` and compare it with the stored
+/// one, for scrubbing jobs that look for bit rot.
+///
+/// A mismatch is not an error: it is reported in the returned [`ScrubResult`] with both
+/// digests, so a scan can log or alert on it and move on. Errors are reserved for images that
+/// cannot be scrubbed at all: an unreadable header, an unknown digest algorithm, a detached
+/// payload, or a payload shorter than declared (`BzImageError::TruncatedPayload`). The payload
+/// is streamed and not decompressed.
+pub fn scrub<R: Read>(r: R) -> Result<ScrubResult, BzImageError> {
+    let (header, mut payload) = BzImageHeader::split_reader(r)?;
+    if header.flags().contains(BzImageFlags::DETACHED_PAYLOAD) {
+        return Err(BzImageError::DetachedPayload);
+    }
+    let declared = header.compressed_size();
+    let mut digester = Digester::new(header.digest_algo()?);
+    io::copy(&mut payload, &mut digester)?;
+    let unread = payload.limit();
+    if unread > 0 {
+        return Err(BzImageError::TruncatedPayload {
+            declared,
+            available: Some(declared - unread),
+        });
+    }
+    Ok(ScrubResult {
+        header,
+        stored: header.checksum_copy(),
+        computed: digester.finalize(),
+    })
+}
+
+/// Verify every image directly inside `dir` on the rayon thread pool.
+///
+/// Files are recognized with `is_bzimage`; anything else, and subdirectories, are skipped.
+/// Each image gets its own [`VerifyReport`] (see [`verify_file`]), so one bad or unreadable
+/// file does not stop the scan; only failing to list `dir` itself is an `Err`. Results are
+/// sorted by path.
+#[cfg(feature = "parallel")]
+pub fn verify_dir(dir: &Path) -> Result<Vec<(std::path::PathBuf, VerifyReport)>, BzImageError> {
+    use crate::is_bzimage;
+    use rayon::prelude::*;
+    use std::fs;
+
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type().is_ok_and(|t| t.is_file()) && is_bzimage(&path) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths
+        .into_par_iter()
+        .map(|path| {
+            let report = verify_file(&path);
+            (path, report)
+        })
+        .collect())
+}
@@ -0,0 +1,79 @@
+//! Reading single header fields straight from image bytes.
+
+use crate::{BzImageError, BzImageHeader, HEADER_SIZE, MAGIC_BE};
+
+/// A header's fields, decoded on demand from the bytes it was written as.
+///
+/// Nothing is copied or checked up front beyond the length, so looking at one field of many
+/// headers costs only that field: the magic, say, to pick out images while scanning. Use
+/// `parse` (or `BzImageHeader::from_bytes`) for the full checks before trusting the rest.
+#[derive(Copy, Clone, Debug)]
+pub struct HeaderView<'a>(&'a [u8; HEADER_SIZE]);
+
+impl<'a> HeaderView<'a> {
+    /// View the header in the first `HEADER_SIZE` bytes of `bytes`, or fail with
+    /// `BzImageError::TruncatedHeader` if there are fewer.
+    pub fn new(bytes: &'a [u8]) -> Result<HeaderView<'a>, BzImageError> {
+        let header = bytes
+            .get(..HEADER_SIZE)
+            .ok_or(BzImageError::TruncatedHeader)?;
+        Ok(HeaderView(header.try_into().unwrap()))
+    }
+
+    fn u32_at(&self, at: usize) -> u32 {
+        let bytes = self.0[at..at + 4].try_into().unwrap();
+        if self.is_big_endian() {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+
+    fn u64_at(&self, at: usize) -> u64 {
+        let bytes = self.0[at..at + 8].try_into().unwrap();
+        if self.is_big_endian() {
+            u64::from_be_bytes(bytes)
+        } else {
+            u64::from_le_bytes(bytes)
+        }
+    }
+
+    /// Whether the integer fields are big-endian (the magic is `MAGIC_BE`). The other
+    /// accessors decode them in whichever order the magic names.
+    pub fn is_big_endian(&self) -> bool {
+        self.magic() == MAGIC_BE
+    }
+
+    /// The magic, `MAGIC` or `MAGIC_BE` in any image.
+    pub fn magic(&self) -> &'a [u8; 4] {
+        self.0[0..4].try_into().unwrap()
+    }
+
+    /// The format version, as a native integer like the other sizes.
+    pub fn version(&self) -> u32 {
+        self.u32_at(4)
+    }
+
+    pub fn uncompressed_size(&self) -> u64 {
+        self.u64_at(12)
+    }
+
+    pub fn compressed_size(&self) -> u64 {
+        self.u64_at(20)
+    }
+
+    /// The stored payload checksum.
+    pub fn checksum(&self) -> &'a [u8; 32] {
+        self.0[28..60].try_into().unwrap()
+    }
+
+    /// The viewed bytes.
+    pub fn as_bytes(&self) -> &'a [u8; HEADER_SIZE] {
+        self.0
+    }
+
+    /// Parse the viewed bytes into a header, with the checks of `BzImageHeader::from_bytes`.
+    pub fn parse(&self) -> Result<BzImageHeader, BzImageError> {
+        BzImageHeader::from_bytes(self.0)
+    }
+}
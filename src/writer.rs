@@ -0,0 +1,72 @@
+//! Streaming creation of an image on a seekable writer.
+
+use crate::encoder::Encoder;
+use crate::{BzImageError, BzImageHeader, Codec, HEADER_SIZE, MAGIC, VERSION};
+use std::io::{self, Seek, Write};
+
+/// Compresses data as it is written and produces an image, without holding the input or the
+/// compressed payload in memory.
+///
+/// The writer must be seekable: the sizes and checksum are only known once compression has
+/// finished, so a zeroed placeholder header is written when the writer is created and `finish`
+/// seeks back to fill it in. For outputs that cannot seek, such as pipes, use `TrailingWriter`.
+/// An image abandoned without calling `finish` keeps the placeholder and will not parse.
+pub struct BzImageWriter<W: Write + Seek> {
+    encoder: Encoder<W>,
+    codec: Codec,
+    header_offset: u64,
+}
+
+impl<W: Write + Seek> BzImageWriter<W> {
+    /// Reserve the header at the current position of `w` and prepare to compress with `codec`
+    /// at its strongest level.
+    pub fn new(w: W, codec: Codec) -> Result<BzImageWriter<W>, BzImageError> {
+        Self::with_level(w, codec, Codec::MAX_LEVEL)
+    }
+
+    /// Like `new`, compressing at `level`, from 0 (fastest) to `Codec::MAX_LEVEL` (smallest).
+    pub fn with_level(
+        mut w: W,
+        codec: Codec,
+        level: u32,
+    ) -> Result<BzImageWriter<W>, BzImageError> {
+        let header_offset = w.stream_position()?;
+        w.write_all(&[0u8; HEADER_SIZE])?;
+        Ok(BzImageWriter {
+            encoder: Encoder::new(w, codec, level)?,
+            codec,
+            header_offset,
+        })
+    }
+
+    /// Finish the payload, fill in the header, and return the writer and the header.
+    ///
+    /// The writer is left positioned at the end of the payload.
+    pub fn finish(self) -> Result<(W, BzImageHeader), BzImageError> {
+        let (mut w, encoded) = self.encoder.finish()?;
+        let mut header = BzImageHeader {
+            magic: *MAGIC,
+            version: VERSION.into(),
+            reserved1: 0u32.into(),
+            uncompressed_size: encoded.uncompressed_len.into(),
+            compressed_size: encoded.compressed_len.into(),
+            checksum: encoded.checksum,
+            reserved2: 0u32.into(),
+        };
+        header.set_codec(self.codec);
+
+        header.overwrite_at(&mut w, self.header_offset)?;
+        w.flush()?;
+        Ok((w, header))
+    }
+}
+
+impl<W: Write + Seek> Write for BzImageWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+}
@@ -0,0 +1,173 @@
+#![cfg(feature = "cli")]
+
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn bzimage(args: &[&Path]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_bzimage"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn pack_info_unpack_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("data.bin");
+    let image = dir.path().join("data.img");
+    let output = dir.path().join("data.out");
+    let data = b"from the command line ".repeat(100);
+    std::fs::write(&input, &data).unwrap();
+
+    let out = bzimage(&["pack".as_ref(), &input, &image]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let out = bzimage(&["info".as_ref(), &image]);
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.starts_with("magic: DMNZ\nversion: 1\ncodec: Gzip\n"), "{stdout}");
+    assert!(stdout.contains(&format!("uncompressed_size: {}", data.len())));
+    assert!(stdout.contains("compression ratio: "), "{stdout}");
+    assert_eq!(stdout.matches("ratio").count(), 1, "{stdout}");
+    assert!(stdout.contains("space saved: "), "{stdout}");
+    assert!(stdout.contains("checksum: valid"));
+
+    let out = bzimage(&["verify".as_ref(), &image]);
+    assert!(out.status.success());
+    assert!(String::from_utf8(out.stdout).unwrap().contains("checksum ok: yes"));
+
+    let out = bzimage(&["unpack".as_ref(), &image, &output]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+}
+
+#[test]
+fn pack_honors_algo() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("data.bin");
+    let image = dir.path().join("data.img");
+    std::fs::write(&input, b"stored as is").unwrap();
+
+    let out = bzimage(&["pack".as_ref(), &input, &image, "--algo".as_ref(), "stored".as_ref()]);
+    assert!(out.status.success());
+    let bytes = std::fs::read(&image).unwrap();
+    assert!(bytes.ends_with(b"stored as is"));
+
+    let out = bzimage(&["pack".as_ref(), &input, &image, "--algo".as_ref(), "zstd".as_ref()]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let stdout = String::from_utf8(bzimage(&["info".as_ref(), &image]).stdout).unwrap();
+    assert!(stdout.contains("codec: Zstd"), "{stdout}");
+    assert!(stdout.contains("checksum: valid"), "{stdout}");
+
+    let out = bzimage(&["pack".as_ref(), &input, &image, "--algo".as_ref(), "lzma".as_ref()]);
+    assert!(!out.status.success());
+
+    let out = bzimage(&["pack".as_ref(), &input, &image, "--level".as_ref(), "1".as_ref()]);
+    assert!(out.status.success());
+    let out = bzimage(&["pack".as_ref(), &input, &image, "--level".as_ref(), "10".as_ref()]);
+    assert!(!out.status.success());
+}
+
+#[test]
+fn corrupt_images_fail_info_and_unpack() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("data.bin");
+    let image = dir.path().join("data.img");
+    let output = dir.path().join("data.out");
+    std::fs::write(&input, b"about to be damaged".repeat(10)).unwrap();
+    assert!(bzimage(&["pack".as_ref(), &input, &image]).status.success());
+
+    let mut bytes = std::fs::read(&image).unwrap();
+    *bytes.last_mut().unwrap() ^= 0xff;
+    std::fs::write(&image, &bytes).unwrap();
+
+    let out = bzimage(&["info".as_ref(), &image]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stdout).unwrap().contains("checksum: MISMATCH"));
+
+    let out = bzimage(&["verify".as_ref(), &image]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stdout).unwrap().contains("checksum ok: no"));
+
+    let out = bzimage(&["unpack".as_ref(), &image, &output]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr).unwrap().contains("checksum mismatch"));
+    assert!(!output.exists());
+}
+
+#[test]
+fn unpack_restores_the_recorded_file_name() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("données.txt");
+    let image = dir.path().join("packed.img");
+    let data = b"named payload".repeat(10);
+    std::fs::write(&input, &data).unwrap();
+
+    let out = bzimage(&["pack".as_ref(), &input, &image]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let stdout = String::from_utf8(bzimage(&["info".as_ref(), &image]).stdout).unwrap();
+    assert!(stdout.contains("name: données.txt"), "{stdout}");
+    assert!(stdout.contains("checksum: valid"), "{stdout}");
+
+    let restore = dir.path().join("restore");
+    std::fs::create_dir(&restore).unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_bzimage"))
+        .args(["unpack".as_ref(), image.as_path()])
+        .current_dir(&restore)
+        .output()
+        .unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let restored = restore.join("données.txt");
+    assert_eq!(std::fs::read(&restored).unwrap(), data);
+    // whole seconds are recorded
+    let secs = |path: &Path| {
+        let modified = std::fs::metadata(path).unwrap().modified().unwrap();
+        modified.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+    };
+    assert_eq!(secs(&restored), secs(&input));
+}
+
+#[test]
+fn unpack_leaves_an_existing_file_of_the_recorded_name_alone() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join(".bashrc");
+    let image = dir.path().join("packed.img");
+    std::fs::write(&input, b"from the image").unwrap();
+    assert!(bzimage(&["pack".as_ref(), &input, &image]).status.success());
+
+    let restore = dir.path().join("restore");
+    std::fs::create_dir(&restore).unwrap();
+    let existing = restore.join(".bashrc");
+    std::fs::write(&existing, b"already here").unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_bzimage"))
+        .args(["unpack".as_ref(), image.as_path()])
+        .current_dir(&restore)
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("already exists"));
+    assert_eq!(std::fs::read(&existing).unwrap(), b"already here");
+
+    // an explicit output path is still overwritten
+    let out = bzimage(&["unpack".as_ref(), &image, &existing]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(std::fs::read(&existing).unwrap(), b"from the image");
+}
+
+#[test]
+fn empty_files_pack_and_unpack() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("empty");
+    let image = dir.path().join("empty.img");
+    let output = dir.path().join("empty.out");
+    std::fs::write(&input, b"").unwrap();
+
+    let out = bzimage(&["pack".as_ref(), &input, &image]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let out = bzimage(&["info".as_ref(), &image]);
+    assert!(out.status.success());
+    assert!(String::from_utf8(out.stdout).unwrap().contains("uncompressed_size: 0"));
+    let out = bzimage(&["unpack".as_ref(), &image, &output]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(std::fs::read(&output).unwrap(), b"");
+}
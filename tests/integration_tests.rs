@@ -1,3 +1,5 @@
+#![cfg(feature = "std")]
+
 use bzimage::{BzImageHeader, MAGIC, VERSION};
 use flate2::write::GzEncoder;
 use flate2::Compression;
@@ -43,17 +45,9 @@ fn round_trip_write_read_validate_decompress() {
 
     // validate magic & sizes via safe copies and endian conversion
     assert_eq!(&read_header.magic_copy(), MAGIC);
-    // Copy endian-typed fields out of the packed struct safely.
-    let uncompressed_field: simple_endian::u64le = unsafe {
-        std::ptr::read_unaligned(std::ptr::addr_of!(read_header.uncompressed_size))
-    };
-    let compressed_field: simple_endian::u64le = unsafe {
-        std::ptr::read_unaligned(std::ptr::addr_of!(read_header.compressed_size))
-    };
-    let un: u64 = uncompressed_field.into();
-    let comp: u64 = compressed_field.into();
-    assert_eq!(un, uncompressed_size);
-    assert_eq!(comp, compressed_size);
+    assert_eq!(read_header.version(), VERSION);
+    assert_eq!(read_header.uncompressed_size(), uncompressed_size);
+    assert_eq!(read_header.compressed_size(), compressed_size);
 
     // read compressed bytes
     let mut compressed_read = Vec::new();
@@ -64,7 +58,7 @@ fn round_trip_write_read_validate_decompress() {
     assert!(read_header.validate_checksum(&compressed_read));
 
     // decompression via helper
-    let decompressed = BzImageHeader::decompress_data(&compressed_read).unwrap();
+    let decompressed = BzImageHeader::decompress_data(&compressed_read, bzimage::Codec::Gzip).unwrap();
     assert_eq!(decompressed, payload);
 }
 
@@ -74,7 +68,7 @@ fn invalid_magic_fails() {
     let mut cur = Cursor::new(Vec::new());
     cur.write_all(b"BAD!").unwrap();
     // pad out to header size
-    cur.write_all(&vec![0u8; 60]).unwrap();
+    cur.write_all(&[0u8; 60]).unwrap();
     cur.seek(SeekFrom::Start(0)).unwrap();
     let res = BzImageHeader::read_from(&mut cur);
     assert!(res.is_err());
@@ -105,8 +99,9 @@ fn checksum_mismatch_detected() {
     // write header + corrupted compressed data
     let mut cur = Cursor::new(Vec::new());
     header.write_to(&mut cur).unwrap();
+    // a gzip stream is never empty, so there is always a byte to corrupt
     let mut corrupted = compressed.clone();
-    if !corrupted.is_empty() { corrupted[0] ^= 0xff; }
+    corrupted[0] ^= 0xff;
     cur.write_all(&corrupted).unwrap();
     cur.seek(SeekFrom::Start(0)).unwrap();
 
@@ -118,6 +113,142 @@ fn checksum_mismatch_detected() {
     assert!(!read_header.validate_checksum(&compressed_read));
 }
 
+#[test]
+fn headers_round_trip_through_a_path() {
+    use bzimage::BzImageError;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("image.bin");
+    let data = b"written by path ".repeat(20);
+    let compressed = bzimage::Codec::Gzip.compress(&data).unwrap();
+    let header = BzImageHeader::new_for_payload(data.len() as u64, &compressed);
+    header.write_to_path(&path, &compressed).unwrap();
+
+    let read = BzImageHeader::read_from_path(&path).unwrap();
+    assert_eq!(read.to_bytes(), header.to_bytes());
+    let file = std::fs::read(&path).unwrap();
+    assert_eq!(file.len(), bzimage::HEADER_SIZE + compressed.len());
+    assert_eq!(BzImageHeader::unpack(&mut &file[..]).unwrap(), data);
+
+    let missing = dir.path().join("missing.bin");
+    assert!(matches!(BzImageHeader::read_from_path(&missing), Err(BzImageError::Io(_))));
+}
+
+#[test]
+fn header_view_reads_the_same_fields_as_a_parsed_header() {
+    use bzimage::{BzImageError, HeaderView};
+
+    let data = b"viewed, not parsed ".repeat(30);
+    let mut image = Vec::new();
+    let header = BzImageHeader::pack(&data, &mut image).unwrap();
+
+    // the view borrows from the image, payload and all
+    let view = HeaderView::new(&image).unwrap();
+    let parsed = BzImageHeader::from_bytes(&image).unwrap();
+    assert_eq!(view.magic(), &parsed.magic_copy());
+    assert_eq!(view.version(), parsed.version());
+    assert_eq!(view.uncompressed_size(), parsed.uncompressed_size());
+    assert_eq!(view.compressed_size(), parsed.compressed_size());
+    assert_eq!(view.checksum(), &parsed.checksum_copy());
+    assert_eq!(view.uncompressed_size(), data.len() as u64);
+    assert_eq!(view.compressed_size(), header.compressed_size());
+    assert_eq!(view.as_bytes(), &header.to_bytes());
+    assert_eq!(view.parse().unwrap().to_bytes(), header.to_bytes());
+
+    // only the length is checked up front
+    let mut junk = image.clone();
+    junk[..4].copy_from_slice(b"JUNK");
+    let view = HeaderView::new(&junk).unwrap();
+    assert_eq!(view.magic(), b"JUNK");
+    assert!(matches!(view.parse(), Err(BzImageError::InvalidMagic { .. })));
+    assert!(matches!(HeaderView::new(&image[..63]), Err(BzImageError::TruncatedHeader)));
+}
+
+#[test]
+fn headers_compare_and_hash_by_their_fields() {
+    use std::collections::HashSet;
+    use std::hash::{BuildHasher, RandomState};
+
+    let data = b"content addressed ".repeat(20);
+    let mut image = Vec::new();
+    let a = BzImageHeader::pack(&data, &mut image).unwrap();
+    let b = BzImageHeader::pack(&data, &mut Vec::new()).unwrap();
+    let parsed = BzImageHeader::from_bytes(&image).unwrap();
+    assert_eq!(a, b);
+    assert_eq!(a, parsed);
+    let state = RandomState::new();
+    assert_eq!(state.hash_one(a), state.hash_one(b));
+
+    let other = BzImageHeader::pack(b"something else", &mut Vec::new()).unwrap();
+    assert_ne!(a, other);
+    let mut be = a;
+    be.set_big_endian(true);
+    assert_ne!(a, be);
+
+    let cache: HashSet<BzImageHeader> = [a, b, parsed, other].into_iter().collect();
+    assert_eq!(cache.len(), 2);
+    assert!(cache.contains(&other));
+}
+
+#[test]
+fn big_endian_headers_round_trip_and_swap_the_integer_fields() {
+    use bzimage::{HEADER_SIZE, HeaderView, MAGIC_BE};
+
+    let data = b"most significant byte first ".repeat(40);
+    let mut image = Vec::new();
+    let le = BzImageHeader::pack(&data, &mut image).unwrap();
+    let payload = image[HEADER_SIZE..].to_vec();
+
+    let mut be = le;
+    be.set_big_endian(true);
+    assert!(be.is_big_endian() && !le.is_big_endian());
+    let mut be_image = Vec::new();
+    be.write_to(&mut be_image).unwrap();
+    be_image.extend_from_slice(&payload);
+
+    // the integers are reversed field by field; the checksum is a byte string either way
+    let (le_bytes, be_bytes) = (le.to_bytes(), be.to_bytes());
+    assert_eq!(&be_bytes[..4], MAGIC_BE);
+    assert_eq!(be_bytes[4..8], VERSION.to_be_bytes());
+    assert_eq!(be_bytes[12..20], (data.len() as u64).to_be_bytes());
+    assert_eq!(be_bytes[20..28], le.compressed_size().to_be_bytes());
+    assert_eq!(be_bytes[28..60], le_bytes[28..60]);
+    assert_ne!(be_bytes[12..20], le_bytes[12..20]);
+
+    let read = BzImageHeader::read_from(&mut Cursor::new(&be_image)).unwrap();
+    assert!(read.is_big_endian());
+    assert_eq!(read.to_bytes(), be_bytes);
+    assert_eq!(read.uncompressed_size(), le.uncompressed_size());
+    assert_eq!(read.compressed_size(), le.compressed_size());
+    assert_eq!(read.flags(), le.flags());
+    assert_eq!(BzImageHeader::unpack(&mut Cursor::new(&be_image)).unwrap(), data);
+
+    let view = HeaderView::new(&be_image).unwrap();
+    assert!(view.is_big_endian());
+    assert_eq!(view.version(), VERSION);
+    assert_eq!(view.uncompressed_size(), data.len() as u64);
+    assert_eq!(view.compressed_size(), le.compressed_size());
+
+    // a header CRC is over the on-disk bytes, so it is taken after choosing the order
+    let mut crc = BzImageHeader::new_for_payload(data.len() as u64, &payload);
+    crc.set_big_endian(true);
+    crc.set_header_crc().unwrap();
+    let parsed = BzImageHeader::from_bytes(&crc.to_bytes()).unwrap();
+    assert!(parsed.verify_header_crc());
+    let mut flipped = parsed;
+    flipped.set_big_endian(false);
+    assert!(!flipped.verify_header_crc());
+
+    let built = BzImageHeader::builder()
+        .uncompressed_size(data.len() as u64)
+        .compressed_size(le.compressed_size())
+        .checksum(le.checksum_copy())
+        .big_endian(true)
+        .build()
+        .unwrap();
+    assert!(built.is_big_endian());
+}
+
 #[test]
 fn header_write_size() {
     // ensure write_to writes exactly HEADER_SIZE bytes
@@ -146,3 +277,3042 @@ fn truncated_header_fails() {
     let r = BzImageHeader::read_from(&mut cur);
     assert!(r.is_err());
 }
+
+#[test]
+fn gzip_interop_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let payload = b"plain gzip interop".to_vec();
+    let mut enc = GzEncoder::new(Vec::new(), Compression::best());
+    enc.write_all(&payload).unwrap();
+    let gz = enc.finish().unwrap();
+
+    let gz_path = dir.path().join("in.gz");
+    let image_path = dir.path().join("image.bz");
+    let out_path = dir.path().join("out.gz");
+    std::fs::write(&gz_path, &gz).unwrap();
+
+    let header = bzimage::from_gzip(&gz_path, &image_path).unwrap();
+    assert!(header.validate_checksum(&gz));
+
+    bzimage::to_gzip(&image_path, &out_path).unwrap();
+    assert_eq!(std::fs::read(&out_path).unwrap(), gz);
+
+    // a file that is not gzip is refused
+    let not_gz = dir.path().join("plain.txt");
+    std::fs::write(&not_gz, b"not compressed").unwrap();
+    assert!(bzimage::from_gzip(&not_gz, &image_path).is_err());
+}
+
+#[test]
+fn tree_digest_validates() {
+    use bzimage::{DigestAlgo, TREE_BLOCK_SIZE, compute_checksum};
+
+    // span several tree leaves, with a short final block
+    let compressed: Vec<u8> = (0..TREE_BLOCK_SIZE * 3 + 17).map(|i| (i % 251) as u8).collect();
+    let mut header = BzImageHeader {
+        magic: *MAGIC,
+        version: VERSION.into(),
+        reserved1: 0u32.into(),
+        uncompressed_size: 0u64.into(),
+        compressed_size: (compressed.len() as u64).into(),
+        checksum: compute_checksum(DigestAlgo::Sha256Tree, &compressed),
+        reserved2: 0u32.into(),
+    };
+    // still flagged as plain SHA-256, so the tree root must not validate
+    assert_eq!(header.digest_algo().unwrap(), DigestAlgo::Sha256);
+    assert!(!header.validate_checksum(&compressed));
+
+    header.set_digest_algo(DigestAlgo::Sha256Tree);
+    assert_eq!(header.digest_algo().unwrap(), DigestAlgo::Sha256Tree);
+    assert!(header.validate_checksum(&compressed));
+    #[cfg(feature = "parallel")]
+    {
+        assert!(header.validate_checksum_parallel(&compressed));
+        let mut corrupted = compressed.clone();
+        corrupted[TREE_BLOCK_SIZE + 1] ^= 0x01;
+        assert!(!header.validate_checksum_parallel(&corrupted));
+    }
+}
+
+#[test]
+fn truncated_payload_reports_available_bytes() {
+    let header = BzImageHeader {
+        magic: *MAGIC,
+        version: VERSION.into(),
+        reserved1: 0u32.into(),
+        uncompressed_size: 0u64.into(),
+        compressed_size: 100u64.into(),
+        checksum: [0u8; 32],
+        reserved2: 0u32.into(),
+    };
+    let mut cur = Cursor::new(Vec::new());
+    header.write_to(&mut cur).unwrap();
+    cur.write_all(&[0u8; 40]).unwrap();
+    cur.seek(SeekFrom::Start(0)).unwrap();
+
+    let err = BzImageHeader::read_header_and_payload(&mut cur).unwrap_err();
+    match err {
+        bzimage::BzImageError::TruncatedPayload {
+            declared,
+            available,
+        } => {
+            assert_eq!(declared, 100);
+            assert_eq!(available, Some(40));
+        }
+        other => panic!("unexpected error: {other:?}"),
+    }
+}
+
+#[test]
+fn partial_reads_keep_what_arrived_of_a_short_payload() {
+    let data = b"an interrupted download ".repeat(50);
+    let mut image = Vec::new();
+    let header = BzImageHeader::pack(&data, &mut image).unwrap();
+    let payload = &image[bzimage::HEADER_SIZE..];
+
+    // the transfer stops 30 bytes into the payload, one byte per read
+    let cut = bzimage::HEADER_SIZE + 30;
+    let short = OneByteAtATime(Cursor::new(&image[..cut]));
+    let (read, partial, complete) = BzImageHeader::read_header_and_payload_partial(short).unwrap();
+    assert!(!complete);
+    assert_eq!(read.to_bytes(), header.to_bytes());
+    assert_eq!(partial, &payload[..30]);
+
+    let (_, full, complete) = BzImageHeader::read_header_and_payload_partial(Cursor::new(&image)).unwrap();
+    assert!(complete);
+    assert_eq!(full, payload);
+
+    // a short header is still an error
+    assert!(matches!(
+        BzImageHeader::read_header_and_payload_partial(Cursor::new(&image[..40])),
+        Err(bzimage::BzImageError::TruncatedHeader)
+    ));
+}
+
+#[test]
+fn append_footer_replaces_existing_footer() {
+    use bzimage::{Footer, append_footer, read_footer};
+
+    let payload = b"footer payload".to_vec();
+    let mut enc = GzEncoder::new(Vec::new(), Compression::best());
+    enc.write_all(&payload).unwrap();
+    let compressed = enc.finish().unwrap();
+    let mut hasher = Sha256::new();
+    hasher.update(&compressed);
+    let header = BzImageHeader {
+        magic: *MAGIC,
+        version: VERSION.into(),
+        reserved1: 0u32.into(),
+        uncompressed_size: (payload.len() as u64).into(),
+        compressed_size: (compressed.len() as u64).into(),
+        checksum: hasher.finalize().into(),
+        reserved2: 0u32.into(),
+    };
+    let mut cur = Cursor::new(Vec::new());
+    header.write_to(&mut cur).unwrap();
+    cur.write_all(&compressed).unwrap();
+    assert_eq!(read_footer(&mut cur).unwrap(), None);
+
+    let mut meta = Footer::new();
+    meta.insert("deployment", "canary-with-a-long-name");
+    meta.insert("build", "1234");
+    append_footer(&mut cur, &meta).unwrap();
+    assert_eq!(read_footer(&mut cur).unwrap(), Some(meta));
+    cur.seek(SeekFrom::Start(0)).unwrap();
+    let flagged = BzImageHeader::read_from(&mut cur).unwrap();
+    assert!(flagged.flags().contains(bzimage::BzImageFlags::HAS_FOOTER));
+
+    let mut shorter = Footer::new();
+    shorter.insert("build", "1235");
+    let end = append_footer(&mut cur, &shorter).unwrap();
+    cur.get_mut().truncate(end as usize);
+    assert_eq!(read_footer(&mut cur).unwrap(), Some(shorter));
+
+    // a footer no reader would accept is not written
+    let mut huge = Footer::new();
+    huge.insert("blob", "x".repeat(bzimage::MAX_FOOTER_SIZE));
+    let before = cur.get_ref().clone();
+    let err = append_footer(&mut cur, &huge).unwrap_err();
+    assert!(matches!(err, bzimage::BzImageError::InvalidFooter("larger than MAX_FOOTER_SIZE")), "{err:?}");
+    assert_eq!(cur.get_ref(), &before);
+    let mut out = Vec::new();
+    assert!(huge.write_to(&mut out).is_err());
+    assert!(out.is_empty());
+
+    // the payload is untouched
+    cur.seek(SeekFrom::Start(0)).unwrap();
+    let (read_header, read_compressed) = BzImageHeader::read_header_and_payload(&mut cur).unwrap();
+    assert!(read_header.validate_checksum(&read_compressed));
+}
+
+#[test]
+fn write_image_auto_picks_smallest_codec() {
+    use bzimage::{Codec, write_image_auto};
+
+    // repetitive input compresses well, so gzip wins (or zstd, when it is built in)
+    let text = b"the quick brown fox ".repeat(500);
+    let mut cur = Cursor::new(Vec::new());
+    let header = write_image_auto(&mut cur, &text).unwrap();
+    let expected = if cfg!(feature = "zstd") { Codec::Zstd } else { Codec::Gzip };
+    assert_eq!(header.codec().unwrap(), expected);
+
+    // pseudo-random input does not, so it is stored
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let noise: Vec<u8> = (0..4096)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    let mut cur = Cursor::new(Vec::new());
+    let header = write_image_auto(&mut cur, &noise).unwrap();
+    assert_eq!(header.codec().unwrap(), Codec::Stored);
+
+    cur.seek(SeekFrom::Start(0)).unwrap();
+    let (read_header, compressed) = BzImageHeader::read_header_and_payload(&mut cur).unwrap();
+    assert!(read_header.validate_checksum(&compressed));
+    let codec = read_header.codec().unwrap();
+    assert_eq!(codec.decompress(&compressed).unwrap(), noise);
+}
+
+/// Reader/writer that transfers at most one byte per call, like a throttled socket.
+struct OneByteAtATime<T>(T);
+
+impl<T: Read> Read for OneByteAtATime<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(1);
+        self.0.read(&mut buf[..n])
+    }
+}
+
+impl<T: Write> Write for OneByteAtATime<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(1);
+        self.0.write(&buf[..n])
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<T: Seek> Seek for OneByteAtATime<T> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+#[test]
+fn one_byte_reads_and_writes_still_parse() {
+    let payload = b"trickled one byte at a time".to_vec();
+    let mut image = OneByteAtATime(Cursor::new(Vec::new()));
+    let written = bzimage::write_image(&mut image, &payload, bzimage::Codec::Gzip).unwrap();
+
+    image.seek(SeekFrom::Start(0)).unwrap();
+    let (header, compressed) = BzImageHeader::read_header_and_payload(&mut image).unwrap();
+    assert_eq!(image.0.get_ref().len(), bzimage::HEADER_SIZE + compressed.len());
+    assert_eq!(&header.magic_copy(), MAGIC);
+    assert_eq!(header.checksum_copy(), written.checksum_copy());
+    assert!(header.validate_checksum(&compressed));
+}
+
+#[test]
+fn validate_payload_checks_size_before_checksum() {
+    use bzimage::BzImageError;
+
+    let payload = b"size before checksum".to_vec();
+    let mut cur = Cursor::new(Vec::new());
+    let header = bzimage::write_image(&mut cur, &payload, bzimage::Codec::Gzip).unwrap();
+    let compressed = cur.get_ref()[bzimage::HEADER_SIZE..].to_vec();
+    header.validate_payload(&compressed).unwrap();
+
+    let mut longer = compressed.clone();
+    longer.push(0);
+    let err = header.validate_payload(&longer).unwrap_err();
+    assert!(matches!(
+        err,
+        BzImageError::SizeMismatch { declared, actual }
+            if declared == compressed.len() as u64 && actual == longer.len() as u64
+    ));
+
+    let mut corrupted = compressed.clone();
+    corrupted[0] ^= 0xff;
+    let err = header.validate_payload(&corrupted).unwrap_err();
+    assert!(matches!(
+        err,
+        BzImageError::ChecksumMismatch { expected, .. } if expected == header.checksum_copy()
+    ));
+}
+
+#[test]
+fn direct_io_writes_are_block_aligned() {
+    use bzimage::{AlignedWriter, DIRECT_IO_ALIGNMENT};
+
+    /// Records the length of every write so alignment can be checked.
+    struct Recorder(Vec<u8>, Vec<usize>);
+    impl Write for Recorder {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.1.push(buf.len());
+            self.0.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let payload: Vec<u8> = (0..3 << 20).map(|i| (i % 7) as u8).collect();
+    let mut rec = Recorder(Vec::new(), Vec::new());
+    let header = bzimage::write_image_direct(&mut rec, &payload, bzimage::Codec::Stored).unwrap();
+    assert!(rec.1.iter().all(|len| len % DIRECT_IO_ALIGNMENT == 0));
+    assert_eq!(rec.0.len() % DIRECT_IO_ALIGNMENT, 0);
+
+    let (read_header, compressed) =
+        BzImageHeader::read_header_and_payload(Cursor::new(&rec.0)).unwrap();
+    assert_eq!(read_header.checksum_copy(), header.checksum_copy());
+    read_header.validate_payload(&compressed).unwrap();
+    assert!(rec.0[bzimage::HEADER_SIZE + compressed.len()..].iter().all(|&b| b == 0));
+
+    // writes of awkward sizes come out the same
+    let mut w = AlignedWriter::new(Vec::new());
+    for chunk in payload.chunks(4097) {
+        w.write_all(chunk).unwrap();
+    }
+    let (out, written) = w.finish().unwrap();
+    assert_eq!(written, payload.len() as u64);
+    assert_eq!(&out[..payload.len()], &payload[..]);
+}
+
+#[test]
+fn reserved_fields_do_not_overlap() {
+    use bzimage::{BzImageFlags, Codec, DigestAlgo};
+
+    let mut header = BzImageHeader {
+        magic: *MAGIC,
+        version: VERSION.into(),
+        reserved1: 0u32.into(),
+        uncompressed_size: 0u64.into(),
+        compressed_size: 0u64.into(),
+        checksum: [0u8; 32],
+        reserved2: 0u32.into(),
+    };
+    let flags = BzImageFlags::from_bits_retain(0xa5c3);
+    header.set_flags(flags);
+    header.set_codec(Codec::Stored);
+    header.set_digest_algo(DigestAlgo::Sha256Tree);
+    assert_eq!(header.flags(), flags);
+    assert_eq!(header.codec().unwrap(), Codec::Stored);
+    assert_eq!(header.digest_algo().unwrap(), DigestAlgo::Sha256Tree);
+
+    header.set_flags(BzImageFlags::empty());
+    header.set_codec(Codec::Gzip);
+    assert_eq!(header.digest_algo().unwrap(), DigestAlgo::Sha256Tree);
+    assert_eq!(header.reserved1(), 0x0000_0100);
+}
+
+#[test]
+fn diff_headers_reports_changed_fields() {
+    let a = bzimage::write_image(std::io::sink(), b"first build", bzimage::Codec::Gzip).unwrap();
+    assert!(bzimage::diff_headers(&a, &a).is_empty());
+
+    let b = bzimage::write_image(std::io::sink(), b"second build", bzimage::Codec::Gzip).unwrap();
+    let diffs = bzimage::diff_headers(&a, &b);
+    let fields: Vec<&str> = diffs.iter().map(|d| d.field).collect();
+    assert_eq!(fields, ["uncompressed_size", "compressed_size", "checksum"]);
+    assert_eq!(diffs[0].to_string(), "uncompressed_size: 11 vs 12");
+
+    let mut c = a;
+    c.set_codec(bzimage::Codec::Stored);
+    let diffs = bzimage::diff_headers(&a, &c);
+    assert_eq!(diffs.len(), 1);
+    assert_eq!(diffs[0].to_string(), "codec: Gzip vs Stored");
+}
+
+#[test]
+fn diagnose_checksum_spots_uncompressed_hash() {
+    use bzimage::{ChecksumDiagnosis, Codec, diagnose_checksum};
+
+    let data = b"checksummed on the wrong side".repeat(8);
+    let compressed = Codec::Gzip.compress(&data).unwrap();
+    let mut header = bzimage::write_image(std::io::sink(), &data, Codec::Gzip).unwrap();
+    assert_eq!(diagnose_checksum(&header, &compressed, &data), ChecksumDiagnosis::Compressed);
+
+    header.checksum = Sha256::digest(&data).into();
+    assert!(!header.validate_checksum(&compressed));
+    assert_eq!(diagnose_checksum(&header, &compressed, &data), ChecksumDiagnosis::Uncompressed);
+
+    header.checksum = [0u8; 32];
+    assert_eq!(diagnose_checksum(&header, &compressed, &data), ChecksumDiagnosis::Neither);
+}
+
+#[test]
+fn expected_total_from_header_matches_image_length() {
+    let mut image = Vec::new();
+    bzimage::write_image(&mut image, &[7u8; 5000], bzimage::Codec::Stored).unwrap();
+    let header_bytes: [u8; bzimage::HEADER_SIZE] = image[..bzimage::HEADER_SIZE].try_into().unwrap();
+    assert_eq!(bzimage::expected_total_from_header(&header_bytes).unwrap(), image.len() as u64);
+
+    let mut bad = header_bytes;
+    bad[..4].copy_from_slice(b"NOPE");
+    assert!(bzimage::expected_total_from_header(&bad).is_err());
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn mmap_write_produces_exact_length_image() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("mapped.bz");
+    let payload = b"mapped image payload ".repeat(10_000);
+
+    let header = bzimage::write_image_mmap(&path, &payload).unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+    let (read_header, compressed) =
+        BzImageHeader::read_header_and_payload(Cursor::new(&bytes)).unwrap();
+    assert_eq!(bytes.len(), bzimage::HEADER_SIZE + compressed.len());
+    assert_eq!(read_header.checksum_copy(), header.checksum_copy());
+    read_header.validate_payload(&compressed).unwrap();
+    assert_eq!(BzImageHeader::decompress_data(&compressed, bzimage::Codec::Gzip).unwrap(), payload);
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn payload_slice_borrows_from_mapping() {
+    use bzimage::{BzImageError, map_payload};
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("mapped.img");
+    let data = b"zero copy ".repeat(100);
+    let mut image = Vec::new();
+    bzimage::write_image(&mut image, &data, bzimage::Codec::Gzip).unwrap();
+    std::fs::write(&path, &image).unwrap();
+
+    let (header, map) = map_payload(&path).unwrap();
+    let payload = header.payload_slice(&map).unwrap();
+    assert_eq!(payload.as_ptr(), map[bzimage::HEADER_SIZE..].as_ptr());
+    assert!(header.validate_checksum(payload));
+    assert_eq!(BzImageHeader::decompress_data(payload, bzimage::Codec::Gzip).unwrap(), data);
+
+    std::fs::write(&path, &image[..image.len() - 1]).unwrap();
+    let (header, map) = map_payload(&path).unwrap();
+    let err = header.payload_slice(&map).unwrap_err();
+    assert!(matches!(
+        err,
+        BzImageError::TruncatedPayload { .. }
+    ));
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn open_mmap_exposes_the_payload_in_place() {
+    use bzimage::{BzImage, BzImageError};
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("mapped.img");
+    let data = b"read heavy ".repeat(500);
+    let compressed = bzimage::Codec::Gzip.compress(&data).unwrap();
+    let mut header = BzImageHeader::new_for_payload(data.len() as u64, &compressed);
+    let mut file = std::fs::File::create(&path).unwrap();
+    header.write_to(&mut file).unwrap();
+    file.write_all(&compressed).unwrap();
+    drop(file);
+
+    let mapped = BzImage::open_mmap(&path).unwrap();
+    assert_eq!(mapped.header().to_bytes(), header.to_bytes());
+    assert_eq!(mapped.payload().len() as u64, mapped.header().compressed_size());
+    assert_eq!(mapped.payload(), &compressed[..]);
+    assert!(mapped.header().validate_checksum(mapped.payload()));
+    mapped.validate().unwrap();
+    let loaded = mapped.load().unwrap();
+    assert_eq!(loaded.data.len() as u64, mapped.header().uncompressed_size());
+    assert_eq!(loaded.data, data);
+
+    // a header claiming more payload than the file holds is refused when opening
+    header.compressed_size = (compressed.len() as u64 + 1).into();
+    let mut image = header.to_bytes().to_vec();
+    image.extend_from_slice(&compressed);
+    std::fs::write(&path, &image).unwrap();
+    let err = BzImage::open_mmap(&path).unwrap_err();
+    assert!(matches!(err, BzImageError::TruncatedPayload { .. }));
+}
+
+#[test]
+fn can_read_reports_first_blocking_reason() {
+    use bzimage::BzImageFlags;
+
+    let header = bzimage::write_image(std::io::sink(), b"negotiate", bzimage::Codec::Gzip).unwrap();
+    header.can_read().unwrap();
+
+    let reason = |h: &BzImageHeader| {
+        format!("{:?}", h.can_read().unwrap_err())
+    };
+
+    let mut newer = header;
+    newer.version = (VERSION + 1).into();
+    assert_eq!(reason(&newer), format!("UnsupportedVersion({})", VERSION + 1));
+
+    let mut odd_codec = header;
+    odd_codec.reserved1 = 0x0000_00eeu32.into();
+    assert_eq!(reason(&odd_codec), "UnknownCodec(238)");
+
+    let mut odd_digest = header;
+    odd_digest.reserved1 = 0x0000_ee00u32.into();
+    assert_eq!(reason(&odd_digest), "UnknownDigestAlgo(238)");
+
+    // unknown optional flags are fine, unknown critical ones are not
+    let mut flagged = header;
+    flagged.set_flags(BzImageFlags::from_bits_retain(0x0080));
+    flagged.can_read().unwrap();
+    flagged.set_flags(BzImageFlags::from_bits_retain(0x8080));
+    assert_eq!(reason(&flagged), "UnknownCriticalFlag(32768)");
+}
+
+#[test]
+fn flags_round_trip_and_unknown_critical_ones_are_refused() {
+    use bzimage::{BzImageError, BzImageFlags};
+
+    let mut header = bzimage::write_image(std::io::sink(), b"flags", bzimage::Codec::Gzip).unwrap();
+    let codec = header.codec().unwrap();
+    header.set_flags(BzImageFlags::HAS_FOOTER | BzImageFlags::SIGNED);
+    assert!(header.flags().contains(BzImageFlags::SIGNED));
+    assert!(!header.flags().contains(BzImageFlags::ENCRYPTED));
+    // the flags share reserved1 with the codec and digest fields without disturbing them
+    assert_eq!(header.codec().unwrap(), codec);
+    let read = BzImageHeader::read_from(&header.to_bytes()[..]).unwrap();
+    assert_eq!(read.flags(), BzImageFlags::HAS_FOOTER | BzImageFlags::SIGNED);
+
+    // an unknown optional bit is kept and does not stop the header being read
+    header.set_flags(BzImageFlags::from_bits_retain(0x0040));
+    let read = BzImageHeader::read_from(&header.to_bytes()[..]).unwrap();
+    assert_eq!(read.flags().bits(), 0x0040);
+
+    // critical bits this build does not understand, reserved or merely claimed, are refused
+    for bits in [0x4000u16, 0x8000, 0x2000 | 0x0001] {
+        header.set_flags(BzImageFlags::from_bits_retain(bits));
+        let bytes = header.to_bytes();
+        let err = BzImageHeader::read_from(&bytes[..]).unwrap_err();
+        assert!(matches!(err, BzImageError::UnknownCriticalFlag(b) if b == bits & 0xff00), "{err:?}");
+        assert!(matches!(BzImageHeader::from_bytes(&bytes), Err(BzImageError::UnknownCriticalFlag(_))));
+    }
+
+    header.set_flags(BzImageFlags::DETACHED_PAYLOAD);
+    BzImageHeader::read_from(&header.to_bytes()[..]).unwrap();
+}
+
+#[test]
+fn each_checksum_algorithm_validates_only_itself() {
+    use bzimage::{ChecksumAlgorithm, Codec, compute_checksum, upgrade_checksum};
+    use sha2::Sha512;
+
+    let algos = [
+        ChecksumAlgorithm::Sha256,
+        ChecksumAlgorithm::Sha256Tree,
+        ChecksumAlgorithm::Sha512Truncated,
+        #[cfg(feature = "blake3")]
+        ChecksumAlgorithm::Blake3,
+    ];
+
+    let data = b"hash me however you like ".repeat(100);
+    let compressed = Codec::Gzip.compress(&data).unwrap();
+    assert_eq!(
+        compute_checksum(ChecksumAlgorithm::Sha512Truncated, &compressed)[..],
+        Sha512::digest(&compressed)[..32]
+    );
+    #[cfg(feature = "blake3")]
+    assert_eq!(compute_checksum(ChecksumAlgorithm::Blake3, &compressed), *blake3::hash(&compressed).as_bytes());
+
+    for &algo in &algos {
+        let mut image = Cursor::new(Vec::new());
+        bzimage::write_image(&mut image, &data, Codec::Gzip).unwrap();
+        let header = upgrade_checksum(&mut image, algo).unwrap();
+        assert_eq!(header.digest_algo().unwrap(), algo);
+        assert_eq!(header.checksum_copy(), compute_checksum(algo, &compressed));
+        assert!(header.validate_checksum(&compressed));
+        assert_eq!(BzImageHeader::unpack(&mut Cursor::new(image.get_ref())).unwrap(), data);
+
+        for &other in algos.iter().filter(|&&other| other != algo) {
+            let mut misread = header;
+            misread.set_digest_algo(other);
+            assert!(!misread.validate_checksum(&compressed), "{algo:?} read as {other:?}");
+        }
+    }
+
+    // a build without BLAKE3 still knows its identifier, but refuses to use it
+    assert_eq!(bzimage::DigestAlgo::from_id(3), Some(ChecksumAlgorithm::Blake3));
+    assert_eq!(ChecksumAlgorithm::Blake3.is_enabled(), cfg!(feature = "blake3"));
+    #[cfg(not(feature = "blake3"))]
+    {
+        use bzimage::BzImageError;
+
+        let mut header = BzImageHeader::new_for_payload(data.len() as u64, &compressed);
+        header.reserved1 = 0x0000_0300u32.into();
+        assert!(matches!(header.digest_algo(), Err(BzImageError::DigestNotEnabled(ChecksumAlgorithm::Blake3))));
+        assert!(matches!(header.can_read(), Err(BzImageError::DigestNotEnabled(_))));
+        assert!(!header.validate_checksum(&compressed));
+        header.reserved1 = 0x0000_0400u32.into();
+        assert!(matches!(header.digest_algo(), Err(BzImageError::UnknownDigestAlgo(4))));
+
+        let mut image = Cursor::new(Vec::new());
+        bzimage::write_image(&mut image, &data, Codec::Gzip).unwrap();
+        let err = upgrade_checksum(&mut image, ChecksumAlgorithm::Blake3).unwrap_err();
+        assert!(matches!(err, BzImageError::DigestNotEnabled(ChecksumAlgorithm::Blake3)));
+    }
+}
+
+#[test]
+fn header_crc_catches_damage_to_the_header() {
+    use bzimage::{BzImageError, BzImageFlags};
+
+    let mut image = Vec::new();
+    let mut header = bzimage::write_image(&mut image, b"self-checking", bzimage::Codec::Gzip).unwrap();
+    assert!(!header.verify_header_crc());
+    header.set_header_crc().unwrap();
+    assert!(header.flags().contains(BzImageFlags::HEADER_CRC32));
+    assert!(header.verify_header_crc());
+    let bytes = header.to_bytes();
+    assert_eq!(header.reserved2(), crc32fast::hash(&bytes[..60]));
+    assert!(BzImageHeader::read_from(&bytes[..]).unwrap().verify_header_crc());
+
+    // any flipped bit before reserved2 is caught, the checksum field included
+    for at in [9, 15, 27, 40, 59] {
+        let mut damaged = bytes;
+        damaged[at] ^= 0x01;
+        let err = BzImageHeader::read_from(&damaged[..]).unwrap_err();
+        assert!(matches!(err, BzImageError::HeaderCrcMismatch { .. }), "byte {at}: {err:?}");
+    }
+    let mut damaged = bytes;
+    damaged[61] ^= 0x80;
+    assert!(matches!(BzImageHeader::from_bytes(&damaged), Err(BzImageError::HeaderCrcMismatch { .. })));
+
+    // reserved2 has room for one CRC only, whichever comes first
+    let mut header = BzImageHeader::new_for_payload(13, &image[bzimage::HEADER_SIZE..]);
+    header.set_uncompressed_crc(b"self-checking").unwrap();
+    assert!(matches!(header.set_header_crc(), Err(BzImageError::Reserved2InUse)));
+    let mut header = BzImageHeader::pack(b"self-checking", &mut Vec::new()).unwrap();
+    header.set_header_crc().unwrap();
+    assert!(matches!(header.set_uncompressed_crc(b"self-checking"), Err(BzImageError::Reserved2InUse)));
+    assert!(header.verify_header_crc());
+}
+
+#[test]
+fn in_place_rewriters_keep_the_header_crc_valid() {
+    use bzimage::{
+        BzImageFlags, Codec, DigestAlgo, Footer, append_footer, append_signature, read_footer, read_signature,
+        rewrite_header, upgrade_checksum, write_image,
+    };
+
+    let data = b"rewritten in place".repeat(10);
+    let protected = || {
+        let mut image = Vec::new();
+        let mut header = write_image(&mut image, &data, Codec::Gzip).unwrap();
+        header.set_header_crc().unwrap();
+        header.write_to(&mut image[..bzimage::HEADER_SIZE]).unwrap();
+        image
+    };
+    let check = |image: &[u8]| {
+        let header = BzImageHeader::from_bytes(image).unwrap();
+        assert!(header.verify_header_crc());
+        assert_eq!(BzImageHeader::unpack(&mut Cursor::new(image)).unwrap(), data);
+        header
+    };
+
+    let mut image = protected();
+    let mut header = BzImageHeader::from_bytes(&image).unwrap();
+    header.set_flags(header.flags() | BzImageFlags::from_bits_retain(0x0040));
+    rewrite_header(Cursor::new(&mut image), &header).unwrap();
+    assert!(check(&image).flags().contains(BzImageFlags::from_bits_retain(0x0040)));
+
+    let mut image = protected();
+    let upgraded = upgrade_checksum(Cursor::new(&mut image), DigestAlgo::Sha256Tree).unwrap();
+    assert!(upgraded.verify_header_crc());
+    assert_eq!(check(&image), upgraded);
+
+    let mut image = protected();
+    let mut footer = Footer::new();
+    footer.insert("build", "42");
+    append_footer(Cursor::new(&mut image), &footer).unwrap();
+    assert!(check(&image).flags().contains(BzImageFlags::HAS_FOOTER));
+    assert_eq!(read_footer(Cursor::new(&image)).unwrap(), Some(footer));
+
+    let mut image = protected();
+    append_signature(Cursor::new(&mut image), b"detached signature").unwrap();
+    assert!(check(&image).has_signature());
+    assert_eq!(read_signature(Cursor::new(&image)).unwrap().unwrap(), b"detached signature");
+}
+
+#[test]
+fn uncompressed_crc_round_trip() {
+    let payload = b"crc of the uncompressed bytes".to_vec();
+    let mut cur = Cursor::new(Vec::new());
+    let mut header = bzimage::write_image(&mut cur, &payload, bzimage::Codec::Gzip).unwrap();
+    assert_eq!(header.uncompressed_crc(), None);
+    assert!(!header.validate_uncompressed_crc(&payload));
+
+    header.set_uncompressed_crc(&payload).unwrap();
+    header.write_to(&mut cur.get_mut()[..bzimage::HEADER_SIZE]).unwrap();
+
+    cur.seek(SeekFrom::Start(0)).unwrap();
+    let (read_header, compressed) = BzImageHeader::read_header_and_payload(&mut cur).unwrap();
+    read_header.can_read().unwrap();
+    let decompressed = BzImageHeader::decompress_data(&compressed, bzimage::Codec::Gzip).unwrap();
+    assert!(read_header.validate_uncompressed_crc(&decompressed));
+    assert!(!read_header.validate_uncompressed_crc(b"something else"));
+}
+
+#[test]
+fn trailing_header_streams_to_non_seekable_writer() {
+    use bzimage::{BzImageFlags, Codec, TrailingWriter, read_trailing_image};
+
+    // a Vec behind a Write-only wrapper: the writer cannot seek back
+    struct Pipe(Vec<u8>);
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let payload = b"streamed to a pipe ".repeat(1000);
+    let mut w = TrailingWriter::new(Pipe(Vec::new()), Codec::Gzip).unwrap();
+    for chunk in payload.chunks(333) {
+        w.write_all(chunk).unwrap();
+    }
+    let (pipe, header) = w.finish().unwrap();
+    assert!(header.flags().contains(BzImageFlags::TRAILING_HEADER));
+    assert_eq!(&pipe.0[..4], bzimage::TRAILING_MAGIC);
+
+    let (read_header, compressed) = read_trailing_image(Cursor::new(&pipe.0)).unwrap();
+    assert_eq!(read_header.checksum_copy(), header.checksum_copy());
+    read_header.validate_payload(&compressed).unwrap();
+    assert_eq!(BzImageHeader::decompress_data(&compressed, bzimage::Codec::Gzip).unwrap(), payload);
+
+    // the prefix must agree with the header about the format version
+    let mut mismatched = pipe.0.clone();
+    mismatched[4..8].copy_from_slice(&(bzimage::VERSION + 1).to_le_bytes());
+    let err = read_trailing_image(Cursor::new(&mismatched)).unwrap_err();
+    assert!(matches!(err, bzimage::BzImageError::InvalidTrailingLayout("prefix and header versions differ")), "{err:?}");
+}
+
+#[test]
+fn payload_cow_borrows_stored_payloads() {
+    use std::borrow::Cow;
+
+    let payload = b"no need to copy me".to_vec();
+    let mut stored = Vec::new();
+    let header = bzimage::write_image(&mut stored, &payload, bzimage::Codec::Stored).unwrap();
+    match header.payload_cow(&stored).unwrap() {
+        Cow::Borrowed(data) => assert_eq!(data, &payload[..]),
+        Cow::Owned(_) => panic!("stored payload was copied"),
+    }
+
+    let mut gzipped = Vec::new();
+    let header = bzimage::write_image(&mut gzipped, &payload, bzimage::Codec::Gzip).unwrap();
+    let data = header.payload_cow(&gzipped).unwrap();
+    assert!(matches!(data, Cow::Owned(_)));
+    assert_eq!(&data[..], &payload[..]);
+
+    assert!(header.payload_cow(&gzipped[..gzipped.len() - 1]).is_err());
+}
+
+#[test]
+fn read_from_at_skips_preamble() {
+    let payload = b"embedded after a preamble".to_vec();
+    let mut file = b"PREAMBLE-0123456789".to_vec();
+    let offset = file.len() as u64;
+    let header = bzimage::write_image(&mut file, &payload, bzimage::Codec::Gzip).unwrap();
+    file.extend_from_slice(b"trailing data");
+
+    let (read_header, compressed) =
+        BzImageHeader::read_from_at(Cursor::new(&file), offset).unwrap();
+    assert_eq!(read_header.checksum_copy(), header.checksum_copy());
+    read_header.validate_payload(&compressed).unwrap();
+
+    assert!(BzImageHeader::read_from_at(Cursor::new(&file), 0).is_err());
+}
+
+#[test]
+fn read_if_trusted_enforces_allowlist() {
+    use bzimage::BzImageError;
+    use std::collections::HashSet;
+
+    let mut good = Vec::new();
+    let header = bzimage::write_image(&mut good, b"known good", bzimage::Codec::Gzip).unwrap();
+    let mut other = Vec::new();
+    bzimage::write_image(&mut other, b"unknown", bzimage::Codec::Gzip).unwrap();
+
+    let allowlist = HashSet::from([header.checksum_copy()]);
+    assert!(header.is_trusted(&allowlist));
+    BzImageHeader::read_if_trusted(Cursor::new(&good), &allowlist).unwrap();
+
+    let err = BzImageHeader::read_if_trusted(Cursor::new(&other), &allowlist).unwrap_err();
+    assert!(matches!(
+        err,
+        BzImageError::UntrustedChecksum(_)
+    ));
+
+    // a trusted header over a tampered payload is still rejected
+    let last = good.len() - 1;
+    good[last] ^= 0xff;
+    let err = BzImageHeader::read_if_trusted(Cursor::new(&good), &allowlist).unwrap_err();
+    assert!(matches!(
+        err,
+        BzImageError::ChecksumMismatch { .. }
+    ));
+}
+
+#[test]
+fn payload_checksum_of_file_streams_and_cross_checks() {
+    use bzimage::{
+        BzImageError, DigestAlgo, OnMismatch, TREE_BLOCK_SIZE, compute_checksum,
+        payload_checksum_of_file, payload_checksum_of_file_with,
+    };
+
+    let compressed: Vec<u8> = (0..TREE_BLOCK_SIZE * 2 + 5).map(|i| (i % 253) as u8).collect();
+    let mut header = BzImageHeader {
+        magic: *MAGIC,
+        version: VERSION.into(),
+        reserved1: 0u32.into(),
+        uncompressed_size: 0u64.into(),
+        compressed_size: (compressed.len() as u64).into(),
+        checksum: compute_checksum(DigestAlgo::Sha256Tree, &compressed),
+        reserved2: 0u32.into(),
+    };
+    header.set_digest_algo(DigestAlgo::Sha256Tree);
+
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    header.write_to(&mut file).unwrap();
+    file.write_all(&compressed).unwrap();
+    file.flush().unwrap();
+    let clean = payload_checksum_of_file(file.path()).unwrap();
+    assert!(clean.is_match());
+    assert_eq!(clean.computed, header.checksum_copy());
+
+    // corrupt one payload byte on disk; the mismatch comes back to the caller
+    file.seek(SeekFrom::Start(bzimage::HEADER_SIZE as u64 + 3)).unwrap();
+    file.write_all(&[0xff]).unwrap();
+    file.flush().unwrap();
+    let reported = payload_checksum_of_file(file.path()).unwrap();
+    assert!(!reported.is_match());
+    assert_eq!(reported.stored, header.checksum_copy());
+    assert_ne!(reported.computed, header.checksum_copy());
+
+    let err = payload_checksum_of_file_with(file.path(), OnMismatch::Error).unwrap_err();
+    match err {
+        BzImageError::ChecksumMismatch { actual, .. } => assert_eq!(actual, reported.computed),
+        other => panic!("unexpected error: {other:?}"),
+    }
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn decompress_stream_async_yields_verified_chunks() {
+    use bzimage::{BzImageError, Codec, STREAM_CHUNK_SIZE, decompress_stream_async};
+    use futures_util::StreamExt;
+
+    let data: Vec<u8> = (0..STREAM_CHUNK_SIZE * 3 + 11).map(|i| (i % 7) as u8).collect();
+    let mut image = Vec::new();
+    let header = bzimage::write_image(&mut image, &data, Codec::Gzip).unwrap();
+    let payload = image[bzimage::HEADER_SIZE..].to_vec();
+
+    let mut stream = Box::pin(decompress_stream_async(Cursor::new(payload.clone()), &header));
+    let mut out = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.unwrap();
+        assert!(chunk.len() <= STREAM_CHUNK_SIZE);
+        out.extend_from_slice(&chunk);
+    }
+    assert_eq!(out, data);
+
+    // flip a bit in the gzip trailer: decoding may succeed, but the checksum must not
+    let mut tampered = payload;
+    let last = tampered.len() - 1;
+    tampered[last] ^= 0x01;
+    let results: Vec<_> = decompress_stream_async(Cursor::new(tampered), &header)
+        .collect()
+        .await;
+    let err = results.into_iter().find_map(Result::err).expect("tampering not detected");
+    assert!(
+        matches!(err, BzImageError::ChecksumMismatch { .. })
+            || err.to_string().contains("decompressing"),
+        "unexpected error: {err:#}"
+    );
+}
+
+#[test]
+fn dedup_dir_links_identical_images() {
+    use bzimage::{Codec, dedup_dir};
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut image = Vec::new();
+    bzimage::write_image(&mut image, b"shared payload", Codec::Gzip).unwrap();
+    let mut other = Vec::new();
+    bzimage::write_image(&mut other, b"something else", Codec::Gzip).unwrap();
+    std::fs::write(dir.path().join("a.img"), &image).unwrap();
+    std::fs::write(dir.path().join("b.img"), &image).unwrap();
+    std::fs::write(dir.path().join("c.img"), &other).unwrap();
+    std::fs::write(dir.path().join("notes.txt"), b"not an image").unwrap();
+
+    let report = dedup_dir(dir.path()).unwrap();
+    assert_eq!(report.groups.len(), 1);
+    let group = &report.groups[0];
+    assert_eq!(group.original, dir.path().join("a.img"));
+    assert_eq!(group.linked, vec![dir.path().join("b.img")]);
+    assert_eq!(report.reclaimed_bytes, image.len() as u64);
+    assert_eq!(std::fs::read(dir.path().join("b.img")).unwrap(), image);
+
+    // a second pass finds nothing left to do
+    assert_eq!(dedup_dir(dir.path()).unwrap(), Default::default());
+}
+
+#[cfg(feature = "ffi")]
+#[test]
+fn ffi_parse_header_fills_native_struct() {
+    use bzimage::ffi::{
+        BZIMAGE_ERR_BAD_MAGIC, BZIMAGE_ERR_NULL, BZIMAGE_ERR_TRUNCATED, BZIMAGE_ERR_UNSUPPORTED,
+        BZIMAGE_OK, CBzImageHeader, bzimage_parse_header,
+    };
+
+    let mut image = Vec::new();
+    let header = bzimage::write_image(&mut image, b"called from C", bzimage::Codec::Gzip).unwrap();
+
+    let mut out = CBzImageHeader::default();
+    let rc = unsafe { bzimage_parse_header(image.as_ptr(), image.len(), &mut out) };
+    assert_eq!(rc, BZIMAGE_OK);
+    assert_eq!(out.magic, *MAGIC);
+    assert_eq!(out.version, VERSION);
+    assert_eq!(out.uncompressed_size, 13);
+    assert_eq!(out.checksum, header.checksum_copy());
+
+    let rc = unsafe { bzimage_parse_header(image.as_ptr(), 10, &mut out) };
+    assert_eq!(rc, BZIMAGE_ERR_TRUNCATED);
+    let rc = unsafe { bzimage_parse_header(std::ptr::null(), 64, &mut out) };
+    assert_eq!(rc, BZIMAGE_ERR_NULL);
+    image[0] = b'X';
+    let rc = unsafe { bzimage_parse_header(image.as_ptr(), image.len(), &mut out) };
+    assert_eq!(rc, BZIMAGE_ERR_BAD_MAGIC);
+
+    let mut newer = header;
+    newer.version = (VERSION + 1).into();
+    let bytes = newer.to_bytes();
+    let rc = unsafe { bzimage_parse_header(bytes.as_ptr(), bytes.len(), &mut out) };
+    assert_eq!(rc, BZIMAGE_ERR_UNSUPPORTED);
+
+    // other failures get codes of their own rather than TRUNCATED or UNSUPPORTED
+    let mut unknown_codec = header;
+    unknown_codec.reserved1 = ((header.reserved1() & !0xff) | 0xee).into();
+    let bytes = unknown_codec.to_bytes();
+    let rc = unsafe { bzimage_parse_header(bytes.as_ptr(), bytes.len(), &mut out) };
+    assert_eq!(rc, bzimage::ffi::BZIMAGE_ERR_UNKNOWN_CODEC);
+    let mut unknown_flag = header;
+    unknown_flag.set_flags(bzimage::BzImageFlags::from_bits_retain(1 << 14));
+    let bytes = unknown_flag.to_bytes();
+    let rc = unsafe { bzimage_parse_header(bytes.as_ptr(), bytes.len(), &mut out) };
+    assert_eq!(rc, bzimage::ffi::BZIMAGE_ERR_UNKNOWN_CRITICAL_FLAG);
+
+    let mut damaged = header;
+    damaged.set_header_crc().unwrap();
+    let mut bytes = damaged.to_bytes();
+    bytes[30] ^= 0x01;
+    let rc = unsafe { bzimage_parse_header(bytes.as_ptr(), bytes.len(), &mut out) };
+    assert_eq!(rc, bzimage::ffi::BZIMAGE_ERR_CORRUPT);
+}
+
+#[test]
+fn detached_payload_round_trip() {
+    use bzimage::{BzImageFlags, read_detached, write_detached};
+
+    let dir = tempfile::tempdir().unwrap();
+    let blobs = dir.path().join("blobs");
+    std::fs::create_dir(&blobs).unwrap();
+    let data = b"payload kept out of line".repeat(100);
+
+    let blob = blobs.join("payload.gz");
+    let index = dir.path().join("image.idx");
+    let header = write_detached(&index, &blob, &data).unwrap();
+    assert!(header.flags().contains(BzImageFlags::DETACHED_PAYLOAD));
+    assert_eq!(read_detached(&index, &blobs).unwrap(), data);
+
+    // the index cannot be read as an ordinary image
+    let bytes = std::fs::read(&index).unwrap();
+    assert!(BzImageHeader::read_header_and_payload(Cursor::new(&bytes)).is_err());
+
+    // a blob that does not match the header is rejected
+    std::fs::write(&blob, b"not the payload").unwrap();
+    assert!(read_detached(&index, &blobs).is_err());
+}
+
+#[test]
+fn error_display_strings_are_stable() {
+    use bzimage::{BzImageError, Codec};
+    use std::error::Error;
+
+    let io = || std::io::Error::new(std::io::ErrorKind::InvalidData, "corrupt deflate stream");
+    let cases = [
+        (
+            BzImageError::InvalidMagic { found: *b"BAD!" },
+            "invalid magic: expected DMNZ, found 0x42414421",
+        ),
+        (
+            BzImageError::TruncatedPayload { declared: 10, available: Some(4) },
+            "truncated payload: header declares 10 bytes, only 4 available",
+        ),
+        (
+            BzImageError::TruncatedPayload { declared: 10, available: None },
+            "truncated payload: header declares 10 bytes",
+        ),
+        (
+            BzImageError::SizeMismatch { declared: 10, actual: 9 },
+            "payload size mismatch: header declares 10 bytes, got 9",
+        ),
+        (
+            BzImageError::UncompressedSizeMismatch { declared: 3, actual: 300 },
+            "uncompressed size mismatch: header declares 3 bytes, decoded 300",
+        ),
+        (
+            BzImageError::OutputLimitExceeded { limit: 1024 },
+            "decompressed output exceeds the 1024 byte limit",
+        ),
+        (
+            BzImageError::ChecksumMismatch { expected: [0xaa; 32], actual: [0x01; 32] },
+            "checksum mismatch: expected aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa, \
+             computed 0101010101010101010101010101010101010101010101010101010101010101",
+        ),
+        (BzImageError::UnsupportedVersion(2), "unsupported format version 2"),
+        (BzImageError::UnknownCodec(9), "unknown codec 9"),
+        (
+            BzImageError::CodecNotEnabled(Codec::Gzip),
+            "codec Gzip is not enabled in this build",
+        ),
+        (BzImageError::UnknownDigestAlgo(7), "unknown digest algorithm 7"),
+        (BzImageError::UnknownCriticalFlag(0x0100), "unknown critical flags 0x0100"),
+        (
+            BzImageError::UntrustedChecksum([0u8; 32]),
+            "untrusted checksum 0000000000000000000000000000000000000000000000000000000000000000",
+        ),
+        (
+            BzImageError::TimedOut(std::time::Duration::from_millis(1500)),
+            "timed out after 1.5s",
+        ),
+        (
+            BzImageError::StreamTooLong { limit: 4096 },
+            "input exceeds the 4096 byte stream limit",
+        ),
+        (
+            BzImageError::PayloadTooLarge { declared: 5000, limit: 4096 },
+            "payload of 5000 bytes exceeds the 4096 byte limit",
+        ),
+        (BzImageError::TruncatedHeader, "truncated header: expected 64 bytes"),
+        (
+            BzImageError::InvalidLevel { level: 12, max: 9 },
+            "compression level 12 is above the maximum of 9",
+        ),
+        (
+            BzImageError::DetachedPayload,
+            "the payload is stored in a separate file; use read_detached",
+        ),
+        (
+            BzImageError::MissingSignature,
+            "image is flagged HAS_SIGNATURE but has no valid signature trailer",
+        ),
+        (BzImageError::Io(io()), "I/O error"),
+        (BzImageError::Decompression(io()), "decompression failed"),
+    ];
+    for (err, text) in &cases {
+        assert_eq!(err.to_string(), *text);
+    }
+
+    let err = BzImageError::Decompression(io());
+    assert_eq!(err.source().unwrap().to_string(), "corrupt deflate stream");
+    assert!(BzImageError::UnknownCodec(9).source().is_none());
+
+    // the typed error comes through parsing, and boxes like any other error
+    let err = BzImageHeader::read_from(Cursor::new(*b"BAD!")).unwrap_err();
+    assert!(matches!(
+        err,
+        BzImageError::InvalidMagic { found } if &found == b"BAD!"
+    ));
+    let boxed: Box<dyn Error + Send + Sync> = Box::new(BzImageError::UnknownCodec(9));
+    assert_eq!(boxed.to_string(), "unknown codec 9");
+}
+
+#[test]
+fn header_apis_return_typed_errors() {
+    use bzimage::{BzImageError, Codec};
+    use std::error::Error;
+
+    let mut image = Vec::new();
+    bzimage::write_image(&mut image, b"typed", Codec::Gzip).unwrap();
+    match BzImageHeader::read_from(Cursor::new(&image[..bzimage::HEADER_SIZE - 1])) {
+        Err(BzImageError::TruncatedHeader) => {}
+        other => panic!("unexpected result: {other:?}"),
+    }
+
+    assert!(matches!(
+        Codec::Gzip.compress_with_level(b"typed", Codec::MAX_LEVEL + 1),
+        Err(BzImageError::InvalidLevel { level: 10, max: 9 })
+    ));
+
+    // the underlying error is still chained behind the typed one
+    let header = BzImageHeader::read_from(Cursor::new(&image)).unwrap();
+    let err = header.write_to(&mut [0u8; 8][..]).unwrap_err();
+    match &err {
+        BzImageError::Io(e) => assert_eq!(e.kind(), std::io::ErrorKind::WriteZero),
+        other => panic!("unexpected error: {other:?}"),
+    }
+    assert!(err.source().is_some());
+
+    // and it still converts into anyhow for callers that use it
+    let err: anyhow::Error = BzImageHeader::read_from(Cursor::new(*b"BAD!")).unwrap_err().into();
+    assert!(matches!(err.downcast_ref::<BzImageError>(), Some(BzImageError::InvalidMagic { .. })));
+}
+
+#[test]
+fn store_cas_names_by_full_hash_and_rejects_corruption() {
+    let store = tempfile::tempdir().unwrap();
+    let mut image = Vec::new();
+    bzimage::write_image(&mut image, &b"content addressed ".repeat(50), bzimage::Codec::Gzip)
+        .unwrap();
+
+    let (digest, path) = bzimage::store_cas(Cursor::new(&image), store.path()).unwrap();
+    assert_eq!(digest, <[u8; 32]>::from(Sha256::digest(&image)));
+    assert_eq!(path.file_name().unwrap().to_str().unwrap().len(), 64);
+    assert_eq!(std::fs::read(&path).unwrap(), image);
+
+    let last = image.len() - 1;
+    image[last] ^= 0xff;
+    assert!(bzimage::store_cas(Cursor::new(&image), store.path()).is_err());
+    // nothing but the first image is left behind
+    assert_eq!(std::fs::read_dir(store.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn signature_trailer_is_skipped_by_readers() {
+    use bzimage::{Footer, append_footer, append_signature, read_footer, read_signature};
+
+    let mut image = Vec::new();
+    bzimage::write_image(&mut image, b"signed payload", bzimage::Codec::Gzip).unwrap();
+    let mut rw = Cursor::new(image);
+    append_signature(&mut rw, b"opaque signature bytes").unwrap();
+
+    let (header, compressed) = BzImageHeader::read_header_and_payload(Cursor::new(rw.get_ref())).unwrap();
+    assert!(header.has_signature());
+    header.validate_payload(&compressed).unwrap();
+    assert_eq!(read_footer(&mut rw).unwrap(), None);
+    assert_eq!(read_signature(&mut rw).unwrap().unwrap(), b"opaque signature bytes");
+
+    // adding a footer keeps the signature last
+    let mut meta = Footer::new();
+    meta.insert("build", "42");
+    let len = append_footer(&mut rw, &meta).unwrap();
+    rw.get_mut().truncate(len as usize);
+    assert_eq!(read_footer(&mut rw).unwrap(), Some(meta));
+    assert_eq!(read_signature(&mut rw).unwrap().unwrap(), b"opaque signature bytes");
+
+    // a payload cut short is not masked by the signature behind it
+    let mut short = Vec::new();
+    let header = bzimage::write_image(&mut short, &[1u8; 100], bzimage::Codec::Stored).unwrap();
+    short.truncate(short.len() - 10);
+    let mut rw = Cursor::new(short);
+    let mut flags = header.flags();
+    flags.insert(bzimage::BzImageFlags::HAS_SIGNATURE);
+    let mut flagged = header;
+    flagged.set_flags(flags);
+    flagged.write_to(&mut rw).unwrap();
+    rw.seek(SeekFrom::End(0)).unwrap();
+    rw.write_all(&[0u8; 40]).unwrap();
+    rw.write_all(&40u32.to_le_bytes()).unwrap();
+    rw.write_all(bzimage::SIGNATURE_MAGIC).unwrap();
+    rw.set_position(0);
+    assert!(BzImageHeader::read_header_and_payload(rw).is_err());
+}
+
+#[test]
+fn compress_data_round_trips_at_every_level() {
+    use bzimage::Codec;
+
+    let data = b"compressible compressible compressible ".repeat(64);
+    for codec in Codec::ALL.iter().copied() {
+        for level in 0..=Codec::MAX_LEVEL {
+            let compressed = BzImageHeader::compress_data(&data, codec, level).unwrap();
+            assert_eq!(BzImageHeader::decompress_data(&compressed, codec).unwrap(), data);
+        }
+    }
+    let fast = BzImageHeader::compress_data(&data, Codec::Gzip, 0).unwrap();
+    let best = BzImageHeader::compress_data(&data, Codec::Gzip, 9).unwrap();
+    assert!(best.len() < fast.len());
+    assert!(BzImageHeader::compress_data(&data, Codec::Gzip, 10).is_err());
+}
+
+#[test]
+fn read_verified_checks_uncompressed_size() {
+    use bzimage::{BzImageError, Codec};
+
+    let data = vec![b'z'; 300];
+    let mut image = Vec::new();
+    let mut header = bzimage::write_image(&mut image, &data, Codec::Gzip).unwrap();
+    let (_, decompressed) = BzImageHeader::read_verified(Cursor::new(&image)).unwrap();
+    assert_eq!(decompressed, data);
+
+    // understate the size; the payload and checksum are untouched
+    header.uncompressed_size = 3u64.into();
+    header.write_to(&mut image[..bzimage::HEADER_SIZE]).unwrap();
+    let err = BzImageHeader::read_verified(Cursor::new(&image)).unwrap_err();
+    match err {
+        BzImageError::UncompressedSizeMismatch { declared: 3, actual: 300 } => {}
+        other => panic!("unexpected error: {other:?}"),
+    }
+
+    header.uncompressed_size = 301u64.into();
+    header.write_to(&mut image[..bzimage::HEADER_SIZE]).unwrap();
+    let err = BzImageHeader::read_verified(Cursor::new(&image)).unwrap_err();
+    assert!(matches!(
+        err,
+        BzImageError::UncompressedSizeMismatch { declared: 301, actual: 300 }
+    ));
+}
+
+#[test]
+fn payload_progress_tracks_compressed_copy() {
+    use bzimage::{Footer, PayloadProgress, append_footer};
+
+    let mut rw = Cursor::new(Vec::new());
+    let header = bzimage::write_image(&mut rw, &[3u8; 1000], bzimage::Codec::Stored).unwrap();
+    let mut meta = Footer::new();
+    meta.insert("k", "v");
+    append_footer(&mut rw, &meta).unwrap();
+
+    rw.set_position(bzimage::HEADER_SIZE as u64);
+    let mut progress = PayloadProgress::new(rw, &header);
+    assert_eq!(progress.remaining(), 1000);
+    assert_eq!(progress.progress(), 0.0);
+
+    let mut buf = [0u8; 250];
+    progress.read_exact(&mut buf).unwrap();
+    assert_eq!(progress.remaining(), 750);
+    assert_eq!(progress.progress(), 0.25);
+
+    // the copy ends at the payload, before the footer
+    let mut rest = Vec::new();
+    progress.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest.len(), 750);
+    assert_eq!(progress.remaining(), 0);
+    assert_eq!(progress.progress(), 1.0);
+}
+
+#[test]
+fn progress_callbacks_are_spaced_and_end_at_the_full_size() {
+    use bzimage::PROGRESS_INTERVAL;
+
+    fn check(calls: &[(u64, u64)], total: u64) {
+        assert_eq!(calls.last(), Some(&(total, total)));
+        assert!(calls.iter().all(|&(_, t)| t == total));
+        // apart from the last, calls are at least PROGRESS_INTERVAL apart
+        let mut last = 0;
+        for &(done, _) in &calls[..calls.len() - 1] {
+            assert!(done - last >= PROGRESS_INTERVAL, "{calls:?}");
+            last = done;
+        }
+        assert!(calls.len() as u64 <= total / PROGRESS_INTERVAL + 1);
+    }
+
+    let data: Vec<u8> = (0..1_000_000u32).map(|i| (i % 253) as u8).collect();
+    let mut calls = Vec::new();
+    let mut image = Vec::new();
+    BzImageHeader::pack_with_progress(&data, &mut image, &mut |done, total| calls.push((done, total)))
+        .unwrap();
+    check(&calls, data.len() as u64);
+    assert!(calls.len() > 1);
+    assert_eq!(BzImageHeader::unpack(&mut &image[..]).unwrap(), data);
+
+    calls.clear();
+    let unpacked =
+        BzImageHeader::unpack_with_progress(&image[..], &mut |done, total| calls.push((done, total))).unwrap();
+    assert_eq!(unpacked, data);
+    check(&calls, data.len() as u64);
+
+    // an empty payload still gets its one final call
+    calls.clear();
+    let mut empty = Vec::new();
+    BzImageHeader::pack_with_progress(&[], &mut empty, &mut |done, total| calls.push((done, total))).unwrap();
+    assert_eq!(calls, [(0, 0)]);
+}
+
+#[test]
+fn all_zero_checksum_is_reported_as_absent() {
+    use bzimage::ChecksumStatus;
+
+    let data = [9u8; 64];
+    let header = bzimage::write_image(std::io::sink(), &data, bzimage::Codec::Stored).unwrap();
+    assert!(header.has_checksum());
+    assert_eq!(header.checksum_status(&data), ChecksumStatus::Valid);
+    assert_eq!(header.checksum_status(&[0u8; 64]), ChecksumStatus::Mismatch);
+
+    let mut unchecked = header;
+    unchecked.checksum = [0u8; 32];
+    assert!(!unchecked.has_checksum());
+    assert_eq!(unchecked.checksum_status(&data), ChecksumStatus::NoChecksumPresent);
+    assert!(unchecked.validate_payload(&data).is_err());
+}
+
+#[test]
+fn rewrite_header_leaves_payload_untouched() {
+    let mut rw = Cursor::new(Vec::new());
+    let header = bzimage::write_image(&mut rw, b"payload stays put", bzimage::Codec::Gzip).unwrap();
+    let before = rw.get_ref().clone();
+
+    let mut repaired = header;
+    repaired.set_uncompressed_crc(b"payload stays put").unwrap();
+    bzimage::rewrite_header(&mut rw, &repaired).unwrap();
+    assert_eq!(rw.position(), bzimage::HEADER_SIZE as u64);
+
+    let after = rw.into_inner();
+    assert_eq!(after.len(), before.len());
+    assert_eq!(after[bzimage::HEADER_SIZE..], before[bzimage::HEADER_SIZE..]);
+    let read_back = BzImageHeader::read_from(Cursor::new(&after)).unwrap();
+    assert!(read_back.validate_uncompressed_crc(b"payload stays put"));
+}
+
+#[cfg(feature = "cache")]
+#[test]
+fn decompress_cache_evicts_least_recently_used() {
+    use bzimage::{Codec, DecompressCache};
+    use std::sync::Arc;
+
+    let image = |fill: u8| {
+        let data = vec![fill; 100];
+        let mut out = Vec::new();
+        let header = bzimage::write_image(&mut out, &data, Codec::Gzip).unwrap();
+        (header, out[bzimage::HEADER_SIZE..].to_vec())
+    };
+    let (a, a_bytes) = image(1);
+    let (b, b_bytes) = image(2);
+    let (c, c_bytes) = image(3);
+
+    let cache = DecompressCache::new(250);
+    let first = cache.get_or_decompress(&a, &a_bytes).unwrap();
+    assert_eq!(*first, vec![1u8; 100]);
+    assert!(Arc::ptr_eq(&first, &cache.get_or_decompress(&a, &a_bytes).unwrap()));
+    cache.get_or_decompress(&b, &b_bytes).unwrap();
+
+    // `a` was used more recently than `b`, so `b` goes to make room for `c`
+    cache.get_or_decompress(&a, &a_bytes).unwrap();
+    cache.get_or_decompress(&c, &c_bytes).unwrap();
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.cached_bytes(), 200);
+    assert!(Arc::ptr_eq(&first, &cache.get_or_decompress(&a, &a_bytes).unwrap()));
+
+    // a corrupt payload is rejected rather than cached
+    let (d, mut d_bytes) = image(4);
+    d_bytes[0] ^= 0xff;
+    assert!(cache.get_or_decompress(&d, &d_bytes).is_err());
+    assert_eq!(cache.len(), 2);
+}
+
+#[test]
+fn split_reader_limits_payload_without_seeking() {
+    use bzimage::{Footer, append_footer};
+
+    let data = b"split reader payload".repeat(20);
+    let mut rw = Cursor::new(Vec::new());
+    bzimage::write_image(&mut rw, &data, bzimage::Codec::Gzip).unwrap();
+    let mut meta = Footer::new();
+    meta.insert("after", "payload");
+    append_footer(&mut rw, &meta).unwrap();
+    let bytes = rw.into_inner();
+
+    // a byte slice is `Read` but not `Seek`
+    let (header, mut payload) = BzImageHeader::split_reader(&bytes[..]).unwrap();
+    let mut compressed = Vec::new();
+    payload.read_to_end(&mut compressed).unwrap();
+    assert_eq!(payload.limit(), 0);
+    header.validate_payload(&compressed).unwrap();
+
+    // the rest of the input, the footer, is still there
+    let rest = payload.into_inner();
+    assert_eq!(rest, &meta.to_bytes()[..]);
+}
+
+#[test]
+fn read_from_accepts_forward_only_readers() {
+    use bzimage::{BzImageError, append_signature};
+
+    /// A pipe-like reader: `Read` only, a few bytes per call.
+    struct Pipe<'a>(&'a [u8]);
+    impl Read for Pipe<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(7);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    let data = b"straight off the wire ".repeat(30);
+    let mut rw = Cursor::new(Vec::new());
+    bzimage::write_image(&mut rw, &data, bzimage::Codec::Gzip).unwrap();
+    let image = rw.get_ref().clone();
+
+    let header = BzImageHeader::read_from(Pipe(&image)).unwrap();
+    assert_eq!(header.uncompressed_size(), data.len() as u64);
+    let (_, compressed) = BzImageHeader::read_header_and_payload(Pipe(&image)).unwrap();
+    header.validate_payload(&compressed).unwrap();
+    let (_, decompressed) = BzImageHeader::read_verified(Pipe(&image)).unwrap();
+    assert_eq!(decompressed, data);
+
+    let err = BzImageHeader::read_header_and_payload(Pipe(&image[..image.len() - 5])).unwrap_err();
+    assert!(matches!(err, BzImageError::TruncatedPayload { available: Some(_), .. }));
+
+    // a signed image reads the same, and a short payload still cannot borrow trailer bytes
+    append_signature(&mut rw, b"signed").unwrap();
+    let signed = rw.into_inner();
+    let (_, compressed) = BzImageHeader::read_header_and_payload(Pipe(&signed)).unwrap();
+    header.validate_payload(&compressed).unwrap();
+    let mut short = signed[..image.len() - 3].to_vec();
+    short.extend_from_slice(&signed[image.len()..]);
+    let err = BzImageHeader::read_header_and_payload(Pipe(&short)).unwrap_err();
+    let declared = header.compressed_size();
+    assert!(matches!(
+        err,
+        BzImageError::TruncatedPayload { declared: d, available: Some(a) } if d == declared && a == declared - 3
+    ));
+}
+
+#[test]
+fn bzimage_round_trips_owned_data() {
+    use bzimage::{BzImage, BzImageError, Codec};
+
+    let data = b"owned and loaded ".repeat(40);
+    let image = BzImage::from_data(&data).unwrap();
+    assert_eq!(image.header.uncompressed_size(), data.len() as u64);
+    assert_eq!(image.header.codec().unwrap(), Codec::Gzip);
+
+    let mut bytes = Vec::new();
+    let written = image.write(&mut bytes).unwrap();
+    assert_eq!(written.to_bytes(), image.header.to_bytes());
+    let read = BzImage::read(&mut Cursor::new(&bytes)).unwrap();
+    assert_eq!(read.data, data);
+    assert_eq!(read.header.to_bytes(), image.header.to_bytes());
+
+    // writing keeps the codec of the header it was read with
+    let mut stored = Vec::new();
+    bzimage::write_image(&mut stored, &data, Codec::Stored).unwrap();
+    let read = BzImage::read(&mut &stored[..]).unwrap();
+    let mut again = Vec::new();
+    assert_eq!(read.write(&mut again).unwrap().codec().unwrap(), Codec::Stored);
+    assert_eq!(again, stored);
+
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    let err = BzImage::read(&mut Cursor::new(&bytes)).unwrap_err();
+    assert!(matches!(err, BzImageError::ChecksumMismatch { .. }));
+}
+
+#[test]
+fn validate_checksum_rejects_any_single_byte_flip() {
+    let mut image = Vec::new();
+    let header = bzimage::write_image(&mut image, b"integrity token", bzimage::Codec::Gzip).unwrap();
+    let compressed = &image[bzimage::HEADER_SIZE..];
+    assert!(header.validate_checksum(compressed));
+
+    // a forged checksum is rejected wherever it differs, first byte to last
+    for i in 0..32 {
+        let mut forged = header;
+        let mut checksum = header.checksum_copy();
+        checksum[i] ^= 0x01;
+        forged.checksum = checksum;
+        assert!(!forged.validate_checksum(compressed), "flip at byte {i} accepted");
+    }
+}
+
+#[test]
+fn validate_checksum_streaming_reads_exactly_the_payload() {
+    use bzimage::{BzImageError, Codec};
+    use std::io::BufReader;
+
+    let data: Vec<u8> = (0..8u32 << 20).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+    let mut image = Vec::new();
+    let header = bzimage::write_image(&mut image, &data, Codec::Stored).unwrap();
+    image.extend_from_slice(b"trailing bytes");
+
+    let mut r = BufReader::with_capacity(512, &image[bzimage::HEADER_SIZE..]);
+    assert!(header.validate_checksum_streaming(&mut r).unwrap());
+    let mut rest = Vec::new();
+    r.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, b"trailing bytes");
+
+    let mut forged = header;
+    forged.checksum = [0x5a; 32];
+    let r = BufReader::with_capacity(512, &image[bzimage::HEADER_SIZE..]);
+    assert!(!forged.validate_checksum_streaming(r).unwrap());
+
+    let short = &image[bzimage::HEADER_SIZE..bzimage::HEADER_SIZE + 1000];
+    let err = header.validate_checksum_streaming(short).unwrap_err();
+    assert!(matches!(err, BzImageError::TruncatedPayload { available: Some(1000), .. }));
+}
+
+#[test]
+fn header_builder_validates_what_it_builds() {
+    use bzimage::{BzImageError, BzImageFlags, Codec};
+
+    let data = b"built, not written out by hand".repeat(8);
+    let compressed = Codec::Stored.compress(&data).unwrap();
+    let mut expected = BzImageHeader::new_for_payload(data.len() as u64, &compressed);
+    expected.set_codec(Codec::Stored);
+    expected.set_flags(BzImageFlags::HAS_FOOTER);
+
+    let complete = BzImageHeader::builder()
+        .uncompressed_size(data.len() as u64)
+        .compressed_size(compressed.len() as u64)
+        .checksum(expected.checksum_copy())
+        .compression(Codec::Stored)
+        .flags(BzImageFlags::HAS_FOOTER);
+    let header = complete.build().unwrap();
+    assert_eq!(header.to_bytes(), expected.to_bytes());
+    assert!(header.validate_payload(&compressed).is_ok());
+
+    let missing = |builder: bzimage::BzImageHeaderBuilder| match builder.build() {
+        Err(BzImageError::IncompleteHeader(field)) => field,
+        other => panic!("expected IncompleteHeader, got {other:?}"),
+    };
+    assert_eq!(missing(BzImageHeader::builder()), "uncompressed_size");
+    assert_eq!(missing(BzImageHeader::builder().uncompressed_size(1)), "compressed_size");
+    assert_eq!(missing(BzImageHeader::builder().uncompressed_size(1).compressed_size(1)), "checksum");
+    // a zeroed checksum is as good as none
+    assert_eq!(missing(complete.checksum([0; 32])), "checksum");
+
+    assert!(matches!(complete.version(VERSION + 1).build(), Err(BzImageError::UnsupportedVersion(v)) if v == VERSION + 1));
+    let unknown = BzImageFlags::from_bits_retain(0x8000);
+    assert!(matches!(complete.flags(unknown).build(), Err(BzImageError::UnknownCriticalFlag(0x8000))));
+
+    // a gzip stream is never empty
+    let empty = complete.compression(Codec::Gzip).compressed_size(0).uncompressed_size(0);
+    assert!(matches!(empty.build(), Err(BzImageError::MalformedHeader(_))));
+}
+
+#[test]
+fn header_builder_fills_or_refuses_reserved2_flags() {
+    use bzimage::{BzImageError, BzImageFlags, Codec};
+
+    let data = b"reserved2 is for one value only".repeat(4);
+    let compressed = Codec::Gzip.compress(&data).unwrap();
+    let builder = BzImageHeader::builder()
+        .uncompressed_size(data.len() as u64)
+        .compressed_size(compressed.len() as u64)
+        .checksum(BzImageHeader::new_for_payload(data.len() as u64, &compressed).checksum_copy());
+
+    // the header CRC is computed by build, over the finished header
+    for big_endian in [false, true] {
+        let header = builder.big_endian(big_endian).flags(BzImageFlags::HEADER_CRC32).build().unwrap();
+        assert!(header.verify_header_crc());
+        let mut image = header.to_bytes().to_vec();
+        image.extend_from_slice(&compressed);
+        assert_eq!(BzImageHeader::from_bytes(&image).unwrap(), header);
+        assert_eq!(BzImageHeader::unpack(&mut &image[..]).unwrap(), data);
+    }
+
+    // the builder has no value to store for these, so it refuses rather than store a zero
+    let missing = |flags: BzImageFlags| match builder.flags(flags).build() {
+        Err(BzImageError::IncompleteHeader(field)) => field,
+        other => panic!("expected IncompleteHeader, got {other:?}"),
+    };
+    assert_eq!(missing(BzImageFlags::UNCOMPRESSED_CRC32), "uncompressed_crc");
+    assert_eq!(missing(BzImageFlags::ZSTD_DICTIONARY), "dictionary_id");
+
+    // set on the built header, they round-trip through from_bytes
+    let mut header = builder.build().unwrap();
+    header.set_uncompressed_crc(&data).unwrap();
+    let parsed = BzImageHeader::from_bytes(&header.to_bytes()).unwrap();
+    assert_eq!(parsed.uncompressed_crc(), header.uncompressed_crc());
+    assert!(parsed.uncompressed_crc().is_some());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn header_round_trips_through_json() {
+    use bzimage::{BzImageFlags, Codec};
+
+    let mut header = bzimage::write_image(std::io::sink(), b"for the manifest", Codec::Stored).unwrap();
+    header.set_flags(BzImageFlags::HAS_FOOTER);
+    header.set_uncompressed_crc(b"for the manifest").unwrap();
+
+    let json = serde_json::to_value(header).unwrap();
+    assert_eq!(json["magic"], "DMNZ");
+    assert_eq!(json["version"], VERSION);
+    assert_eq!(json["compressed_size"], header.compressed_size());
+    let hex: String = header.checksum_copy().iter().map(|b| format!("{b:02x}")).collect();
+    assert_eq!(json["checksum"], hex);
+
+    let back: BzImageHeader = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(back.to_bytes(), header.to_bytes());
+
+    // a big-endian header keeps its order, and its header CRC, through JSON
+    let mut be = header;
+    be.set_flags(BzImageFlags::empty());
+    be.set_big_endian(true);
+    be.set_header_crc().unwrap();
+    let be_json = serde_json::to_value(be).unwrap();
+    assert_eq!(be_json["magic"], "ZNMD");
+    let back: BzImageHeader = serde_json::from_value(be_json).unwrap();
+    assert_eq!(back.to_bytes(), be.to_bytes());
+
+    let mut bad_magic = json.clone();
+    bad_magic["magic"] = "NOPE".into();
+    let err = serde_json::from_value::<BzImageHeader>(bad_magic).unwrap_err();
+    assert!(err.to_string().contains("invalid magic"), "{err}");
+
+    let mut bad_checksum = json.clone();
+    bad_checksum["checksum"] = "abc".into();
+    assert!(serde_json::from_value::<BzImageHeader>(bad_checksum).is_err());
+
+    let mut newer = json;
+    newer["version"] = (VERSION + 1).into();
+    let err = serde_json::from_value::<BzImageHeader>(newer).unwrap_err();
+    assert!(err.to_string().contains("unsupported format version"), "{err}");
+}
+
+#[test]
+fn header_display_and_summary_are_readable() {
+    let data = vec![b'z'; 1000];
+    let header = BzImageHeader::new_for_payload(data.len() as u64, &data[..250]);
+    let hex: String = header.checksum_copy().iter().map(|b| format!("{b:02x}")).collect();
+    assert_eq!(
+        header.to_string(),
+        format!("DMNZ v{VERSION} Gzip, 1000 -> 250 bytes (25.0%), checksum {hex}")
+    );
+
+    let summary = header.summary();
+    let lines: Vec<&str> = summary.lines().collect();
+    assert_eq!(lines[0], "magic: DMNZ");
+    assert_eq!(lines[1], format!("version: {VERSION}"));
+    assert!(lines.contains(&"compressed_size: 250"));
+    assert!(lines.contains(&format!("checksum: {hex}").as_str()));
+    assert_eq!(lines.last(), Some(&"reserved2: 0x00000000"));
+    assert!(!summary.contains('%'), "the ratio is left to stats: {summary}");
+
+    let empty = BzImageHeader::new_for_payload(0, &[]);
+    assert!(empty.to_string().contains("0 -> 0 bytes (n/a)"));
+}
+
+#[test]
+fn stats_report_ratio_and_space_saved() {
+    use bzimage::Stats;
+
+    let header = BzImageHeader::new_for_payload(1000, &[0u8; 250]);
+    assert_eq!(header.compression_ratio(), Some(4.0));
+    assert_eq!(header.space_saved(), Some(0.75));
+    assert_eq!(
+        header.stats(),
+        Stats {
+            uncompressed_size: 1000,
+            compressed_size: 250,
+            compression_ratio: Some(4.0),
+            space_saved: Some(0.75),
+        }
+    );
+
+    // a payload larger than the data saved negative space
+    let grown = BzImageHeader::new_for_payload(100, &[0u8; 125]);
+    assert_eq!(grown.space_saved(), Some(-0.25));
+
+    // nothing to divide by
+    let empty = BzImageHeader::new_for_payload(0, &[]);
+    assert_eq!(empty.compression_ratio(), None);
+    assert_eq!(empty.space_saved(), None);
+    let stats = empty.stats();
+    assert_eq!((stats.uncompressed_size, stats.compressed_size), (0, 0));
+    assert_eq!((stats.compression_ratio, stats.space_saved), (None, None));
+}
+
+#[test]
+fn decompress_verified_checks_the_declared_length() {
+    use bzimage::BzImageError;
+
+    let data = b"length matters ".repeat(64);
+    let compressed = bzimage::Codec::Gzip.compress(&data).unwrap();
+    let mut header = BzImageHeader::new_for_payload(data.len() as u64, &compressed);
+    assert_eq!(header.decompress_verified(&compressed).unwrap(), data);
+
+    for wrong in [data.len() as u64 - 1, data.len() as u64 + 1, 0] {
+        header.uncompressed_size = wrong.into();
+        // the checksum still matches; only the decoded length gives the header away
+        assert!(header.validate_checksum(&compressed));
+        let err = header.decompress_verified(&compressed).unwrap_err();
+        assert!(
+            matches!(err, BzImageError::UncompressedSizeMismatch { declared, actual }
+                if declared == wrong && actual == data.len() as u64),
+            "{err:?}"
+        );
+    }
+}
+
+#[test]
+fn decompress_to_streams_into_a_writer() {
+    use bzimage::{BzImageError, Codec};
+
+    let data = b"extract me straight to disk ".repeat(10_000);
+    for &codec in Codec::ALL {
+        let mut image = Vec::new();
+        let header = bzimage::write_image(&mut image, &data, codec).unwrap();
+        let compressed = &image[bzimage::HEADER_SIZE..];
+
+        let mut out = Vec::new();
+        let written = header.decompress_to(compressed, &mut out).unwrap();
+        assert_eq!(written, header.uncompressed_size());
+        assert_eq!(out, data);
+    }
+
+    let mut image = Vec::new();
+    let header = bzimage::write_image(&mut image, &data, Codec::Gzip).unwrap();
+    let err = header.decompress_to(&[0x42; 40], &mut std::io::sink()).unwrap_err();
+    assert!(matches!(err, BzImageError::Decompression(_)));
+    let mut small = [0u8; 16];
+    let err = header.decompress_to(&image[bzimage::HEADER_SIZE..], &mut &mut small[..]).unwrap_err();
+    assert!(matches!(err, BzImageError::Io(_)));
+}
+
+#[test]
+fn verify_file_reports_each_failure() {
+    use bzimage::{BzImageError, Codec, verify_file};
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut image = Vec::new();
+    bzimage::write_image(&mut image, &b"verify me ".repeat(100), Codec::Gzip).unwrap();
+    let good = dir.path().join("good.img");
+    std::fs::write(&good, &image).unwrap();
+    let report = verify_file(&good);
+    assert!(report.is_ok(), "{:?}", report.error);
+    assert!(bzimage::is_bzimage(&good));
+
+    let truncated = dir.path().join("truncated.img");
+    std::fs::write(&truncated, &image[..image.len() - 5]).unwrap();
+    let report = verify_file(&truncated);
+    assert!(report.header.is_some());
+    assert!(matches!(
+        report.error,
+        Some(BzImageError::TruncatedPayload { .. } | BzImageError::Decompression(_))
+    ));
+
+    let missing = verify_file(&dir.path().join("missing.img"));
+    assert!(missing.header.is_none() && !missing.is_ok());
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn verify_dir_checks_every_image() {
+    use bzimage::{Codec, verify_dir};
+
+    let dir = tempfile::tempdir().unwrap();
+    for i in 0..8u8 {
+        let mut image = Vec::new();
+        bzimage::write_image(&mut image, &[i; 500], Codec::Gzip).unwrap();
+        if i == 3 {
+            let last = image.len() - 1;
+            image[last] ^= 0xff;
+        }
+        std::fs::write(dir.path().join(format!("{i}.img")), &image).unwrap();
+    }
+    std::fs::write(dir.path().join("README"), b"not an image").unwrap();
+
+    let reports = verify_dir(dir.path()).unwrap();
+    assert_eq!(reports.len(), 8);
+    let failed: Vec<_> = reports.iter().filter(|(_, r)| !r.is_ok()).map(|(p, _)| p.clone()).collect();
+    assert_eq!(failed, vec![dir.path().join("3.img")]);
+}
+
+#[test]
+fn gzip_native_name_and_comment_round_trip() {
+    use bzimage::{gzip_meta, write_image_gzip_meta};
+
+    let data = b"kernel image bytes".repeat(10);
+    let mut image = Vec::new();
+    write_image_gzip_meta(&mut image, &data, Some("vmlinux"), Some("built by ci")).unwrap();
+    let (header, compressed) = BzImageHeader::read_header_and_payload(Cursor::new(&image)).unwrap();
+    header.validate_payload(&compressed).unwrap();
+    assert_eq!(
+        gzip_meta(&compressed),
+        (Some("vmlinux".to_string()), Some("built by ci".to_string()))
+    );
+    assert_eq!(BzImageHeader::decompress_data(&compressed, bzimage::Codec::Gzip).unwrap(), data);
+
+    let plain = bzimage::Codec::Gzip.compress(&data).unwrap();
+    assert_eq!(gzip_meta(&plain), (None, None));
+    assert_eq!(gzip_meta(b"not gzip at all"), (None, None));
+}
+
+#[test]
+fn validate_checksum_reader_hashes_chunked_input() {
+    let data = b"streamed through a reader".repeat(40);
+    let mut image = Vec::new();
+    let header = bzimage::write_image(&mut image, &data, bzimage::Codec::Gzip).unwrap();
+    let payload = &image[bzimage::HEADER_SIZE..];
+
+    assert!(header.validate_checksum_reader(OneByteAtATime(payload)).unwrap());
+    let mut flipped = payload.to_vec();
+    flipped[5] ^= 0x20;
+    assert!(!header.validate_checksum_reader(OneByteAtATime(&flipped[..])).unwrap());
+
+    let (_, payload) = BzImageHeader::split_reader(OneByteAtATime(&image[..])).unwrap();
+    assert!(header.validate_checksum_reader(payload).unwrap());
+}
+
+#[test]
+fn read_and_verify_header_discards_payload() {
+    use bzimage::BzImageError;
+
+    let mut image = Vec::new();
+    let written = bzimage::write_image(&mut image, &[0x5a; 4096], bzimage::Codec::Stored).unwrap();
+    let header = BzImageHeader::read_and_verify_header(&image[..]).unwrap();
+    assert_eq!(header.checksum_copy(), written.checksum_copy());
+
+    let err = BzImageHeader::read_and_verify_header(&image[..image.len() - 96]).unwrap_err();
+    assert!(matches!(
+        err,
+        BzImageError::TruncatedPayload { declared: 4096, available: Some(4000) }
+    ));
+
+    let last = image.len() - 1;
+    image[last] = 0;
+    let err = BzImageHeader::read_and_verify_header(&image[..]).unwrap_err();
+    assert!(matches!(
+        err,
+        BzImageError::ChecksumMismatch { .. }
+    ));
+}
+
+#[test]
+fn options_configure_writes_and_reads() {
+    use bzimage::{BzImageError, BzImageOptions, Codec, read_with_options, write_image_with_options};
+
+    let data = b"options options options ".repeat(100);
+    let defaults = BzImageOptions::default();
+    let mut image = Vec::new();
+    let header = write_image_with_options(&mut image, &data, &defaults).unwrap();
+    let mut plain = Vec::new();
+    bzimage::write_image(&mut plain, &data, Codec::Gzip).unwrap();
+    assert_eq!(image, plain, "defaults match write_image");
+    assert_eq!(header.codec().unwrap(), Codec::Gzip);
+
+    let stored = BzImageOptions::new().with_codec(Codec::Stored).with_verify(true);
+    let mut image = Vec::new();
+    write_image_with_options(&mut image, &data, &stored).unwrap();
+    let (_, read) = read_with_options(Cursor::new(&image), &stored).unwrap();
+    assert_eq!(read, data);
+
+    let limited = stored.with_max_uncompressed_size(Some(100));
+    let err = read_with_options(Cursor::new(&image), &limited).unwrap_err();
+    assert!(matches!(err, BzImageError::OutputLimitExceeded { limit: 100 }));
+    // without verification the header's size claim is not trusted, but the limit still holds
+    let err = read_with_options(Cursor::new(&image), &limited.with_verify(false)).unwrap_err();
+    assert!(matches!(err, BzImageError::OutputLimitExceeded { limit: 100 }));
+
+    // verification covers a recorded CRC-32 of the data, and needs no Seek
+    let mut header = BzImageHeader::read_from(&image[..]).unwrap();
+    header.set_uncompressed_crc(&data).unwrap();
+    header.write_to(&mut image[..bzimage::HEADER_SIZE]).unwrap();
+    assert_eq!(read_with_options(&image[..], &stored).unwrap().1, data);
+    image[60] ^= 0x01;
+    let err = read_with_options(&image[..], &stored).unwrap_err();
+    assert!(matches!(err, BzImageError::UncompressedCrcMismatch { .. }), "{err:?}");
+    assert_eq!(read_with_options(&image[..], &stored.with_verify(false)).unwrap().1, data);
+}
+
+#[test]
+fn extract_to_preallocated_writes_declared_length() {
+    use bzimage::{BzImageError, Codec, extract_to_preallocated};
+
+    let data = b"preallocated output ".repeat(500);
+    let mut image = Vec::new();
+    let header = bzimage::write_image(&mut image, &data, Codec::Gzip).unwrap();
+    let payload = &image[bzimage::HEADER_SIZE..];
+
+    let out = tempfile::tempfile().unwrap();
+    assert_eq!(extract_to_preallocated(&header, payload, &out).unwrap(), data.len() as u64);
+    let mut written = Vec::new();
+    (&out).seek(SeekFrom::Start(0)).unwrap();
+    (&out).read_to_end(&mut written).unwrap();
+    assert_eq!(written, data);
+
+    // a header that overstates the size leaves the file at the real length
+    let mut overstated = header;
+    overstated.uncompressed_size = (data.len() as u64 + 1000).into();
+    let out = tempfile::tempfile().unwrap();
+    let err = extract_to_preallocated(&overstated, payload, &out).unwrap_err();
+    assert!(matches!(err, BzImageError::UncompressedSizeMismatch { .. }));
+    assert_eq!(out.metadata().unwrap().len(), data.len() as u64);
+}
+
+#[test]
+fn try_decompress_recovers_mislabeled_payload() {
+    use bzimage::{Codec, try_decompress};
+
+    let data = b"mislabeled as stored".repeat(30);
+    let mut image = Vec::new();
+    let mut header = bzimage::write_image(&mut image, &data, Codec::Gzip).unwrap();
+    header.set_codec(Codec::Stored);
+    let payload = &image[bzimage::HEADER_SIZE..];
+
+    let (recovered, codec) = try_decompress(payload).unwrap();
+    assert_eq!(codec, Codec::Gzip);
+    assert_eq!(recovered, data);
+    assert_eq!(recovered.len() as u64, header.uncompressed_size());
+
+    assert!(try_decompress(b"definitely not compressed").is_err());
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn async_reads_time_out_on_stalled_peers() {
+    use bzimage::{BzImageError, Codec};
+    use std::time::Duration;
+
+    let mut image = Vec::new();
+    let header = bzimage::write_image(&mut image, b"over the network", Codec::Gzip).unwrap();
+    let (read_header, compressed) =
+        BzImageHeader::read_header_and_payload_async_timeout(&image[..], Duration::from_secs(5))
+            .await
+            .unwrap();
+    assert_eq!(read_header.checksum_copy(), header.checksum_copy());
+    read_header.validate_payload(&compressed).unwrap();
+
+    let mut written = Vec::new();
+    header.write_to_async_timeout(&mut written, Duration::from_secs(5)).await.unwrap();
+    assert_eq!(written, image[..bzimage::HEADER_SIZE]);
+
+    // a peer that sends half the header and then stalls
+    let (mut client, server) = tokio::io::duplex(1024);
+    tokio::io::AsyncWriteExt::write_all(&mut client, &image[..32]).await.unwrap();
+    let err = BzImageHeader::read_from_async_timeout(server, Duration::from_millis(50))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, BzImageError::TimedOut(_)));
+    drop(client);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn async_header_io_round_trips_over_a_duplex() {
+    use bzimage::{BzImageError, Codec};
+    use tokio::io::AsyncWriteExt;
+
+    let data = b"parsed without blocking the executor ".repeat(100);
+    let mut image = Vec::new();
+    let header = bzimage::write_image(&mut image, &data, Codec::Gzip).unwrap();
+
+    // a small buffer makes both ends wait on each other
+    let (mut client, server) = tokio::io::duplex(16);
+    let payload = image[bzimage::HEADER_SIZE..].to_vec();
+    let writer = tokio::spawn(async move {
+        header.write_to_async(&mut client).await.unwrap();
+        client.write_all(&payload).await.unwrap();
+    });
+    let (read, compressed) = BzImageHeader::read_header_and_payload_async(server).await.unwrap();
+    writer.await.unwrap();
+    assert_eq!(read.to_bytes(), header.to_bytes());
+    assert_eq!(compressed, &image[bzimage::HEADER_SIZE..]);
+    read.validate_payload(&compressed).unwrap();
+
+    // the same errors as the sync readers
+    let err = BzImageHeader::read_from_async(&b"BAD!"[..]).await.unwrap_err();
+    assert!(matches!(err, BzImageError::InvalidMagic { found } if &found == b"BAD!"));
+    let err = BzImageHeader::read_from_async(&image[..40]).await.unwrap_err();
+    assert!(matches!(err, BzImageError::TruncatedHeader));
+    let err = BzImageHeader::read_header_and_payload_async(&image[..image.len() - 4]).await.unwrap_err();
+    assert!(matches!(err, BzImageError::TruncatedPayload { available: Some(_), .. }));
+}
+
+#[test]
+fn upgrade_checksum_rehashes_only_intact_images() {
+    use bzimage::{BzImageError, Codec, DigestAlgo, compute_checksum, upgrade_checksum, write_image};
+
+    let mut image = Vec::new();
+    write_image(&mut image, b"upgrade me in place", Codec::Gzip).unwrap();
+    let payload = image[bzimage::HEADER_SIZE..].to_vec();
+
+    let header = upgrade_checksum(Cursor::new(&mut image), DigestAlgo::Sha256Tree).unwrap();
+    assert_eq!(header.digest_algo().unwrap(), DigestAlgo::Sha256Tree);
+    assert_eq!(header.checksum_copy(), compute_checksum(DigestAlgo::Sha256Tree, &payload));
+    assert_eq!(&image[bzimage::HEADER_SIZE..], &payload[..]);
+    let (read, compressed) = BzImageHeader::read_header_and_payload(Cursor::new(&image)).unwrap();
+    read.validate_payload(&compressed).unwrap();
+
+    // a corrupt payload is refused and the header is left alone
+    let last = image.len() - 1;
+    image[last] ^= 0xff;
+    let before = image.clone();
+    let err = upgrade_checksum(Cursor::new(&mut image), DigestAlgo::Sha256).unwrap_err();
+    assert!(matches!(err, BzImageError::ChecksumMismatch { .. }));
+    assert_eq!(image, before);
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn recompress_moves_images_between_codecs() {
+    use bzimage::{BzImageError, Codec, recompress};
+
+    let data = b"migrating the backlog to zstd ".repeat(200);
+    let mut gzip = Vec::new();
+    BzImageHeader::pack(&data, &mut gzip).unwrap();
+
+    let mut zstd = Vec::new();
+    let header = recompress(Cursor::new(&gzip), &mut zstd, Codec::Zstd, 6).unwrap();
+    assert_eq!(header.codec().unwrap(), Codec::Zstd);
+    assert_eq!(BzImageHeader::from_bytes(&zstd).unwrap().to_bytes(), header.to_bytes());
+    assert_eq!(BzImageHeader::unpack(&mut Cursor::new(&zstd)).unwrap(), data);
+
+    let mut back = Vec::new();
+    let header = recompress(Cursor::new(&zstd), &mut back, Codec::Gzip, Codec::MAX_LEVEL).unwrap();
+    assert_eq!(header.codec().unwrap(), Codec::Gzip);
+    assert_eq!(BzImageHeader::unpack(&mut Cursor::new(&back)).unwrap(), data);
+
+    // a damaged source is refused before anything is written
+    let last = gzip.len() - 1;
+    gzip[last] ^= 0xff;
+    let mut out = Vec::new();
+    let err = recompress(Cursor::new(&gzip), &mut out, Codec::Zstd, 6).unwrap_err();
+    assert!(matches!(err, BzImageError::ChecksumMismatch { .. }));
+    assert!(out.is_empty());
+}
+
+#[test]
+fn tee_writer_produces_image_and_raw_copy() {
+    use bzimage::{Codec, TeeWriter};
+
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    let mut tee = TeeWriter::new(Cursor::new(Vec::new()), Vec::new(), Codec::Gzip).unwrap();
+    for chunk in data.chunks(7000) {
+        tee.write_all(chunk).unwrap();
+    }
+    let (image, raw, header) = tee.finish().unwrap();
+    assert_eq!(raw, data);
+    assert_eq!(header.uncompressed_size(), data.len() as u64);
+
+    let (read, decompressed) = BzImageHeader::read_verified(Cursor::new(image.into_inner())).unwrap();
+    assert_eq!(read.checksum_copy(), header.checksum_copy());
+    assert_eq!(decompressed, data);
+}
+
+#[test]
+fn read_to_end_paths_stop_on_endless_input() {
+    use bzimage::{BzImageError, Codec, write_image};
+
+    let mut image = Vec::new();
+    let header = write_image(&mut image, b"finite", Codec::Gzip).unwrap();
+    let payload = &image[bzimage::HEADER_SIZE..];
+    assert!(header.validate_checksum_reader_limited(payload, 1 << 20).unwrap());
+
+    // std::io::repeat never reaches end of input
+    let err = header
+        .validate_checksum_reader_limited(std::io::repeat(0), 1 << 20)
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        BzImageError::StreamTooLong { limit } if limit == 1 << 20
+    ));
+}
+
+#[test]
+fn verify_reports_each_check_without_decompressing() {
+    use bzimage::{BzImageError, Codec, IntegrityReport};
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("checked.img");
+    let data = b"intact or not ".repeat(200);
+    let mut image = Vec::new();
+    let header = bzimage::write_image(&mut image, &data, Codec::Gzip).unwrap();
+    std::fs::write(&path, &image).unwrap();
+
+    let verify = |bytes: &[u8]| BzImageHeader::verify(bytes).unwrap();
+    let mut file = std::fs::File::open(&path).unwrap();
+    let report = BzImageHeader::verify(&mut file).unwrap();
+    assert_eq!(
+        report,
+        IntegrityReport {
+            magic_ok: true,
+            version_supported: true,
+            payload_complete: true,
+            checksum_ok: true,
+            uncompressed_size: data.len() as u64,
+            compressed_size: header.compressed_size(),
+        }
+    );
+    assert!(report.is_ok());
+
+    let mut corrupt = image.clone();
+    corrupt[bzimage::HEADER_SIZE + 5] ^= 0x20;
+    let report = verify(&corrupt);
+    assert!(report.payload_complete && !report.checksum_ok && !report.is_ok());
+
+    let report = verify(&image[..image.len() - 1]);
+    assert!(!report.payload_complete && !report.checksum_ok);
+
+    let mut newer = image.clone();
+    newer[4..8].copy_from_slice(&(VERSION + 1).to_le_bytes());
+    let report = verify(&newer);
+    assert!(report.magic_ok && !report.version_supported);
+    assert_eq!(report.compressed_size, header.compressed_size());
+
+    let mut foreign = image.clone();
+    foreign[..4].copy_from_slice(b"ELF\x7f");
+    assert!(!verify(&foreign).magic_ok);
+
+    assert!(matches!(BzImageHeader::verify(&image[..10]), Err(BzImageError::TruncatedHeader)));
+}
+
+#[test]
+fn verify_reads_big_endian_images() {
+    use bzimage::{Codec, verify_file};
+
+    let data = b"verified most significant byte first ".repeat(50);
+    let mut image = Vec::new();
+    let mut header = bzimage::write_image(&mut image, &data, Codec::Gzip).unwrap();
+    header.set_big_endian(true);
+    header.write_to(&mut image[..bzimage::HEADER_SIZE]).unwrap();
+
+    let report = BzImageHeader::verify(&image[..]).unwrap();
+    assert!(report.is_ok(), "{report:?}");
+    assert_eq!(report.uncompressed_size, data.len() as u64);
+    assert_eq!(report.compressed_size, header.compressed_size());
+
+    let mut newer = image.clone();
+    newer[4..8].copy_from_slice(&(VERSION + 1).to_be_bytes());
+    let report = BzImageHeader::verify(&newer[..]).unwrap();
+    assert!(report.magic_ok && !report.version_supported);
+    assert_eq!(report.uncompressed_size, data.len() as u64);
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("big-endian.img");
+    std::fs::write(&path, &image).unwrap();
+    let report = verify_file(&path);
+    assert!(report.is_ok(), "{:?}", report.error);
+    assert!(report.header.unwrap().is_big_endian());
+}
+
+#[test]
+fn scrub_reports_checksum_drift() {
+    use bzimage::{Codec, scrub, write_image};
+
+    let mut image = Vec::new();
+    let header = write_image(&mut image, b"scrub this on a schedule", Codec::Gzip).unwrap();
+    let clean = scrub(&image[..]).unwrap();
+    assert!(clean.is_clean());
+    assert_eq!(clean.drift(), None);
+    assert!(clean.to_string().starts_with("checksum ok: "));
+
+    // flip a bit in the stored payload, as rot on disk would
+    let last = image.len() - 1;
+    image[last] ^= 0x01;
+    let rotted = scrub(&image[..]).unwrap();
+    let (stored, computed) = rotted.drift().unwrap();
+    assert_eq!(stored, header.checksum_copy());
+    assert_ne!(computed, stored);
+    assert!(rotted.to_string().starts_with("checksum drift: stored "));
+
+    assert!(scrub(&image[..image.len() - 4]).is_err());
+}
+
+#[test]
+fn framed_images_read_back_to_back() {
+    use bzimage::{read_framed, write_framed};
+
+    let big = vec![7u8; 300_000];
+    let mut stream = Vec::new();
+    write_framed(&mut stream, b"first").unwrap();
+    write_framed(&mut stream, &big).unwrap();
+    write_framed(&mut stream, b"").unwrap();
+
+    let mut r = &stream[..];
+    let mut decoded = Vec::new();
+    while !r.is_empty() {
+        let (header, compressed) = read_framed(&mut r).unwrap();
+        header.validate_payload(&compressed).unwrap();
+        decoded.push(BzImageHeader::decompress_data(&compressed, header.codec().unwrap()).unwrap().len());
+    }
+    assert_eq!(decoded, vec![5, big.len(), 0]);
+    assert!(read_framed(&mut r).is_err());
+
+    // a frame cut short is an error, not a short image
+    assert!(read_framed(&stream[..20]).is_err());
+}
+
+#[test]
+fn compatibility_compares_format_versions() {
+    use bzimage::{Codec, Compatibility, write_image};
+
+    let mut image = Vec::new();
+    let mut header = write_image(&mut image, b"versioned", Codec::Gzip).unwrap();
+    assert_eq!(header.compatibility(), Compatibility::Current);
+
+    header.version = (VERSION + 1).into();
+    assert_eq!(header.compatibility(), Compatibility::Newer);
+    assert!(header.can_read().is_err());
+
+    header.version = (VERSION - 1).into();
+    assert_eq!(header.compatibility(), Compatibility::Older);
+}
+
+#[test]
+fn read_from_rejects_unsupported_versions() {
+    use bzimage::{BzImageError, Codec, SUPPORTED_VERSIONS, write_image};
+
+    assert!(BzImageHeader::is_version_supported(VERSION));
+    assert!(SUPPORTED_VERSIONS.contains(&VERSION));
+    assert!(!BzImageHeader::is_version_supported(0));
+    assert!(!BzImageHeader::is_version_supported(999));
+
+    let mut image = Vec::new();
+    let mut header = write_image(&mut image, b"from the future", Codec::Gzip).unwrap();
+    header.version = 999u32.into();
+    header.write_to(&mut image[..bzimage::HEADER_SIZE]).unwrap();
+
+    let err = BzImageHeader::read_from(Cursor::new(&image)).unwrap_err();
+    assert!(matches!(err, BzImageError::UnsupportedVersion(999)));
+    let err = BzImageHeader::read_header_and_payload(Cursor::new(&image)).unwrap_err();
+    assert!(matches!(err, BzImageError::UnsupportedVersion(999)));
+}
+
+#[test]
+fn header_bytes_roundtrip_without_io() {
+    use bzimage::{BzImageError, Codec, write_image};
+
+    let mut image = Vec::new();
+    let header = write_image(&mut image, b"in memory", Codec::Gzip).unwrap();
+    let bytes = header.to_bytes();
+    assert_eq!(bytes[..], image[..bzimage::HEADER_SIZE]);
+
+    let parsed = BzImageHeader::from_bytes(&bytes).unwrap();
+    assert_eq!(parsed.to_bytes(), bytes);
+    assert_eq!(parsed.compressed_size(), header.compressed_size());
+    assert_eq!(parsed.checksum_copy(), header.checksum_copy());
+
+    // the payload after the header is ignored
+    let parsed = BzImageHeader::from_bytes(&image).unwrap();
+    assert_eq!(parsed.to_bytes(), bytes);
+
+    assert!(matches!(
+        BzImageHeader::from_bytes(&bytes[..bzimage::HEADER_SIZE - 1]),
+        Err(BzImageError::TruncatedHeader)
+    ));
+    let mut bad = bytes;
+    bad[0] = b'X';
+    assert!(matches!(BzImageHeader::from_bytes(&bad), Err(BzImageError::InvalidMagic { .. })));
+}
+
+#[test]
+fn new_for_payload_keeps_header_and_payload_consistent() {
+    use bzimage::{Codec, DigestAlgo};
+
+    let data = b"consistent by construction".repeat(8);
+    let compressed = Codec::Gzip.compress(&data).unwrap();
+    let mut header = BzImageHeader::new_for_payload(data.len() as u64, &compressed);
+    assert_eq!(header.version(), VERSION);
+    assert_eq!(header.uncompressed_size(), data.len() as u64);
+    assert_eq!(header.compressed_size(), compressed.len() as u64);
+    assert_eq!(header.codec().unwrap(), Codec::Gzip);
+    assert!(header.validate_checksum(&compressed));
+
+    let mut image = Vec::new();
+    header.write_with_payload(&compressed, &mut image).unwrap();
+    let (read, payload) = BzImageHeader::read_verified(Cursor::new(&image)).unwrap();
+    assert_eq!(read.to_bytes(), header.to_bytes());
+    assert_eq!(payload, data);
+
+    // editing the payload afterwards cannot leave a stale checksum behind
+    let edited = Codec::Stored.compress(&data).unwrap();
+    header.set_codec(Codec::Stored);
+    header.set_digest_algo(DigestAlgo::Sha256Tree);
+    let mut image = Vec::new();
+    header.write_with_payload(&edited, &mut image).unwrap();
+    assert_eq!(header.compressed_size(), edited.len() as u64);
+    let (_, payload) = BzImageHeader::read_verified(Cursor::new(&image)).unwrap();
+    assert_eq!(payload, data);
+}
+
+#[test]
+fn compression_algorithm_dispatches_on_the_header() {
+    use bzimage::{BzImageError, CompressionAlgorithm};
+
+    let mut header = bzimage::write_image(std::io::sink(), b"x", bzimage::Codec::Gzip).unwrap();
+    header.set_codec(CompressionAlgorithm::Zstd);
+    assert_eq!(header.compression_algorithm().unwrap(), CompressionAlgorithm::Zstd);
+
+    header.reserved1 = 0x0000_0077u32.into();
+    let err = header.compression_algorithm().unwrap_err();
+    assert!(matches!(err, BzImageError::UnknownCodec(0x77)));
+
+    #[cfg(not(feature = "zstd"))]
+    {
+        let err = BzImageHeader::decompress_data(b"anything", CompressionAlgorithm::Zstd).unwrap_err();
+        assert!(matches!(
+            err,
+            BzImageError::CodecNotEnabled(CompressionAlgorithm::Zstd)
+        ));
+    }
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn zstd_payload_round_trips() {
+    use bzimage::{Codec, TrailingWriter, read_trailing_image, write_image};
+
+    let data = b"zstd zstd zstd zstd zstd ".repeat(400);
+    let mut image = Vec::new();
+    let header = write_image(&mut image, &data, Codec::Zstd).unwrap();
+    assert_eq!(&image[bzimage::HEADER_SIZE..bzimage::HEADER_SIZE + 4], &[0x28, 0xb5, 0x2f, 0xfd]);
+    assert_eq!(header.compression_algorithm().unwrap(), Codec::Zstd);
+    let (read, decompressed) = BzImageHeader::read_verified(Cursor::new(&image)).unwrap();
+    assert_eq!(read.codec().unwrap(), Codec::Zstd);
+    assert_eq!(decompressed, data);
+
+    // the streaming encoder produces the same format
+    let mut w = TrailingWriter::new(Vec::new(), Codec::Zstd).unwrap();
+    w.write_all(&data).unwrap();
+    let (trailing, _) = w.finish().unwrap();
+    let (header, compressed) = read_trailing_image(Cursor::new(trailing)).unwrap();
+    assert_eq!(BzImageHeader::decompress_data(&compressed, header.codec().unwrap()).unwrap(), data);
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn zstd_dictionary_round_trips_and_is_required() {
+    use bzimage::{BzImageError, BzImageFlags, Codec, dictionary_id};
+
+    let record = |i: u32| {
+        format!(r#"{{"id":{i},"kind":"sensor","unit":"celsius","reading":{},"ok":{}}}"#, i * 37 % 101, !i.is_multiple_of(3))
+    };
+    let samples: Vec<Vec<u8>> = (0..500).map(|i| record(i).into_bytes()).collect();
+    let dict = zstd::dict::from_samples(&samples, 4096).unwrap();
+
+    let data = record(9_001).into_bytes();
+    let mut image = Vec::new();
+    let header = BzImageHeader::pack_with_dict(&data, &dict, &mut image).unwrap();
+    assert!(header.flags().contains(BzImageFlags::ZSTD_DICTIONARY));
+    assert_eq!(header.dictionary_id(), Some(dictionary_id(&dict)));
+    assert_eq!(BzImageHeader::unpack_with_dict(&mut &image[..], &dict).unwrap(), data);
+
+    // the dictionary pays for itself on small, similar records
+    let mut plain = Vec::new();
+    BzImageHeader::pack_with(&data, Codec::Zstd, Codec::MAX_LEVEL, &mut plain).unwrap();
+    assert!(image.len() < plain.len(), "{} >= {}", image.len(), plain.len());
+
+    // without the dictionary, or with another one, nothing is decompressed
+    let err = BzImageHeader::unpack(&mut &image[..]).unwrap_err();
+    assert!(matches!(err, BzImageError::DictionaryRequired(id) if id == dictionary_id(&dict)), "{err:?}");
+    let other = zstd::dict::from_samples(&samples[..250], 2048).unwrap();
+    let err = BzImageHeader::unpack_with_dict(&mut &image[..], &other).unwrap_err();
+    assert!(matches!(err, BzImageError::DictionaryMismatch { .. }), "{err:?}");
+    let err = BzImageHeader::unpack_with_dict(&mut &plain[..], &dict).unwrap_err();
+    assert!(matches!(err, BzImageError::NoDictionary), "{err:?}");
+
+    // reserved2 holds the id, so it has no room for a header CRC
+    let mut header = header;
+    assert!(matches!(header.set_header_crc(), Err(BzImageError::Reserved2InUse)));
+}
+
+#[test]
+fn empty_payloads_round_trip() {
+    use bzimage::{BzImage, BzImageReader, Codec, write_image};
+
+    let mut image = Vec::new();
+    let header = BzImageHeader::pack(&[], &mut image).unwrap();
+    assert_eq!(header.uncompressed_size(), 0);
+    // the payload is gzip's stream for no data, which still has a header and trailer
+    let empty_gzip = Codec::Gzip.compress(&[]).unwrap();
+    assert!(!empty_gzip.is_empty());
+    assert_eq!(header.compressed_size(), empty_gzip.len() as u64);
+    assert_eq!(image.len(), bzimage::HEADER_SIZE + empty_gzip.len());
+
+    let (read, payload) = BzImageHeader::read_header_and_payload(&image[..]).unwrap();
+    assert_eq!(payload.len() as u64, header.compressed_size());
+    assert!(read.validate_checksum(&payload));
+    assert!(!read.validate_checksum(&[]));
+    assert_eq!(BzImageHeader::unpack(&mut &image[..]).unwrap(), Vec::<u8>::new());
+    assert!(BzImage::read(&mut &image[..]).unwrap().data.is_empty());
+    let mut streamed = Vec::new();
+    BzImageReader::new(&image[..]).unwrap().read_to_end(&mut streamed).unwrap();
+    assert!(streamed.is_empty());
+
+    // every codec, including one whose payload is empty too
+    for &codec in Codec::ALL {
+        let mut image = Vec::new();
+        let header = write_image(&mut image, &[], codec).unwrap();
+        assert_eq!(header.compressed_size() == 0, codec == Codec::Stored, "{codec:?}");
+        assert!(BzImageHeader::unpack(&mut &image[..]).unwrap().is_empty(), "{codec:?}");
+    }
+}
+
+#[test]
+fn zero_compressed_size_is_malformed_unless_the_payload_is_empty() {
+    use bzimage::{BzImageError, Codec, write_image};
+
+    // a gzip header claiming a 0-byte payload, with data after it anyway
+    let mut header = BzImageHeader::new_for_payload(0, &[]);
+    assert!(matches!(header.validate_header(), Err(BzImageError::MalformedHeader(_))));
+    let mut image = header.to_bytes().to_vec();
+    image.extend_from_slice(&Codec::Gzip.compress(b"hidden").unwrap());
+    // the header itself still parses
+    BzImageHeader::read_from(&image[..]).unwrap();
+    for err in [
+        BzImageHeader::read_header_and_payload(&image[..]).unwrap_err(),
+        BzImageHeader::unpack(&mut &image[..]).unwrap_err(),
+        BzImageHeader::split_reader(&image[..]).unwrap_err(),
+    ] {
+        assert!(matches!(err, BzImageError::MalformedHeader(_)), "{err:?}");
+    }
+
+    // no payload cannot hold data, whatever the codec
+    header.set_codec(Codec::Stored);
+    header.uncompressed_size = 6u64.into();
+    assert!(matches!(header.validate_header(), Err(BzImageError::MalformedHeader(_))));
+
+    // unlike a stored image of no data, which really is empty
+    let mut stored = Vec::new();
+    let header = write_image(&mut stored, &[], Codec::Stored).unwrap();
+    assert_eq!(header.compressed_size(), 0);
+    header.validate_header().unwrap();
+    assert!(BzImageHeader::unpack(&mut &stored[..]).unwrap().is_empty());
+    // and an empty gzip image, whose payload is not
+    let mut gzip = Vec::new();
+    BzImageHeader::pack(&[], &mut gzip).unwrap().validate_header().unwrap();
+}
+
+#[test]
+fn pack_and_unpack_round_trip() {
+    use bzimage::BzImageError;
+
+    let data = b"one call each way".repeat(20);
+    let mut image = Vec::new();
+    let header = BzImageHeader::pack(&data, &mut image).unwrap();
+    assert_eq!(header.uncompressed_size(), data.len() as u64);
+    assert_eq!(BzImageHeader::unpack(&mut Cursor::new(&image)).unwrap(), data);
+
+    // a flipped payload byte is a checksum failure
+    let mut corrupt = image.clone();
+    corrupt[bzimage::HEADER_SIZE + 12] ^= 0xff;
+    let err = BzImageHeader::unpack(&mut Cursor::new(&corrupt)).unwrap_err();
+    assert!(matches!(err, BzImageError::ChecksumMismatch { .. }));
+
+    // a well-hashed payload that is not gzip is a decompression failure
+    let mut garbage = Vec::new();
+    let mut header = BzImageHeader::pack(b"", &mut garbage).unwrap();
+    let bogus = vec![0x42u8; 40];
+    header.compressed_size = (bogus.len() as u64).into();
+    header.checksum = Sha256::digest(&bogus).into();
+    let mut image = Vec::new();
+    header.write_to(&mut image).unwrap();
+    image.extend_from_slice(&bogus);
+    let err = BzImageHeader::unpack(&mut Cursor::new(&image)).unwrap_err();
+    assert!(matches!(err, BzImageError::Decompression(_)));
+}
+
+#[test]
+fn detect_compression_sniffs_payload_magic() {
+    use bzimage::{Codec, detect_compression};
+
+    let gzip = Codec::Gzip.compress(b"sniffed").unwrap();
+    assert_eq!(detect_compression(&gzip), Some(Codec::Gzip));
+    assert_eq!(detect_compression(&[0x28, 0xb5, 0x2f, 0xfd, 0x00]), Some(Codec::Zstd));
+    assert_eq!(detect_compression(&[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00]), None);
+    assert_eq!(detect_compression(b"plain text"), None);
+    assert_eq!(detect_compression(&[0x1f]), None);
+    assert_eq!(detect_compression(&[]), None);
+}
+
+#[test]
+fn unpack_sniffs_the_codec_when_reserved1_is_zero() {
+    use bzimage::Codec;
+
+    let data = b"written by an older tool ".repeat(40);
+    #[cfg(feature = "zstd")]
+    let payload = Codec::Zstd.compress(&data).unwrap();
+    #[cfg(not(feature = "zstd"))]
+    let payload = [&[0x28, 0xb5, 0x2f, 0xfd][..], b"not really zstd"].concat();
+
+    // the header claims gzip, the codec old writers left at zero
+    let header = BzImageHeader::new_for_payload(data.len() as u64, &payload);
+    assert_eq!(header.reserved1(), 0);
+    let mut image = header.to_bytes().to_vec();
+    image.extend_from_slice(&payload);
+
+    let result = BzImageHeader::unpack(&mut Cursor::new(&image));
+    #[cfg(feature = "zstd")]
+    assert_eq!(result.unwrap(), data);
+    #[cfg(not(feature = "zstd"))]
+    assert!(matches!(result, Err(bzimage::BzImageError::CodecNotEnabled(Codec::Zstd))));
+
+    // gzip images with a zero reserved1 are unaffected
+    let mut image = Vec::new();
+    let header = bzimage::write_image(&mut image, &data, Codec::Gzip).unwrap();
+    assert_eq!(header.reserved1(), 0);
+    assert_eq!(BzImageHeader::unpack(&mut Cursor::new(&image)).unwrap(), data);
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn zstd_images_round_trip_through_unpack() {
+    use bzimage::Codec;
+
+    let data = b"kernel-sized, give or take ".repeat(2000);
+    let mut image = Vec::new();
+    let header = BzImageHeader::pack_with(&data, Codec::Zstd, 3, &mut image).unwrap();
+    assert_eq!(header.codec().unwrap(), Codec::Zstd);
+    let compressed = &image[bzimage::HEADER_SIZE..];
+    assert!(header.validate_checksum(compressed));
+    assert_eq!(BzImageHeader::decompress_data(compressed, Codec::Zstd).unwrap(), data);
+    assert_eq!(BzImageHeader::unpack(&mut Cursor::new(&image)).unwrap(), data);
+
+    // native zstd levels, including the fast negative ones
+    for level in [-5, 1, 19] {
+        let compressed = BzImageHeader::compress_zstd(&data, level).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(BzImageHeader::decompress_data(&compressed, Codec::Zstd).unwrap(), data);
+    }
+}
+
+#[test]
+fn gzip_levels_trade_speed_for_size_and_all_decode() {
+    use bzimage::{BzImageError, BzImageWriter, Codec};
+
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8 ^ (i / 997) as u8).collect();
+    let fast = BzImageHeader::compress_gzip(&data, 1).unwrap();
+    let best = BzImageHeader::compress_gzip(&data, 9).unwrap();
+    assert_ne!(fast, best);
+    for compressed in [&fast, &best] {
+        assert_eq!(BzImageHeader::decompress_data(compressed, Codec::Gzip).unwrap(), data);
+    }
+    // the default is still the strongest level
+    assert_eq!(Codec::Gzip.compress(&data).unwrap(), best);
+
+    let mut image = Vec::new();
+    BzImageHeader::pack_with(&data, Codec::Gzip, 1, &mut image).unwrap();
+    assert_eq!(&image[bzimage::HEADER_SIZE..], &fast[..]);
+    assert_eq!(BzImageHeader::unpack(&mut Cursor::new(&image)).unwrap(), data);
+
+    let mut writer = BzImageWriter::with_level(Cursor::new(Vec::new()), Codec::Gzip, 1).unwrap();
+    writer.write_all(&data).unwrap();
+    let (cur, header) = writer.finish().unwrap();
+    assert_eq!(&cur.get_ref()[bzimage::HEADER_SIZE..], &fast[..]);
+    assert!(header.validate_checksum(&fast));
+
+    let err = BzImageWriter::with_level(Cursor::new(Vec::new()), Codec::Gzip, 10).err().unwrap();
+    assert!(matches!(err, BzImageError::InvalidLevel { level: 10, .. }));
+}
+
+#[test]
+fn unpack_checks_a_recorded_uncompressed_crc() {
+    use bzimage::BzImageError;
+
+    let data = b"checked after decoding".repeat(30);
+    let mut image = Vec::new();
+    let mut header = BzImageHeader::pack(&data, &mut image).unwrap();
+    // pack leaves reserved2 free for a header CRC
+    assert_eq!(header.uncompressed_crc(), None);
+    header.set_uncompressed_crc(&data).unwrap();
+    header.write_to(&mut image[..bzimage::HEADER_SIZE]).unwrap();
+    assert_eq!(header.uncompressed_crc(), Some(crc32fast::hash(&data)));
+    let read = BzImageHeader::read_from(&image[..]).unwrap();
+    assert!(read.validate_uncompressed_crc(&data));
+    assert!(read.validate_uncompressed_checksum(&data));
+    assert!(!read.validate_uncompressed_checksum(&data[1..]));
+    assert_eq!(BzImageHeader::unpack(&mut Cursor::new(&image)).unwrap(), data);
+
+    // the payload checksum does not cover reserved2, so only the decoded data can tell
+    image[60] ^= 0x01;
+    let err = BzImageHeader::unpack(&mut Cursor::new(&image)).unwrap_err();
+    assert!(
+        matches!(err, BzImageError::UncompressedCrcMismatch { expected, actual }
+            if expected == crc32fast::hash(&data) ^ 0x01 && actual == crc32fast::hash(&data)),
+        "{err:?}"
+    );
+
+    // the streaming reader checks it once the payload ends, and so unpack_with_progress does
+    let mut out = Vec::new();
+    let err = bzimage::BzImageReader::new(Cursor::new(&image)).unwrap().read_to_end(&mut out).unwrap_err();
+    let inner = err.get_ref().and_then(|e| e.downcast_ref::<BzImageError>());
+    assert!(matches!(inner, Some(BzImageError::UncompressedCrcMismatch { .. })), "{err:?}");
+    let err = BzImageHeader::unpack_with_progress(Cursor::new(&image), &mut |_, _| {}).unwrap_err();
+    assert!(format!("{err:#}").contains("uncompressed CRC-32 mismatch"), "{err:#}");
+
+    #[cfg(feature = "mmap")]
+    {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("crc.img");
+        std::fs::write(&path, &image).unwrap();
+        let err = bzimage::BzImage::open_mmap(&path).unwrap().load().unwrap_err();
+        assert!(matches!(err, BzImageError::UncompressedCrcMismatch { .. }), "{err:?}");
+    }
+
+    // without the flag nothing is checked
+    let mut header = BzImageHeader::read_from(&image[..]).unwrap();
+    let mut flags = header.flags();
+    flags.remove(bzimage::BzImageFlags::UNCOMPRESSED_CRC32);
+    header.set_flags(flags);
+    header.write_to(&mut image[..bzimage::HEADER_SIZE]).unwrap();
+    assert_eq!(BzImageHeader::unpack(&mut Cursor::new(&image)).unwrap(), data);
+}
+
+#[test]
+fn bzimage_reader_streams_without_over_reading() {
+    use bzimage::{BzImageError, BzImageReader, Codec, write_image};
+
+    let mut state = 1u32;
+    let data: Vec<u8> = (0..3 << 20)
+        .map(|i| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            if i % 3 == 0 { (state >> 24) as u8 } else { b'a' }
+        })
+        .collect();
+    let mut stream = Vec::new();
+    write_image(&mut stream, &data, Codec::Gzip).unwrap();
+    stream.extend_from_slice(b"NEXT");
+
+    let mut reader = BzImageReader::new(&stream[..]).unwrap();
+    assert_eq!(reader.header().uncompressed_size(), data.len() as u64);
+    let mut out = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = reader.read(&mut buf).unwrap();
+        if n == 0 {
+            break;
+        }
+        out.extend_from_slice(&buf[..n]);
+    }
+    assert!(out == data);
+    assert_eq!(reader.into_inner(), b"NEXT");
+
+    // a corrupt payload surfaces on the final read
+    let mut stored = Vec::new();
+    write_image(&mut stored, &data[..10_000], Codec::Stored).unwrap();
+    stored[bzimage::HEADER_SIZE + 7] ^= 0xff;
+    let mut reader = BzImageReader::new(&stored[..]).unwrap();
+    let err = std::io::copy(&mut reader, &mut std::io::sink()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    let inner = err.get_ref().and_then(|e| e.downcast_ref::<BzImageError>());
+    assert!(matches!(inner, Some(BzImageError::ChecksumMismatch { .. })));
+    // and keeps failing instead of looking like a clean end
+    for _ in 0..2 {
+        let again = reader.read(&mut [0u8; 16]).unwrap_err();
+        assert_eq!(again.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(again.to_string(), err.to_string());
+    }
+}
+
+#[test]
+fn bzimage_writer_patches_the_header_on_finish() {
+    use bzimage::{BzImageWriter, Codec};
+
+    let data = b"written a piece at a time ".repeat(2000);
+    let mut w = BzImageWriter::new(Cursor::new(Vec::new()), Codec::Gzip).unwrap();
+    for chunk in data.chunks(333) {
+        w.write_all(chunk).unwrap();
+    }
+    let (cur, header) = w.finish().unwrap();
+    let image = cur.into_inner();
+    assert_eq!(header.uncompressed_size(), data.len() as u64);
+    assert_eq!(
+        header.compressed_size(),
+        (image.len() - bzimage::HEADER_SIZE) as u64
+    );
+
+    let (read, decompressed) = BzImageHeader::read_verified(Cursor::new(&image)).unwrap();
+    assert_eq!(read.checksum_copy(), header.checksum_copy());
+    assert_eq!(decompressed, data);
+}
+
+#[test]
+fn decompress_limited_stops_decompression_bombs() {
+    use bzimage::{BzImageError, Codec, write_image};
+
+    // 16 MiB of zeros compresses to a few kilobytes
+    let zeros = vec![0u8; 16 << 20];
+    let mut image = Vec::new();
+    let mut header = write_image(&mut image, &zeros, Codec::Gzip).unwrap();
+    let compressed = &image[bzimage::HEADER_SIZE..];
+    assert!(compressed.len() < 64 << 10);
+
+    let limit = 1 << 20;
+    let err = BzImageHeader::decompress_data_limited(compressed, Codec::Gzip, limit).unwrap_err();
+    assert!(matches!(
+        err,
+        BzImageError::OutputLimitExceeded { limit: l } if l == limit as u64
+    ));
+    assert_eq!(
+        BzImageHeader::decompress_data_limited(compressed, Codec::Gzip, 16 << 20).unwrap().len(),
+        16 << 20
+    );
+
+    // a header that understates the expansion is caught against uncompressed_size
+    header.uncompressed_size = 1000u64.into();
+    let err = header.decompress_limited(compressed, 32 << 20).unwrap_err();
+    assert!(matches!(
+        err,
+        BzImageError::UncompressedSizeMismatch { declared: 1000, .. }
+    ));
+}
+
+#[test]
+fn absurd_compressed_size_fails_without_allocating() {
+    use bzimage::{BzImageError, Codec, write_image};
+
+    let mut image = Vec::new();
+    let mut header = write_image(&mut image, b"tiny", Codec::Gzip).unwrap();
+    header.compressed_size = u64::MAX.into();
+    let mut absurd = Vec::new();
+    header.write_to(&mut absurd).unwrap();
+    absurd.extend_from_slice(&image[bzimage::HEADER_SIZE..]);
+
+    let err = BzImageHeader::read_header_and_payload_limited(Cursor::new(&absurd), 1 << 20)
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        BzImageError::PayloadTooLarge { declared: u64::MAX, limit: 1048576 }
+    ));
+
+    // the unbounded read reports the truncation instead of trying to allocate u64::MAX bytes
+    let err = BzImageHeader::read_header_and_payload(Cursor::new(&absurd)).unwrap_err();
+    assert!(matches!(
+        err,
+        BzImageError::TruncatedPayload { declared: u64::MAX, .. }
+    ));
+
+    let (_, compressed) =
+        BzImageHeader::read_header_and_payload_limited(Cursor::new(&image), 1 << 20).unwrap();
+    assert_eq!(compressed, &image[bzimage::HEADER_SIZE..]);
+}
+
+#[test]
+fn framed_reader_stops_at_the_corrupt_chunk() {
+    use bzimage::{BzImageError, BzImageFlags, BzImageReader, Codec, FramedReader, FramedWriter};
+
+    let data: Vec<u8> = (0..3000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let mut w = FramedWriter::with_chunk_size(Cursor::new(Vec::new()), Codec::Gzip, 1000).unwrap();
+    w.write_all(&data).unwrap();
+    let (cursor, header) = w.finish().unwrap();
+    let image = cursor.into_inner();
+    assert!(header.flags().contains(BzImageFlags::STREAMING_FRAMED));
+    assert_eq!(header.uncompressed_size(), 3000);
+
+    // every decoding path understands the chunked payload
+    let mut reader = FramedReader::new(Cursor::new(&image)).unwrap();
+    let mut chunks = Vec::new();
+    while let Some(chunk) = reader.next_chunk().unwrap() {
+        chunks.push(chunk);
+    }
+    assert_eq!(chunks.len(), 3);
+    assert_eq!(chunks.concat(), data);
+    assert_eq!(BzImageHeader::unpack(&mut Cursor::new(&image)).unwrap(), data);
+    let mut streamed = Vec::new();
+    BzImageReader::new(Cursor::new(&image)).unwrap().read_to_end(&mut streamed).unwrap();
+    assert_eq!(streamed, data);
+
+    // damage the data of the middle chunk
+    let first = u32::from_le_bytes(image[bzimage::HEADER_SIZE..][..4].try_into().unwrap());
+    let middle = bzimage::HEADER_SIZE + 40 + first as usize;
+    let mut corrupt = image.clone();
+    corrupt[middle + 40 + 5] ^= 0xff;
+
+    let mut reader = FramedReader::new(Cursor::new(&corrupt)).unwrap();
+    assert_eq!(reader.next_chunk().unwrap().unwrap(), &data[..1000]);
+    assert!(matches!(
+        reader.next_chunk(),
+        Err(BzImageError::ChunkChecksumMismatch { index: 1 })
+    ));
+    assert!(reader.next_chunk().unwrap().is_none());
+
+    // a plain image is refused
+    let mut plain = Vec::new();
+    BzImageHeader::pack(&data, &mut plain).unwrap();
+    assert!(matches!(FramedReader::new(Cursor::new(&plain)), Err(BzImageError::NotFramed)));
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn pack_parallel_output_does_not_depend_on_thread_count() {
+    use bzimage::{Codec, DEFAULT_CHUNK_SIZE, FramedReader, FramedWriter};
+
+    // two full chunks and a partial one
+    let data: Vec<u8> = (0..(2 * DEFAULT_CHUNK_SIZE + 12_345) as u32)
+        .map(|i| (i.wrapping_mul(2_654_435_761) >> 27) as u8)
+        .collect();
+    let images: Vec<Vec<u8>> = [1, 2, 8]
+        .into_iter()
+        .map(|threads| {
+            let mut image = Vec::new();
+            BzImageHeader::pack_parallel(&data, threads, &mut image).unwrap();
+            image
+        })
+        .collect();
+    assert_eq!(images[0], images[1]);
+    assert_eq!(images[0], images[2]);
+
+    let image = &images[0];
+    assert_eq!(BzImageHeader::unpack(&mut &image[..]).unwrap(), data);
+    let mut reader = FramedReader::new(&image[..]).unwrap();
+    let mut chunks = 0;
+    while reader.next_chunk().unwrap().is_some() {
+        chunks += 1;
+    }
+    assert_eq!(chunks, 3);
+
+    // the same bytes the sequential writer produces
+    let mut w = FramedWriter::new(Cursor::new(Vec::new()), Codec::Gzip).unwrap();
+    w.write_all(&data).unwrap();
+    assert_eq!(&w.finish().unwrap().0.into_inner(), image);
+}
+
+#[test]
+fn iter_walks_concatenated_images() {
+    use bzimage::{BzImageError, BzImageIter, Codec, write_image};
+
+    let mut archive = Vec::new();
+    let mut expected = Vec::new();
+    for (data, codec) in [(&b"first"[..], Codec::Gzip), (&[7u8; 5000][..], Codec::Stored), (&b""[..], Codec::Gzip)] {
+        let offset = archive.len() as u64;
+        let header = write_image(&mut archive, data, codec).unwrap();
+        expected.push((header, offset));
+    }
+
+    let found: Vec<_> = BzImageIter::new(Cursor::new(&archive)).map(Result::unwrap).collect();
+    assert_eq!(found.len(), 3);
+    for ((header, offset), (want, want_offset)) in found.iter().zip(&expected) {
+        assert_eq!(offset, want_offset);
+        assert_eq!(header.uncompressed_size(), want.uncompressed_size());
+        assert_eq!(header.compressed_size(), want.compressed_size());
+    }
+    assert_eq!(found[1].0.uncompressed_size(), 5000);
+
+    // a partial header after the last image is an error, and ends the iteration
+    archive.extend_from_slice(&bzimage::MAGIC[..]);
+    let mut iter = BzImageIter::new(Cursor::new(&archive));
+    assert_eq!(iter.by_ref().take(3).filter(Result::is_ok).count(), 3);
+    assert!(matches!(iter.next(), Some(Err(BzImageError::TruncatedHeader))));
+    assert!(iter.next().is_none());
+
+    // so is a payload cut short
+    let cut = &archive[..expected[1].1 as usize + bzimage::HEADER_SIZE + 10];
+    let results: Vec<_> = BzImageIter::new(Cursor::new(cut)).collect();
+    assert_eq!(results.len(), 2);
+    assert!(matches!(results[1], Err(BzImageError::TruncatedPayload { declared: 5000, available: Some(10) })));
+}
+
+#[test]
+fn overwrite_at_patches_a_placeholder_header() {
+    use bzimage::Codec;
+
+    // an image after some unrelated leading bytes, as inside a container
+    let mut out = Cursor::new(b"prefix".to_vec());
+    let offset = out.seek(SeekFrom::End(0)).unwrap();
+    out.write_all(&[0u8; bzimage::HEADER_SIZE]).unwrap();
+    let data = b"streamed after the placeholder".repeat(20);
+    let compressed = Codec::Gzip.compress(&data).unwrap();
+    for piece in compressed.chunks(16) {
+        out.write_all(piece).unwrap();
+    }
+    let end = out.stream_position().unwrap();
+
+    let header = BzImageHeader::new_for_payload(data.len() as u64, &compressed);
+    header.overwrite_at(&mut out, offset).unwrap();
+    assert_eq!(out.stream_position().unwrap(), end);
+
+    let image = out.into_inner();
+    assert_eq!(&image[..6], b"prefix");
+    assert_eq!(BzImageHeader::unpack(&mut &image[6..]).unwrap(), data);
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn encrypted_images_need_the_right_key_and_an_untouched_payload() {
+    use bzimage::{BzImageError, BzImageFlags, NONCE_SIZE};
+
+    let key = [0x42u8; 32];
+    let data = b"confidential payload ".repeat(50);
+    let mut image = Vec::new();
+    let header = BzImageHeader::pack_encrypted(&data, &key, &mut image).unwrap();
+    assert!(header.flags().contains(BzImageFlags::ENCRYPTED));
+    assert_eq!(header.uncompressed_size(), data.len() as u64);
+    // the checksum covers the nonce and ciphertext, so damage is found without the key
+    assert!(header.validate_checksum(&image[bzimage::HEADER_SIZE..]));
+    assert!(!image.windows(12).any(|w| w == &data[..12]));
+
+    assert_eq!(BzImageHeader::unpack_encrypted(&mut &image[..], &key).unwrap(), data);
+    // each image gets its own nonce
+    let mut again = Vec::new();
+    BzImageHeader::pack_encrypted(&data, &key, &mut again).unwrap();
+    assert_ne!(again[bzimage::HEADER_SIZE..][..NONCE_SIZE], image[bzimage::HEADER_SIZE..][..NONCE_SIZE]);
+
+    let wrong = [0x43u8; 32];
+    assert!(matches!(
+        BzImageHeader::unpack_encrypted(&mut &image[..], &wrong),
+        Err(BzImageError::AuthenticationFailed)
+    ));
+
+    // tampering that also fixes up the checksum is caught by the tag
+    let mut payload = image[bzimage::HEADER_SIZE..].to_vec();
+    let last = payload.len() - 20;
+    payload[last] ^= 1;
+    let mut tampered = Vec::new();
+    let mut fixed = header;
+    fixed.write_with_payload(&payload, &mut tampered).unwrap();
+    assert!(matches!(
+        BzImageHeader::unpack_encrypted(&mut &tampered[..], &key),
+        Err(BzImageError::AuthenticationFailed)
+    ));
+
+    // plain readers refuse rather than decompress ciphertext
+    assert!(matches!(BzImageHeader::unpack(&mut &image[..]), Err(BzImageError::Encrypted)));
+    let mut plain = Vec::new();
+    BzImageHeader::pack(&data, &mut plain).unwrap();
+    assert!(matches!(
+        BzImageHeader::unpack_encrypted(&mut &plain[..], &key),
+        Err(BzImageError::NotEncrypted)
+    ));
+}
+
+#[test]
+fn signed_images_detect_tampering_that_fixes_up_the_checksum() {
+    use bzimage::{BzImageError, Codec, HMAC_TAG_SIZE, read_signature};
+
+    let key = b"shared secret";
+    let data = b"authentic payload".repeat(30);
+    let mut image = Vec::new();
+    let header = BzImageHeader::pack_signed(&data, key, &mut image).unwrap();
+    assert_eq!(BzImageHeader::unpack_signed(&mut Cursor::new(&image), key).unwrap(), data);
+    // readers without the key see an ordinary image with a signature trailer
+    assert_eq!(BzImageHeader::unpack(&mut &image[..]).unwrap(), data);
+    let tag = read_signature(Cursor::new(&image)).unwrap().unwrap();
+    assert_eq!(tag.len(), HMAC_TAG_SIZE);
+    let compressed = &image[bzimage::HEADER_SIZE..][..header.compressed_size() as usize];
+    assert!(header.verify_signature(key, &[], compressed, &tag));
+    assert!(!header.verify_signature(b"other secret", &[], compressed, &tag));
+
+    assert!(matches!(
+        BzImageHeader::unpack_signed(&mut Cursor::new(&image), b"other secret"),
+        Err(BzImageError::AuthenticationFailed)
+    ));
+
+    // swap in a different payload with a correct checksum, keeping the old tag
+    let forged = Codec::Gzip.compress(b"malicious payload").unwrap();
+    let mut tampered_header = header;
+    let mut tampered = Vec::new();
+    tampered_header.write_with_payload(&forged, &mut tampered).unwrap();
+    tampered.extend_from_slice(&image[image.len() - HMAC_TAG_SIZE - 8..]);
+    assert!(tampered_header.validate_checksum(&forged));
+    assert!(!tampered_header.verify_signature(key, &[], &forged, &tag));
+    assert!(matches!(
+        BzImageHeader::unpack_signed(&mut Cursor::new(&tampered), key),
+        Err(BzImageError::AuthenticationFailed)
+    ));
+
+    let mut plain = Vec::new();
+    BzImageHeader::pack(&data, &mut plain).unwrap();
+    assert!(matches!(
+        BzImageHeader::unpack_signed(&mut Cursor::new(&plain), key),
+        Err(BzImageError::NotSigned)
+    ));
+}
+
+#[test]
+fn signed_images_cover_the_extended_header() {
+    use bzimage::{BzImageError, BzImageFlags, Codec, ExtendedHeader, HMAC_TAG_SIZE};
+
+    let key = b"shared secret";
+    let data = b"payload behind a signed extended header".repeat(10);
+    let compressed = Codec::Gzip.compress(&data).unwrap();
+    let mut extended = ExtendedHeader::new();
+    extended.set_metadata("release.bin", 1_700_000_000);
+    let region = extended.to_bytes().unwrap();
+
+    let mut header = BzImageHeader::new_for_payload(data.len() as u64, &compressed);
+    header.set_flags(header.flags() | BzImageFlags::HAS_EXTENDED_HEADER);
+    let tag = header.sign(key, &region, &compressed);
+    let mut image = header.to_bytes().to_vec();
+    image.extend_from_slice(&region);
+    image.extend_from_slice(&compressed);
+    image.extend_from_slice(&tag);
+    image.extend_from_slice(&(HMAC_TAG_SIZE as u32).to_le_bytes());
+    image.extend_from_slice(b"DMNS");
+    assert_eq!(BzImageHeader::unpack_signed(&mut Cursor::new(&image), key).unwrap(), data);
+    assert!(!header.verify_signature(key, &[], &compressed, &tag));
+
+    // rename the file in the extended header without touching the header or payload
+    let at = bzimage::HEADER_SIZE + region.windows(7).position(|w| w == b"release").unwrap();
+    image[at] = b'R';
+    assert!(matches!(
+        BzImageHeader::unpack_signed(&mut Cursor::new(&image), key),
+        Err(BzImageError::AuthenticationFailed)
+    ));
+}
+
+#[test]
+fn extended_header_round_trips_a_non_ascii_file_name() {
+    use bzimage::{BzImage, BzImageError, BzImageFlags, ExtendedHeader};
+
+    let mut image = BzImage::from_data(b"payload behind an extended header").unwrap();
+    image.set_metadata("résumé – 履歴書.txt", 1_700_000_000);
+    let mut bytes = Vec::new();
+    let header = image.write(&mut bytes).unwrap();
+    assert!(header.flags().contains(BzImageFlags::HAS_EXTENDED_HEADER));
+
+    let read = BzImage::read(&mut &bytes[..]).unwrap();
+    assert_eq!(read.metadata(), Some(("résumé – 履歴書.txt", 1_700_000_000)));
+    assert_eq!(read.data, image.data);
+
+    // the other readers skip the region to find the payload
+    assert_eq!(BzImageHeader::unpack(&mut &bytes[..]).unwrap(), image.data);
+    let parsed = BzImageHeader::read_from(&bytes[..]).unwrap();
+    let extended = parsed.read_extended(&bytes[bzimage::HEADER_SIZE..]).unwrap().unwrap();
+    assert_eq!(extended.name(), Some("résumé – 履歴書.txt"));
+    let (_, region_len) = ExtendedHeader::from_bytes(&bytes[bzimage::HEADER_SIZE..]).unwrap();
+    assert!(parsed.validate_checksum(&bytes[bzimage::HEADER_SIZE + region_len..]));
+
+    // an image without metadata has no region at all
+    let mut plain = Vec::new();
+    BzImage::from_data(b"plain").unwrap().write(&mut plain).unwrap();
+    assert!(!BzImageHeader::read_from(&plain[..]).unwrap().flags().contains(BzImageFlags::HAS_EXTENDED_HEADER));
+    assert!(BzImage::read(&mut &plain[..]).unwrap().metadata().is_none());
+
+    let cut = &bytes[..bzimage::HEADER_SIZE + 6];
+    assert!(matches!(BzImageHeader::unpack(&mut &cut[..]), Err(BzImageError::InvalidExtendedHeader(_))));
+}
+
+#[test]
+fn extended_header_metadata_map() {
+    use bzimage::{BzImage, BzImageError, ExtendedHeader, MAX_METADATA_SIZE};
+
+    let mut image = BzImage::from_data(b"built artifact").unwrap();
+    image.extended.set_meta("build-id", "4f2a9c").unwrap();
+    image.extended.set_meta("builder", "ci-17").unwrap();
+    image.extended.set_meta("build-id", "4f2a9d").unwrap();
+    let mut bytes = Vec::new();
+    image.write(&mut bytes).unwrap();
+
+    let read = BzImage::read(&mut &bytes[..]).unwrap();
+    assert_eq!(read.extended.get_meta("build-id"), Some("4f2a9d"));
+    assert_eq!(read.extended.get_meta("builder"), Some("ci-17"));
+    assert_eq!(read.extended.get_meta("missing"), None);
+    assert!(read.metadata().is_none());
+
+    // insertion order does not change the bytes
+    let mut a = ExtendedHeader::new();
+    let mut b = ExtendedHeader::new();
+    for (k, v) in [("z", "1"), ("a", "2"), ("m", "3")] {
+        a.set_meta(k, v).unwrap();
+    }
+    for (k, v) in [("m", "3"), ("z", "1"), ("a", "2")] {
+        b.set_meta(k, v).unwrap();
+    }
+    assert_eq!(a.to_bytes().unwrap(), b.to_bytes().unwrap());
+    let keys: Vec<_> = a.meta_iter().map(|(k, _)| k).collect();
+    assert_eq!(keys, ["a", "m", "z"]);
+    assert_eq!(ExtendedHeader::from_bytes(&a.to_bytes().unwrap()).unwrap().0, a);
+
+    // too much metadata is refused and leaves the map as it was
+    let mut big = ExtendedHeader::new();
+    big.set_meta("half", "x".repeat(MAX_METADATA_SIZE / 2)).unwrap();
+    let err = big.set_meta("more", "x".repeat(MAX_METADATA_SIZE / 2)).unwrap_err();
+    assert!(matches!(err, BzImageError::InvalidExtendedHeader(_)), "{err:?}");
+    assert_eq!(big.get_meta("more"), None);
+    assert_eq!(big.meta_iter().count(), 1);
+}
@@ -0,0 +1,39 @@
+//! The header API that stays available without the `std` feature. Run with
+//! `cargo test --no-default-features --test no_std` to check it builds that way.
+
+use bzimage::{BzImageError, BzImageHeader, Codec, HEADER_SIZE, MAGIC, VERSION};
+
+#[test]
+fn header_round_trips_through_bytes() {
+    let payload = b"early boot payload";
+    let mut header = BzImageHeader::new_for_payload(payload.len() as u64, payload);
+    header.set_codec(Codec::Stored);
+
+    let bytes = header.to_bytes();
+    assert_eq!(&bytes[..4], MAGIC);
+    let parsed = BzImageHeader::from_bytes(&bytes).unwrap();
+    assert_eq!(parsed.version(), VERSION);
+    assert_eq!(parsed.uncompressed_size(), payload.len() as u64);
+    assert_eq!(parsed.compressed_size(), payload.len() as u64);
+    assert_eq!(parsed.codec().unwrap(), Codec::Stored);
+    parsed.can_read().unwrap();
+    assert!(parsed.validate_checksum(payload));
+    parsed.validate_payload(payload).unwrap();
+
+    let mut tampered = *payload;
+    tampered[0] ^= 0xff;
+    assert!(!parsed.validate_checksum(&tampered));
+    assert!(matches!(
+        parsed.validate_payload(&tampered),
+        Err(BzImageError::ChecksumMismatch { .. })
+    ));
+
+    assert!(matches!(
+        BzImageHeader::from_bytes(&bytes[..HEADER_SIZE - 1]),
+        Err(BzImageError::TruncatedHeader)
+    ));
+    assert_eq!(
+        bzimage::expected_total_from_header(&bytes).unwrap(),
+        (HEADER_SIZE + payload.len()) as u64
+    );
+}